@@ -913,8 +913,8 @@ PlasmoidItem {
         return nonEmptyString(entry.identity.accountEmail).length > 0;
     }
 
-    function isSubscriptionPlan(loginMethod) {
-        var method = nonEmptyString(loginMethod).toLowerCase();
+    function isSubscriptionPlan(plan) {
+        var method = nonEmptyString(plan).toLowerCase();
         if (method.length === 0) {
             return false;
         }
@@ -940,8 +940,9 @@ PlasmoidItem {
         var dashboardUrl = nonEmptyString(metadata.dashboardUrl);
 
         if (provider === "claude") {
-            var loginMethod = entry && entry.identity ? entry.identity.loginMethod : "";
-            if (isSubscriptionPlan(loginMethod)) {
+            var identity = entry && entry.identity ? entry.identity : null;
+            var plan = identity ? nonEmptyString(identity.plan) || identity.loginMethod : "";
+            if (isSubscriptionPlan(plan)) {
                 var subscriptionUrl = nonEmptyString(metadata.subscriptionDashboardUrl);
                 if (subscriptionUrl.length > 0) {
                     dashboardUrl = subscriptionUrl;
@@ -1,28 +1,69 @@
-use anyhow::{bail, Context, Result};
-use clap::{Parser, Subcommand, ValueEnum};
-use codexbar_core::{now_iso8601, IdentityInfo, ProviderEntry, RateWindow, StatusInfo};
-use serde::Deserialize;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use codexbar_core::{
+    format_timestamp, now_unix_seconds, parse_unix_seconds, round_to_decimals, Clock,
+    CodexBarError, ProviderEntry, RateWindow, SystemClock, JSON_DECIMALS,
+};
+use codexbar_fetch::{
+    acquire_rpc_lock, active_secret_account, claude_credentials_paths,
+    claude_oauth_access_token_from_file, clean_token_value, clear_secret, codexbar_cache_dir,
+    collect_entries, create_private_file, fetch_concurrently, fetch_cursor_usage_summary_json,
+    find_binary_on_path, first_env_value, init_local_offset,
+    load_claude_oauth_access_token_from_credentials_file, load_custom_providers, load_usage_config,
+    local_offset_at, logout_secrets, parse_timeout_seconds, peek_claude_oauth_access_token,
+    probe_codex_app_server, provider_alias_notes, provider_health, provider_registry,
+    requested_providers, resolve_claude_oauth_access_token, resolve_claude_token_source,
+    run_command_with_timeout, select_providers, split_curl_body_and_status, store_secret,
+    ClaudeTokenSource, CodexSource, CustomProvider, FetchOptions, KeyringSecretBackend, RawDump,
+    UsageConfig, WindowOrder, PROVIDER_ENV, SOURCE_ENV,
+};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Map, Value};
-use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt::Write as _;
 use std::fs;
-use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::io::{self, ErrorKind, IsTerminal, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Child, ChildStdin, ChildStdout, Command, Output, Stdio};
-use std::time::{Duration, Instant, UNIX_EPOCH};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use time::{OffsetDateTime, UtcOffset};
 
 #[derive(Debug, Parser)]
 #[command(name = "codexbar")]
 #[command(about = "Rust CodexBar CLI (Linux-first bootstrap)")]
+#[command(
+    after_help = "Exit codes: 0 ok, 1 unexpected error, 2 usage error, 3 missing dependency, 4 auth required, 5 timeout, 6 no usage data, 7 upstream HTTP error, 8 unparseable response."
+)]
 struct Cli {
+    /// Keep human-readable output to plain ASCII; JSON is unaffected.
+    /// Implied when the locale isn't UTF-8.
+    #[arg(long, global = true, default_value_t = false)]
+    ascii_only: bool,
+
+    /// Load `KEY=VALUE` lines (e.g. `CODEXBAR_CLAUDE_OAUTH_TOKEN=...`) into
+    /// the environment before anything reads it. Variables that are already
+    /// set win over the file.
+    #[arg(long, global = true, value_name = "PATH")]
+    env_file: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
-    Usage(UsageArgs),
+    Usage(Box<UsageArgs>),
     Auth(AuthArgs),
     Remove(RemoveArgs),
+    ListProviders(ListProvidersArgs),
+    /// Check for the tools and credentials usage fetches rely on. Read-only.
+    Doctor,
+    /// Fetch every provider and write a shareable Markdown or HTML report.
+    Export(ExportArgs),
 }
 
 #[derive(Debug, Parser, Clone)]
@@ -30,23 +71,189 @@ struct UsageArgs {
     #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
     format: OutputFormat,
 
-    #[arg(long, default_value = "all")]
+    /// A provider, `all`, or a comma-separated list like `codex,gemini`.
+    /// Defaults to `$CODEXBAR_PROVIDER`, then `all`.
+    #[arg(long, default_value_t = default_usage_provider())]
     provider: String,
 
-    #[arg(long, default_value = "auto")]
+    /// Defaults to `$CODEXBAR_SOURCE`, then `auto`.
+    #[arg(long, default_value_t = default_usage_source())]
     source: String,
 
+    /// Fail instead of warning when `--source` isn't a known source label.
+    #[arg(long, default_value_t = false)]
+    strict_source: bool,
+
     #[arg(long, default_value_t = false)]
     status: bool,
 
     #[arg(long, default_value_t = false)]
     pretty: bool,
+
+    /// Text output as one line per provider, e.g. `codex 72% | 39% | $92`,
+    /// for status bars. Missing fields are left out.
+    #[arg(long, default_value_t = false)]
+    compact: bool,
+
+    /// Only print these values, e.g. `secondary.remaining,credits`: tab-separated
+    /// per provider in text output, an object with just these keys in JSON.
+    #[arg(
+        long,
+        value_name = "FIELD",
+        value_delimiter = ',',
+        value_parser = clap::builder::PossibleValuesParser::new(USAGE_FIELDS),
+    )]
+    fields: Vec<String>,
+
+    #[arg(long, value_enum, default_value_t = WindowOrder::Duration)]
+    window_order: WindowOrder,
+
+    #[arg(long, value_enum, default_value_t = CodexSource::Auto)]
+    codex_source: CodexSource,
+
+    /// Add `resetsAtUnix`/`resetsInSeconds` next to each window's `resetsAt`.
+    #[arg(long, default_value_t = false)]
+    rich_resets: bool,
+
+    /// Also flatten `accountEmail`/`accountOrganization`/`loginMethod` into
+    /// `usage`, next to the nested `identity`, for older widget versions.
+    #[arg(long, default_value_t = false)]
+    legacy_identity: bool,
+
+    /// Only show usage for this organization. An entry whose identity names a
+    /// different organization is an error; entries without one are kept.
+    /// Matching is case-insensitive. Filtering happens after the fetch since
+    /// codex's `account/read` has no organization parameter.
+    #[arg(long, value_name = "NAME")]
+    org: Option<String>,
+
+    /// With `--org`, drop entries for other organizations instead of failing.
+    #[arg(long, default_value_t = false, requires = "org")]
+    org_optional: bool,
+
+    /// Decimal places for percentages in text output.
+    #[arg(long, default_value_t = 0)]
+    precision: usize,
+
+    /// Decimal places kept for percentages and credits in JSON output.
+    #[arg(long, default_value_t = JSON_DECIMALS, value_parser = clap::value_parser!(u32).range(0..=9))]
+    decimals: u32,
+
+    /// Write whole numbers in JSON output as integers (`61`, not `61.0`).
+    #[arg(long, default_value_t = false)]
+    json_compact_numbers: bool,
+
+    /// Only check that each provider's CLI/credentials are present; skips usage fetches.
+    #[arg(long, default_value_t = false)]
+    health_only: bool,
+
+    /// Add each window's consumption rate since it last reset (`percentPerHour`).
+    #[arg(long, default_value_t = false)]
+    since_reset: bool,
+
+    /// Emit a placeholder entry, with the failure reason, for providers without data.
+    #[arg(long, default_value_t = false)]
+    include_missing: bool,
+
+    /// `source` reported by placeholder entries from `--include-missing`.
+    #[arg(long, default_value = "unavailable")]
+    missing_source: String,
+
+    /// Bar width for `--format bars`; defaults to fit `$COLUMNS`.
+    #[arg(long)]
+    bar_width: Option<usize>,
+
+    /// Render text and bars output with ASCII characters only.
+    #[arg(long, default_value_t = false)]
+    ascii: bool,
+
+    /// Remaining percent at or below which text and bars turn yellow.
+    #[arg(long, default_value_t = 20.0)]
+    warn_percent: f64,
+
+    /// Remaining percent at or below which text and bars turn red.
+    #[arg(long, default_value_t = 5.0)]
+    crit_percent: f64,
+
+    /// Print the value at this JSON Pointer (RFC 6901) into the JSON payload
+    /// instead of the usual output. Repeatable; one result per line.
+    #[arg(long = "query", value_name = "POINTER")]
+    queries: Vec<String>,
+
+    /// JSON or TOML file defining extra HTTP providers; see
+    /// `CustomProviderDefinition`. Repeatable; an id defined twice is an
+    /// error.
+    #[arg(long, value_name = "PATH")]
+    provider_file: Vec<PathBuf>,
+
+    /// The `--provider-file` definitions, loaded once before fetching.
+    #[arg(skip)]
+    custom_providers: Vec<CustomProvider>,
+
+    /// Seconds to wait on provider subprocesses and HTTP calls; each fetch
+    /// keeps its own default when unset.
+    #[arg(long, value_name = "SECONDS", value_parser = parse_timeout_seconds, allow_negative_numbers = true)]
+    timeout: Option<Duration>,
+
+    /// Reuse entries cached under `$XDG_CACHE_HOME/codexbar` for this many
+    /// seconds; a failed live fetch falls back to a cached entry up to a day
+    /// old. Entries are only reused for the same account and fetch options.
+    #[arg(long, value_name = "SECONDS")]
+    cache_ttl: Option<u64>,
+
+    /// Extra attempts at the Claude usage API after a 429 or 5xx response.
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+
+    /// Extra attempts at starting the codex app-server, with a short
+    /// randomized delay between them, before falling back to `/status`.
+    #[arg(long, default_value_t = 2)]
+    codex_start_retries: u32,
+
+    /// `CODEX_HOME` for the spawned `codex`, for credentials and config kept
+    /// outside `~/.codex`; without it an exported `CODEX_HOME` is passed on.
+    #[arg(long, value_name = "PATH")]
+    codex_home: Option<PathBuf>,
+
+    /// Also write the raw codex app-server messages and Claude usage
+    /// response to stderr, with tokens and cookies redacted.
+    #[arg(long, default_value_t = false)]
+    raw: bool,
+
+    /// Like `--raw`, but write one file per upstream into this directory.
+    #[arg(long, value_name = "DIR")]
+    raw_dir: Option<PathBuf>,
+
+    /// Seconds within which a repeated identical run reuses the previous
+    /// result instead of fetching again; 0 disables.
+    #[arg(long, value_name = "SECONDS", default_value_t = 5)]
+    min_interval: u64,
+
+    /// Re-render every this many seconds until Ctrl-C (twice to abandon a
+    /// hung fetch), keeping each provider's last good data (marked stale)
+    /// when its refresh fails.
+    #[arg(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    watch: Option<u64>,
 }
 
 #[derive(Debug, Parser, Clone)]
 struct AuthArgs {
-    #[arg(long, default_value = "claude")]
+    /// Also accepted after `status` or `logout`.
+    #[arg(long, global = true, default_value = "claude")]
     provider: String,
+
+    #[command(subcommand)]
+    action: Option<AuthAction>,
+}
+
+#[derive(Debug, Subcommand, Clone)]
+enum AuthAction {
+    /// Report whether a provider is signed in, without starting a login.
+    /// Never prints the token.
+    Status,
+    /// Delete the provider's tokens from the keyring (Secret Service and
+    /// KWallet). Succeeds when nothing was stored.
+    Logout,
 }
 
 #[derive(Debug, Parser, Clone)]
@@ -58,44 +265,545 @@ struct RemoveArgs {
     yes: bool,
 }
 
+#[derive(Debug, Parser, Clone)]
+struct ListProvidersArgs {
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+}
+
+#[derive(Debug, Parser, Clone)]
+struct ExportArgs {
+    #[arg(long, value_enum, default_value_t = ExportFormat::Md)]
+    format: ExportFormat,
+
+    /// Defaults like `usage --provider`: `$CODEXBAR_PROVIDER`, then
+    /// `config.toml`, then `all`.
+    #[arg(long)]
+    provider: Option<String>,
+
+    /// Write the report here instead of stdout.
+    #[arg(long, value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    /// Keep account emails in the report; they are redacted by default.
+    #[arg(long, default_value_t = false)]
+    include_identity: bool,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+enum ExportFormat {
+    Md,
+    Html,
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
 enum OutputFormat {
     Text,
     Json,
+    /// Text with a progress bar per window.
+    Bars,
+    /// InfluxDB line protocol, one line per window.
+    Influx,
+    /// One compact JSON object per provider per line.
+    Jsonl,
+    /// The JSON payload as a YAML sequence; `--pretty` has no effect.
+    Yaml,
 }
 
 impl Default for UsageArgs {
     fn default() -> Self {
         Self {
             format: OutputFormat::Text,
-            provider: "all".to_string(),
-            source: "auto".to_string(),
+            provider: default_usage_provider(),
+            source: default_usage_source(),
+            strict_source: false,
             status: false,
             pretty: false,
+            compact: false,
+            fields: Vec::new(),
+            window_order: WindowOrder::Duration,
+            codex_source: CodexSource::Auto,
+            rich_resets: false,
+            legacy_identity: false,
+            org: None,
+            org_optional: false,
+            precision: 0,
+            decimals: JSON_DECIMALS,
+            json_compact_numbers: false,
+            health_only: false,
+            since_reset: false,
+            include_missing: false,
+            missing_source: "unavailable".to_string(),
+            bar_width: None,
+            ascii: false,
+            warn_percent: 20.0,
+            crit_percent: 5.0,
+            queries: Vec::new(),
+            provider_file: Vec::new(),
+            custom_providers: Vec::new(),
+            timeout: None,
+            cache_ttl: None,
+            retries: 3,
+            codex_start_retries: 2,
+            codex_home: None,
+            raw: false,
+            raw_dir: None,
+            min_interval: 5,
+            watch: None,
+        }
+    }
+}
+
+impl UsageArgs {
+    fn fetch_options(&self) -> FetchOptions {
+        FetchOptions {
+            source: self.source.clone(),
+            status: self.status,
+            window_order: self.window_order,
+            codex_source: self.codex_source,
+            timeout: self.timeout,
+            retries: self.retries,
+            codex_start_retries: self.codex_start_retries,
+            codex_home: self.codex_home.clone(),
+            missing_source: self.include_missing.then(|| self.missing_source.clone()),
+            raw: match (&self.raw_dir, self.raw) {
+                (Some(dir), _) => Some(RawDump::Dir(dir.clone())),
+                (None, true) => Some(RawDump::Stderr),
+                (None, false) => None,
+            },
+            ..FetchOptions::default()
         }
     }
 }
 
 fn main() {
+    init_local_offset();
     if let Err(error) = run() {
         eprintln!("codexbar: {error:#}");
-        std::process::exit(1);
+        std::process::exit(exit_code(&error));
     }
 }
 
+/// See `CodexBarError::exit_code`; anything without a structured cause is 1.
+fn exit_code(error: &anyhow::Error) -> i32 {
+    error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<CodexBarError>())
+        .map_or(1, CodexBarError::exit_code)
+}
+
 fn run() -> Result<()> {
-    let cli = Cli::parse();
-    let command = cli.command.unwrap_or(Commands::Usage(UsageArgs::default()));
+    // Loaded ahead of clap so `$CODEXBAR_PROVIDER`-style defaults see it.
+    if let Some(path) = env_file_from_args(std::env::args_os().skip(1)) {
+        load_env_file(&path)?;
+    }
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+    let ascii_only = cli.ascii_only || !locale_is_utf8(&current_locale());
+    let command = cli
+        .command
+        .unwrap_or_else(|| Commands::Usage(Box::default()));
 
     match command {
-        Commands::Usage(args) => run_usage(&args),
+        Commands::Usage(mut args) => {
+            args.ascii |= ascii_only;
+            let usage_matches = matches.subcommand_matches("usage");
+            apply_config(
+                &load_usage_config()?,
+                &mut args,
+                |id| {
+                    usage_matches.is_some_and(|matches| {
+                        matches.value_source(id) == Some(ValueSource::CommandLine)
+                    })
+                },
+                process_env,
+            );
+            args.custom_providers = load_custom_providers(&args.provider_file)?;
+            run_usage(&args)
+        }
         Commands::Auth(args) => run_auth(&args),
         Commands::Remove(args) => run_remove(&args),
+        Commands::ListProviders(args) => run_list_providers(&args),
+        Commands::Doctor => run_doctor(),
+        Commands::Export(args) => run_export(&args),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct ProviderListing {
+    id: &'static str,
+    sources: &'static [&'static str],
+    has_credentials: bool,
+}
+
+fn run_list_providers(args: &ListProvidersArgs) -> Result<()> {
+    let listings = requested_providers("all")?
+        .into_iter()
+        .map(|provider| ProviderListing {
+            id: provider,
+            sources: provider_data_sources(provider),
+            has_credentials: provider_health(provider).healthy,
+        })
+        .collect::<Vec<_>>();
+
+    match args.format {
+        OutputFormat::Json => println!("{}", serde_json::to_string(&listings)?),
+        OutputFormat::Yaml => print!("{}", serde_yaml::to_string(&listings)?),
+        OutputFormat::Jsonl => {
+            for listing in &listings {
+                println!("{}", serde_json::to_string(listing)?);
+            }
+        }
+        OutputFormat::Text | OutputFormat::Bars | OutputFormat::Influx => {
+            for listing in &listings {
+                let credentials = if listing.has_credentials {
+                    "credentials found"
+                } else {
+                    "no credentials"
+                };
+                println!(
+                    "{} [{}] {credentials}",
+                    listing.id,
+                    listing.sources.join(", ")
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn provider_data_sources(provider: &str) -> &'static [&'static str] {
+    match provider {
+        "codex" => &["rpc", "status", "api"],
+        "claude" | "gemini" | "cursor" | "copilot" => &["api"],
+        _ => &[],
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct DoctorCheck {
+    name: String,
+    passed: bool,
+    /// Failing a required check makes `codexbar doctor` exit non-zero.
+    required: bool,
+    detail: String,
+}
+
+impl DoctorCheck {
+    fn new(name: &str, passed: bool, required: bool, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            passed,
+            required,
+            detail: detail.into(),
+        }
+    }
+}
+
+fn run_doctor() -> Result<()> {
+    let checks = doctor_checks();
+    print!("{}", render_doctor(&checks));
+
+    let missing = checks
+        .iter()
+        .filter(|check| check.required && !check.passed)
+        .map(|check| check.name.as_str())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        bail!("missing required dependencies: {}", missing.join(", "));
+    }
+    Ok(())
+}
+
+/// Probes only: nothing is written and no token is printed or refreshed.
+fn doctor_checks() -> Vec<DoctorCheck> {
+    let mut checks = [
+        ("curl", true, "needed by every usage API"),
+        ("codex", false, "needed for codex rpc and status sources"),
+        (
+            "claude",
+            false,
+            "needed for `codexbar auth --provider claude`",
+        ),
+        (
+            "secret-tool",
+            false,
+            "stores tokens in the Secret Service keyring",
+        ),
+        ("kwallet-query", false, "reads tokens from KWallet"),
+    ]
+    .into_iter()
+    .map(
+        |(program, required, purpose)| match find_binary_on_path(program) {
+            Some(path) => DoctorCheck::new(program, true, required, path.display().to_string()),
+            None => DoctorCheck::new(program, false, required, format!("not on PATH; {purpose}")),
+        },
+    )
+    .collect::<Vec<_>>();
+
+    checks.push(match peek_claude_oauth_access_token() {
+        Some(_) => DoctorCheck::new("claude oauth token", true, false, "resolved"),
+        None => DoctorCheck::new(
+            "claude oauth token",
+            false,
+            false,
+            "not found; run `codexbar auth --provider claude`",
+        ),
+    });
+
+    checks.push(match probe_codex_app_server() {
+        Ok(()) => DoctorCheck::new("codex app-server", true, false, "initialized"),
+        Err(error) => DoctorCheck::new("codex app-server", false, false, format!("{error:#}")),
+    });
+
+    checks
+}
+
+fn render_doctor(checks: &[DoctorCheck]) -> String {
+    let mut out = String::new();
+    for check in checks {
+        let mark = match (check.passed, check.required) {
+            (true, _) => "[ok]  ",
+            (false, true) => "[FAIL]",
+            (false, false) => "[warn]",
+        };
+        let _ = writeln!(out, "{mark} {}: {}", check.name, check.detail);
+    }
+    out
+}
+
+fn run_export(args: &ExportArgs) -> Result<()> {
+    let usage = export_usage_args(args, &load_usage_config()?, process_env);
+    validate_env_provider(&usage, process_env)?;
+    print_provider_alias_notes(&usage.provider);
+    let entries = selected_entries(&usage)?;
+    let sections = report_sections(&entries, args.include_identity);
+    let generated_at = format_timestamp(now_unix_seconds());
+    let report = match args.format {
+        ExportFormat::Md => render_markdown_report(&sections, &generated_at),
+        ExportFormat::Html => render_html_report(&sections, &generated_at),
+    };
+    match args.output.as_deref() {
+        Some(path) => fs::write(path, report)
+            .with_context(|| format!("failed to write report to {}", path.display())),
+        None => {
+            print!("{report}");
+            Ok(())
+        }
+    }
+}
+
+/// The fetch `usage` would run with the same config and environment, with
+/// status always on since the report has a row for it.
+fn export_usage_args(
+    args: &ExportArgs,
+    config: &UsageConfig,
+    env: impl Fn(&str) -> Option<String>,
+) -> UsageArgs {
+    let mut usage = UsageArgs {
+        provider: default_usage_provider_from(&env),
+        source: default_usage_source_from(&env),
+        status: true,
+        ..UsageArgs::default()
+    };
+    let explicit = |id: &str| match id {
+        "provider" => args.provider.is_some(),
+        "status" => true,
+        _ => false,
+    };
+    apply_config(config, &mut usage, explicit, env);
+    if let Some(provider) = &args.provider {
+        usage.provider = provider.clone();
+    }
+    usage
+}
+
+const REPORT_TITLE: &str = "CodexBar usage report";
+
+/// One provider of an export, already formatted so the Markdown and HTML
+/// renderers only differ in markup.
+#[derive(Debug, Clone, PartialEq)]
+struct ReportSection {
+    provider: String,
+    /// Label and value; fields the provider doesn't report are left out.
+    details: Vec<(&'static str, String)>,
+    /// Name, used, left and reset time per window.
+    windows: Vec<[String; 4]>,
+    error: Option<String>,
+}
+
+fn report_sections(entries: &[ProviderEntry], include_identity: bool) -> Vec<ReportSection> {
+    let percent = |value: Option<f64>| match value {
+        Some(value) => format!("{value:.0}%"),
+        None => "n/a".to_string(),
+    };
+    entries
+        .iter()
+        .map(|entry| {
+            let mut entry = entry.clone();
+            entry.name_unnamed_windows();
+            let identity = entry.identity.as_ref();
+            let status = entry.status.as_ref();
+            let details = [
+                ("Source", entry.source.clone()),
+                ("Plan", identity.and_then(|identity| identity.plan.clone())),
+                (
+                    "Login",
+                    identity.and_then(|identity| identity.login_method.clone()),
+                ),
+                (
+                    "Account",
+                    identity
+                        .and_then(|identity| identity.account_email.clone())
+                        .map(|email| {
+                            if include_identity {
+                                email
+                            } else {
+                                "[redacted]".to_string()
+                            }
+                        }),
+                ),
+                (
+                    "Organization",
+                    identity.and_then(|identity| identity.account_organization.clone()),
+                ),
+                (
+                    "Credits",
+                    entry
+                        .credits_remaining
+                        .map(|credits| format!("{credits:.1}")),
+                ),
+                (
+                    "Code review left",
+                    entry
+                        .code_review_remaining_percent
+                        .map(|left| percent(Some(left))),
+                ),
+                (
+                    "Status",
+                    status.and_then(|status| {
+                        status
+                            .description
+                            .clone()
+                            .or_else(|| status.indicator.clone())
+                    }),
+                ),
+                ("Updated", Some(entry.updated_at.clone())),
+            ]
+            .into_iter()
+            .filter_map(|(label, value)| Some((label, value?)))
+            .collect();
+            let windows = entry
+                .windows
+                .iter()
+                .map(|named| {
+                    [
+                        named.name.clone(),
+                        percent(named.window.used_percent),
+                        percent(named.window.remaining_percent()),
+                        named
+                            .window
+                            .resets_at
+                            .clone()
+                            .unwrap_or_else(|| "n/a".to_string()),
+                    ]
+                })
+                .collect();
+            ReportSection {
+                provider: entry.provider.clone(),
+                details,
+                windows,
+                error: entry.error.clone(),
+            }
+        })
+        .collect()
+}
+
+fn render_markdown_report(sections: &[ReportSection], generated_at: &str) -> String {
+    // A newline would end the table row, so it becomes a `<br>`.
+    let cell = |text: &str| {
+        text.replace('|', "\\|")
+            .replace("\r\n", "<br>")
+            .replace('\n', "<br>")
+    };
+    let mut out = format!("# {REPORT_TITLE}\n\nGenerated {generated_at}.\n");
+    for section in sections {
+        let _ = writeln!(out, "\n## {}\n", section.provider);
+        if let Some(error) = section.error.as_deref() {
+            let _ = writeln!(out, "Error: {}", cell(error));
+            continue;
+        }
+        out.push_str("| Field | Value |\n| --- | --- |\n");
+        for (label, value) in &section.details {
+            let _ = writeln!(out, "| {label} | {} |", cell(value));
+        }
+        if section.windows.is_empty() {
+            continue;
+        }
+        out.push_str("\n| Window | Used | Left | Resets |\n| --- | ---: | ---: | --- |\n");
+        for row in &section.windows {
+            let row = row.iter().map(|value| cell(value)).collect::<Vec<_>>();
+            let _ = writeln!(out, "| {} |", row.join(" | "));
+        }
+    }
+    out
+}
+
+fn render_html_report(sections: &[ReportSection], generated_at: &str) -> String {
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{REPORT_TITLE}</title>\n</head>\n<body>\n<h1>{REPORT_TITLE}</h1>\n<p>Generated {}.</p>\n",
+        html_escape(generated_at)
+    );
+    for section in sections {
+        let _ = writeln!(out, "<h2>{}</h2>", html_escape(&section.provider));
+        if let Some(error) = section.error.as_deref() {
+            let _ = writeln!(out, "<p>Error: {}</p>", html_escape(error));
+            continue;
+        }
+        out.push_str("<table>\n<tr><th>Field</th><th>Value</th></tr>\n");
+        for (label, value) in &section.details {
+            let _ = writeln!(
+                out,
+                "<tr><td>{label}</td><td>{}</td></tr>",
+                html_escape(value)
+            );
+        }
+        out.push_str("</table>\n");
+        if section.windows.is_empty() {
+            continue;
+        }
+        out.push_str(
+            "<table>\n<tr><th>Window</th><th>Used</th><th>Left</th><th>Resets</th></tr>\n",
+        );
+        for row in &section.windows {
+            out.push_str("<tr>");
+            for value in row {
+                let _ = write!(out, "<td>{}</td>", html_escape(value));
+            }
+            out.push_str("</tr>\n");
+        }
+        out.push_str("</table>\n");
     }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
 
 fn run_auth(args: &AuthArgs) -> Result<()> {
     let provider = normalize_provider_id(args.provider.trim());
+    match args.action {
+        Some(AuthAction::Status) => return run_auth_status(&provider),
+        Some(AuthAction::Logout) => return run_auth_logout(&provider),
+        None => {}
+    }
     if matches!(provider.as_str(), "interactive" | "prompt" | "wizard") {
         return run_interactive_auth_flow();
     }
@@ -106,6 +814,67 @@ fn run_auth(args: &AuthArgs) -> Result<()> {
     Ok(())
 }
 
+/// Prints where the provider's credentials come from, failing with
+/// `AuthRequired` (exit code 4) when there are none.
+fn run_auth_status(provider: &str) -> Result<()> {
+    let (authenticated, detail) = match provider {
+        "claude" => match resolve_claude_token_source() {
+            Some(ClaudeTokenSource::CredentialsFile) => {
+                let path = claude_credentials_paths()
+                    .into_iter()
+                    .find(|path| claude_oauth_access_token_from_file(path).is_some());
+                let detail = match path {
+                    Some(path) => format!("token from credentials file {}", path.display()),
+                    None => "token from credentials file".to_string(),
+                };
+                (true, detail)
+            }
+            Some(source) => (true, format!("token from {}", source.describe())),
+            None => (
+                false,
+                "no token in env, keyring or credentials file".to_string(),
+            ),
+        },
+        "codex" => match probe_codex_app_server() {
+            Ok(()) => (true, "codex app-server initialized".to_string()),
+            Err(error) => (false, format!("{error:#}")),
+        },
+        _ => {
+            let health = provider_health(provider);
+            (health.healthy, health.reason)
+        }
+    };
+
+    if authenticated {
+        println!("{provider}: authenticated ({detail})");
+        Ok(())
+    } else {
+        println!("{provider}: not authenticated ({detail})");
+        Err(
+            CodexBarError::AuthRequired(format!("run `codexbar auth --provider {provider}`"))
+                .into(),
+        )
+    }
+}
+
+fn run_auth_logout(provider: &str) -> Result<()> {
+    if !is_pinnable_provider(provider) {
+        bail!("unsupported provider '{provider}'");
+    }
+    let removed = logout_secrets(
+        &KeyringSecretBackend::system(),
+        provider,
+        &active_secret_account(),
+    );
+    if removed.is_empty() {
+        println!("{provider}: no stored credentials");
+    }
+    for entry in removed {
+        println!("{provider}: removed {entry}");
+    }
+    Ok(())
+}
+
 fn run_remove(args: &RemoveArgs) -> Result<()> {
     let provider = normalize_provider_id(args.provider.trim());
     if !is_pinnable_provider(&provider) {
@@ -250,9 +1019,10 @@ fn remove_provider_data(provider: &str) -> Result<()> {
     match provider {
         "codex" => {
             run_optional_command("codex", &["logout"], Duration::from_secs(12));
+            clear_secret("codex", "access_token");
         }
         "claude" => {
-            clear_claude_secret("oauth_access_token");
+            clear_secret("claude", "oauth_access_token");
         }
         "cursor" => {}
         "gemini" => {
@@ -304,7 +1074,8 @@ fn run_claude_auth() -> Result<()> {
     if let Some(access_token) = load_claude_oauth_access_token_from_credentials_file()
         .or_else(resolve_claude_oauth_access_token)
     {
-        if let Err(error) = store_claude_secret(
+        if let Err(error) = store_secret(
+            "claude",
             "oauth_access_token",
             "CodexBar Claude OAuth Access Token",
             &access_token,
@@ -654,2297 +1425,2780 @@ fn is_pinnable_provider(provider_id: &str) -> bool {
     )
 }
 
-fn run_usage(args: &UsageArgs) -> Result<()> {
-    let entries = selected_entries(args)?;
+/// The process environment, as the functions below read it by default;
+/// tests pass their own lookup instead of mutating it.
+fn process_env(name: &str) -> Option<String> {
+    first_env_value(&[name])
+}
 
-    match args.format {
-        OutputFormat::Json => {
-            let payload = entries
-                .iter()
-                .map(|entry| cli_payload(entry, args))
-                .collect::<Vec<_>>();
+fn default_usage_provider() -> String {
+    default_usage_provider_from(process_env)
+}
 
-            if args.pretty {
-                println!("{}", serde_json::to_string_pretty(&payload)?);
-            } else {
-                println!("{}", serde_json::to_string(&payload)?);
-            }
+fn default_usage_provider_from(env: impl Fn(&str) -> Option<String>) -> String {
+    env(PROVIDER_ENV).unwrap_or_else(|| "all".to_string())
+}
+
+fn default_usage_source() -> String {
+    default_usage_source_from(process_env)
+}
+
+fn default_usage_source_from(env: impl Fn(&str) -> Option<String>) -> String {
+    env(SOURCE_ENV).unwrap_or_else(|| "auto".to_string())
+}
+
+/// Once per command, not per fetch: `--watch` resolves the list every tick.
+fn print_provider_alias_notes(provider: &str) {
+    for note in provider_alias_notes(provider) {
+        eprintln!("codexbar: note: {note}");
+    }
+}
+
+/// Rejects a bad `$CODEXBAR_PROVIDER` by name, unless `--provider` overrode it.
+fn validate_env_provider(args: &UsageArgs, env: impl Fn(&str) -> Option<String>) -> Result<()> {
+    match env(PROVIDER_ENV) {
+        Some(provider) if provider == args.provider => {
+            select_providers(&provider_registry(&args.custom_providers), &provider)
+                .map(|_| ())
+                .with_context(|| format!("invalid {PROVIDER_ENV}"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Applies `config.toml` under the precedence `UsageConfig::fetch_options`
+/// uses; `explicit` reports whether an argument id was given on the command
+/// line, and such flags win over both the file and the environment.
+fn apply_config(
+    config: &UsageConfig,
+    args: &mut UsageArgs,
+    explicit: impl Fn(&str) -> bool,
+    env: impl Fn(&str) -> Option<String>,
+) {
+    if let Some(provider) = &config.provider {
+        if !explicit("provider") && env(PROVIDER_ENV).is_none() {
+            args.provider = provider.clone();
+        }
+    }
+    if let Some(source) = &config.source {
+        if !explicit("source") && env(SOURCE_ENV).is_none() {
+            args.source = source.clone();
+        }
+    }
+    if let Some(status) = config.status {
+        if !explicit("status") {
+            args.status = status;
+        }
+    }
+    if let Some(timeout) = config.timeout {
+        if !explicit("timeout") {
+            args.timeout = Some(timeout);
+        }
+    }
+}
+
+/// Finds `--env-file PATH` or `--env-file=PATH` anywhere before `--`; the
+/// last one wins, as it does for clap.
+fn env_file_from_args<I>(args: I) -> Option<PathBuf>
+where
+    I: IntoIterator<Item = OsString>,
+{
+    let mut args = args.into_iter();
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
         }
-        OutputFormat::Text => {
-            print_text(entries);
+        if arg == "--env-file" {
+            path = args.next().map(PathBuf::from);
+        } else if let Some(value) = arg.to_str().and_then(|arg| arg.strip_prefix("--env-file=")) {
+            path = Some(PathBuf::from(value));
         }
     }
+    path
+}
 
+fn load_env_file(path: &Path) -> Result<()> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("failed to read env file {}", path.display()))?;
+    let pairs = parse_env_file(&text).map_err(|error| anyhow!("{}:{error}", path.display()))?;
+    for (key, value) in pairs {
+        if std::env::var_os(&key).is_none() {
+            std::env::set_var(key, value);
+        }
+    }
     Ok(())
 }
 
-fn selected_entries(args: &UsageArgs) -> Result<Vec<ProviderEntry>> {
-    let providers = requested_providers(&args.provider)?;
-    let mut entries = Vec::with_capacity(providers.len());
-
-    for provider in providers {
-        let live = match fetch_live_entry(provider, args) {
-            Ok(entry) => entry,
-            Err(error) => {
-                eprintln!("codexbar: provider '{provider}' live fetch failed: {error:#}");
-                None
+/// Parses dotenv lines: `KEY=value`, optionally prefixed with `export`.
+/// Blank lines and `#` comments are skipped, as is a ` #` comment after an
+/// unquoted value. Single-quoted values are literal; double-quoted ones
+/// understand `\n`, `\"` and `\\`. Errors are `<line>: <message>`.
+fn parse_env_file(text: &str) -> Result<Vec<(String, String)>, String> {
+    let mut pairs = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let invalid = |message: &str| format!("{}: {message}", index + 1);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").map_or(line, str::trim_start);
+        let (key, raw) = line
+            .split_once('=')
+            .ok_or_else(|| invalid("expected KEY=VALUE"))?;
+        let key = key.trim();
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || ch == '_')
+        {
+            return Err(invalid(&format!("`{key}` is not a valid variable name")));
+        }
+
+        let raw = raw.trim();
+        let value = if let Some(rest) = raw.strip_prefix('\'') {
+            let (value, trailing) = rest
+                .split_once('\'')
+                .ok_or_else(|| invalid("unterminated single quote"))?;
+            env_file_trailing(trailing).map_err(invalid)?;
+            value.to_string()
+        } else if let Some(rest) = raw.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = rest.chars();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => value.push('\n'),
+                        Some(other) => value.push(other),
+                        None => return Err(invalid("unterminated double quote")),
+                    },
+                    Some(ch) => value.push(ch),
+                    None => return Err(invalid("unterminated double quote")),
+                }
+            }
+            env_file_trailing(chars.as_str()).map_err(invalid)?;
+            value
+        } else {
+            match raw.find(" #") {
+                Some(comment) => raw[..comment].trim_end().to_string(),
+                None => raw.to_string(),
             }
         };
+        pairs.push((key.to_string(), value));
+    }
+    Ok(pairs)
+}
 
-        if let Some(entry) = live {
-            entries.push(entry);
-        } else {
-            eprintln!("codexbar: provider '{provider}' has no live usage data");
-        }
+/// Only whitespace or a comment may follow a quoted value.
+fn env_file_trailing(rest: &str) -> Result<(), &'static str> {
+    let rest = rest.trim();
+    if rest.is_empty() || rest.starts_with('#') {
+        Ok(())
+    } else {
+        Err("unexpected text after quoted value")
     }
+}
 
-    if entries.is_empty() {
-        bail!(
-            "no live usage data available for provider '{}'; ensure corresponding CLI tools are installed and authenticated",
-            args.provider
-        );
+/// Labels the fetchers report as `source`. A `--source` other than `auto`
+/// replaces them verbatim, so anything else is most likely a typo.
+const KNOWN_SOURCES: [&str; 10] = [
+    "codex-cli",
+    "codex-status",
+    "claude-oauth-api",
+    "oauth",
+    "openai-web",
+    "rust",
+    "api",
+    "cursor-usage-summary",
+    "github-copilot-api",
+    "custom",
+];
+
+fn validate_source(args: &UsageArgs) -> Result<()> {
+    let source = args.source.trim();
+    if source.eq_ignore_ascii_case("auto")
+        || KNOWN_SOURCES
+            .iter()
+            .any(|known| known.eq_ignore_ascii_case(source))
+    {
+        return Ok(());
     }
 
-    Ok(entries)
+    let message = format!(
+        "unknown source '{source}'; expected auto or one of: {}",
+        KNOWN_SOURCES.join(", ")
+    );
+    if args.strict_source {
+        bail!(message);
+    }
+    eprintln!("codexbar: warning: {message}");
+    Ok(())
 }
 
-fn cli_payload(entry: &ProviderEntry, args: &UsageArgs) -> Value {
-    let resolved_source = if args.source.eq_ignore_ascii_case("auto") {
-        entry.source.as_deref().unwrap_or("rust").to_string()
-    } else {
-        args.source.clone()
-    };
-
-    let identity_payload = entry
-        .identity
-        .as_ref()
-        .map(|identity| {
-            json!({
-                "providerID": entry.provider,
-                "accountEmail": identity.account_email,
-                "accountOrganization": identity.account_organization,
-                "loginMethod": identity.login_method,
-            })
-        })
-        .unwrap_or(Value::Null);
-
-    let usage = json!({
-        "primary": rate_window_value(entry.primary.as_ref()),
-        "secondary": rate_window_value(entry.secondary.as_ref()),
-        "tertiary": rate_window_value(entry.tertiary.as_ref()),
-        "updatedAt": entry.updated_at,
-        "identity": identity_payload,
-        "accountEmail": entry.identity.as_ref().and_then(|identity| identity.account_email.clone()),
-        "accountOrganization": entry.identity.as_ref().and_then(|identity| identity.account_organization.clone()),
-        "loginMethod": entry.identity.as_ref().and_then(|identity| identity.login_method.clone()),
-    });
-
-    let credits = entry
-        .credits_remaining
-        .map(|remaining| {
-            json!({
-                "remaining": remaining,
-                "updatedAt": entry.updated_at
-            })
-        })
-        .unwrap_or(Value::Null);
-
-    let openai_dashboard = entry
-        .code_review_remaining_percent
-        .map(|remaining| {
-            json!({
-                "codeReviewRemainingPercent": remaining,
-                "updatedAt": entry.updated_at
-            })
-        })
-        .unwrap_or(Value::Null);
-
-    let status = if args.status {
-        entry
-            .status
-            .as_ref()
-            .map(|status| {
-                json!({
-                    "indicator": status.indicator,
-                    "description": status.description,
-                    "updatedAt": status.updated_at,
-                    "url": status.url
-                })
-            })
-            .unwrap_or(Value::Null)
-    } else {
-        Value::Null
-    };
-
-    json!({
-        "provider": entry.provider,
-        "version": env!("CARGO_PKG_VERSION"),
-        "source": resolved_source,
-        "status": status,
-        "usage": usage,
-        "credits": credits,
-        "antigravityPlanInfo": Value::Null,
-        "openaiDashboard": openai_dashboard
-    })
-}
-
-fn rate_window_value(window: Option<&RateWindow>) -> Value {
-    match window {
-        Some(window) => json!({
-            "usedPercent": window.used_percent,
-            "windowMinutes": window.window_minutes,
-            "resetsAt": window.resets_at,
-        }),
-        None => Value::Null,
+fn run_usage(args: &UsageArgs) -> Result<()> {
+    validate_env_provider(args, process_env)?;
+    print_provider_alias_notes(&args.provider);
+    validate_source(args)?;
+    if args.pretty && args.format == OutputFormat::Jsonl {
+        bail!("--pretty cannot be combined with --format jsonl; each line must be one JSON object");
+    }
+    if args.compact && args.format != OutputFormat::Text {
+        bail!("--compact only applies to --format text");
+    }
+    if !args.fields.is_empty() {
+        if args.compact || !args.queries.is_empty() {
+            bail!("--fields cannot be combined with --compact or --query");
+        }
+        if matches!(args.format, OutputFormat::Bars | OutputFormat::Influx) {
+            bail!("--fields only applies to text, json, jsonl and yaml output");
+        }
+    }
+    if args.health_only {
+        return run_health_check(args);
+    }
+    if let Some(interval) = args.watch {
+        if !args.queries.is_empty() {
+            bail!("--watch cannot be combined with --query");
+        }
+        return run_watch(args, Duration::from_secs(interval));
     }
-}
 
-fn print_text(entries: Vec<ProviderEntry>) {
-    for entry in entries {
-        let session_left = remaining_percent(entry.primary.as_ref());
-        let weekly_left = remaining_percent(entry.secondary.as_ref());
+    let entries = selected_entries(args)?;
 
-        println!(
-            "== {} ({}) ==",
-            entry.provider,
-            entry.source.unwrap_or_else(|| "rust".to_string())
+    if !args.queries.is_empty() {
+        let payload = Value::Array(
+            entries
+                .iter()
+                .map(|entry| cli_payload(entry, args))
+                .collect(),
         );
-        println!("Session: {}", format_percent(session_left));
-        println!("Weekly: {}", format_percent(weekly_left));
-        if let Some(credits) = entry.credits_remaining {
-            println!("Credits: {:.1}", credits);
+        for line in query_json_pointers(&payload, &args.queries)? {
+            println!("{line}");
         }
-        println!("Updated: {}", entry.updated_at);
-        println!();
+        return Ok(());
     }
-}
 
-fn remaining_percent(window: Option<&RateWindow>) -> Option<f64> {
-    window.and_then(|window| {
-        window
-            .used_percent
-            .map(|used| (100.0 - used).max(0.0).min(100.0))
-    })
+    print!("{}", render_usage(&entries, args)?);
+    Ok(())
 }
 
-fn format_percent(value: Option<f64>) -> String {
-    match value {
-        Some(value) => format!("{value:.0}% left"),
-        None => "n/a".to_string(),
-    }
+fn render_usage(entries: &[ProviderEntry], args: &UsageArgs) -> Result<String> {
+    render_usage_at(entries, args, now_unix_seconds())
 }
 
-fn requested_providers(raw: &str) -> Result<Vec<&'static str>> {
-    let normalized = raw.trim().to_ascii_lowercase();
-    match normalized.as_str() {
-        "all" | "both" => Ok(vec!["codex", "claude", "gemini", "cursor", "copilot"]),
-        "codex" => Ok(vec!["codex"]),
-        "claude" => Ok(vec!["claude"]),
-        "gemini" => Ok(vec!["gemini"]),
-        "cursor" => Ok(vec!["cursor"]),
-        "copilot" => Ok(vec!["copilot"]),
-        _ => bail!("unknown provider '{}'", raw),
+/// [`render_usage`] with relative reset times counted from `now`.
+fn render_usage_at(entries: &[ProviderEntry], args: &UsageArgs, now: i64) -> Result<String> {
+    if !args.fields.is_empty() {
+        return render_fields(entries, args);
     }
+    Ok(match args.format {
+        OutputFormat::Json => {
+            let payload = entries
+                .iter()
+                .map(|entry| cli_payload(entry, args))
+                .collect::<Vec<_>>();
+
+            if args.pretty {
+                format!("{}\n", serde_json::to_string_pretty(&payload)?)
+            } else {
+                format!("{}\n", serde_json::to_string(&payload)?)
+            }
+        }
+        OutputFormat::Text => render_text(entries, args, now, stdout_supports_color()),
+        OutputFormat::Bars => render_bars(entries, args, &BarStyle::detect(args)),
+        OutputFormat::Influx => entries
+            .iter()
+            .flat_map(influx_lines)
+            .map(|line| line + "\n")
+            .collect(),
+        OutputFormat::Jsonl => jsonl_lines(entries, args)?
+            .into_iter()
+            .map(|line| line + "\n")
+            .collect(),
+        OutputFormat::Yaml => {
+            let payload = entries
+                .iter()
+                .map(|entry| cli_payload(entry, args))
+                .collect::<Vec<_>>();
+            serde_yaml::to_string(&payload)?
+        }
+    })
 }
 
-fn fetch_live_entry(provider: &str, args: &UsageArgs) -> Result<Option<ProviderEntry>> {
-    match provider {
-        "codex" => fetch_codex_entry(args),
-        "claude" => fetch_claude_entry(args),
-        "gemini" => fetch_gemini_entry(args),
-        "cursor" => fetch_cursor_entry(args),
-        "copilot" => fetch_copilot_entry(args),
-        _ => Ok(None),
+/// `--watch`: redraws human formats in place and streams machine formats one
+/// tick after another. The first Ctrl-C only sets a flag, so an in-flight
+/// fetch finishes and drops its codex app-server child before the loop exits.
+/// A second Ctrl-C exits at once for a fetch that hangs; the terminal delivers
+/// that SIGINT to the app-server child as well.
+fn run_watch(args: &UsageArgs, interval: Duration) -> Result<()> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = Arc::clone(&interrupted);
+    // SAFETY: the handler only touches an atomic and calls `_exit`, both of
+    // which are async-signal-safe.
+    unsafe {
+        signal_hook_registry::register(libc::SIGINT, move || {
+            if flag.swap(true, Ordering::SeqCst) {
+                libc::_exit(130);
+            }
+        })
     }
-}
+    .context("failed to install Ctrl-C handler")?;
+
+    let registry = provider_registry(&args.custom_providers);
+    let providers = select_providers(&registry, &args.provider)?;
+    let mut shown = Vec::new();
+    while !interrupted.load(Ordering::SeqCst) {
+        let fresh = selected_entries(args).map_err(|error| format!("{error:#}"));
+        if interrupted.load(Ordering::SeqCst) {
+            break;
+        }
+        let (entries, stale) = merge_watch_entries(&shown, fresh, &providers);
+        shown = entries;
 
-fn fetch_codex_entry(args: &UsageArgs) -> Result<Option<ProviderEntry>> {
-    match fetch_codex_entry_via_rpc(args) {
-        Ok(Some(entry)) => return Ok(Some(entry)),
-        Ok(None) => {}
-        Err(error) => {
-            eprintln!("codexbar: codex RPC fetch failed, trying /status fallback: {error:#}");
+        print!("{}", watch_frame(&shown, args, &stale, &SystemClock)?);
+        io::stdout().flush().context("failed to flush stdout")?;
+
+        let deadline = Instant::now() + interval;
+        while Instant::now() < deadline && !interrupted.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(100));
         }
     }
-
-    fetch_codex_entry_via_status(args)
+    Ok(())
 }
 
-fn fetch_codex_entry_via_rpc(args: &UsageArgs) -> Result<Option<ProviderEntry>> {
-    let mut session = match CodexRpcSession::start()? {
-        Some(session) => session,
-        None => return Ok(None),
-    };
-
-    session.initialize()?;
-    let account = session.fetch_account().ok();
-    let limits = session
-        .fetch_rate_limits()
-        .context("failed to fetch codex rate limits via app-server")?;
-
-    let primary = rate_window_from_codex(limits.rate_limits.primary);
-    let secondary = rate_window_from_codex(limits.rate_limits.secondary);
-    if primary.is_none() && secondary.is_none() {
-        return Ok(None);
-    }
-
-    let identity =
-        account
-            .and_then(|response| response.account)
-            .and_then(|details| match details {
-                RpcAccountDetails::ApiKey => None,
-                RpcAccountDetails::ChatGPT { email, plan_type } => Some(IdentityInfo {
-                    account_email: email,
-                    account_organization: None,
-                    login_method: plan_type,
-                }),
-            });
-
-    let credits_remaining = limits
-        .rate_limits
-        .credits
-        .and_then(|credits| credits.balance)
-        .and_then(|balance| balance.parse::<f64>().ok());
-
-    Ok(Some(build_codex_entry(
-        args,
-        primary,
-        secondary,
-        credits_remaining,
-        identity,
-        "codex-cli",
-    )))
-}
-
-fn fetch_codex_entry_via_status(args: &UsageArgs) -> Result<Option<ProviderEntry>> {
-    let output = match run_command_with_timeout_and_input(
-        "codex",
-        &["-s", "read-only", "-a", "untrusted"],
-        Some("/status\n"),
-        Duration::from_secs(20),
-    ) {
-        Ok(output) => output,
-        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
-        Err(error) if error.kind() == ErrorKind::TimedOut => return Ok(None),
-        Err(error) => return Err(error).context("failed to run codex /status"),
+/// A provider `--watch` is still showing the previous refresh for, and why.
+#[derive(Debug, Clone, PartialEq)]
+struct StaleProvider {
+    provider: String,
+    reason: String,
+}
+
+/// Folds one `--watch` refresh into the entries on screen, provider by
+/// provider: one the refresh failed for (the whole fetch, or just it, leaving
+/// it absent or as an error placeholder) keeps its previous entry and is
+/// reported stale.
+fn merge_watch_entries(
+    shown: &[ProviderEntry],
+    fresh: Result<Vec<ProviderEntry>, String>,
+    providers: &[&str],
+) -> (Vec<ProviderEntry>, Vec<StaleProvider>) {
+    let (fresh, failure) = match fresh {
+        Ok(entries) => (entries, None),
+        Err(error) => (Vec::new(), Some(error)),
     };
-
-    let text = format!(
-        "{}\n{}",
-        String::from_utf8_lossy(&output.stdout),
-        String::from_utf8_lossy(&output.stderr)
-    );
-    let cleaned = strip_ansi_sequences(&text);
-
-    let five_line = first_line_containing_case_insensitive(&cleaned, "5h limit");
-    let weekly_line = first_line_containing_case_insensitive(&cleaned, "weekly limit");
-    let five_left = five_line
-        .as_deref()
-        .and_then(extract_percent_left_from_line)
-        .map(|left| (100.0 - left).clamp(0.0, 100.0));
-    let weekly_left = weekly_line
-        .as_deref()
-        .and_then(extract_percent_left_from_line)
-        .map(|left| (100.0 - left).clamp(0.0, 100.0));
-    let credits_remaining = extract_credits_from_status(&cleaned);
-
-    if five_left.is_none() && weekly_left.is_none() && credits_remaining.is_none() {
-        return Ok(None);
-    }
-
-    let primary = five_left.map(|used| RateWindow {
-        used_percent: Some(used),
-        window_minutes: Some(300),
-        resets_at: None,
-    });
-    let secondary = weekly_left.map(|used| RateWindow {
-        used_percent: Some(used),
-        window_minutes: Some(10080),
-        resets_at: None,
-    });
-
-    Ok(Some(build_codex_entry(
-        args,
-        primary,
-        secondary,
-        credits_remaining,
-        None,
-        "codex-status",
-    )))
+    let mut entries = Vec::new();
+    let mut stale = Vec::new();
+    for &provider in providers {
+        let fresh = fresh.iter().find(|entry| entry.provider == provider);
+        if let Some(entry) = fresh.filter(|entry| entry.error.is_none()) {
+            entries.push(entry.clone());
+            continue;
+        }
+        let previous = shown
+            .iter()
+            .find(|entry| entry.provider == provider && entry.error.is_none());
+        match (previous, fresh) {
+            (Some(previous), _) => {
+                let reason = failure
+                    .clone()
+                    .or_else(|| fresh.and_then(|entry| entry.error.clone()))
+                    .unwrap_or_else(|| "no live usage data".to_string());
+                entries.push(previous.clone());
+                stale.push(StaleProvider {
+                    provider: provider.to_string(),
+                    reason,
+                });
+            }
+            (None, Some(placeholder)) => entries.push(placeholder.clone()),
+            (None, None) => {}
+        }
+    }
+    (entries, stale)
 }
 
-fn build_codex_entry(
+/// One `--watch` refresh. Text and bars clear the screen first and carry a
+/// `(stale)` line per provider whose refresh failed; other formats report
+/// them on stderr so their output stays parseable.
+fn watch_frame(
+    entries: &[ProviderEntry],
     args: &UsageArgs,
-    primary: Option<RateWindow>,
-    secondary: Option<RateWindow>,
-    credits_remaining: Option<f64>,
-    identity: Option<IdentityInfo>,
-    default_source: &str,
-) -> ProviderEntry {
-    let source = if args.source.eq_ignore_ascii_case("auto") {
-        default_source.to_string()
-    } else {
-        args.source.clone()
-    };
-    let status = if args.status {
-        Some(StatusInfo {
-            indicator: Some("none".to_string()),
-            description: Some("Operational".to_string()),
-            updated_at: Some(now_iso8601()),
-            url: Some("https://status.openai.com/".to_string()),
-        })
-    } else {
-        None
-    };
+    stale: &[StaleProvider],
+    clock: &dyn Clock,
+) -> Result<String> {
+    let rendered = render_usage_at(entries, args, clock.unix_seconds())?;
+    if !matches!(args.format, OutputFormat::Text | OutputFormat::Bars) {
+        for StaleProvider { provider, reason } in stale {
+            eprintln!(
+                "codexbar: provider '{provider}' refresh failed, repeating last data (stale): {reason}"
+            );
+        }
+        return Ok(rendered);
+    }
 
-    ProviderEntry {
-        provider: "codex".to_string(),
-        source: Some(source),
-        updated_at: now_iso8601(),
-        primary,
-        secondary,
-        tertiary: None,
-        credits_remaining,
-        code_review_remaining_percent: None,
-        identity,
-        status,
+    let mut frame = String::from("\x1b[2J\x1b[H");
+    for StaleProvider { provider, reason } in stale {
+        let _ = writeln!(frame, "(stale) {provider}: refresh failed: {reason}");
+    }
+    if !stale.is_empty() {
+        frame.push('\n');
     }
+    frame.push_str(&rendered);
+    Ok(frame)
 }
 
-fn fetch_claude_entry(args: &UsageArgs) -> Result<Option<ProviderEntry>> {
-    let access_token = match resolve_claude_oauth_access_token() {
-        Some(value) => value,
-        None => return Ok(None),
-    };
-
-    let output =
-        match fetch_json_with_bearer("https://api.anthropic.com/api/oauth/usage", &access_token) {
-            Ok(output) => output,
-            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
-            Err(error) if error.kind() == ErrorKind::TimedOut => return Ok(None),
-            Err(error) => return Err(error).context("failed to query Claude OAuth usage API"),
-        };
-
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let (body, status_code) = match split_curl_body_and_status(&stdout) {
-        Some(parts) => parts,
-        None => return Ok(None),
+fn jsonl_lines(entries: &[ProviderEntry], args: &UsageArgs) -> Result<Vec<String>> {
+    entries
+        .iter()
+        .map(|entry| Ok(serde_json::to_string(&cli_payload(entry, args))?))
+        .collect()
+}
+
+/// Values `--fields` can select.
+const USAGE_FIELDS: &[&str] = &[
+    "provider",
+    "source",
+    "plan",
+    "primary.remaining",
+    "primary.used",
+    "primary.resetsAt",
+    "secondary.remaining",
+    "secondary.used",
+    "secondary.resetsAt",
+    "tertiary.remaining",
+    "tertiary.used",
+    "tertiary.resetsAt",
+    "credits",
+    "updatedAt",
+    "error",
+];
+
+/// One `--fields` value for `entry`, with numbers rounded to `decimals`;
+/// `null` when the entry doesn't have it.
+fn usage_field(entry: &ProviderEntry, field: &str, decimals: u32) -> Value {
+    let round = |value: f64| round_to_decimals(value, decimals);
+    let (window, part) = match field.split_once('.') {
+        Some(("primary", part)) => (entry.primary.as_ref(), part),
+        Some(("secondary", part)) => (entry.secondary.as_ref(), part),
+        Some(("tertiary", part)) => (entry.tertiary.as_ref(), part),
+        _ => {
+            return match field {
+                "provider" => json!(entry.provider),
+                "source" => json!(entry.source),
+                "plan" => json!(entry
+                    .identity
+                    .as_ref()
+                    .and_then(|identity| identity.plan.clone())),
+                "credits" => json!(entry.credits_remaining.map(round)),
+                "updatedAt" => json!(entry.updated_at),
+                "error" => json!(entry.error),
+                _ => Value::Null,
+            }
+        }
     };
-    if status_code != 200 {
-        return Ok(None);
+    match part {
+        "remaining" => json!(remaining_percent(window).map(round)),
+        "used" => json!(window.and_then(|window| window.used_percent).map(round)),
+        "resetsAt" => json!(window.and_then(|window| window.resets_at.clone())),
+        _ => Value::Null,
     }
-
-    Ok(claude_entry_from_usage_json(body, args, "claude-oauth-api"))
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-enum GeminiAuthType {
-    OauthPersonal,
-    ApiKey,
-    VertexAi,
-    Unknown,
-}
+/// `--fields`: text prints the values tab-separated, one provider per line,
+/// with numbers at `--precision` and missing values left empty. Structured
+/// formats follow `--decimals` and `--json-compact-numbers` like the full
+/// payload does.
+fn render_fields(entries: &[ProviderEntry], args: &UsageArgs) -> Result<String> {
+    let mut objects = entries
+        .iter()
+        .map(|entry| {
+            args.fields
+                .iter()
+                .map(|field| (field.clone(), usage_field(entry, field, args.decimals)))
+                .collect::<Map<_, _>>()
+        })
+        .map(Value::Object)
+        .collect::<Vec<_>>();
+    if args.json_compact_numbers {
+        objects.iter_mut().for_each(compact_json_numbers);
+    }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-enum GeminiUserTierId {
-    Free,
-    Legacy,
-    Standard,
+    Ok(match args.format {
+        OutputFormat::Json if args.pretty => {
+            format!("{}\n", serde_json::to_string_pretty(&objects)?)
+        }
+        OutputFormat::Json => format!("{}\n", serde_json::to_string(&objects)?),
+        OutputFormat::Jsonl => {
+            let mut out = String::new();
+            for object in &objects {
+                let _ = writeln!(out, "{}", serde_json::to_string(object)?);
+            }
+            out
+        }
+        OutputFormat::Yaml => serde_yaml::to_string(&objects)?,
+        OutputFormat::Text | OutputFormat::Bars | OutputFormat::Influx => {
+            let mut out = String::new();
+            for entry in entries {
+                let values = args
+                    .fields
+                    .iter()
+                    .map(|field| match usage_field(entry, field, JSON_DECIMALS) {
+                        Value::Null => String::new(),
+                        Value::String(text) => text,
+                        Value::Number(number) => match number.as_f64() {
+                            Some(value) => {
+                                format!("{value:.precision$}", precision = args.precision)
+                            }
+                            None => number.to_string(),
+                        },
+                        other => other.to_string(),
+                    })
+                    .collect::<Vec<_>>();
+                let _ = writeln!(out, "{}", values.join("\t"));
+            }
+            out
+        }
+    })
 }
 
-#[derive(Debug, Deserialize)]
-struct GeminiOAuthCredentials {
-    #[serde(default)]
-    access_token: Option<String>,
-    #[serde(default)]
-    id_token: Option<String>,
-    #[serde(default)]
-    refresh_token: Option<String>,
-    #[serde(default)]
-    expiry_date: Option<f64>,
+/// Resolves each pointer against `payload`. Strings print bare so shell
+/// scripts can capture them directly; other values print as compact JSON.
+fn query_json_pointers(payload: &Value, pointers: &[String]) -> Result<Vec<String>> {
+    pointers
+        .iter()
+        .map(|pointer| {
+            let value = payload
+                .pointer(pointer)
+                .with_context(|| format!("query '{pointer}' did not match the usage payload"))?;
+            Ok(match value {
+                Value::String(text) => text.clone(),
+                other => other.to_string(),
+            })
+        })
+        .collect()
 }
 
-#[derive(Debug)]
-struct GeminiTokenClaims {
-    email: Option<String>,
-    hosted_domain: Option<String>,
-}
+fn run_health_check(args: &UsageArgs) -> Result<()> {
+    let registry = provider_registry(&args.custom_providers);
+    let report = select_providers(&registry, &args.provider)?
+        .into_iter()
+        .filter_map(|id| registry.iter().find(|provider| provider.id() == id))
+        .map(|provider| provider.health())
+        .collect::<Vec<_>>();
 
-#[derive(Debug, Clone)]
-struct GeminiModelQuota {
-    remaining_fraction: f64,
-    reset_time: Option<String>,
-}
+    match args.format {
+        OutputFormat::Json => {
+            if args.pretty {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!("{}", serde_json::to_string(&report)?);
+            }
+        }
+        OutputFormat::Yaml => print!("{}", serde_yaml::to_string(&report)?),
+        OutputFormat::Jsonl => {
+            for health in &report {
+                println!("{}", serde_json::to_string(health)?);
+            }
+        }
+        OutputFormat::Text | OutputFormat::Bars | OutputFormat::Influx => {
+            for health in &report {
+                let state = if health.healthy { "ok" } else { "unhealthy" };
+                println!("{}: {state} ({})", health.provider, health.reason);
+            }
+        }
+    }
 
-#[derive(Debug, Clone)]
-struct GeminiCodeAssistStatus {
-    tier: Option<GeminiUserTierId>,
-    project_id: Option<String>,
+    Ok(())
 }
 
-#[derive(Debug, Clone)]
-struct GeminiOAuthClientCredentials {
-    client_id: String,
-    client_secret: String,
+fn selected_entries(args: &UsageArgs) -> Result<Vec<ProviderEntry>> {
+    filter_entries_by_org(throttled_entries(args)?, args)
 }
 
-fn fetch_gemini_entry(args: &UsageArgs) -> Result<Option<ProviderEntry>> {
-    let home = resolve_home_directory()?;
-    let auth_type = current_gemini_auth_type(&home);
-    match auth_type {
-        GeminiAuthType::ApiKey => {
-            bail!("Gemini API key auth is not supported; use Gemini OAuth login")
-        }
-        GeminiAuthType::VertexAi => {
-            bail!("Gemini Vertex AI auth is not supported in Gemini provider")
-        }
-        GeminiAuthType::OauthPersonal | GeminiAuthType::Unknown => {}
-    }
-
-    let mut credentials = match load_gemini_oauth_credentials(&home)? {
-        Some(credentials) => credentials,
-        None => return Ok(None),
+fn throttled_entries(args: &UsageArgs) -> Result<Vec<ProviderEntry>> {
+    let Some(throttle) = FetchThrottle::open(args) else {
+        return fetch_selected_entries(args);
     };
-
-    let mut access_token = match credentials
-        .access_token
-        .as_deref()
-        .and_then(clean_token_value)
-    {
-        Some(token) => token,
-        None => return Ok(None),
+    if create_private_file(&throttle.path).is_err() {
+        return fetch_selected_entries(args);
+    }
+    // Waits out a concurrent run so its result can be reused below.
+    let _lock = match acquire_rpc_lock(&throttle.path, THROTTLE_LOCK_TIMEOUT) {
+        Ok(lock) => lock,
+        Err(_) => return fetch_selected_entries(args),
     };
 
-    let token_expired = credentials
-        .expiry_date
-        .map(|expiry_ms| expiry_ms < current_unix_millis())
-        .unwrap_or(false);
-    if token_expired {
-        let refresh_token = match credentials
-            .refresh_token
-            .as_deref()
-            .and_then(clean_token_value)
-        {
-            Some(token) => token,
-            None => return Ok(None),
-        };
-
-        access_token = refresh_gemini_access_token(&refresh_token, &home)?;
-        credentials.access_token = Some(access_token.clone());
+    let now = now_unix_seconds();
+    if let Some(entries) = throttle.recent(args, now) {
+        return Ok(entries);
     }
+    let entries = fetch_selected_entries(args)?;
+    throttle.record(args, &entries, now);
+    Ok(entries)
+}
 
-    let claims = extract_gemini_token_claims(credentials.id_token.as_deref());
-    let code_assist = load_gemini_code_assist_status(&access_token)?;
-    let project_id = if let Some(project_id) = code_assist.project_id.clone() {
-        Some(project_id)
-    } else {
-        discover_gemini_project_id(&access_token)?
-    };
-
-    let quota_output = match fetch_gemini_quota_json(&access_token, project_id.as_deref()) {
-        Ok(output) => output,
-        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
-        Err(error) if error.kind() == ErrorKind::TimedOut => return Ok(None),
-        Err(error) => return Err(error).context("failed to query Gemini quota API"),
-    };
-    let quota_stdout = String::from_utf8_lossy(&quota_output.stdout);
-    let (quota_body, quota_status) = match split_curl_body_and_status(&quota_stdout) {
-        Some(parts) => parts,
-        None => return Ok(None),
+/// Applies `--org`; see `UsageArgs::org`.
+fn filter_entries_by_org(
+    entries: Vec<ProviderEntry>,
+    args: &UsageArgs,
+) -> Result<Vec<ProviderEntry>> {
+    let Some(org) = args.org.as_deref() else {
+        return Ok(entries);
     };
-    if quota_status == 401 {
-        return Ok(None);
+    let mut kept = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let entry_org = entry
+            .identity
+            .as_ref()
+            .and_then(|identity| identity.account_organization.as_deref());
+        match entry_org {
+            Some(entry_org) if !entry_org.trim().eq_ignore_ascii_case(org.trim()) => {
+                if !args.org_optional {
+                    bail!(
+                        "provider '{}' is signed in to organization '{entry_org}', not '{org}'",
+                        entry.provider
+                    );
+                }
+            }
+            _ => kept.push(entry),
+        }
     }
-    if quota_status != 200 {
-        bail!("Gemini quota API returned HTTP {quota_status}");
+    if kept.is_empty() {
+        return Err(
+            CodexBarError::NoData(format!("no provider matches organization '{org}'")).into(),
+        );
     }
+    Ok(kept)
+}
 
-    let (primary, secondary) = parse_gemini_quota_windows(quota_body)?;
-    let source = if args.source.eq_ignore_ascii_case("auto") {
-        "api".to_string()
-    } else {
-        args.source.clone()
-    };
-    let plan_label = gemini_plan_label(code_assist.tier, claims.hosted_domain.as_deref());
-    let status = if args.status {
-        Some(StatusInfo {
-            indicator: Some("none".to_string()),
-            description: Some("Operational".to_string()),
-            updated_at: Some(now_iso8601()),
-            url: Some(
-                "https://www.google.com/appsstatus/dashboard/products/npdyhgECDJ6tB66MxXyo/history"
-                    .to_string(),
-            ),
-        })
-    } else {
-        None
+fn fetch_selected_entries(args: &UsageArgs) -> Result<Vec<ProviderEntry>> {
+    let registry = provider_registry(&args.custom_providers);
+    let providers = select_providers(&registry, &args.provider)?;
+    let options = args.fetch_options();
+    let fetch_live_entry = |id: &str| match registry.iter().find(|provider| provider.id() == id) {
+        Some(provider) => provider.fetch(&options).map_err(anyhow::Error::from),
+        None => Ok(None),
     };
+    let cache = args
+        .cache_ttl
+        .and_then(|ttl_seconds| EntryCache::open(ttl_seconds, &options));
+    let results = fetch_concurrently(&providers, |provider| match &cache {
+        Some(cache) => cache.fetch(provider, now_unix_seconds(), || fetch_live_entry(provider)),
+        None => fetch_live_entry(provider),
+    });
+    let (entries, first_error) = collect_entries(&providers, results, &options);
 
-    Ok(Some(ProviderEntry {
-        provider: "gemini".to_string(),
-        source: Some(source),
-        updated_at: now_iso8601(),
-        primary: Some(primary),
-        secondary,
-        tertiary: None,
-        credits_remaining: None,
-        code_review_remaining_percent: None,
-        identity: Some(IdentityInfo {
-            account_email: claims.email,
-            account_organization: None,
-            login_method: plan_label,
-        }),
-        status,
-    }))
-}
+    if entries.is_empty() {
+        let message = format!(
+            "no live usage data available for provider '{}'; ensure corresponding CLI tools are installed and authenticated",
+            args.provider
+        );
+        // The first fetch error decides the exit code; without one, it's "no data".
+        return Err(match first_error {
+            Some(error) => error.context(message),
+            None => CodexBarError::NoData(message).into(),
+        });
+    }
 
-fn resolve_home_directory() -> Result<PathBuf> {
-    let home = std::env::var("HOME").context("HOME is not set")?;
-    Ok(PathBuf::from(home))
+    Ok(entries)
 }
 
-fn current_unix_millis() -> f64 {
-    UNIX_EPOCH
-        .elapsed()
-        .map(|duration| duration.as_millis() as f64)
-        .unwrap_or(0.0)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CachedEntry {
+    cached_at: i64,
+    /// `EntryCache::request` at the time of writing.
+    #[serde(default)]
+    request: String,
+    entry: ProviderEntry,
 }
 
-fn current_gemini_auth_type(home: &Path) -> GeminiAuthType {
-    let settings_path = home.join(".gemini").join("settings.json");
-    let raw = match fs::read_to_string(settings_path) {
-        Ok(raw) => raw,
-        Err(_) => return GeminiAuthType::Unknown,
-    };
-    let json = match serde_json::from_str::<Value>(&raw) {
-        Ok(json) => json,
-        Err(_) => return GeminiAuthType::Unknown,
-    };
-    let selected_type = json
-        .get("security")
-        .and_then(|security| security.get("auth"))
-        .and_then(|auth| auth.get("selectedType"))
-        .and_then(Value::as_str)
-        .map(str::trim)
-        .unwrap_or_default();
+/// Oldest cached entry a failed fetch falls back to.
+const CACHE_FALLBACK_MAX_AGE_SECONDS: i64 = 24 * 60 * 60;
 
-    match selected_type {
-        "oauth-personal" => GeminiAuthType::OauthPersonal,
-        "api-key" => GeminiAuthType::ApiKey,
-        "vertex-ai" => GeminiAuthType::VertexAi,
-        _ => GeminiAuthType::Unknown,
+/// One `<provider>.json` per provider, written after each successful fetch.
+#[derive(Debug, Clone)]
+struct EntryCache {
+    dir: PathBuf,
+    ttl_seconds: i64,
+    /// Account and fetch options the entries were fetched with; an entry
+    /// cached under another request is never served.
+    request: String,
+}
+
+impl EntryCache {
+    fn open(ttl_seconds: u64, options: &FetchOptions) -> Option<Self> {
+        Some(Self {
+            dir: codexbar_cache_dir()?,
+            ttl_seconds: i64::try_from(ttl_seconds).unwrap_or(i64::MAX),
+            request: Self::request_key(&active_secret_account(), options),
+        })
     }
-}
 
-fn load_gemini_oauth_credentials(home: &Path) -> Result<Option<GeminiOAuthCredentials>> {
-    let creds_path = home.join(".gemini").join("oauth_creds.json");
-    let raw = match fs::read_to_string(&creds_path) {
-        Ok(raw) => raw,
-        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
-        Err(error) => {
-            return Err(error).with_context(|| format!("failed to read {}", creds_path.display()))
-        }
-    };
-    let credentials = serde_json::from_str::<GeminiOAuthCredentials>(&raw)
-        .with_context(|| format!("failed to parse {}", creds_path.display()))?;
-    Ok(Some(credentials))
-}
-
-fn extract_gemini_token_claims(id_token: Option<&str>) -> GeminiTokenClaims {
-    let token = match id_token.and_then(clean_token_value) {
-        Some(token) => token,
-        None => {
-            return GeminiTokenClaims {
-                email: None,
-                hosted_domain: None,
-            }
-        }
-    };
-    let mut parts = token.split('.');
-    let _header = parts.next();
-    let payload = match parts.next() {
-        Some(payload) => payload,
-        None => {
-            return GeminiTokenClaims {
-                email: None,
-                hosted_domain: None,
-            }
-        }
-    };
-
-    let mut normalized = payload.replace('-', "+").replace('_', "/");
-    while normalized.len() % 4 != 0 {
-        normalized.push('=');
+    /// Everything in `options` that changes what a fetch returns.
+    fn request_key(account: &str, options: &FetchOptions) -> String {
+        format!(
+            "{account}|{}|{:?}|{}|{:?}|{:?}",
+            options.source,
+            options.codex_source,
+            options.status,
+            options.window_order,
+            options.codex_home
+        )
     }
-    let decoded = match base64_decode(&normalized) {
-        Some(decoded) => decoded,
-        None => {
-            return GeminiTokenClaims {
-                email: None,
-                hosted_domain: None,
-            }
-        }
-    };
-    let value = match serde_json::from_slice::<Value>(&decoded) {
-        Ok(value) => value,
-        Err(_) => {
-            return GeminiTokenClaims {
-                email: None,
-                hosted_domain: None,
-            }
-        }
-    };
 
-    GeminiTokenClaims {
-        email: value
-            .get("email")
-            .and_then(Value::as_str)
-            .map(str::trim)
-            .filter(|value| !value.is_empty())
-            .map(ToOwned::to_owned),
-        hosted_domain: value
-            .get("hd")
-            .and_then(Value::as_str)
-            .map(str::trim)
-            .filter(|value| !value.is_empty())
-            .map(ToOwned::to_owned),
+    fn path(&self, provider: &str) -> PathBuf {
+        self.dir.join(format!("{provider}.json"))
     }
-}
 
-fn base64_decode(input: &str) -> Option<Vec<u8>> {
-    let mut output = Vec::new();
-    let mut chunk = [0u8; 4];
-    let mut chunk_len = 0usize;
+    fn read(&self, provider: &str) -> Option<CachedEntry> {
+        let raw = fs::read_to_string(self.path(provider)).ok()?;
+        serde_json::from_str::<CachedEntry>(&raw)
+            .ok()
+            .filter(|cached| cached.request == self.request)
+    }
 
-    for byte in input.bytes() {
-        if matches!(byte, b' ' | b'\n' | b'\r' | b'\t') {
-            continue;
-        }
-        let value = match byte {
-            b'A'..=b'Z' => byte - b'A',
-            b'a'..=b'z' => 26 + (byte - b'a'),
-            b'0'..=b'9' => 52 + (byte - b'0'),
-            b'+' => 62,
-            b'/' => 63,
-            b'=' => 64,
-            _ => return None,
+    fn write(&self, provider: &str, entry: &ProviderEntry, now: i64) -> Result<()> {
+        let cached = CachedEntry {
+            cached_at: now,
+            request: self.request.clone(),
+            entry: entry.clone(),
         };
-        chunk[chunk_len] = value;
-        chunk_len += 1;
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("failed to create {}", self.dir.display()))?;
+        let path = self.path(provider);
+        fs::write(&path, serde_json::to_vec(&cached)?)
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
 
-        if chunk_len == 4 {
-            if chunk[0] == 64 || chunk[1] == 64 {
-                return None;
+    /// Serves a fresh cache hit without calling `fetch`. Otherwise fetches,
+    /// caching a success; when the fetch yields nothing the last cached entry
+    /// is returned if it is at most `CACHE_FALLBACK_MAX_AGE_SECONDS` old,
+    /// with `updated_at` set to when it was actually fetched.
+    fn fetch<F>(&self, provider: &str, now: i64, fetch: F) -> Result<Option<ProviderEntry>>
+    where
+        F: FnOnce() -> Result<Option<ProviderEntry>>,
+    {
+        let cached = self.read(provider);
+        if let Some(cached) = &cached {
+            let age = now - cached.cached_at;
+            if (0..self.ttl_seconds).contains(&age) {
+                return Ok(Some(cached.entry.clone()));
             }
-            output.push((chunk[0] << 2) | (chunk[1] >> 4));
-            if chunk[2] != 64 {
-                output.push((chunk[1] << 4) | (chunk[2] >> 2));
+        }
+
+        let cached = cached.filter(|cached| {
+            (0..=CACHE_FALLBACK_MAX_AGE_SECONDS).contains(&(now - cached.cached_at))
+        });
+        let result = fetch();
+        match (result, cached) {
+            (Ok(Some(entry)), _) => {
+                if let Err(error) = self.write(provider, &entry, now) {
+                    eprintln!("codexbar: failed to cache {provider} entry: {error:#}");
+                }
+                Ok(Some(entry))
             }
-            if chunk[3] != 64 {
-                output.push((chunk[2] << 6) | chunk[3]);
+            (Ok(None), Some(cached)) | (Err(_), Some(cached)) => {
+                eprintln!(
+                    "codexbar: provider '{provider}' live fetch failed; using cached entry from {}s ago",
+                    now - cached.cached_at
+                );
+                let mut entry = cached.entry;
+                entry.updated_at = format_timestamp(cached.cached_at);
+                Ok(Some(entry))
             }
-            chunk_len = 0;
+            (result, None) => result,
         }
     }
+}
 
-    if chunk_len != 0 {
-        return None;
-    }
+const THROTTLE_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+/// Records older than this are ignored whatever `--min-interval` says.
+const THROTTLE_STALE_AFTER_SECONDS: i64 = 300;
 
-    Some(output)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ThrottleRecord {
+    fetched_at: i64,
+    request: String,
+    entries: Vec<ProviderEntry>,
 }
 
-fn load_gemini_code_assist_status(access_token: &str) -> Result<GeminiCodeAssistStatus> {
-    let output = match fetch_gemini_load_code_assist_json(access_token) {
-        Ok(output) => output,
-        Err(error) if error.kind() == ErrorKind::NotFound => {
-            return Ok(GeminiCodeAssistStatus {
-                tier: None,
-                project_id: None,
-            })
-        }
-        Err(error) if error.kind() == ErrorKind::TimedOut => {
-            return Ok(GeminiCodeAssistStatus {
-                tier: None,
-                project_id: None,
-            })
-        }
-        Err(error) => return Err(error).context("failed to query Gemini loadCodeAssist API"),
-    };
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let (body, status_code) = match split_curl_body_and_status(&stdout) {
-        Some(parts) => parts,
-        None => {
-            return Ok(GeminiCodeAssistStatus {
-                tier: None,
-                project_id: None,
-            })
-        }
-    };
-    if status_code != 200 {
-        return Ok(GeminiCodeAssistStatus {
-            tier: None,
-            project_id: None,
-        });
-    }
-    let value = match serde_json::from_str::<Value>(body) {
-        Ok(value) => value,
-        Err(_) => {
-            return Ok(GeminiCodeAssistStatus {
-                tier: None,
-                project_id: None,
-            })
-        }
-    };
-
-    let project_id = extract_gemini_code_assist_project_id(&value);
-    let tier = value
-        .get("currentTier")
-        .and_then(|tier| tier.get("id"))
-        .and_then(Value::as_str)
-        .and_then(gemini_tier_from_str);
-    Ok(GeminiCodeAssistStatus { tier, project_id })
+/// `--min-interval` guard for a misbehaving widget launching `codexbar usage`
+/// in a loop. The lock file doubles as the record of the last successful run.
+/// It is only ever flock-ed, so a crashed run can't leave it held. The record
+/// holds account identities, so it lives in `$XDG_RUNTIME_DIR` or the cache
+/// directory, mode 0600, never in a shared directory such as `/tmp`.
+struct FetchThrottle {
+    path: PathBuf,
+    min_interval_seconds: i64,
+    /// `active_secret_account()`; runs for different accounts never share.
+    account: String,
 }
 
-fn extract_gemini_code_assist_project_id(value: &Value) -> Option<String> {
-    if let Some(project) = value.get("cloudaicompanionProject").and_then(Value::as_str) {
-        let trimmed = project.trim();
-        if !trimmed.is_empty() {
-            return Some(trimmed.to_string());
+impl FetchThrottle {
+    fn open(args: &UsageArgs) -> Option<Self> {
+        if args.min_interval == 0 {
+            return None;
         }
+        let dir = match first_env_value(&["XDG_RUNTIME_DIR"])
+            .map(PathBuf::from)
+            .filter(|path| path.is_absolute())
+        {
+            Some(runtime_dir) => runtime_dir,
+            None => {
+                let dir = codexbar_cache_dir()?;
+                fs::create_dir_all(&dir).ok()?;
+                dir
+            }
+        };
+        Some(Self {
+            path: dir.join("codexbar.lock"),
+            min_interval_seconds: i64::try_from(args.min_interval)
+                .unwrap_or(i64::MAX)
+                .min(THROTTLE_STALE_AFTER_SECONDS),
+            account: active_secret_account(),
+        })
     }
 
-    let project_obj = value
-        .get("cloudaicompanionProject")
-        .and_then(Value::as_object)?;
-    if let Some(project_id) = project_obj.get("id").and_then(Value::as_str) {
-        let trimmed = project_id.trim();
-        if !trimmed.is_empty() {
-            return Some(trimmed.to_string());
-        }
+    /// Arguments that change which entries a run produces.
+    fn request_key(&self, args: &UsageArgs) -> String {
+        format!(
+            "{}|{}|{}|{:?}|{}|{}",
+            self.account,
+            args.provider,
+            args.source,
+            args.codex_source,
+            args.status,
+            args.include_missing
+        )
     }
-    if let Some(project_id) = project_obj.get("projectId").and_then(Value::as_str) {
-        let trimmed = project_id.trim();
-        if !trimmed.is_empty() {
-            return Some(trimmed.to_string());
-        }
+
+    fn recent(&self, args: &UsageArgs, now: i64) -> Option<Vec<ProviderEntry>> {
+        let raw = fs::read_to_string(&self.path).ok()?;
+        let record = serde_json::from_str::<ThrottleRecord>(&raw).ok()?;
+        let age = now - record.fetched_at;
+        ((0..self.min_interval_seconds).contains(&age) && record.request == self.request_key(args))
+            .then_some(record.entries)
     }
-    None
-}
 
-fn gemini_tier_from_str(raw: &str) -> Option<GeminiUserTierId> {
-    match raw.trim() {
-        "free-tier" => Some(GeminiUserTierId::Free),
-        "legacy-tier" => Some(GeminiUserTierId::Legacy),
-        "standard-tier" => Some(GeminiUserTierId::Standard),
-        _ => None,
+    fn record(&self, args: &UsageArgs, entries: &[ProviderEntry], now: i64) {
+        let record = ThrottleRecord {
+            fetched_at: now,
+            request: self.request_key(args),
+            entries: entries.to_vec(),
+        };
+        if let Ok(json) = serde_json::to_vec(&record) {
+            let _ = fs::write(&self.path, json);
+        }
     }
 }
 
-fn discover_gemini_project_id(access_token: &str) -> Result<Option<String>> {
-    let output = match fetch_gemini_projects_json(access_token) {
-        Ok(output) => output,
-        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
-        Err(error) if error.kind() == ErrorKind::TimedOut => return Ok(None),
-        Err(error) => return Err(error).context("failed to query Google Cloud project list"),
-    };
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let (body, status_code) = match split_curl_body_and_status(&stdout) {
-        Some(parts) => parts,
-        None => return Ok(None),
-    };
-    if status_code != 200 {
-        return Ok(None);
-    }
-    let value = match serde_json::from_str::<Value>(body) {
-        Ok(value) => value,
-        Err(_) => return Ok(None),
-    };
-    let projects = match value.get("projects").and_then(Value::as_array) {
-        Some(projects) => projects,
-        None => return Ok(None),
+fn cli_payload(entry: &ProviderEntry, args: &UsageArgs) -> Value {
+    let resolved_source = if args.source.eq_ignore_ascii_case("auto") || entry.error.is_some() {
+        entry.source.as_deref().unwrap_or("rust").to_string()
+    } else {
+        args.source.clone()
     };
 
-    for project in projects {
-        let project_id = match project.get("projectId").and_then(Value::as_str) {
-            Some(project_id) => project_id.trim(),
-            None => continue,
-        };
-        if project_id.is_empty() {
-            continue;
-        }
-        if project_id.starts_with("gen-lang-client") {
-            return Ok(Some(project_id.to_string()));
-        }
-        if project
-            .get("labels")
-            .and_then(Value::as_object)
-            .map(|labels| labels.contains_key("generative-language"))
-            .unwrap_or(false)
-        {
-            return Ok(Some(project_id.to_string()));
+    let identity_payload = entry
+        .identity
+        .as_ref()
+        .map(|identity| {
+            json!({
+                "providerID": entry.provider,
+                "accountEmail": identity.account_email,
+                "accountOrganization": identity.account_organization,
+                "loginMethod": identity.login_method,
+                "plan": identity.plan,
+            })
+        })
+        .unwrap_or(Value::Null);
+
+    let now = now_unix_seconds();
+    let rich_resets_now = args.rich_resets.then_some(now);
+    let window_value = |window: Option<&RateWindow>| {
+        let mut value = rate_window_value(window, rich_resets_now, args.decimals);
+        if args.since_reset {
+            if let Some(rate) = window.and_then(|window| percent_per_hour(window, now)) {
+                value["percentPerHour"] = json!(round_to_decimals(rate, args.decimals));
+            }
         }
+        value
+    };
+    let mut usage = json!({
+        "primary": window_value(entry.primary.as_ref()),
+        "secondary": window_value(entry.secondary.as_ref()),
+        "tertiary": window_value(entry.tertiary.as_ref()),
+        "extraWindows": entry
+            .extra_windows
+            .iter()
+            .map(|window| window_value(Some(window)))
+            .collect::<Vec<_>>(),
+        "windows": entry
+            .windows
+            .iter()
+            .map(|named| {
+                let mut value = window_value(Some(&named.window));
+                value["name"] = json!(named.name);
+                if let Some(slot) = named.slot {
+                    value["slot"] = json!(slot);
+                }
+                value
+            })
+            .collect::<Vec<_>>(),
+        "updatedAt": entry.updated_at,
+        "identity": identity_payload,
+    });
+    if args.legacy_identity {
+        let identity = entry.identity.as_ref();
+        usage["accountEmail"] = json!(identity.and_then(|identity| identity.account_email.clone()));
+        usage["accountOrganization"] =
+            json!(identity.and_then(|identity| identity.account_organization.clone()));
+        usage["loginMethod"] = json!(identity.and_then(|identity| identity.login_method.clone()));
     }
 
-    Ok(None)
-}
-
-fn fetch_gemini_load_code_assist_json(access_token: &str) -> io::Result<Output> {
-    fetch_json_post_with_bearer(
-        "https://cloudcode-pa.googleapis.com/v1internal:loadCodeAssist",
-        access_token,
-        "{\"metadata\":{\"ideType\":\"GEMINI_CLI\",\"pluginType\":\"GEMINI\"}}",
-    )
-}
+    let credits = entry
+        .credits_remaining
+        .map(|remaining| {
+            json!({
+                "remaining": round_to_decimals(remaining, args.decimals),
+                "updatedAt": entry.updated_at
+            })
+        })
+        .unwrap_or(Value::Null);
 
-fn fetch_gemini_projects_json(access_token: &str) -> io::Result<Output> {
-    fetch_json_get_with_bearer(
-        "https://cloudresourcemanager.googleapis.com/v1/projects",
-        access_token,
-    )
-}
+    let openai_dashboard = entry
+        .code_review_remaining_percent
+        .map(|remaining| {
+            json!({
+                "codeReviewRemainingPercent": round_to_decimals(remaining, args.decimals),
+                "updatedAt": entry.updated_at
+            })
+        })
+        .unwrap_or(Value::Null);
 
-fn fetch_gemini_quota_json(access_token: &str, project_id: Option<&str>) -> io::Result<Output> {
-    let body = match project_id {
-        Some(project_id) => json!({"project": project_id}).to_string(),
-        None => "{}".to_string(),
+    let status = if args.status {
+        entry
+            .status
+            .as_ref()
+            .map(|status| {
+                json!({
+                    "indicator": status.indicator,
+                    "description": status.description,
+                    "updatedAt": status.updated_at,
+                    "url": status.url
+                })
+            })
+            .unwrap_or(Value::Null)
+    } else {
+        Value::Null
     };
-    fetch_json_post_with_bearer(
-        "https://cloudcode-pa.googleapis.com/v1internal:retrieveUserQuota",
-        access_token,
-        &body,
-    )
-}
-
-fn fetch_json_get_with_bearer(endpoint: &str, access_token: &str) -> io::Result<Output> {
-    let args_owned = [
-        "-sS".to_string(),
-        "--location".to_string(),
-        "--max-time".to_string(),
-        "15".to_string(),
-        "-H".to_string(),
-        format!("Authorization: Bearer {access_token}"),
-        "-H".to_string(),
-        "Accept: application/json".to_string(),
-        "-w".to_string(),
-        "\n%{http_code}".to_string(),
-        endpoint.to_string(),
-    ];
-    let args = args_owned.iter().map(String::as_str).collect::<Vec<_>>();
-    run_command_with_timeout("curl", &args, Duration::from_secs(20))
-}
-
-fn fetch_json_post_with_bearer(
-    endpoint: &str,
-    access_token: &str,
-    body_json: &str,
-) -> io::Result<Output> {
-    let args_owned = [
-        "-sS".to_string(),
-        "--location".to_string(),
-        "--max-time".to_string(),
-        "15".to_string(),
-        "-H".to_string(),
-        format!("Authorization: Bearer {access_token}"),
-        "-H".to_string(),
-        "Accept: application/json".to_string(),
-        "-H".to_string(),
-        "Content-Type: application/json".to_string(),
-        "--data".to_string(),
-        body_json.to_string(),
-        "-w".to_string(),
-        "\n%{http_code}".to_string(),
-        endpoint.to_string(),
-    ];
-    let args = args_owned.iter().map(String::as_str).collect::<Vec<_>>();
-    run_command_with_timeout("curl", &args, Duration::from_secs(20))
-}
-
-fn parse_gemini_quota_windows(raw_json: &str) -> Result<(RateWindow, Option<RateWindow>)> {
-    let value =
-        serde_json::from_str::<Value>(raw_json).context("failed to parse Gemini quota JSON")?;
-    let buckets = value
-        .get("buckets")
-        .and_then(Value::as_array)
-        .context("Gemini quota JSON did not include buckets")?;
-    if buckets.is_empty() {
-        bail!("Gemini quota JSON contained no buckets");
-    }
-
-    let mut model_quotas: HashMap<String, GeminiModelQuota> = HashMap::new();
-    for bucket in buckets {
-        let model_id = match bucket.get("modelId").and_then(Value::as_str).map(str::trim) {
-            Some(model_id) if !model_id.is_empty() => model_id,
-            _ => continue,
-        };
-        let remaining_fraction = match bucket.get("remainingFraction").and_then(json_number_value) {
-            Some(remaining) => remaining.clamp(0.0, 1.0),
-            None => continue,
-        };
-        let reset_time = bucket
-            .get("resetTime")
-            .and_then(Value::as_str)
-            .map(str::trim)
-            .filter(|value| !value.is_empty())
-            .map(ToOwned::to_owned);
 
-        let entry = model_quotas
-            .entry(model_id.to_string())
-            .or_insert_with(|| GeminiModelQuota {
-                remaining_fraction,
-                reset_time: reset_time.clone(),
-            });
-        if remaining_fraction < entry.remaining_fraction {
-            entry.remaining_fraction = remaining_fraction;
-            entry.reset_time = reset_time;
-        }
+    let mut payload = json!({
+        "provider": entry.provider,
+        "version": env!("CARGO_PKG_VERSION"),
+        "source": resolved_source,
+        "status": status,
+        "usage": usage,
+        "credits": credits,
+        "antigravityPlanInfo": antigravity_plan_info(entry),
+        "openaiDashboard": openai_dashboard
+    });
+    if let Some(error) = entry.error.as_ref() {
+        payload["error"] = json!({ "message": error });
     }
-
-    if model_quotas.is_empty() {
-        bail!("Gemini quota JSON did not include usable quota buckets");
+    if args.json_compact_numbers {
+        compact_json_numbers(&mut payload);
     }
-
-    let pro_min = model_quotas
-        .iter()
-        .filter(|(model_id, _)| model_id.to_ascii_lowercase().contains("pro"))
-        .map(|(_, quota)| quota)
-        .min_by(|lhs, rhs| lhs.remaining_fraction.total_cmp(&rhs.remaining_fraction));
-    let flash_min = model_quotas
-        .iter()
-        .filter(|(model_id, _)| model_id.to_ascii_lowercase().contains("flash"))
-        .map(|(_, quota)| quota)
-        .min_by(|lhs, rhs| lhs.remaining_fraction.total_cmp(&rhs.remaining_fraction));
-
-    let pro_used = pro_min
-        .map(|quota| (100.0 - (quota.remaining_fraction * 100.0)).clamp(0.0, 100.0))
-        .unwrap_or(0.0);
-    let primary = RateWindow {
-        used_percent: Some(pro_used),
-        window_minutes: Some(1440),
-        resets_at: pro_min.and_then(|quota| quota.reset_time.clone()),
-    };
-    let secondary = flash_min.map(|quota| RateWindow {
-        used_percent: Some((100.0 - (quota.remaining_fraction * 100.0)).clamp(0.0, 100.0)),
-        window_minutes: Some(1440),
-        resets_at: quota.reset_time.clone(),
-    });
-
-    Ok((primary, secondary))
+    payload
 }
 
-fn gemini_plan_label(
-    tier: Option<GeminiUserTierId>,
-    hosted_domain: Option<&str>,
-) -> Option<String> {
-    match (tier, hosted_domain) {
-        (Some(GeminiUserTierId::Standard), _) => Some("Paid".to_string()),
-        (Some(GeminiUserTierId::Free), Some(domain)) if !domain.trim().is_empty() => {
-            Some("Workspace".to_string())
-        }
-        (Some(GeminiUserTierId::Free), _) => Some("Free".to_string()),
-        (Some(GeminiUserTierId::Legacy), _) => Some("Legacy".to_string()),
-        (None, _) => None,
+/// Gemini's plan, mirrored into `antigravityPlanInfo` for widgets that read
+/// the plan from there; `null` for other providers or an unknown tier.
+fn antigravity_plan_info(entry: &ProviderEntry) -> Value {
+    if entry.provider != "gemini" {
+        return Value::Null;
     }
+    entry
+        .identity
+        .as_ref()
+        .and_then(|identity| identity.plan.as_ref())
+        .map(|plan| {
+            json!({
+                "planName": plan,
+                "accountEmail": entry.identity.as_ref().and_then(|identity| identity.account_email.clone()),
+            })
+        })
+        .unwrap_or(Value::Null)
 }
 
-fn refresh_gemini_access_token(refresh_token: &str, home: &Path) -> Result<String> {
-    let oauth_credentials = extract_gemini_oauth_client_credentials()
-        .context("Could not find Gemini CLI OAuth configuration")?;
-    let refresh_body = format!(
-        "client_id={}&client_secret={}&refresh_token={}&grant_type=refresh_token",
-        percent_encode_form_value(&oauth_credentials.client_id),
-        percent_encode_form_value(&oauth_credentials.client_secret),
-        percent_encode_form_value(refresh_token),
-    );
-
-    let output = fetch_oauth_token_refresh_json(&refresh_body)
-        .context("failed to request Google OAuth token refresh")?;
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let (body, status_code) =
-        split_curl_body_and_status(&stdout).context("failed to parse OAuth refresh response")?;
-    if status_code != 200 {
-        bail!("Gemini OAuth refresh failed with HTTP {status_code}");
-    }
-
-    let refresh_json = serde_json::from_str::<Value>(body)
-        .context("failed to parse OAuth refresh JSON payload")?;
-    let access_token = refresh_json
-        .get("access_token")
-        .and_then(Value::as_str)
-        .and_then(clean_token_value)
-        .context("OAuth refresh response did not include access_token")?;
-
-    update_gemini_stored_credentials(home, &refresh_json)?;
-    Ok(access_token)
-}
-
-fn fetch_oauth_token_refresh_json(form_body: &str) -> io::Result<Output> {
-    let args_owned = [
-        "-sS".to_string(),
-        "--location".to_string(),
-        "--max-time".to_string(),
-        "15".to_string(),
-        "-H".to_string(),
-        "Content-Type: application/x-www-form-urlencoded".to_string(),
-        "--data".to_string(),
-        form_body.to_string(),
-        "-w".to_string(),
-        "\n%{http_code}".to_string(),
-        "https://oauth2.googleapis.com/token".to_string(),
-    ];
-    let args = args_owned.iter().map(String::as_str).collect::<Vec<_>>();
-    run_command_with_timeout("curl", &args, Duration::from_secs(20))
+fn round_json_number(value: f64) -> f64 {
+    round_to_decimals(value, JSON_DECIMALS)
 }
 
-fn percent_encode_form_value(value: &str) -> String {
-    let mut output = String::with_capacity(value.len());
-    for byte in value.bytes() {
-        let is_unreserved =
-            byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~');
-        if is_unreserved {
-            output.push(byte as char);
-        } else {
-            output.push('%');
-            output.push_str(&format!("{byte:02X}"));
+/// `--json-compact-numbers`: rewrites floats without a fractional part as
+/// integers, throughout `value`.
+fn compact_json_numbers(value: &mut Value) {
+    match value {
+        Value::Number(number) if number.is_f64() => {
+            if let Some(float) = number.as_f64() {
+                // Beyond 2^53 an f64 no longer maps onto a unique integer.
+                if float.fract() == 0.0 && float.abs() < 9_007_199_254_740_992.0 {
+                    *value = Value::from(float as i64);
+                }
+            }
         }
+        Value::Array(items) => items.iter_mut().for_each(compact_json_numbers),
+        Value::Object(map) => map.values_mut().for_each(compact_json_numbers),
+        _ => {}
     }
-    output
 }
 
-fn update_gemini_stored_credentials(home: &Path, refresh_json: &Value) -> Result<()> {
-    let creds_path = home.join(".gemini").join("oauth_creds.json");
-    let raw = fs::read_to_string(&creds_path)
-        .with_context(|| format!("failed to read {}", creds_path.display()))?;
-    let mut root = serde_json::from_str::<Value>(&raw)
-        .with_context(|| format!("failed to parse {}", creds_path.display()))?;
-    if !root.is_object() {
-        bail!("Gemini credentials file was not a JSON object");
-    }
-    let obj = root
-        .as_object_mut()
-        .context("internal error: Gemini credentials root was not an object")?;
+/// `rich_resets_now` is the current Unix time when `--rich-resets` is set;
+/// the reset is then also emitted pre-parsed so widgets skip timestamp math.
+fn rate_window_value(
+    window: Option<&RateWindow>,
+    rich_resets_now: Option<i64>,
+    decimals: u32,
+) -> Value {
+    let window = match window {
+        Some(window) => window,
+        None => return Value::Null,
+    };
 
-    if let Some(token) = refresh_json
-        .get("access_token")
-        .and_then(Value::as_str)
-        .and_then(clean_token_value)
-    {
-        obj.insert("access_token".to_string(), Value::String(token));
-    }
-    if let Some(expires_in) = refresh_json.get("expires_in").and_then(json_number_value) {
-        let expiry_date_ms = current_unix_millis() + (expires_in * 1000.0);
-        if let Some(number) = serde_json::Number::from_f64(expiry_date_ms) {
-            obj.insert("expiry_date".to_string(), Value::Number(number));
-        }
+    let mut value = json!({
+        "usedPercent": window.used_percent.map(|used| round_to_decimals(used, decimals)),
+        "windowMinutes": window.window_minutes,
+        "resetsAt": window.resets_at,
+    });
+    if let Some(now) = rich_resets_now {
+        let resets_at_unix = window.resets_at.as_deref().and_then(parse_unix_seconds);
+        value["resetsAtUnix"] = json!(resets_at_unix);
+        value["resetsInSeconds"] = json!(resets_at_unix.map(|reset| (reset - now).max(0)));
     }
-    if let Some(id_token) = refresh_json
-        .get("id_token")
-        .and_then(Value::as_str)
-        .and_then(clean_token_value)
-    {
-        obj.insert("id_token".to_string(), Value::String(id_token));
+    value
+}
+
+/// Usage consumed per hour since the window's last reset, derived from
+/// `resets_at - window_minutes`. `None` when the window start is unknown or
+/// the reset has already passed.
+fn percent_per_hour(window: &RateWindow, now: i64) -> Option<f64> {
+    let used = window.used_percent?;
+    let resets_at = parse_unix_seconds(window.resets_at.as_deref()?)?;
+    let window_seconds = i64::try_from(window.window_minutes?)
+        .ok()?
+        .checked_mul(60)?;
+    if now >= resets_at {
+        return None;
     }
 
-    let encoded = serde_json::to_string_pretty(&root)
-        .with_context(|| format!("failed to encode {}", creds_path.display()))?;
-    fs::write(&creds_path, format!("{encoded}\n"))
-        .with_context(|| format!("failed to write {}", creds_path.display()))?;
-    Ok(())
-}
-
-fn extract_gemini_oauth_client_credentials() -> Option<GeminiOAuthClientCredentials> {
-    let gemini_path = resolve_gemini_binary_path()?;
-    let resolved_binary = resolve_single_symlink_path(&gemini_path);
-    let bin_dir = resolved_binary.parent()?;
-    let base_dir = bin_dir.parent()?;
-
-    let candidates = [
-        base_dir.join("libexec/lib/node_modules/@google/gemini-cli/node_modules/@google/gemini-cli-core/dist/src/code_assist/oauth2.js"),
-        base_dir.join("lib/node_modules/@google/gemini-cli/node_modules/@google/gemini-cli-core/dist/src/code_assist/oauth2.js"),
-        base_dir.join("share/gemini-cli/node_modules/@google/gemini-cli-core/dist/src/code_assist/oauth2.js"),
-        base_dir.join("../gemini-cli-core/dist/src/code_assist/oauth2.js"),
-        base_dir.join("node_modules/@google/gemini-cli-core/dist/src/code_assist/oauth2.js"),
-    ];
-
-    for path in candidates {
-        let content = match fs::read_to_string(&path) {
-            Ok(content) => content,
-            Err(_) => continue,
-        };
-        let client_id = match extract_js_constant(&content, "OAUTH_CLIENT_ID") {
-            Some(value) => value,
-            None => continue,
-        };
-        let client_secret = match extract_js_constant(&content, "OAUTH_CLIENT_SECRET") {
-            Some(value) => value,
-            None => continue,
-        };
-        return Some(GeminiOAuthClientCredentials {
-            client_id,
-            client_secret,
-        });
+    let elapsed = now - (resets_at - window_seconds);
+    if elapsed <= 0 {
+        return None;
     }
-
-    None
+    Some(used * 3600.0 / elapsed as f64)
 }
 
-fn resolve_gemini_binary_path() -> Option<PathBuf> {
-    if let Some(path) = std::env::var("GEMINI_CLI_PATH")
-        .ok()
-        .and_then(|value| clean_token_value(&value))
-        .map(PathBuf::from)
-        .filter(|path| path.is_file())
-    {
-        return Some(path);
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Severity {
+    Ok,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    /// Thresholds are remaining percentages and inclusive.
+    fn of(remaining: f64, warn_percent: f64, crit_percent: f64) -> Self {
+        if remaining <= crit_percent {
+            Self::Critical
+        } else if remaining <= warn_percent {
+            Self::Warning
+        } else {
+            Self::Ok
+        }
     }
 
-    let which_output =
-        run_command_with_timeout("which", &["gemini"], Duration::from_secs(5)).ok()?;
-    if !which_output.status.success() {
-        return None;
-    }
-    let which_stdout = String::from_utf8_lossy(&which_output.stdout).to_string();
-    let candidate = which_stdout
-        .lines()
-        .next()
-        .map(str::trim)
-        .filter(|line| !line.is_empty())?
-        .to_string();
-    let path = PathBuf::from(candidate);
-    if path.is_file() {
-        Some(path)
-    } else {
-        None
+    fn paint(self, text: &str) -> String {
+        let color = match self {
+            Self::Ok => "32",
+            Self::Warning => "33",
+            Self::Critical => "31",
+        };
+        format!("\x1b[{color}m{text}\x1b[0m")
     }
 }
 
-fn resolve_single_symlink_path(path: &Path) -> PathBuf {
-    let link_target = match fs::read_link(path) {
-        Ok(target) => target,
-        Err(_) => return path.to_path_buf(),
-    };
-    if link_target.is_absolute() {
-        return link_target;
-    }
-    match path.parent() {
-        Some(parent) => parent.join(link_target),
-        None => link_target,
-    }
+fn stdout_supports_color() -> bool {
+    io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none()
 }
 
-fn extract_js_constant(content: &str, key: &str) -> Option<String> {
-    let key_index = content.find(key)?;
-    let after_key = &content[(key_index + key.len())..];
-    let equals_index = after_key.find('=')?;
-    let after_equals = after_key[(equals_index + 1)..].trim_start();
-    let quote = after_equals.chars().next()?;
-    if quote != '\'' && quote != '"' {
-        return None;
+fn render_text(entries: &[ProviderEntry], args: &UsageArgs, now: i64, color: bool) -> String {
+    if args.compact {
+        return render_compact(entries, args, color);
     }
-    let quoted = &after_equals[1..];
-    let end_index = quoted.find(quote)?;
-    let value = quoted[..end_index].trim();
-    if value.is_empty() {
-        None
-    } else {
-        Some(value.to_string())
-    }
-}
-
-fn fetch_cursor_entry(args: &UsageArgs) -> Result<Option<ProviderEntry>> {
-    let cookie_header = match resolve_cursor_cookie_header() {
-        Some(header) => header,
-        None => return Ok(None),
-    };
-
-    let output = match fetch_cursor_usage_summary_json(&cookie_header) {
-        Ok(output) => output,
-        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
-        Err(error) if error.kind() == ErrorKind::TimedOut => return Ok(None),
-        Err(error) => return Err(error).context("failed to query Cursor usage summary API"),
+    let precision = args.precision;
+    let percent = |remaining: Option<f64>| {
+        let text = format_percent(remaining, precision);
+        match remaining {
+            Some(remaining) if color => {
+                Severity::of(remaining, args.warn_percent, args.crit_percent).paint(&text)
+            }
+            _ => text,
+        }
     };
-
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let (body, status_code) = match split_curl_body_and_status(&stdout) {
-        Some(parts) => parts,
-        None => return Ok(None),
+    let resets = |out: &mut String, window: Option<&RateWindow>| {
+        if let Some(reset) = window
+            .and_then(|window| window.resets_at.as_deref())
+            .and_then(|resets_at| format_reset(resets_at, now, local_offset_at))
+        {
+            let _ = writeln!(out, "  Resets: {reset}");
+        }
     };
-    if status_code != 200 {
-        return Ok(None);
-    }
-
-    Ok(cursor_entry_from_usage_summary_json(
-        body,
-        args,
-        "cursor-usage-summary",
-    ))
-}
-
-fn resolve_cursor_cookie_header() -> Option<String> {
-    first_env_value(&[
-        "CODEXBAR_CURSOR_COOKIE_HEADER",
-        "CURSOR_COOKIE_HEADER",
-        "CURSOR_COOKIE",
-    ])
-    .and_then(|value| clean_token_value(&value))
-    .or_else(load_cursor_cookie_header_from_config)
-}
-
-fn load_cursor_cookie_header_from_config() -> Option<String> {
-    let home = std::env::var("HOME").ok()?;
-    let path = PathBuf::from(home).join(".codexbar").join("config.json");
-    let raw = fs::read_to_string(path).ok()?;
-    let json = serde_json::from_str::<Value>(&raw).ok()?;
-    let providers = json.get("providers").and_then(Value::as_array)?;
+    let mut out = String::new();
+    for entry in entries {
+        let session_left = remaining_percent(entry.primary.as_ref());
+        let weekly_left = remaining_percent(entry.secondary.as_ref());
 
-    for provider in providers {
-        let id = provider
-            .get("id")
-            .and_then(Value::as_str)
-            .unwrap_or_default()
-            .trim();
-        if !id.eq_ignore_ascii_case("cursor") {
+        let _ = writeln!(
+            out,
+            "== {} ({}) ==",
+            entry.provider,
+            entry.source.as_deref().unwrap_or("rust")
+        );
+        if let Some(error) = entry.error.as_ref() {
+            let _ = writeln!(out, "Error: {error}\n");
             continue;
         }
-
-        let cookie_source = provider
-            .get("cookieSource")
-            .and_then(Value::as_str)
-            .unwrap_or_default()
-            .trim();
-        if cookie_source.eq_ignore_ascii_case("off") {
-            return None;
+        if let Some(plan) = entry
+            .identity
+            .as_ref()
+            .and_then(|identity| identity.plan.as_ref())
+        {
+            let _ = writeln!(out, "Plan: {plan}");
         }
-
-        if let Some(cookie_header) = provider.get("cookieHeader").and_then(Value::as_str) {
-            if let Some(value) = clean_token_value(cookie_header) {
-                return Some(value);
-            }
+        let _ = writeln!(out, "Session: {}", percent(session_left));
+        resets(&mut out, entry.primary.as_ref());
+        let _ = writeln!(out, "Weekly: {}", percent(weekly_left));
+        resets(&mut out, entry.secondary.as_ref());
+        // Claude's third window is its Opus weekly limit; newer codex
+        // app-servers report an unnamed one. Skip the row when absent.
+        if entry.tertiary.is_some() {
+            let tertiary_left = remaining_percent(entry.tertiary.as_ref());
+            let label = if entry.provider == "claude" {
+                "Opus Weekly"
+            } else {
+                "Tertiary"
+            };
+            let _ = writeln!(out, "{label}: {}", percent(tertiary_left));
+            resets(&mut out, entry.tertiary.as_ref());
+        }
+        if let Some(code_review_left) = entry.code_review_remaining_percent {
+            let _ = writeln!(out, "Code review: {}", percent(Some(code_review_left)));
+        }
+        if args.since_reset {
+            let pace = |window: Option<&RateWindow>| match window
+                .and_then(|window| percent_per_hour(window, now))
+            {
+                Some(rate) => format!("{rate:.precision$}%/h", precision = precision.max(1)),
+                None => "n/a".to_string(),
+            };
+            let _ = writeln!(out, "Session pace: {}", pace(entry.primary.as_ref()));
+            let _ = writeln!(out, "Weekly pace: {}", pace(entry.secondary.as_ref()));
+        }
+        if let Some(credits) = entry.credits_remaining {
+            let _ = writeln!(out, "Credits: {:.1}", credits);
         }
+        let _ = writeln!(out, "Updated: {}\n", entry.updated_at);
     }
 
-    None
-}
-
-fn fetch_cursor_usage_summary_json(cookie_header: &str) -> io::Result<Output> {
-    let args_owned = [
-        "-sS".to_string(),
-        "--location".to_string(),
-        "--max-time".to_string(),
-        "15".to_string(),
-        "-H".to_string(),
-        "Accept: application/json".to_string(),
-        "-H".to_string(),
-        format!("Cookie: {cookie_header}"),
-        "-w".to_string(),
-        "\n%{http_code}".to_string(),
-        "https://cursor.com/api/usage-summary".to_string(),
-    ];
-    let args = args_owned.iter().map(String::as_str).collect::<Vec<_>>();
-    run_command_with_timeout("curl", &args, Duration::from_secs(20))
+    if args.ascii {
+        to_ascii(&out)
+    } else {
+        out
+    }
 }
 
-fn cursor_entry_from_usage_summary_json(
-    raw_json: &str,
-    args: &UsageArgs,
-    source_label: &str,
-) -> Option<ProviderEntry> {
-    let value = serde_json::from_str::<Value>(raw_json).ok()?;
-    let plan_usage = value
-        .get("individualUsage")
-        .and_then(|usage| usage.get("plan"));
-    let on_demand_usage = value
-        .get("individualUsage")
-        .and_then(|usage| usage.get("onDemand"));
-
-    let plan_used_raw = plan_usage
-        .and_then(|plan| plan.get("used"))
-        .and_then(json_number_value)
-        .unwrap_or(0.0);
-    let plan_limit_raw = plan_usage
-        .and_then(|plan| plan.get("limit"))
-        .and_then(json_number_value)
-        .unwrap_or(0.0);
-    let plan_total_percent_used = plan_usage
-        .and_then(|plan| plan.get("totalPercentUsed"))
-        .and_then(json_number_value);
-    let plan_used_percent = if plan_limit_raw > 0.0 {
-        ((plan_used_raw / plan_limit_raw) * 100.0).clamp(0.0, 100.0)
-    } else if let Some(total_percent) = plan_total_percent_used {
-        if total_percent <= 1.0 {
-            (total_percent * 100.0).clamp(0.0, 100.0)
-        } else {
-            total_percent.clamp(0.0, 100.0)
+/// `--compact`: `provider session | weekly [| opus] [| $credits]`, skipping
+/// windows without data. Errors collapse to `provider error`.
+fn render_compact(entries: &[ProviderEntry], args: &UsageArgs, color: bool) -> String {
+    let mut out = String::new();
+    for entry in entries {
+        if entry.error.is_some() {
+            let _ = writeln!(out, "{} error", entry.provider);
+            continue;
         }
-    } else {
-        0.0
-    };
-
-    let on_demand_used_raw = on_demand_usage
-        .and_then(|usage| usage.get("used"))
-        .and_then(json_number_value)
-        .unwrap_or(0.0);
-    let on_demand_limit_raw = on_demand_usage
-        .and_then(|usage| usage.get("limit"))
-        .and_then(json_number_value);
-
-    let billing_cycle_end = value
-        .get("billingCycleEnd")
-        .and_then(Value::as_str)
-        .map(str::trim)
-        .filter(|text| !text.is_empty())
-        .map(ToOwned::to_owned);
-
-    let primary = Some(RateWindow {
-        used_percent: Some(plan_used_percent),
-        window_minutes: None,
-        resets_at: billing_cycle_end.clone(),
-    });
-    let secondary = on_demand_limit_raw.and_then(|limit_raw| {
-        if limit_raw > 0.0 {
-            Some(RateWindow {
-                used_percent: Some(((on_demand_used_raw / limit_raw) * 100.0).clamp(0.0, 100.0)),
-                window_minutes: None,
-                resets_at: billing_cycle_end.clone(),
+        let mut fields = [&entry.primary, &entry.secondary, &entry.tertiary]
+            .into_iter()
+            .filter_map(|window| remaining_percent(window.as_ref()))
+            .map(|remaining| {
+                let text = format!("{remaining:.precision$}%", precision = args.precision);
+                if color {
+                    Severity::of(remaining, args.warn_percent, args.crit_percent).paint(&text)
+                } else {
+                    text
+                }
             })
+            .collect::<Vec<_>>();
+        if let Some(credits) = entry.credits_remaining {
+            fields.push(format!("${credits:.0}"));
+        }
+        if fields.is_empty() {
+            let _ = writeln!(out, "{}", entry.provider);
         } else {
-            None
+            let _ = writeln!(out, "{} {}", entry.provider, fields.join(" | "));
         }
-    });
+    }
 
-    let source = if args.source.eq_ignore_ascii_case("auto") {
-        source_label.to_string()
-    } else {
-        args.source.clone()
-    };
-    let membership_label = value
-        .get("membershipType")
-        .and_then(Value::as_str)
-        .map(format_cursor_membership_type);
-    let status = if args.status {
-        Some(StatusInfo {
-            indicator: Some("none".to_string()),
-            description: Some("Operational".to_string()),
-            updated_at: Some(now_iso8601()),
-            url: Some("https://status.cursor.com".to_string()),
-        })
+    if args.ascii {
+        to_ascii(&out)
     } else {
-        None
-    };
-
-    Some(ProviderEntry {
-        provider: "cursor".to_string(),
-        source: Some(source),
-        updated_at: now_iso8601(),
-        primary,
-        secondary,
-        tertiary: None,
-        credits_remaining: None,
-        code_review_remaining_percent: None,
-        identity: Some(IdentityInfo {
-            account_email: None,
-            account_organization: None,
-            login_method: membership_label,
-        }),
-        status,
-    })
-}
-
-fn format_cursor_membership_type(raw: &str) -> String {
-    match raw.trim().to_ascii_lowercase().as_str() {
-        "enterprise" => "Cursor Enterprise".to_string(),
-        "pro" => "Cursor Pro".to_string(),
-        "hobby" => "Cursor Hobby".to_string(),
-        "team" => "Cursor Team".to_string(),
-        value if !value.is_empty() => format!("Cursor {}", title_case_words(value)),
-        _ => "Cursor".to_string(),
+        out
     }
 }
 
-fn fetch_copilot_entry(args: &UsageArgs) -> Result<Option<ProviderEntry>> {
-    let access_token = match resolve_copilot_token_for_internal_api() {
-        Some(token) => token,
-        None => return Ok(None),
-    };
-
-    let output = match fetch_copilot_internal_usage_json(&access_token) {
-        Ok(output) => output,
-        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
-        Err(error) if error.kind() == ErrorKind::TimedOut => return Ok(None),
-        Err(error) => {
-            return Err(error).context("failed to query GitHub Copilot internal usage API");
+fn render_bars(entries: &[ProviderEntry], args: &UsageArgs, style: &BarStyle) -> String {
+    let mut out = String::new();
+    for entry in entries {
+        let _ = writeln!(
+            out,
+            "== {} ({}) ==",
+            entry.provider,
+            entry.source.as_deref().unwrap_or("rust")
+        );
+        if let Some(error) = entry.error.as_ref() {
+            let _ = writeln!(out, "Error: {error}\n");
+            continue;
         }
-    };
 
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let (body, status_code) = match split_curl_body_and_status(&stdout) {
-        Some(parts) => parts,
-        None => return Ok(None),
-    };
-    if status_code != 200 {
-        return Ok(None);
+        let windows = [
+            ("Session", entry.primary.as_ref()),
+            ("Weekly", entry.secondary.as_ref()),
+            ("Tertiary", entry.tertiary.as_ref()),
+        ];
+        for (label, window) in windows {
+            if label == "Tertiary" && window.is_none() {
+                continue;
+            }
+            let _ = match remaining_percent(window) {
+                Some(remaining) => writeln!(
+                    out,
+                    "{label:<8} [{}] {remaining:.precision$}%",
+                    render_bar(remaining, style),
+                    precision = args.precision
+                ),
+                None => writeln!(out, "{label:<8} n/a"),
+            };
+        }
+        if let Some(credits) = entry.credits_remaining {
+            let _ = writeln!(out, "Credits: {:.1}", credits);
+        }
+        out.push('\n');
     }
 
-    Ok(copilot_entry_from_internal_usage_json(
-        body,
-        args,
-        "github-copilot-api",
-    ))
+    if args.ascii {
+        to_ascii(&out)
+    } else {
+        out
+    }
 }
 
-fn resolve_copilot_token_for_internal_api() -> Option<String> {
-    first_env_value(&["CODEXBAR_COPILOT_API_TOKEN", "COPILOT_API_TOKEN"])
-        .and_then(|value| clean_token_value(&value))
-        .or_else(load_copilot_token_from_codexbar_config)
-        .or_else(load_copilot_token_from_gh_auth)
-}
+/// Line protocol for one entry: a `codexbar` point per window tagged with the
+/// provider and window name, plus a credits point when present. Timestamps are
+/// the entry's `updatedAt` in nanoseconds, omitted when it can't be parsed.
+fn influx_lines(entry: &ProviderEntry) -> Vec<String> {
+    let timestamp = parse_unix_seconds(&entry.updated_at)
+        .map(|seconds| format!(" {}", i128::from(seconds) * 1_000_000_000))
+        .unwrap_or_default();
+    let provider = influx_escape_tag(&entry.provider);
 
-fn load_copilot_token_from_codexbar_config() -> Option<String> {
-    let home = std::env::var("HOME").ok()?;
-    let path = PathBuf::from(home).join(".codexbar").join("config.json");
-    let raw = fs::read_to_string(path).ok()?;
-    let json = serde_json::from_str::<Value>(&raw).ok()?;
-    let providers = json.get("providers").and_then(Value::as_array)?;
+    let mut windows = vec![
+        ("primary".to_string(), entry.primary.as_ref()),
+        ("secondary".to_string(), entry.secondary.as_ref()),
+        ("tertiary".to_string(), entry.tertiary.as_ref()),
+    ];
+    windows.extend(
+        entry
+            .extra_windows
+            .iter()
+            .enumerate()
+            .map(|(index, window)| (format!("extra{index}"), Some(window))),
+    );
 
-    for provider in providers {
-        let id = provider
-            .get("id")
-            .and_then(Value::as_str)
-            .unwrap_or_default();
-        if !id.eq_ignore_ascii_case("copilot") {
+    let mut lines = Vec::new();
+    for (name, window) in windows {
+        let Some(window) = window else {
             continue;
+        };
+        let Some(used) = window.used_percent else {
+            continue;
+        };
+        let mut fields = format!(
+            "used_percent={},remaining_percent={}",
+            round_json_number(used),
+            round_json_number((100.0 - used).clamp(0.0, 100.0))
+        );
+        if let Some(minutes) = window.window_minutes {
+            let _ = write!(fields, ",window_minutes={minutes}i");
         }
-
-        let api_key = provider.get("apiKey").and_then(Value::as_str)?;
-        if let Some(token) = clean_token_value(api_key) {
-            return Some(token);
+        if let Some(resets_at) = window.resets_at.as_deref().and_then(parse_unix_seconds) {
+            let _ = write!(fields, ",resets_at={resets_at}i");
         }
+        lines.push(format!(
+            "codexbar,provider={provider},window={} {fields}{timestamp}",
+            influx_escape_tag(&name)
+        ));
     }
 
-    None
+    if let Some(credits) = entry.credits_remaining {
+        lines.push(format!(
+            "codexbar,provider={provider} credits_remaining={}{timestamp}",
+            round_json_number(credits)
+        ));
+    }
+    lines
 }
 
-fn load_copilot_token_from_gh_auth() -> Option<String> {
-    let args = ["auth", "token", "-h", "github.com"];
-    let output = run_command_with_timeout("gh", &args, Duration::from_secs(8)).ok()?;
-    if !output.status.success() {
-        return None;
+fn influx_escape_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for ch in value.chars() {
+        if matches!(ch, ',' | '=' | ' ' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(ch);
+    }
+    escaped
+}
+
+/// Folds human output down to ASCII for consoles without UTF-8: common
+/// punctuation gets a readable stand-in, anything else becomes `?`.
+fn to_ascii(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            ch if ch.is_ascii() => out.push(ch),
+            '→' => out.push_str("->"),
+            '←' => out.push_str("<-"),
+            '…' => out.push_str("..."),
+            '–' | '—' => out.push('-'),
+            '‘' | '’' => out.push('\''),
+            '“' | '”' => out.push('"'),
+            '•' | '·' => out.push('*'),
+            '█' => out.push('#'),
+            '░' => out.push('-'),
+            _ => out.push('?'),
+        }
+    }
+    out
+}
+
+/// Columns taken by the label, brackets and percentage around a bar.
+const BAR_DECORATION_COLUMNS: usize = 16;
+const DEFAULT_BAR_WIDTH: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BarStyle {
+    width: usize,
+    ascii: bool,
+    color: bool,
+    /// `--warn-percent` and `--crit-percent`, as for text output.
+    warn_percent: f64,
+    crit_percent: f64,
+}
+
+impl BarStyle {
+    fn detect(args: &UsageArgs) -> Self {
+        let width = args.bar_width.unwrap_or_else(|| {
+            std::env::var("COLUMNS")
+                .ok()
+                .and_then(|columns| columns.trim().parse::<usize>().ok())
+                .map(|columns| columns.saturating_sub(BAR_DECORATION_COLUMNS).clamp(10, 40))
+                .unwrap_or(DEFAULT_BAR_WIDTH)
+        });
+        Self {
+            width: width.max(1),
+            ascii: args.ascii,
+            color: stdout_supports_color(),
+            warn_percent: args.warn_percent,
+            crit_percent: args.crit_percent,
+        }
     }
+}
 
-    clean_token_value(String::from_utf8_lossy(&output.stdout).trim())
-}
-
-fn fetch_copilot_internal_usage_json(access_token: &str) -> io::Result<Output> {
-    let args_owned = [
-        "-sS".to_string(),
-        "--location".to_string(),
-        "--max-time".to_string(),
-        "15".to_string(),
-        "-H".to_string(),
-        format!("Authorization: token {access_token}"),
-        "-H".to_string(),
-        "Accept: application/json".to_string(),
-        "-H".to_string(),
-        "Editor-Version: vscode/1.96.2".to_string(),
-        "-H".to_string(),
-        "Editor-Plugin-Version: copilot-chat/0.26.7".to_string(),
-        "-H".to_string(),
-        "User-Agent: GitHubCopilotChat/0.26.7".to_string(),
-        "-H".to_string(),
-        "X-Github-Api-Version: 2025-04-01".to_string(),
-        "-w".to_string(),
-        "\n%{http_code}".to_string(),
-        "https://api.github.com/copilot_internal/user".to_string(),
-    ];
-    let args = args_owned.iter().map(String::as_str).collect::<Vec<_>>();
-    run_command_with_timeout("curl", &args, Duration::from_secs(20))
+/// The effective character-set locale, following POSIX precedence.
+fn current_locale() -> String {
+    ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .find_map(|key| std::env::var(key).ok().filter(|value| !value.is_empty()))
+        .unwrap_or_default()
 }
 
-fn copilot_entry_from_internal_usage_json(
-    raw_json: &str,
-    args: &UsageArgs,
-    source_label: &str,
-) -> Option<ProviderEntry> {
-    let value = serde_json::from_str::<Value>(raw_json).ok()?;
-    let primary =
-        copilot_window_from_internal_usage(&value, "premium_interactions").or_else(|| {
-            Some(RateWindow {
-                used_percent: Some(0.0),
-                window_minutes: None,
-                resets_at: None,
-            })
-        });
-    let secondary = copilot_window_from_internal_usage(&value, "chat");
-    let source = if args.source.eq_ignore_ascii_case("auto") {
-        source_label.to_string()
+fn locale_is_utf8(locale: &str) -> bool {
+    let locale = locale.to_ascii_lowercase();
+    locale.contains("utf-8") || locale.contains("utf8")
+}
+
+fn render_bar(remaining: f64, style: &BarStyle) -> String {
+    let filled = ((remaining.clamp(0.0, 100.0) / 100.0) * style.width as f64).round() as usize;
+    let (full, empty) = if style.ascii {
+        ("#", "-")
     } else {
-        args.source.clone()
+        ("█", "░")
     };
-    let plan_label = value
-        .get("copilot_plan")
-        .and_then(Value::as_str)
-        .map(title_case_words)
-        .filter(|label| !label.is_empty());
-    let status = if args.status {
-        Some(StatusInfo {
-            indicator: Some("none".to_string()),
-            description: Some("Operational".to_string()),
-            updated_at: Some(now_iso8601()),
-            url: Some("https://www.githubstatus.com/".to_string()),
-        })
-    } else {
-        None
+    let bar = format!(
+        "{}{}",
+        full.repeat(filled),
+        empty.repeat(style.width - filled)
+    );
+    if !style.color {
+        return bar;
+    }
+    Severity::of(remaining, style.warn_percent, style.crit_percent).paint(&bar)
+}
+
+/// `resets_at` as local wall-clock time with how far off it is, e.g.
+/// `2026-02-11 22:00 (in 2h13m)`. `offset_at` gives the offset in effect at
+/// the reset itself, which differs from today's across a DST change. `None`
+/// when it doesn't parse.
+fn format_reset(
+    resets_at: &str,
+    now: i64,
+    offset_at: impl Fn(OffsetDateTime) -> UtcOffset,
+) -> Option<String> {
+    let reset = parse_unix_seconds(resets_at)?;
+    let instant = OffsetDateTime::from_unix_timestamp(reset).ok()?;
+    let local = instant.to_offset(offset_at(instant));
+    let relative = match reset - now {
+        left if left <= 0 => "passed".to_string(),
+        left => format!("in {}", format_duration_short(left)),
     };
+    Some(format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} ({relative})",
+        local.year(),
+        u8::from(local.month()),
+        local.day(),
+        local.hour(),
+        local.minute()
+    ))
+}
 
-    Some(ProviderEntry {
-        provider: "copilot".to_string(),
-        source: Some(source),
-        updated_at: now_iso8601(),
-        primary,
-        secondary,
-        tertiary: None,
-        credits_remaining: None,
-        code_review_remaining_percent: None,
-        identity: Some(IdentityInfo {
-            account_email: None,
-            account_organization: None,
-            login_method: plan_label,
-        }),
-        status,
+/// `2d4h`, `2h13m`, `13m`; anything under a minute is `<1m`.
+fn format_duration_short(seconds: i64) -> String {
+    let (days, hours, minutes) = (seconds / 86_400, seconds / 3_600 % 24, seconds / 60 % 60);
+    match (days, hours, minutes) {
+        (0, 0, 0) => "<1m".to_string(),
+        (0, 0, minutes) => format!("{minutes}m"),
+        (0, hours, minutes) => format!("{hours}h{minutes}m"),
+        (days, hours, _) => format!("{days}d{hours}h"),
+    }
+}
+
+fn remaining_percent(window: Option<&RateWindow>) -> Option<f64> {
+    window.and_then(|window| {
+        window
+            .used_percent
+            .map(|used| (100.0 - used).clamp(0.0, 100.0))
     })
 }
 
-fn copilot_window_from_internal_usage(value: &Value, key: &str) -> Option<RateWindow> {
-    // Preferred schema (documented in docs/copilot.md).
-    let used_percent = if let Some(percent_remaining) = value
-        .get("quota_snapshots")
-        .and_then(|snapshots| snapshots.get(key))
-        .and_then(|snapshot| snapshot.get("percent_remaining"))
-        .and_then(json_number_value)
-    {
-        (100.0 - percent_remaining).clamp(0.0, 100.0)
-    } else {
-        // Variant observed for limited/free plans where quotas are represented as
-        // remaining/entitlement counts rather than percent_remaining.
-        let bucket = match key {
-            "premium_interactions" => "completions",
-            "chat" => "chat",
-            _ => return None,
-        };
+fn format_percent(value: Option<f64>, precision: usize) -> String {
+    match value {
+        Some(value) => format!("{value:.precision$}% left"),
+        None => "n/a".to_string(),
+    }
+}
 
-        let total = value
-            .get("monthly_quotas")
-            .and_then(|quotas| quotas.get(bucket))
-            .and_then(json_number_value)?;
-        let remaining = value
-            .get("limited_user_quotas")
-            .and_then(|quotas| quotas.get(bucket))
-            .and_then(json_number_value)?;
-
-        if total <= 0.0 {
-            0.0
-        } else {
-            ((1.0 - (remaining / total)) * 100.0).clamp(0.0, 100.0)
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codexbar_core::{FixedClock, IdentityInfo};
+    use codexbar_fetch::{
+        claude_entry_from_usage_json, claude_rate_windows, codex_entry_from_usage_json,
+        gemini_entry_from_quota_json, gemini_plan_label, placeholder_entry, GeminiUserTierId,
+        ProviderFailure,
     };
+    use std::sync::Mutex;
+
+    /// Serializes tests that read or mutate process environment variables.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn json_numbers_drop_float_tails() {
+        let mut entry = codex_entry_from_usage_json(
+            r#"{"rate_limit": {"primary_window": {"used_percent": 28, "limit_window_seconds": 18000}}}"#,
+            &FetchOptions::default(),
+            "openai-web",
+        )
+        .unwrap();
+        entry.primary.as_mut().unwrap().used_percent = Some(100.0 - 38.99999999999999);
+        entry.credits_remaining = Some(0.1 + 0.2);
+        entry.code_review_remaining_percent = Some(100.0 / 3.0);
+
+        let payload = cli_payload(&entry, &UsageArgs::default());
+        assert_eq!(payload["usage"]["primary"]["usedPercent"], json!(61.0));
+        assert_eq!(payload["credits"]["remaining"], json!(0.3));
+        assert_eq!(
+            payload["openaiDashboard"]["codeReviewRemainingPercent"],
+            json!(33.33)
+        );
+        assert_eq!(format_percent(Some(61.0 / 3.0), 0), "20% left");
+        assert_eq!(format_percent(Some(61.0 / 3.0), 2), "20.33% left");
+    }
 
-    Some(RateWindow {
-        used_percent: Some(used_percent),
-        window_minutes: None,
-        resets_at: None,
-    })
-}
+    #[test]
+    fn identity_is_nested_unless_legacy_identity() {
+        let mut entry = codex_entry_from_usage_json(
+            r#"{"rate_limit": {"primary_window": {"used_percent": 28}}}"#,
+            &FetchOptions::default(),
+            "openai-web",
+        )
+        .unwrap();
+        entry.identity = Some(IdentityInfo {
+            account_email: Some("dev@example.com".to_string()),
+            account_organization: None,
+            login_method: Some("chatgpt".to_string()),
+            plan: Some("plus".to_string()),
+        });
 
-fn title_case_words(input: &str) -> String {
-    input
-        .split_whitespace()
-        .filter(|part| !part.is_empty())
-        .map(|part| {
-            let mut chars = part.chars();
-            match chars.next() {
-                Some(first) => format!(
-                    "{}{}",
-                    first.to_uppercase(),
-                    chars.as_str().to_ascii_lowercase()
-                ),
-                None => String::new(),
-            }
-        })
-        .collect::<Vec<_>>()
-        .join(" ")
-}
+        // Default shape: identity only under `usage.identity`.
+        let nested = cli_payload(&entry, &UsageArgs::default());
+        let usage = nested["usage"].as_object().unwrap();
+        assert_eq!(usage["identity"]["accountEmail"], json!("dev@example.com"));
+        assert_eq!(usage["identity"]["loginMethod"], json!("chatgpt"));
+        assert_eq!(usage["identity"]["plan"], json!("plus"));
+        for key in ["accountEmail", "accountOrganization", "loginMethod"] {
+            assert!(!usage.contains_key(key), "{key} should not be flattened");
+        }
 
-fn clean_token_value(raw: &str) -> Option<String> {
-    let mut token = raw.trim().to_string();
-    if token.is_empty() {
-        return None;
+        // `--legacy-identity` adds the flattened copies back alongside it.
+        let args = UsageArgs {
+            legacy_identity: true,
+            ..UsageArgs::default()
+        };
+        let legacy = cli_payload(&entry, &args);
+        assert_eq!(legacy["usage"]["identity"], nested["usage"]["identity"]);
+        assert_eq!(legacy["usage"]["accountEmail"], json!("dev@example.com"));
+        assert_eq!(legacy["usage"]["accountOrganization"], Value::Null);
+        assert_eq!(legacy["usage"]["loginMethod"], json!("chatgpt"));
     }
 
-    if (token.starts_with('"') && token.ends_with('"'))
-        || (token.starts_with('\'') && token.ends_with('\''))
-    {
-        token.remove(0);
-        token.pop();
+    #[test]
+    fn renders_doctor_checklist() {
+        let checks = [
+            DoctorCheck::new("curl", true, true, "/usr/bin/curl"),
+            DoctorCheck::new("kwallet-query", false, false, "not on PATH"),
+            DoctorCheck::new("curl", false, true, "not on PATH"),
+        ];
+        assert_eq!(
+            render_doctor(&checks),
+            "[ok]   curl: /usr/bin/curl\n\
+             [warn] kwallet-query: not on PATH\n\
+             [FAIL] curl: not on PATH\n"
+        );
     }
 
-    let cleaned = token.trim().to_string();
-    if cleaned.is_empty() {
-        None
-    } else {
-        Some(cleaned)
+    #[test]
+    fn warns_or_fails_on_unknown_sources() {
+        let args = |source: &str, strict_source: bool| UsageArgs {
+            source: source.to_string(),
+            strict_source,
+            ..UsageArgs::default()
+        };
+
+        assert!(validate_source(&args("auto", true)).is_ok());
+        assert!(validate_source(&args("Claude-OAuth-API", true)).is_ok());
+        assert!(validate_source(&args("oath", false)).is_ok());
+        let error = validate_source(&args("oath", true)).unwrap_err();
+        assert!(error.to_string().starts_with("unknown source 'oath'"));
     }
-}
 
-fn first_env_value(names: &[&str]) -> Option<String> {
-    names.iter().find_map(|name| {
-        std::env::var(name)
-            .ok()
-            .map(|value| value.trim().to_string())
-            .filter(|value| !value.is_empty())
-    })
-}
+    #[test]
+    fn exit_code_follows_structured_cause() {
+        let auth = anyhow::Error::from(CodexBarError::AuthRequired("token expired".to_string()))
+            .context("no live usage data available");
+        assert_eq!(exit_code(&auth), 4);
+        assert_eq!(
+            exit_code(&CodexBarError::NoData("empty".to_string()).into()),
+            6
+        );
+        assert_eq!(exit_code(&anyhow!("config.toml: 3: unknown key")), 1);
+        // 2 stays clap's, for usage errors.
+        let usage = Cli::try_parse_from(["codexbar", "--no-such-flag"]).unwrap_err();
+        assert_eq!(usage.exit_code(), 2);
+    }
 
-fn resolve_claude_oauth_access_token() -> Option<String> {
-    first_env_value(&["CODEXBAR_CLAUDE_OAUTH_TOKEN", "CLAUDE_OAUTH_TOKEN"])
-        .or_else(|| lookup_claude_secret("oauth_access_token"))
-        .or_else(load_claude_oauth_access_token_from_credentials_file)
-}
-
-fn load_claude_oauth_access_token_from_credentials_file() -> Option<String> {
-    let home = std::env::var("HOME").ok()?;
-    let path = PathBuf::from(home)
-        .join(".claude")
-        .join(".credentials.json");
-    let raw = fs::read_to_string(path).ok()?;
-    let json = serde_json::from_str::<Value>(&raw).ok()?;
-    let token = json
-        .get("claudeAiOauth")
-        .and_then(|value| value.get("accessToken"))
-        .and_then(Value::as_str)?
-        .trim()
-        .to_string();
-    if token.is_empty() {
-        None
-    } else {
-        Some(token)
-    }
-}
-
-fn fetch_json_with_bearer(endpoint: &str, access_token: &str) -> io::Result<Output> {
-    let args_owned = [
-        "-sS".to_string(),
-        "--location".to_string(),
-        "--max-time".to_string(),
-        "15".to_string(),
-        "-H".to_string(),
-        format!("Authorization: Bearer {access_token}"),
-        "-H".to_string(),
-        "anthropic-beta: oauth-2025-04-20".to_string(),
-        "-H".to_string(),
-        "Accept: application/json".to_string(),
-        "-w".to_string(),
-        "\n%{http_code}".to_string(),
-        endpoint.to_string(),
-    ];
-    let args = args_owned.iter().map(String::as_str).collect::<Vec<_>>();
-    run_command_with_timeout("curl", &args, Duration::from_secs(20))
-}
+    #[test]
+    fn filters_entries_by_organization() {
+        let with_org = |provider: &str, org: Option<&str>| {
+            let mut entry = codexbar_core::WidgetSnapshot::sample().entries.remove(0);
+            entry.provider = provider.to_string();
+            entry.identity.as_mut().unwrap().account_organization = org.map(str::to_string);
+            entry
+        };
+        let entries = vec![
+            with_org("codex", Some("Acme")),
+            with_org("claude", Some("Other Co")),
+            with_org("gemini", None),
+        ];
+        let providers = |entries: Vec<ProviderEntry>| {
+            entries
+                .into_iter()
+                .map(|entry| entry.provider)
+                .collect::<Vec<_>>()
+        };
 
-fn lookup_claude_secret(field: &str) -> Option<String> {
-    lookup_claude_secret_via_secret_tool(field).or_else(|| lookup_claude_secret_via_kwallet(field))
-}
+        let unfiltered = UsageArgs::default();
+        assert_eq!(
+            providers(filter_entries_by_org(entries.clone(), &unfiltered).unwrap()),
+            ["codex", "claude", "gemini"]
+        );
 
-fn clear_claude_secret(field: &str) {
-    clear_claude_secret_via_secret_tool(field);
-    clear_claude_secret_via_kwallet(field);
-}
+        let strict = UsageArgs {
+            org: Some("acme".to_string()),
+            ..UsageArgs::default()
+        };
+        let error = filter_entries_by_org(entries.clone(), &strict).unwrap_err();
+        assert!(
+            error.to_string().contains("'Other Co', not 'acme'"),
+            "{error}"
+        );
+        assert_eq!(
+            providers(filter_entries_by_org(entries[..1].to_vec(), &strict).unwrap()),
+            ["codex"]
+        );
 
-fn clear_claude_secret_via_secret_tool(field: &str) {
-    let args = [
-        "clear", "service", "codexbar", "provider", "claude", "field", field,
-    ];
-    let _ = run_command_with_timeout("secret-tool", &args, Duration::from_secs(8));
-}
+        let optional = UsageArgs {
+            org_optional: true,
+            ..strict
+        };
+        // A missing organization can't be ruled out, so it stays.
+        assert_eq!(
+            providers(filter_entries_by_org(entries.clone(), &optional).unwrap()),
+            ["codex", "gemini"]
+        );
+        let error = filter_entries_by_org(entries[1..2].to_vec(), &optional).unwrap_err();
+        assert_eq!(exit_code(&error), 6);
 
-fn clear_claude_secret_via_kwallet(field: &str) {
-    let entry = format!("claude.{field}");
-    for wallet in ["kdewallet", "kdewallet5"] {
-        let args = ["-f", "CodexBar", "-d", entry.as_str(), wallet];
-        let _ = run_command_with_timeout("kwallet-query", &args, Duration::from_secs(8));
+        assert!(UsageArgs::try_parse_from(["usage", "--org-optional"]).is_err());
     }
-}
 
-fn lookup_claude_secret_via_secret_tool(field: &str) -> Option<String> {
-    let args = [
-        "lookup", "service", "codexbar", "provider", "claude", "field", field,
-    ];
-    let output = run_command_with_timeout("secret-tool", &args, Duration::from_secs(8)).ok()?;
-    if !output.status.success() {
-        return None;
+    #[test]
+    fn every_known_provider_lists_sources() {
+        for provider in requested_providers("all").unwrap() {
+            assert!(!provider_data_sources(provider).is_empty(), "{provider}");
+        }
+        let listing = ProviderListing {
+            id: "codex",
+            sources: provider_data_sources("codex"),
+            has_credentials: false,
+        };
+        assert_eq!(
+            serde_json::to_value(&listing).unwrap(),
+            json!({"id": "codex", "sources": ["rpc", "status", "api"], "hasCredentials": false})
+        );
     }
 
-    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
-    if value.is_empty() {
-        None
-    } else {
-        Some(value)
-    }
-}
+    #[test]
+    fn rich_resets_add_unix_and_relative_fields() {
+        let window = RateWindow {
+            used_percent: Some(28.0),
+            window_minutes: Some(300),
+            resets_at: Some("2026-02-11T20:00:00Z".to_string()),
+        };
+        let value = rate_window_value(Some(&window), Some(1_770_832_620), JSON_DECIMALS);
+        assert_eq!(value["resetsAt"], json!("2026-02-11T20:00:00Z"));
+        assert_eq!(value["resetsAtUnix"], json!(1_770_840_000));
+        assert_eq!(value["resetsInSeconds"], json!(7380));
 
-fn store_claude_secret(field: &str, label: &str, value: &str) -> Result<()> {
-    if store_claude_secret_via_secret_tool(field, label, value).is_ok() {
-        return Ok(());
-    }
+        let past = rate_window_value(Some(&window), Some(1_770_900_000), JSON_DECIMALS);
+        assert_eq!(past["resetsInSeconds"], json!(0));
 
-    if store_claude_secret_via_kwallet(field, value).is_ok() {
-        return Ok(());
-    }
+        let plain = rate_window_value(Some(&window), None, JSON_DECIMALS);
+        assert!(plain.get("resetsAtUnix").is_none());
 
-    bail!(
-        "failed to store Claude credentials securely; install libsecret-tools (secret-tool) or ensure KDE Wallet is available"
-    );
-}
+        let unknown = RateWindow {
+            resets_at: None,
+            ..window
+        };
+        assert_eq!(
+            rate_window_value(Some(&unknown), Some(0), JSON_DECIMALS)["resetsInSeconds"],
+            Value::Null
+        );
+    }
 
-fn store_claude_secret_via_secret_tool(field: &str, label: &str, value: &str) -> Result<()> {
-    let args = [
-        "store", "--label", label, "service", "codexbar", "provider", "claude", "field", field,
-    ];
+    #[test]
+    fn maps_unexpected_claude_window_keys() {
+        let body = r#"{
+            "five_hour": {"utilization": 10.0, "resets_at": null},
+            "seven_day": {"utilization": 20.0, "resets_at": null},
+            "seven_day_opus": {"utilization": 40.0, "resets_at": null},
+            "seven_day_sonnet": {"utilization": 30.0, "resets_at": null},
+            "one_hour_haiku": {"utilization": 5.0, "resets_at": "2030-01-01T00:00:00Z"},
+            "monthly_research": {"utilization": 50.0, "resets_at": null},
+            "extra_usage": {"is_enabled": true, "utilization": 90.0, "monthly_limit": 10.0, "used_credits": 9.0}
+        }"#;
+        let used = |window: Option<&RateWindow>| window.and_then(|window| window.used_percent);
+
+        let entry =
+            claude_entry_from_usage_json(body, &FetchOptions::default(), "claude-oauth-api")
+                .unwrap();
+        // The shorter unknown window sorts first but never takes a slot.
+        assert_eq!(entry.windows[0].name, "Haiku 1h");
+        assert_eq!(entry.windows[0].slot, None);
+        assert_eq!(used(entry.primary.as_ref()), Some(10.0));
+        assert_eq!(entry.primary.as_ref().unwrap().window_minutes, Some(300));
+        assert_eq!(used(entry.secondary.as_ref()), Some(20.0));
+        assert_eq!(used(entry.tertiary.as_ref()), Some(30.0));
+        assert_eq!(
+            entry
+                .extra_windows
+                .iter()
+                .map(|window| (window.used_percent, window.window_minutes))
+                .collect::<Vec<_>>(),
+            vec![
+                (Some(5.0), Some(60)),
+                (Some(40.0), Some(10080)),
+                (Some(50.0), None),
+            ]
+        );
 
-    let mut secret = value.to_string();
-    secret.push('\n');
-    let output = run_command_with_timeout_and_input(
-        "secret-tool",
-        &args,
-        Some(secret.as_str()),
-        Duration::from_secs(12),
-    )
-    .with_context(|| {
-        "failed to invoke secret-tool; install libsecret-tools (secret-tool)".to_string()
-    })?;
-
-    if !output.status.success() {
-        bail!(
-            "failed to store Claude credentials securely: {}",
-            String::from_utf8_lossy(&output.stderr).trim()
+        let opus_only = r#"{
+            "one_hour_haiku": {"utilization": 5.0, "resets_at": null},
+            "seven_day_opus": {"utilization": 40.0, "resets_at": null}
+        }"#;
+        let entry =
+            claude_entry_from_usage_json(opus_only, &FetchOptions::default(), "claude-oauth-api")
+                .unwrap();
+        assert_eq!(entry.primary, None);
+        assert_eq!(entry.secondary, None);
+        assert_eq!(used(entry.tertiary.as_ref()), Some(40.0));
+        assert_eq!(used(entry.extra_windows.first()), Some(5.0));
+
+        let response = UsageArgs {
+            window_order: WindowOrder::Response,
+            ..UsageArgs::default()
+        };
+        let labels =
+            claude_rate_windows(&serde_json::from_str(body).unwrap(), WindowOrder::Response)
+                .into_iter()
+                .map(|(label, _)| label)
+                .collect::<Vec<_>>();
+        assert_eq!(
+            labels,
+            [
+                "five_hour",
+                "seven_day",
+                "seven_day_sonnet",
+                "seven_day_opus",
+                "monthly_research",
+                "one_hour_haiku",
+            ]
+        );
+        let entry =
+            claude_entry_from_usage_json(body, &response.fetch_options(), "claude-oauth-api")
+                .unwrap();
+        assert_eq!(used(entry.tertiary.as_ref()), Some(30.0));
+        assert_eq!(entry.extra_windows.len(), 3);
+        assert_eq!(
+            entry
+                .windows
+                .iter()
+                .map(|window| window.name.as_str())
+                .collect::<Vec<_>>(),
+            [
+                "5h",
+                "7d",
+                "Sonnet 7d",
+                "Opus 7d",
+                "Monthly research",
+                "Haiku 1h"
+            ]
         );
+        let payload = cli_payload(&entry, &response);
+        assert_eq!(payload["usage"]["windows"][3]["name"], "Opus 7d");
+        assert_eq!(payload["usage"]["windows"][3]["usedPercent"], 40.0);
+        assert_eq!(payload["usage"]["windows"][2]["slot"], "tertiary");
+        assert_eq!(payload["usage"]["windows"][3].get("slot"), None);
     }
 
-    Ok(())
-}
-
-fn lookup_claude_secret_via_kwallet(field: &str) -> Option<String> {
-    let entry = format!("claude.{field}");
-    for wallet in ["kdewallet", "kdewallet5"] {
-        let args = ["-f", "CodexBar", "-r", entry.as_str(), wallet];
-        let output = match run_command_with_timeout("kwallet-query", &args, Duration::from_secs(8))
-        {
-            Ok(output) => output,
-            Err(_) => continue,
+    #[test]
+    fn computes_percent_per_hour_since_reset() {
+        let window = RateWindow {
+            used_percent: Some(30.0),
+            window_minutes: Some(300),
+            resets_at: Some("unix:20000".to_string()),
         };
-        if !output.status.success() {
-            continue;
-        }
+        // The window started at 2000; two hours in at 30% used.
+        assert_eq!(percent_per_hour(&window, 9200), Some(15.0));
+        assert_eq!(percent_per_hour(&window, 2000), None);
+        assert_eq!(percent_per_hour(&window, 20000), None);
+
+        let no_minutes = RateWindow {
+            window_minutes: None,
+            ..window.clone()
+        };
+        assert_eq!(percent_per_hour(&no_minutes, 9200), None);
+        let no_reset = RateWindow {
+            resets_at: None,
+            ..window
+        };
+        assert_eq!(percent_per_hour(&no_reset, 9200), None);
+    }
 
-        let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
-        if !value.is_empty() {
-            return Some(value);
+    #[test]
+    fn maps_provider_failures_to_placeholders() {
+        let cases = [
+            (
+                ProviderFailure::Unavailable("codex CLI not found on PATH".to_string()),
+                "codex CLI not found on PATH",
+            ),
+            (ProviderFailure::NoUsageData, "no live usage data"),
+            (
+                ProviderFailure::FetchFailed("Claude OAuth token expired".to_string()),
+                "fetch failed: Claude OAuth token expired",
+            ),
+        ];
+        for (failure, reason) in cases {
+            let entry = placeholder_entry("claude", &failure, "unavailable");
+            assert_eq!(entry.error.as_deref(), Some(reason));
+            assert_eq!(entry.source.as_deref(), Some("unavailable"));
+            assert!(entry.primary.is_none());
+
+            let args = UsageArgs {
+                source: "oauth".to_string(),
+                ..UsageArgs::default()
+            };
+            let payload = cli_payload(&entry, &args);
+            assert_eq!(payload["source"], json!("unavailable"));
+            assert_eq!(payload["error"]["message"], json!(reason));
+
+            let parsed = ProviderEntry::from_codexbar_cli_value(&payload).unwrap();
+            assert_eq!(parsed.error.as_deref(), Some(reason));
         }
     }
 
-    None
-}
-
-fn store_claude_secret_via_kwallet(field: &str, value: &str) -> Result<()> {
-    let entry = format!("claude.{field}");
-    let mut last_error = None;
+    #[test]
+    fn renders_bars_by_remaining_percent() {
+        let ascii = BarStyle {
+            width: 10,
+            ascii: true,
+            color: false,
+            warn_percent: 20.0,
+            crit_percent: 5.0,
+        };
+        assert_eq!(render_bar(72.0, &ascii), "#######---");
+        assert_eq!(render_bar(0.0, &ascii), "----------");
+        assert_eq!(render_bar(120.0, &ascii), "##########");
 
-    for wallet in ["kdewallet", "kdewallet5"] {
-        let args = ["-f", "CodexBar", "-w", entry.as_str(), wallet];
-        let mut secret = value.to_string();
-        secret.push('\n');
-        let output = match run_command_with_timeout_and_input(
-            "kwallet-query",
-            &args,
-            Some(secret.as_str()),
-            Duration::from_secs(12),
-        ) {
-            Ok(output) => output,
-            Err(error) => {
-                last_error = Some(error.to_string());
-                continue;
-            }
+        let unicode = BarStyle {
+            ascii: false,
+            ..ascii
         };
+        assert_eq!(render_bar(50.0, &unicode), "█████░░░░░");
 
-        if output.status.success() {
-            return Ok(());
-        }
+        let colored = BarStyle {
+            color: true,
+            ..ascii
+        };
+        assert_eq!(render_bar(10.0, &colored), "\x1b[33m#---------\x1b[0m");
+        assert!(render_bar(4.0, &colored).starts_with("\x1b[31m"));
+        assert!(render_bar(30.0, &colored).starts_with("\x1b[32m"));
+        let strict = BarStyle {
+            warn_percent: 50.0,
+            crit_percent: 30.0,
+            ..colored
+        };
+        assert!(render_bar(30.0, &strict).starts_with("\x1b[31m"));
 
-        last_error = Some(String::from_utf8_lossy(&output.stderr).trim().to_string());
+        assert!(locale_is_utf8("en_US.UTF-8"));
+        assert!(locale_is_utf8("C.utf8"));
+        assert!(!locale_is_utf8("C"));
     }
 
-    bail!(
-        "failed to store Claude credentials with KDE Wallet: {}",
-        last_error.unwrap_or_else(|| "unknown error".to_string())
-    )
-}
-
-fn split_curl_body_and_status(output: &str) -> Option<(&str, u16)> {
-    let trimmed = output.trim_end_matches(['\r', '\n']);
-    let (body, status_line) = trimmed.rsplit_once('\n')?;
-    let status_code = status_line.trim().parse::<u16>().ok()?;
-    Some((body, status_code))
-}
+    #[test]
+    fn env_provider_and_source_are_defaults_only() {
+        let env = |provider: &str| {
+            let provider = provider.to_string();
+            move |name: &str| match name {
+                PROVIDER_ENV => Some(provider.clone()),
+                SOURCE_ENV => Some("oauth".to_string()),
+                _ => None,
+            }
+        };
+        let unset = |_: &str| None;
+        assert_eq!(default_usage_provider_from(env("claude")), "claude");
+        assert_eq!(default_usage_source_from(env("claude")), "oauth");
+        assert_eq!(default_usage_provider_from(unset), "all");
+        assert_eq!(default_usage_source_from(unset), "auto");
+
+        let defaults = UsageArgs {
+            provider: "claude".to_string(),
+            ..UsageArgs::default()
+        };
+        assert!(validate_env_provider(&defaults, env("claude")).is_ok());
+        let bad = UsageArgs {
+            provider: "nope".to_string(),
+            ..UsageArgs::default()
+        };
+        let error = validate_env_provider(&bad, env("nope")).unwrap_err();
+        assert!(format!("{error:#}").contains(PROVIDER_ENV));
+        // `--provider codex` overrode the bad value.
+        let explicit = UsageArgs {
+            provider: "codex".to_string(),
+            ..UsageArgs::default()
+        };
+        assert!(validate_env_provider(&explicit, env("nope")).is_ok());
 
-fn claude_entry_from_usage_json(
-    raw_json: &str,
-    args: &UsageArgs,
-    source_label: &str,
-) -> Option<ProviderEntry> {
-    let value = serde_json::from_str::<Value>(raw_json).ok()?;
-    let primary = rate_window_from_claude_json(&value, "five_hour", 300);
-    let secondary = rate_window_from_claude_json(&value, "seven_day", 10080);
-    let tertiary = rate_window_from_claude_json(&value, "seven_day_sonnet", 10080)
-        .or_else(|| rate_window_from_claude_json(&value, "seven_day_opus", 10080));
-
-    if primary.is_none() && secondary.is_none() && tertiary.is_none() {
-        return None;
+        // The env beats config.toml but not the command line.
+        let config = UsageConfig {
+            provider: Some("gemini".to_string()),
+            source: Some("api".to_string()),
+            ..UsageConfig::default()
+        };
+        let mut args = defaults.clone();
+        apply_config(&config, &mut args, |_| false, env("claude"));
+        assert_eq!(args.provider, "claude");
+        assert_eq!(args.source, UsageArgs::default().source);
+        let mut args = defaults.clone();
+        apply_config(&config, &mut args, |_| false, unset);
+        assert_eq!(args.provider, "gemini");
+        assert_eq!(args.source, "api");
     }
 
-    let source = if args.source.eq_ignore_ascii_case("auto") {
-        source_label.to_string()
-    } else {
-        args.source.clone()
-    };
+    #[test]
+    fn queries_payload_with_json_pointers() {
+        let payload = json!([{
+            "provider": "codex",
+            "usage": {"primary": {"usedPercent": 28.5, "resetsAt": null}},
+        }]);
+        let queries = [
+            "/0/usage/primary/usedPercent".to_string(),
+            "/0/provider".to_string(),
+            "/0/usage/primary/resetsAt".to_string(),
+        ];
+        assert_eq!(
+            query_json_pointers(&payload, &queries).unwrap(),
+            vec!["28.5", "codex", "null"]
+        );
 
-    let status = if args.status {
-        Some(StatusInfo {
-            indicator: Some("none".to_string()),
-            description: Some("Operational".to_string()),
-            updated_at: Some(now_iso8601()),
-            url: Some("https://status.claude.com/".to_string()),
-        })
-    } else {
-        None
-    };
+        let missing = query_json_pointers(&payload, &["/1/provider".to_string()]);
+        assert!(missing.unwrap_err().to_string().contains("/1/provider"));
+        assert!(query_json_pointers(&payload, &["0/provider".to_string()]).is_err());
+    }
 
-    Some(ProviderEntry {
-        provider: "claude".to_string(),
-        source: Some(source),
-        updated_at: now_iso8601(),
-        primary,
-        secondary,
-        tertiary,
-        credits_remaining: None,
-        code_review_remaining_percent: None,
-        identity: Some(IdentityInfo {
-            account_email: None,
-            account_organization: None,
-            login_method: Some("oauth".to_string()),
-        }),
-        status,
-    })
-}
+    #[test]
+    fn ascii_only_output_snapshot() {
+        let mut entry = codexbar_core::WidgetSnapshot::sample().entries.remove(0);
+        entry.source = Some("openai–web".to_string());
+        entry.updated_at = "unix:1760000000".to_string();
+        let missing = placeholder_entry(
+            "claude",
+            &ProviderFailure::Unavailable("token expired → run “codexbar auth”".to_string()),
+            "unavailable",
+        );
+        let entries = vec![entry, missing];
+        let args = UsageArgs {
+            ascii: true,
+            ..UsageArgs::default()
+        };
 
-fn rate_window_from_claude_json(
-    value: &Value,
-    key: &str,
-    window_minutes: u64,
-) -> Option<RateWindow> {
-    let window = value.get(key)?;
-    let used_percent = window
-        .get("utilization")
-        .and_then(json_number_value)
-        .map(|value| value.clamp(0.0, 100.0));
-    let resets_at = match window.get("resets_at") {
-        Some(Value::String(text)) if !text.trim().is_empty() => Some(text.trim().to_string()),
-        Some(Value::Number(number)) => number
-            .as_i64()
-            .map(|seconds| format!("unix:{seconds}"))
-            .or_else(|| number.as_u64().map(|seconds| format!("unix:{seconds}"))),
-        _ => None,
-    };
+        // 2026-02-11T17:47:00Z; tests run without `init_local_offset`, so UTC.
+        let now = 1_770_832_020;
+        assert_eq!(
+            render_text(&entries, &args, now, false),
+            "== codex (openai-web) ==\n\
+             Plan: plus\n\
+             Session: 72% left\n\
+             \x20 Resets: 2026-02-11 20:00 (in 2h13m)\n\
+             Weekly: 39% left\n\
+             \x20 Resets: 2026-02-14 20:00 (in 3d2h)\n\
+             Code review: 100% left\n\
+             Credits: 92.4\n\
+             Updated: unix:1760000000\n\
+             \n\
+             == claude (unavailable) ==\n\
+             Error: token expired -> run \"codexbar auth\"\n\
+             \n"
+        );
 
-    if used_percent.is_none() && resets_at.is_none() {
-        return None;
+        let style = BarStyle {
+            width: 10,
+            ascii: false,
+            color: false,
+            warn_percent: 20.0,
+            crit_percent: 5.0,
+        };
+        let bars = render_bars(&entries[..1], &args, &style);
+        assert!(bars.is_ascii());
+        assert!(bars.contains("Session  [#######---] 72%"));
     }
 
-    Some(RateWindow {
-        used_percent,
-        window_minutes: Some(window_minutes),
-        resets_at,
-    })
-}
+    #[test]
+    fn compact_text_is_one_line_per_provider() {
+        let mut entries = codexbar_core::WidgetSnapshot::sample().entries;
+        assert_eq!(entries[1].credits_remaining, None);
+        entries[1].secondary = None;
+        entries.push(placeholder_entry(
+            "cursor",
+            &ProviderFailure::NoUsageData,
+            "unavailable",
+        ));
+        let args = UsageArgs {
+            compact: true,
+            ..UsageArgs::default()
+        };
 
-fn json_number_value(value: &Value) -> Option<f64> {
-    match value {
-        Value::Number(number) => number.as_f64(),
-        Value::String(string_value) => string_value.trim().parse::<f64>().ok(),
-        _ => None,
+        assert_eq!(
+            render_text(&entries, &args, 0, false),
+            "codex 72% | 39% | $92\nclaude 59%\ncursor error\n"
+        );
+
+        let json = UsageArgs {
+            format: OutputFormat::Json,
+            ..args
+        };
+        assert!(run_usage(&json).is_err());
     }
-}
 
-fn rate_window_from_codex(window: Option<RpcRateLimitWindow>) -> Option<RateWindow> {
-    let window = window?;
-    let used_percent = window.used_percent?;
+    #[test]
+    fn fields_project_selected_values() {
+        let mut entries = codexbar_core::WidgetSnapshot::sample().entries;
+        entries[1].secondary = None;
+        let cli = Cli::try_parse_from([
+            "codexbar",
+            "usage",
+            "--fields",
+            "provider,secondary.remaining,credits",
+        ])
+        .unwrap();
+        let Some(Commands::Usage(args)) = cli.command else {
+            panic!("expected usage subcommand");
+        };
 
-    Some(RateWindow {
-        used_percent: Some(used_percent),
-        window_minutes: window.window_duration_mins,
-        resets_at: window
-            .resets_at
-            .map(|timestamp| format!("unix:{timestamp}")),
-    })
-}
+        assert_eq!(
+            render_usage(&entries, &args).unwrap(),
+            "codex\t39\t92\nclaude\t\t\n"
+        );
 
-fn run_command_with_timeout(program: &str, args: &[&str], timeout: Duration) -> io::Result<Output> {
-    run_command_with_timeout_and_input(program, args, None, timeout)
-}
+        let json = UsageArgs {
+            format: OutputFormat::Json,
+            fields: vec!["primary.remaining".to_string(), "credits".to_string()],
+            ..UsageArgs::default()
+        };
+        let payload: Value = serde_json::from_str(&render_usage(&entries, &json).unwrap()).unwrap();
+        assert_eq!(
+            payload,
+            json!([
+                {"primary.remaining": 72.0, "credits": 92.4},
+                {"primary.remaining": 59.0, "credits": null},
+            ])
+        );
 
-fn run_command_with_timeout_and_input(
-    program: &str,
-    args: &[&str],
-    input: Option<&str>,
-    timeout: Duration,
-) -> io::Result<Output> {
-    let mut command = Command::new(program);
-    command
-        .args(args)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped());
-    if input.is_some() {
-        command.stdin(Stdio::piped());
+        let rounded = UsageArgs {
+            decimals: 0,
+            json_compact_numbers: true,
+            ..json
+        };
+        let payload: Value =
+            serde_json::from_str(&render_usage(&entries, &rounded).unwrap()).unwrap();
+        assert_eq!(
+            payload,
+            json!([
+                {"primary.remaining": 72, "credits": 92},
+                {"primary.remaining": 59, "credits": null},
+            ])
+        );
+        assert_eq!(payload[0]["credits"].as_u64(), Some(92));
     }
 
-    let mut child = command.spawn()?;
-    if let Some(input_text) = input {
-        if let Some(mut stdin) = child.stdin.take() {
-            stdin.write_all(input_text.as_bytes())?;
-            stdin.flush()?;
-        }
+    #[test]
+    fn unknown_fields_list_valid_names() {
+        let error = UsageArgs::try_parse_from(["usage", "--fields", "primary.remaining,weekly"])
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("'weekly'"), "{error}");
+        assert!(error.contains("secondary.remaining"), "{error}");
+        assert!(error.contains("credits"), "{error}");
     }
 
-    let deadline = Instant::now() + timeout;
-    loop {
-        if child.try_wait()?.is_some() {
-            return child.wait_with_output();
-        }
-
-        if Instant::now() >= deadline {
-            let _ = child.kill();
-            let _ = child.wait();
-            return Err(io::Error::new(
-                ErrorKind::TimedOut,
-                format!("{program} timed out"),
-            ));
-        }
+    #[test]
+    fn compact_numbers_drop_whole_number_fractions() {
+        let entry = codexbar_core::WidgetSnapshot::sample().entries.remove(0);
+        let args = UsageArgs {
+            json_compact_numbers: true,
+            ..UsageArgs::default()
+        };
 
-        std::thread::sleep(Duration::from_millis(100));
+        let payload = cli_payload(&entry, &args);
+        assert!(payload["usage"]["secondary"]["usedPercent"].is_i64());
+        assert_eq!(payload["usage"]["secondary"]["usedPercent"], json!(61));
+        assert_eq!(payload["credits"]["remaining"], json!(92.4));
+        let json = serde_json::to_string(&payload).unwrap();
+        assert!(json.contains(r#""usedPercent":61,"#), "{json}");
+        assert!(json.contains(r#""remaining":92.4"#), "{json}");
+
+        let plain = cli_payload(&entry, &UsageArgs::default());
+        assert!(plain["usage"]["secondary"]["usedPercent"].is_f64());
+
+        let rounded = cli_payload(
+            &entry,
+            &UsageArgs {
+                decimals: 0,
+                ..args
+            },
+        );
+        assert_eq!(rounded["credits"]["remaining"], json!(92));
     }
-}
-
-fn strip_ansi_sequences(input: &str) -> String {
-    let mut output = String::with_capacity(input.len());
-    let bytes = input.as_bytes();
-    let mut index = 0;
 
-    while index < bytes.len() {
-        if bytes[index] == 0x1B {
-            index += 1;
-            if index < bytes.len() && bytes[index] == b'[' {
-                index += 1;
-                while index < bytes.len() {
-                    let byte = bytes[index];
-                    index += 1;
-                    if (0x40..=0x7E).contains(&byte) {
-                        break;
-                    }
-                }
-                continue;
-            }
-            continue;
+    #[test]
+    fn exports_sample_snapshot_as_markdown() {
+        let mut snapshot = codexbar_core::WidgetSnapshot::sample();
+        for entry in &mut snapshot.entries {
+            entry.updated_at = "2026-02-11T12:00:00Z".to_string();
         }
 
-        output.push(bytes[index] as char);
-        index += 1;
+        let report = render_markdown_report(
+            &report_sections(&snapshot.entries, false),
+            "2026-02-11T12:00:00Z",
+        );
+        assert_eq!(report, include_str!("../testdata/export-sample.md"));
+        assert!(!report.contains("@example.com"));
+
+        let identified = report_sections(&snapshot.entries, true);
+        assert!(identified[0]
+            .details
+            .contains(&("Account", "codex@example.com".to_string())));
+
+        let mut multiline = identified.clone();
+        multiline[0].details = vec![("Plan", "plus\nteam | org".to_string())];
+        multiline[1].error = Some("fetch failed:\r\nHTTP 503".to_string());
+        let report = render_markdown_report(&multiline, "2026-02-11T12:00:00Z");
+        assert!(report.contains("| Plan | plus<br>team \\| org |\n"));
+        assert!(report.contains("Error: fetch failed:<br>HTTP 503\n"));
+
+        let html = render_html_report(&identified, "2026-02-11T12:00:00Z");
+        assert!(html.contains("<h2>claude</h2>"));
+        assert!(html
+            .contains("<tr><td>5h</td><td>41%</td><td>59%</td><td>2026-02-11T23:30:00Z</td></tr>"));
+        assert_eq!(
+            html_escape("a<b & \"c\">"),
+            "a&lt;b &amp; &quot;c&quot;&gt;"
+        );
     }
 
-    output
-}
+    #[test]
+    fn prints_tertiary_window_only_when_present() {
+        let codex = codexbar_core::WidgetSnapshot::sample().entries.remove(0);
+        let mut claude = codex.clone();
+        claude.provider = "claude".to_string();
+        claude.tertiary = Some(RateWindow {
+            used_percent: Some(40.0),
+            window_minutes: Some(10080),
+            resets_at: None,
+        });
+        let args = UsageArgs::default();
+        let now = 1_770_832_020;
 
-fn first_line_containing_case_insensitive(text: &str, needle: &str) -> Option<String> {
-    let needle_lower = needle.to_ascii_lowercase();
-    text.lines()
-        .find(|line| line.to_ascii_lowercase().contains(&needle_lower))
-        .map(ToOwned::to_owned)
-}
+        let text = render_text(&[codex.clone(), claude.clone()], &args, now, false);
+        assert_eq!(text.matches("Opus Weekly:").count(), 1);
+        assert!(text.contains("(in 3d2h)\nOpus Weekly: 60% left\n"));
 
-fn extract_percent_left_from_line(line: &str) -> Option<f64> {
-    if !line.to_ascii_lowercase().contains("left") {
-        return None;
+        let mut codex = codex;
+        codex.tertiary = claude.tertiary;
+        let text = render_text(&[codex], &args, now, false);
+        assert!(text.contains("(in 3d2h)\nTertiary: 60% left\n"));
     }
-    let percent_index = line.find('%')?;
-    let prefix = &line[..percent_index];
-    parse_last_number(prefix)
-}
 
-fn extract_credits_from_status(text: &str) -> Option<f64> {
-    for line in text.lines() {
-        let lower = line.to_ascii_lowercase();
-        if !lower.contains("credits") {
-            continue;
-        }
-        if let Some((_, tail)) = line.split_once(':') {
-            if let Some(value) = parse_first_number(tail) {
-                return Some(value);
-            }
-        }
-        if let Some(value) = parse_first_number(line) {
-            return Some(value);
-        }
-    }
-    None
-}
+    #[test]
+    fn prints_code_review_only_when_present() {
+        let mut entries = codexbar_core::WidgetSnapshot::sample().entries;
+        entries[0].code_review_remaining_percent = Some(42.4);
+        assert_eq!(entries[1].code_review_remaining_percent, None);
 
-fn parse_first_number(input: &str) -> Option<f64> {
-    let bytes = input.as_bytes();
-    if bytes.is_empty() {
-        return None;
+        let text = render_text(&entries, &UsageArgs::default(), 1_770_832_020, false);
+        assert_eq!(text.matches("Code review:").count(), 1);
+        assert!(text.contains("(in 3d2h)\nCode review: 42% left\n"));
     }
 
-    let mut start = 0;
-    while start < bytes.len() && !bytes[start].is_ascii_digit() {
-        start += 1;
-    }
-    if start == bytes.len() {
-        return None;
-    }
+    #[test]
+    fn formats_reset_times_in_local_time() {
+        let now = 1_770_832_020; // 2026-02-11T17:47:00Z
+        let berlin = |_| UtcOffset::from_hms(1, 0, 0).unwrap();
+        let new_york = |_| UtcOffset::from_hms(-5, 0, 0).unwrap();
+        let utc = |_| UtcOffset::UTC;
 
-    let mut end = start;
-    while end < bytes.len()
-        && (bytes[end].is_ascii_digit() || bytes[end] == b'.' || bytes[end] == b',')
-    {
-        end += 1;
-    }
+        assert_eq!(
+            format_reset("2026-02-11T20:00:00Z", now, berlin).as_deref(),
+            Some("2026-02-11 21:00 (in 2h13m)")
+        );
+        assert_eq!(
+            format_reset("2026-02-12T01:30:00Z", now, new_york).as_deref(),
+            Some("2026-02-11 20:30 (in 7h43m)")
+        );
+        assert_eq!(
+            format_reset("2026-02-14T20:00:00Z", now, utc).as_deref(),
+            Some("2026-02-14 20:00 (in 3d2h)")
+        );
+        assert_eq!(
+            format_reset("2026-02-11T17:47:30Z", now, utc).as_deref(),
+            Some("2026-02-11 17:47 (in <1m)")
+        );
+        assert_eq!(
+            format_reset("2026-02-11T17:00:00Z", now, utc).as_deref(),
+            Some("2026-02-11 17:00 (passed)")
+        );
+        assert_eq!(format_reset("soon", now, berlin), None);
 
-    let raw = input[start..end].replace(',', "");
-    raw.parse::<f64>().ok()
-}
+        // Berlin moves to CEST at 2026-03-29T01:00Z; a reset after that
+        // shows summer time even when read in winter.
+        let berlin_dst = |instant: OffsetDateTime| {
+            let hours = if instant.unix_timestamp() >= 1_774_746_000 {
+                2
+            } else {
+                1
+            };
+            UtcOffset::from_hms(hours, 0, 0).unwrap()
+        };
+        let winter = 1_774_652_400; // 2026-03-27T23:00:00Z
+        assert_eq!(
+            format_reset("2026-03-28T20:00:00Z", winter, berlin_dst).as_deref(),
+            Some("2026-03-28 21:00 (in 21h0m)")
+        );
+        assert_eq!(
+            format_reset("2026-03-30T20:00:00Z", winter, berlin_dst).as_deref(),
+            Some("2026-03-30 22:00 (in 2d21h)")
+        );
 
-fn parse_last_number(input: &str) -> Option<f64> {
-    let bytes = input.as_bytes();
-    if bytes.is_empty() {
-        return None;
+        let mut entry = codexbar_core::WidgetSnapshot::sample().entries.remove(0);
+        entry.primary.as_mut().unwrap().resets_at = Some("soon".to_string());
+        entry.secondary.as_mut().unwrap().resets_at = None;
+        let text = render_text(&[entry], &UsageArgs::default(), now, false);
+        assert!(!text.contains("Resets:"));
     }
 
-    let mut end = bytes.len();
-    while end > 0 && !bytes[end - 1].is_ascii_digit() {
-        end -= 1;
-    }
-    if end == 0 {
-        return None;
-    }
+    #[test]
+    fn jsonl_lines_parse_independently() {
+        let mut entries = codexbar_core::WidgetSnapshot::sample().entries;
+        entries.push(placeholder_entry(
+            "cursor",
+            &ProviderFailure::NoUsageData,
+            "unavailable",
+        ));
+        let args = UsageArgs {
+            format: OutputFormat::Jsonl,
+            ..UsageArgs::default()
+        };
 
-    let mut start = end;
-    while start > 0 {
-        let byte = bytes[start - 1];
-        if byte.is_ascii_digit() || byte == b'.' {
-            start -= 1;
-        } else {
-            break;
+        let output = jsonl_lines(&entries, &args).unwrap().join("\n");
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), entries.len());
+        for (line, entry) in lines.iter().zip(&entries) {
+            let value = serde_json::from_str::<Value>(line).unwrap();
+            assert_eq!(value, cli_payload(entry, &args));
         }
+
+        let pretty = UsageArgs {
+            pretty: true,
+            ..args
+        };
+        assert!(run_usage(&pretty).is_err());
     }
 
-    input[start..end].parse::<f64>().ok()
-}
+    #[test]
+    fn yaml_output_round_trips_to_json_payload() {
+        let mut entries = codexbar_core::WidgetSnapshot::sample().entries;
+        entries.push(placeholder_entry(
+            "cursor",
+            &ProviderFailure::NoUsageData,
+            "unavailable",
+        ));
+        let args = UsageArgs {
+            format: OutputFormat::Yaml,
+            ..UsageArgs::default()
+        };
 
-struct CodexRpcSession {
-    child: Child,
-    stdin: ChildStdin,
-    stdout: BufReader<ChildStdout>,
-    next_id: i64,
-}
+        let yaml = render_usage(&entries, &args).unwrap();
+        let parsed = serde_yaml::from_str::<Value>(&yaml).unwrap();
+        let json = UsageArgs {
+            format: OutputFormat::Json,
+            ..args.clone()
+        };
+        let expected =
+            serde_json::from_str::<Value>(&render_usage(&entries, &json).unwrap()).unwrap();
+        assert_eq!(parsed, expected);
 
-impl CodexRpcSession {
-    fn start() -> Result<Option<Self>> {
-        let mut child = match Command::new("codex")
-            .args(["-s", "read-only", "-a", "untrusted", "app-server"])
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::null())
-            .spawn()
-        {
-            Ok(child) => child,
-            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
-            Err(error) => return Err(error).context("failed to launch codex app-server"),
+        let pretty = UsageArgs {
+            pretty: true,
+            ..args.clone()
         };
+        assert_eq!(render_usage(&entries, &pretty).unwrap(), yaml);
+    }
 
-        let stdin = child
-            .stdin
-            .take()
-            .context("failed to open codex app-server stdin")?;
-        let stdout = child
-            .stdout
-            .take()
-            .context("failed to open codex app-server stdout")?;
-
-        Ok(Some(Self {
-            child,
-            stdin,
-            stdout: BufReader::new(stdout),
-            next_id: 1,
-        }))
-    }
-
-    fn initialize(&mut self) -> Result<()> {
-        let _ = self.request(
-            "initialize",
-            json!({
-                "clientInfo": {
-                    "name": "codexbar-rust",
-                    "version": env!("CARGO_PKG_VERSION")
-                }
-            }),
-        )?;
-        self.notify("initialized", json!({}))?;
-        Ok(())
+    #[test]
+    fn export_fetches_like_usage() {
+        let config = UsageConfig {
+            provider: Some("claude".to_string()),
+            source: Some("oauth".to_string()),
+            status: Some(false),
+            timeout: Some(Duration::from_secs(30)),
+        };
+        let export = ExportArgs::try_parse_from(["export"]).unwrap();
+        let usage = export_usage_args(&export, &config, |_| None);
+        assert_eq!(usage.provider, "claude");
+        assert_eq!(usage.source, "oauth");
+        assert!(usage.status);
+        assert_eq!(usage.timeout, Some(Duration::from_secs(30)));
+
+        let env = |name: &str| (name == SOURCE_ENV).then(|| "api".to_string());
+        assert_eq!(export_usage_args(&export, &config, env).source, "api");
+
+        let explicit = ExportArgs::try_parse_from(["export", "--provider", "codex"]).unwrap();
+        assert_eq!(
+            export_usage_args(&explicit, &config, |_| None).provider,
+            "codex"
+        );
     }
 
-    fn fetch_account(&mut self) -> Result<RpcAccountResponse> {
-        let value = self.request("account/read", json!({}))?;
-        serde_json::from_value(value).context("failed to decode codex account response")
+    #[test]
+    fn command_line_flags_override_config() {
+        let config = UsageConfig {
+            provider: Some("claude".to_string()),
+            source: Some("oauth".to_string()),
+            status: Some(true),
+            timeout: Some(Duration::from_secs(30)),
+        };
+        let mut args = UsageArgs {
+            provider: "codex".to_string(),
+            ..UsageArgs::default()
+        };
+        apply_config(&config, &mut args, |id| id == "provider", |_| None);
+
+        assert_eq!(args.provider, "codex");
+        assert_eq!(args.source, "oauth");
+        assert!(args.status);
+        assert_eq!(args.timeout, Some(Duration::from_secs(30)));
     }
 
-    fn fetch_rate_limits(&mut self) -> Result<RpcRateLimitsResponse> {
-        let value = self.request("account/rateLimits/read", json!({}))?;
-        serde_json::from_value(value).context("failed to decode codex rate limits response")
+    #[test]
+    fn entry_cache_serves_fresh_hits_and_falls_back_on_failure() {
+        let dir = std::env::temp_dir().join(format!("codexbar-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let options = FetchOptions::default();
+        let cache = EntryCache {
+            dir: dir.clone(),
+            ttl_seconds: 60,
+            request: EntryCache::request_key("default", &options),
+        };
+        let entry = codexbar_core::WidgetSnapshot::sample().entries.remove(0);
+        let now = 1_760_000_000;
+
+        let fetched = cache
+            .fetch("codex", now, || Ok(Some(entry.clone())))
+            .unwrap();
+        assert_eq!(fetched.as_ref(), Some(&entry));
+
+        // Fresh hit: the live fetch must not run.
+        let hit = cache
+            .fetch("codex", now + 30, || panic!("fetched within the TTL"))
+            .unwrap();
+        assert_eq!(hit.as_ref(), Some(&entry));
+
+        // Expired: fetches again and refreshes the cache.
+        let mut refreshed = entry.clone();
+        refreshed.credits_remaining = Some(10.0);
+        let miss = cache
+            .fetch("codex", now + 90, || Ok(Some(refreshed.clone())))
+            .unwrap();
+        assert_eq!(miss.as_ref(), Some(&refreshed));
+        assert_eq!(cache.read("codex").unwrap().cached_at, now + 90);
+
+        // Failure after expiry: stale entry, stamped with its fetch time.
+        let fallback = cache
+            .fetch("codex", now + 500, || bail!("app-server crashed"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(fallback.credits_remaining, Some(10.0));
+        assert_eq!(fallback.updated_at, format_timestamp(now + 90));
+        let none = cache.fetch("codex", now + 500, || Ok(None)).unwrap();
+        assert_eq!(none.unwrap().credits_remaining, Some(10.0));
+
+        // Too old to stand in for a live fetch.
+        let day_later = now + 91 + CACHE_FALLBACK_MAX_AGE_SECONDS;
+        assert!(cache
+            .fetch("codex", day_later, || bail!("offline"))
+            .is_err());
+        assert_eq!(cache.fetch("codex", day_later, || Ok(None)).unwrap(), None);
+
+        // Another account or other options never see this entry.
+        let work = EntryCache {
+            request: EntryCache::request_key("work", &options),
+            ..cache.clone()
+        };
+        assert!(work.fetch("codex", now + 91, || bail!("offline")).is_err());
+        let oauth = EntryCache {
+            request: EntryCache::request_key(
+                "default",
+                &FetchOptions {
+                    source: "oauth".to_string(),
+                    ..FetchOptions::default()
+                },
+            ),
+            ..cache.clone()
+        };
+        assert!(oauth.fetch("codex", now + 91, || bail!("offline")).is_err());
+
+        assert!(cache.fetch("claude", now, || bail!("offline")).is_err());
+        let _ = fs::remove_dir_all(&dir);
     }
 
-    fn request(&mut self, method: &str, params: Value) -> Result<Value> {
-        let id = self.next_id;
-        self.next_id += 1;
+    #[test]
+    fn throttle_reuses_recent_identical_runs() {
+        let path =
+            std::env::temp_dir().join(format!("codexbar-throttle-{}.lock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let throttle = FetchThrottle {
+            path: path.clone(),
+            min_interval_seconds: 5,
+            account: "default".to_string(),
+        };
+        let args = UsageArgs {
+            provider: "codex".to_string(),
+            ..UsageArgs::default()
+        };
+        let entries = codexbar_core::WidgetSnapshot::sample().entries;
+        let now = 1_760_000_000;
 
-        self.send_payload(json!({
-            "id": id,
-            "method": method,
-            "params": params
-        }))?;
+        assert!(throttle.recent(&args, now).is_none());
+        throttle.record(&args, &entries, now);
+        assert_eq!(throttle.recent(&args, now + 4), Some(entries));
+        assert!(throttle.recent(&args, now + 5).is_none());
 
-        loop {
-            let message = self.read_message()?;
-            let message_id = message.get("id").and_then(Value::as_i64);
-            if message_id != Some(id) {
-                continue;
-            }
+        let other = UsageArgs {
+            provider: "claude".to_string(),
+            ..args.clone()
+        };
+        assert!(throttle.recent(&other, now + 1).is_none());
+        let work = FetchThrottle {
+            account: "work".to_string(),
+            ..throttle
+        };
+        assert!(work.recent(&args, now + 1).is_none());
+        let throttle = FetchThrottle {
+            account: "default".to_string(),
+            ..work
+        };
 
-            if let Some(error) = message.get("error") {
-                bail!("codex app-server request '{method}' failed: {error}");
-            }
+        fs::write(&path, "not json").unwrap();
+        assert!(throttle.recent(&args, now + 1).is_none());
+        let _ = fs::remove_file(&path);
+    }
 
-            if let Some(result) = message.get("result") {
-                return Ok(result.clone());
-            }
+    #[cfg(unix)]
+    #[test]
+    fn private_files_are_user_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path =
+            std::env::temp_dir().join(format!("codexbar-private-{}.lock", std::process::id()));
+        fs::write(&path, "{}").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        create_private_file(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{}");
+        let _ = fs::remove_file(&path);
+    }
 
-            bail!("codex app-server response missing result for method '{method}'");
-        }
+    #[test]
+    fn watch_frames_mark_stale_data() {
+        let entries = codexbar_core::WidgetSnapshot::sample().entries;
+        let text = UsageArgs::default();
+        let clock = FixedClock::at_unix_seconds(1_770_811_200);
+
+        let fresh = watch_frame(&entries, &text, &[], &clock).unwrap();
+        assert!(fresh.starts_with("\x1b[2J\x1b[H== codex"));
+        let crashed = [StaleProvider {
+            provider: "codex".to_string(),
+            reason: "app-server crashed".to_string(),
+        }];
+        let stale = watch_frame(&entries, &text, &crashed, &clock).unwrap();
+        assert!(stale.contains("(stale) codex: refresh failed: app-server crashed\n"));
+        assert!(stale.ends_with(&render_text(&entries, &text, clock.unix_seconds(), false)));
+
+        let json = UsageArgs {
+            format: OutputFormat::Json,
+            ..UsageArgs::default()
+        };
+        let tick = watch_frame(&entries, &json, &crashed, &clock).unwrap();
+        assert_eq!(tick.lines().count(), 1);
+        let payload = serde_json::from_str::<Value>(&tick).unwrap();
+        assert_eq!(payload.as_array().unwrap().len(), entries.len());
     }
 
-    fn notify(&mut self, method: &str, params: Value) -> Result<()> {
-        self.send_payload(json!({
-            "method": method,
-            "params": params
-        }))
+    #[test]
+    fn watch_keeps_the_last_entry_of_each_failed_provider() {
+        let shown = codexbar_core::WidgetSnapshot::sample().entries;
+        let providers = ["codex", "claude"];
+        let mut codex = shown[0].clone();
+        codex.primary.as_mut().unwrap().used_percent = Some(30.0);
+
+        // claude failed alone and was dropped from the refresh.
+        let (entries, stale) = merge_watch_entries(&shown, Ok(vec![codex.clone()]), &providers);
+        assert_eq!(entries, [codex.clone(), shown[1].clone()]);
+        assert_eq!(
+            stale,
+            [StaleProvider {
+                provider: "claude".to_string(),
+                reason: "no live usage data".to_string(),
+            }]
+        );
+
+        // ... or came back as an `--include-missing` placeholder.
+        let placeholder = placeholder_entry(
+            "claude",
+            &ProviderFailure::FetchFailed("HTTP 503".to_string()),
+            "unavailable",
+        );
+        let (entries, stale) = merge_watch_entries(
+            &shown,
+            Ok(vec![codex.clone(), placeholder.clone()]),
+            &providers,
+        );
+        assert_eq!(entries[1], shown[1]);
+        assert_eq!(stale[0].reason, "fetch failed: HTTP 503");
+
+        // With nothing to fall back on, the placeholder is shown.
+        let (entries, stale) = merge_watch_entries(
+            &[],
+            Ok(vec![codex.clone(), placeholder.clone()]),
+            &providers,
+        );
+        assert_eq!(entries, [codex, placeholder]);
+        assert!(stale.is_empty());
+
+        // The whole refresh failed.
+        let (entries, stale) = merge_watch_entries(&shown, Err("offline".to_string()), &providers);
+        assert_eq!(entries, shown);
+        assert_eq!(stale.len(), 2);
+        assert!(stale.iter().all(|stale| stale.reason == "offline"));
     }
 
-    fn send_payload(&mut self, payload: Value) -> Result<()> {
-        let bytes =
-            serde_json::to_vec(&payload).context("failed to serialize codex RPC payload")?;
-        self.stdin
-            .write_all(&bytes)
-            .context("failed to write codex RPC payload")?;
-        self.stdin
-            .write_all(b"\n")
-            .context("failed to terminate codex RPC payload line")?;
-        self.stdin
-            .flush()
-            .context("failed to flush codex RPC payload")?;
-        Ok(())
+    #[test]
+    fn picks_severity_from_remaining_thresholds() {
+        assert_eq!(Severity::of(72.0, 20.0, 5.0), Severity::Ok);
+        assert_eq!(Severity::of(20.0, 20.0, 5.0), Severity::Warning);
+        assert_eq!(Severity::of(12.5, 20.0, 5.0), Severity::Warning);
+        assert_eq!(Severity::of(5.0, 20.0, 5.0), Severity::Critical);
+        assert_eq!(Severity::of(0.0, 20.0, 5.0), Severity::Critical);
+        // A critical threshold above the warning one still wins.
+        assert_eq!(Severity::of(15.0, 10.0, 30.0), Severity::Critical);
+
+        assert_eq!(Severity::Warning.paint("9% left"), "\x1b[33m9% left\x1b[0m");
     }
 
-    fn read_message(&mut self) -> Result<Value> {
-        let mut line = String::new();
-        loop {
-            line.clear();
-            let read = self
-                .stdout
-                .read_line(&mut line)
-                .context("failed reading codex app-server output")?;
-            if read == 0 {
-                bail!("codex app-server closed stdout");
-            }
+    #[test]
+    fn writes_influx_line_protocol() {
+        let mut entry = placeholder_entry("codex", &ProviderFailure::NoUsageData, "rpc");
+        entry.error = None;
+        entry.updated_at = "unix:1760000000".to_string();
+        entry.primary = Some(RateWindow {
+            used_percent: Some(28.0),
+            window_minutes: Some(300),
+            resets_at: Some("unix:1760018000".to_string()),
+        });
+        entry.secondary = Some(RateWindow {
+            used_percent: None,
+            window_minutes: Some(10080),
+            resets_at: None,
+        });
+        entry.credits_remaining = Some(92.4);
+
+        assert_eq!(
+            influx_lines(&entry),
+            vec![
+                "codexbar,provider=codex,window=primary used_percent=28,remaining_percent=72,window_minutes=300i,resets_at=1760018000i 1760000000000000000",
+                "codexbar,provider=codex credits_remaining=92.4 1760000000000000000",
+            ]
+        );
 
-            let trimmed = line.trim();
-            if trimmed.is_empty() {
-                continue;
-            }
+        entry.provider = "my provider,x=1".to_string();
+        entry.updated_at = "unknown".to_string();
+        entry.credits_remaining = None;
+        assert_eq!(
+            influx_lines(&entry),
+            vec![
+                r"codexbar,provider=my\ provider\,x\=1,window=primary used_percent=28,remaining_percent=72,window_minutes=300i,resets_at=1760018000i"
+            ]
+        );
+    }
 
-            if let Ok(value) = serde_json::from_str::<Value>(trimmed) {
-                return Ok(value);
-            }
-        }
+    #[test]
+    fn maps_gemini_quota_response_to_entry() {
+        let body = r#"{
+            "buckets": [
+                {"modelId": "gemini-2.5-pro", "remainingFraction": 0.75, "resetTime": "2026-02-12T00:00:00Z"},
+                {"modelId": "gemini-2.5-pro", "remainingFraction": 0.4, "resetTime": "2026-02-12T06:00:00Z"},
+                {"modelId": "gemini-2.5-flash", "remainingFraction": 0.9, "resetTime": "2026-02-12T00:00:00Z"},
+                {"modelId": "", "remainingFraction": 0.0}
+            ]
+        }"#;
+        let entry = gemini_entry_from_quota_json(
+            body,
+            &FetchOptions::default(),
+            Some("me@example.com".to_string()),
+            gemini_plan_label(Some(GeminiUserTierId::Standard), None),
+        )
+        .unwrap();
+
+        assert_eq!(entry.provider, "gemini");
+        assert_eq!(entry.source.as_deref(), Some("api"));
+        let primary = entry.primary.as_ref().unwrap();
+        assert_eq!(primary.used_percent, Some(60.0));
+        assert_eq!(primary.window_minutes, Some(1440));
+        assert_eq!(primary.resets_at.as_deref(), Some("2026-02-12T06:00:00Z"));
+        let secondary = entry.secondary.as_ref().unwrap();
+        assert!((secondary.used_percent.unwrap() - 10.0).abs() < 1e-9);
+
+        let payload = cli_payload(&entry, &UsageArgs::default());
+        assert_eq!(payload["antigravityPlanInfo"]["planName"], json!("Paid"));
+        assert_eq!(
+            payload["antigravityPlanInfo"]["accountEmail"],
+            json!("me@example.com")
+        );
+
+        assert!(gemini_entry_from_quota_json(
+            r#"{"buckets": []}"#,
+            &FetchOptions::default(),
+            None,
+            None
+        )
+        .is_err());
     }
-}
 
-impl Drop for CodexRpcSession {
-    fn drop(&mut self) {
-        if let Ok(None) = self.child.try_wait() {
-            let _ = self.child.kill();
-            let _ = self.child.wait();
+    #[test]
+    fn parses_and_validates_timeout() {
+        let usage = |timeout: &str| {
+            Cli::try_parse_from(["codexbar", "usage", "--timeout", timeout]).map(|cli| {
+                match cli.command.unwrap() {
+                    Commands::Usage(args) => args.timeout,
+                    _ => unreachable!(),
+                }
+            })
+        };
+
+        assert_eq!(usage("45").unwrap(), Some(Duration::from_secs(45)));
+        assert_eq!(usage("2.5").unwrap(), Some(Duration::from_millis(2500)));
+        for invalid in ["0", "-5", "soon"] {
+            assert!(usage(invalid).is_err(), "{invalid} should be rejected");
         }
-    }
-}
 
-#[derive(Debug, Deserialize)]
-struct RpcAccountResponse {
-    account: Option<RpcAccountDetails>,
-}
+        let args = UsageArgs::default();
+        assert_eq!(
+            args.fetch_options().timeout_or(Duration::from_secs(20)),
+            Duration::from_secs(20)
+        );
+    }
 
-#[derive(Debug, Deserialize)]
-#[serde(tag = "type")]
-enum RpcAccountDetails {
-    #[serde(rename = "apiKey")]
-    ApiKey,
-    #[serde(rename = "chatgpt")]
-    ChatGPT {
-        email: Option<String>,
-        #[serde(rename = "planType")]
-        plan_type: Option<String>,
-    },
-}
+    #[test]
+    fn env_file_supplies_claude_token_without_overriding() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|error| error.into_inner());
+        std::env::remove_var("CODEXBAR_CLAUDE_OAUTH_TOKEN");
+        std::env::remove_var("CLAUDE_OAUTH_TOKEN");
+        std::env::set_var("CODEXBAR_ENV_FILE_TEST_SET", "shell");
+
+        let path = std::env::temp_dir().join(format!("codexbar-env-{}.env", std::process::id()));
+        fs::write(
+            &path,
+            "# tokens for codexbar\n\
+             export CODEXBAR_CLAUDE_OAUTH_TOKEN=\"env \\\"file\\\" token\" # quoted\n\
+             CODEXBAR_ENV_FILE_TEST_SET=file\n\
+             CODEXBAR_ENV_FILE_TEST_LITERAL='a \\n #b' \n\
+             CODEXBAR_ENV_FILE_TEST_BARE = plain value # trailing\n",
+        )
+        .unwrap();
+        load_env_file(&path).unwrap();
+
+        assert_eq!(
+            std::env::var("CODEXBAR_CLAUDE_OAUTH_TOKEN").unwrap(),
+            "env \"file\" token"
+        );
+        assert_eq!(
+            std::env::var("CODEXBAR_ENV_FILE_TEST_SET").unwrap(),
+            "shell"
+        );
+        assert_eq!(
+            std::env::var("CODEXBAR_ENV_FILE_TEST_LITERAL").unwrap(),
+            "a \\n #b"
+        );
+        assert_eq!(
+            std::env::var("CODEXBAR_ENV_FILE_TEST_BARE").unwrap(),
+            "plain value"
+        );
 
-#[derive(Debug, Deserialize)]
-struct RpcRateLimitsResponse {
-    #[serde(rename = "rateLimits")]
-    rate_limits: RpcRateLimitSnapshot,
-}
+        let argv = |args: &[&str]| env_file_from_args(args.iter().map(OsString::from));
+        assert_eq!(
+            argv(&["--env-file", "a.env", "usage"]),
+            Some(PathBuf::from("a.env"))
+        );
+        assert_eq!(
+            argv(&["usage", "--provider", "codex", "--env-file=b.env"]),
+            Some(PathBuf::from("b.env"))
+        );
+        assert_eq!(
+            argv(&["--env-file=a.env", "usage", "--env-file", "b.env"]),
+            Some(PathBuf::from("b.env"))
+        );
+        assert_eq!(argv(&["usage", "--", "--env-file", "a.env"]), None);
+        assert_eq!(argv(&["usage", "--env-file"]), None);
+
+        for (text, line) in [
+            ("A=1\nnot a pair\n", "2:"),
+            ("B=\"open\n", "1:"),
+            ("C D=1", "1:"),
+        ] {
+            let error = parse_env_file(text).unwrap_err();
+            assert!(error.starts_with(line), "{text:?}: {error}");
+        }
 
-#[derive(Debug, Deserialize)]
-struct RpcRateLimitSnapshot {
-    primary: Option<RpcRateLimitWindow>,
-    secondary: Option<RpcRateLimitWindow>,
-    credits: Option<RpcCreditsSnapshot>,
-}
+        for key in [
+            "CODEXBAR_CLAUDE_OAUTH_TOKEN",
+            "CODEXBAR_ENV_FILE_TEST_SET",
+            "CODEXBAR_ENV_FILE_TEST_LITERAL",
+            "CODEXBAR_ENV_FILE_TEST_BARE",
+        ] {
+            std::env::remove_var(key);
+        }
+        fs::remove_file(&path).unwrap();
+    }
 
-#[derive(Debug, Deserialize)]
-struct RpcRateLimitWindow {
-    #[serde(rename = "usedPercent")]
-    used_percent: Option<f64>,
-    #[serde(rename = "windowDurationMins")]
-    window_duration_mins: Option<u64>,
-    #[serde(rename = "resetsAt")]
-    resets_at: Option<i64>,
-}
+    #[test]
+    fn parses_auth_status_subcommand() {
+        let auth = |argv: &[&str]| {
+            let Some(Commands::Auth(auth)) = Cli::parse_from(argv).command else {
+                panic!("expected auth command");
+            };
+            auth
+        };
+        for argv in [
+            ["codexbar", "auth", "status", "--provider", "codex"],
+            ["codexbar", "auth", "--provider", "codex", "status"],
+        ] {
+            let status = auth(&argv);
+            assert!(matches!(status.action, Some(AuthAction::Status)));
+            assert_eq!(status.provider, "codex");
+        }
+        let status = auth(&["codexbar", "auth", "status"]);
+        assert_eq!(status.provider, "claude");
+    }
 
-#[derive(Debug, Deserialize)]
-struct RpcCreditsSnapshot {
-    balance: Option<String>,
+    #[test]
+    fn auth_logout_targets_the_provider_given_before_the_subcommand() {
+        let Some(Commands::Auth(auth)) =
+            Cli::parse_from(["codexbar", "auth", "--provider", "codex", "logout"]).command
+        else {
+            panic!("expected auth command");
+        };
+        assert!(matches!(auth.action, Some(AuthAction::Logout)));
+        assert_eq!(auth.provider, "codex");
+    }
 }
@@ -1,6 +1,10 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::fmt;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
@@ -8,6 +12,17 @@ pub struct WidgetSnapshot {
     pub generated_at: String,
     pub enabled_providers: Vec<String>,
     pub entries: Vec<ProviderEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_reset_at: Option<NextReset>,
+}
+
+/// The earliest upcoming reset across every provider window.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NextReset {
+    pub provider: String,
+    pub window: String,
+    pub resets_at: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -19,10 +34,20 @@ pub struct ProviderEntry {
     pub primary: Option<RateWindow>,
     pub secondary: Option<RateWindow>,
     pub tertiary: Option<RateWindow>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_windows: Vec<RateWindow>,
+    /// Every window with its name, in the same order as
+    /// `primary`/`secondary`/`tertiary`/`extra_windows`; see
+    /// [`ProviderEntry::set_windows`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub windows: Vec<NamedRateWindow>,
     pub credits_remaining: Option<f64>,
     pub code_review_remaining_percent: Option<f64>,
     pub identity: Option<IdentityInfo>,
     pub status: Option<StatusInfo>,
+    /// Why the provider has no usage data; only set on placeholder entries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -33,12 +58,36 @@ pub struct RateWindow {
     pub resets_at: Option<String>,
 }
 
+/// A window with the name its provider gives it, e.g. `Opus 7d`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NamedRateWindow {
+    pub name: String,
+    /// The fixed field this window fills; `None` for `extra_windows`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slot: Option<WindowSlot>,
+    #[serde(flatten)]
+    pub window: RateWindow,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum WindowSlot {
+    Primary,
+    Secondary,
+    Tertiary,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct IdentityInfo {
     pub account_email: Option<String>,
     pub account_organization: Option<String>,
+    /// How the account signed in, e.g. `oauth` or `chatgpt`.
     pub login_method: Option<String>,
+    /// The subscription, e.g. `plus` or `max`, when the provider reports it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plan: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -50,27 +99,286 @@ pub struct StatusInfo {
     pub url: Option<String>,
 }
 
-impl WidgetSnapshot {
-    pub fn from_codexbar_cli_values(values: &[Value]) -> Self {
-        let entries = values
-            .iter()
-            .filter_map(ProviderEntry::from_codexbar_cli_value)
-            .collect::<Vec<_>>();
+/// Used-percent levels reported in [`WindowChange::crossed_thresholds`].
+pub const USAGE_THRESHOLDS: [f64; 4] = [50.0, 80.0, 90.0, 100.0];
 
-        let enabled_providers = entries
-            .iter()
-            .map(|entry| entry.provider.clone())
-            .collect::<Vec<_>>();
+/// What changed between two polls; see [`WidgetSnapshot::diff`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SnapshotDiff {
+    pub added_providers: Vec<String>,
+    pub removed_providers: Vec<String>,
+    pub changes: Vec<WindowChange>,
+}
 
-        Self {
-            generated_at: now_iso8601(),
+impl SnapshotDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added_providers.is_empty()
+            && self.removed_providers.is_empty()
+            && self.changes.is_empty()
+    }
+}
+
+/// A `primary`/`secondary`/`tertiary` window whose `used_percent` moved.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WindowChange {
+    pub provider: String,
+    pub window: String,
+    pub previous_used_percent: Option<f64>,
+    pub used_percent: Option<f64>,
+    /// Entries of [`USAGE_THRESHOLDS`] the window rose to or past since the
+    /// previous poll; empty when either reading is missing.
+    pub crossed_thresholds: Vec<f64>,
+}
+
+/// Builds a [`WidgetSnapshot`] from `codexbar usage --format json` output or
+/// from entries produced in-process, for apps that embed CodexBar data
+/// instead of going through the service.
+///
+/// ```
+/// use codexbar_core::SnapshotBuilder;
+///
+/// let raw = r#"[{"provider": "codex", "usage": {"primary": {"usedPercent": 28}}}]"#;
+/// let snapshot = SnapshotBuilder::from_cli_json(raw)
+///     .unwrap()
+///     .enabled_providers(["codex", "claude"])
+///     .build();
+///
+/// assert_eq!(snapshot.entries[0].provider, "codex");
+/// assert_eq!(snapshot.enabled_providers, ["codex", "claude"]);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotBuilder {
+    entries: Vec<ProviderEntry>,
+    enabled_providers: Option<Vec<String>>,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl SnapshotBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Maps CLI payload objects to entries, skipping ones without a provider.
+    pub fn from_values(values: &[Value]) -> Self {
+        Self::new().with_provider_entries(
+            values
+                .iter()
+                .filter_map(ProviderEntry::from_codexbar_cli_value),
+        )
+    }
+
+    /// Accepts a JSON array, a single object, or one object per line.
+    pub fn from_cli_json(raw: &str) -> Result<Self, CliJsonError> {
+        Ok(Self::from_values(&parse_cli_json_values(raw)?))
+    }
+
+    pub fn with_provider_entries(
+        mut self,
+        entries: impl IntoIterator<Item = ProviderEntry>,
+    ) -> Self {
+        self.entries.extend(entries);
+        self
+    }
+
+    /// Overrides the enabled providers, which otherwise follow the entries.
+    pub fn enabled_providers<I, S>(mut self, providers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.enabled_providers = Some(providers.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Where `generatedAt` comes from; [`SystemClock`] by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn build(self) -> WidgetSnapshot {
+        let enabled_providers = self.enabled_providers.unwrap_or_else(|| {
+            self.entries
+                .iter()
+                .map(|entry| entry.provider.clone())
+                .collect()
+        });
+
+        let generated_at = match &self.clock {
+            Some(clock) => clock.iso8601(),
+            None => now_iso8601(),
+        };
+
+        WidgetSnapshot {
+            generated_at,
             enabled_providers,
-            entries,
+            entries: self.entries,
+            next_reset_at: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CliJsonError {
+    Empty,
+    NotObjectOrArray,
+    Unparseable,
+}
+
+impl fmt::Display for CliJsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty JSON payload"),
+            Self::NotObjectOrArray => write!(f, "JSON payload must be an object or an array"),
+            Self::Unparseable => write!(f, "unable to parse payload as JSON"),
+        }
+    }
+}
+
+impl std::error::Error for CliJsonError {}
+
+/// Why a provider fetch failed, so embedders (the D-Bus service, the widget)
+/// can tell a missing tool from an expired login instead of only printing it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodexBarError {
+    /// A binary the fetch shells out to (`codex`, `curl`, ...) isn't installed.
+    MissingDependency(String),
+    /// Credentials are missing or were rejected; re-running `codexbar auth` helps.
+    AuthRequired(String),
+    Timeout(String),
+    Parse(String),
+    /// The usage API answered with a non-success HTTP status.
+    Upstream {
+        service: String,
+        status: u16,
+    },
+    /// Every fetch came back without usage data.
+    NoData(String),
+    Other(String),
+}
+
+impl CodexBarError {
+    /// The stable process exit code for this failure. `1` is kept for
+    /// errors that don't carry a `CodexBarError` at all, and `2` for
+    /// command-line usage errors, which clap reports.
+    ///
+    /// | code | variant             |
+    /// |------|---------------------|
+    /// | 3    | `MissingDependency` |
+    /// | 4    | `AuthRequired`      |
+    /// | 5    | `Timeout`           |
+    /// | 6    | `NoData`            |
+    /// | 7    | `Upstream`          |
+    /// | 8    | `Parse`             |
+    /// | 1    | `Other`             |
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::MissingDependency(_) => 3,
+            Self::AuthRequired(_) => 4,
+            Self::Timeout(_) => 5,
+            Self::NoData(_) => 6,
+            Self::Upstream { .. } => 7,
+            Self::Parse(_) => 8,
+            Self::Other(_) => 1,
+        }
+    }
+
+    /// A stable camelCase name for the variant, for JSON consumers.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::MissingDependency(_) => "missingDependency",
+            Self::AuthRequired(_) => "authRequired",
+            Self::Timeout(_) => "timeout",
+            Self::NoData(_) => "noData",
+            Self::Upstream { .. } => "upstream",
+            Self::Parse(_) => "parse",
+            Self::Other(_) => "other",
+        }
+    }
+}
+
+impl fmt::Display for CodexBarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingDependency(message) => write!(f, "missing dependency: {message}"),
+            Self::AuthRequired(message) => write!(f, "authentication required: {message}"),
+            Self::Timeout(message) => write!(f, "timed out: {message}"),
+            Self::Parse(message) => write!(f, "unparseable response: {message}"),
+            Self::Upstream { service, status } => write!(f, "{service} returned HTTP {status}"),
+            Self::NoData(message) => write!(f, "no usage data: {message}"),
+            Self::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for CodexBarError {}
+
+impl From<CliJsonError> for CodexBarError {
+    fn from(error: CliJsonError) -> Self {
+        Self::Parse(error.to_string())
+    }
+}
+
+/// Splits CLI output into payload objects: a JSON array, a single object,
+/// or newline-delimited objects (unparseable lines are skipped).
+pub fn parse_cli_json_values(raw: &str) -> Result<Vec<Value>, CliJsonError> {
+    let trimmed = raw.trim();
+    if trimmed.is_empty() {
+        return Err(CliJsonError::Empty);
+    }
+
+    if let Ok(value) = serde_json::from_str::<Value>(trimmed) {
+        return match value {
+            Value::Array(items) => Ok(items),
+            Value::Object(_) => Ok(vec![value]),
+            _ => Err(CliJsonError::NotObjectOrArray),
+        };
+    }
+
+    let values = scan_json_values(trimmed);
+    if values.is_empty() {
+        return Err(CliJsonError::Unparseable);
+    }
+
+    Ok(values)
+}
+
+/// Collects every object, and the objects inside every array, that parses
+/// starting at a `{` or `[`, skipping text in between. That covers JSONL as
+/// well as a payload wrapped in warnings or log lines the CLI printed to the
+/// same stream.
+fn scan_json_values(raw: &str) -> Vec<Value> {
+    let mut values = Vec::new();
+    let mut rest = raw;
+    while let Some(start) = rest.find(['{', '[']) {
+        let candidate = &rest[start..];
+        let mut stream = serde_json::Deserializer::from_str(candidate).into_iter::<Value>();
+        match stream.next() {
+            Some(Ok(value)) => {
+                match value {
+                    Value::Array(items) => {
+                        values.extend(items.into_iter().filter(Value::is_object));
+                    }
+                    Value::Object(_) => values.push(value),
+                    _ => {}
+                }
+                rest = &candidate[stream.byte_offset()..];
+            }
+            _ => rest = &candidate[1..],
         }
     }
+    values
+}
+
+impl WidgetSnapshot {
+    pub fn from_codexbar_cli_values(values: &[Value]) -> Self {
+        SnapshotBuilder::from_values(values).build()
+    }
 
     pub fn sample() -> Self {
-        Self {
+        let mut snapshot = Self {
             generated_at: now_iso8601(),
             enabled_providers: vec!["codex".to_string(), "claude".to_string()],
             entries: vec![
@@ -89,12 +397,15 @@ impl WidgetSnapshot {
                         resets_at: Some("2026-02-14T20:00:00Z".to_string()),
                     }),
                     tertiary: None,
+                    extra_windows: Vec::new(),
+                    windows: Vec::new(),
                     credits_remaining: Some(92.4),
                     code_review_remaining_percent: Some(100.0),
                     identity: Some(IdentityInfo {
                         account_email: Some("codex@example.com".to_string()),
                         account_organization: None,
-                        login_method: Some("plus".to_string()),
+                        login_method: Some("chatgpt".to_string()),
+                        plan: Some("plus".to_string()),
                     }),
                     status: Some(StatusInfo {
                         indicator: Some("none".to_string()),
@@ -102,6 +413,7 @@ impl WidgetSnapshot {
                         updated_at: Some(now_iso8601()),
                         url: Some("https://status.openai.com/".to_string()),
                     }),
+                    error: None,
                 },
                 ProviderEntry {
                     provider: "claude".to_string(),
@@ -118,12 +430,15 @@ impl WidgetSnapshot {
                         resets_at: Some("2026-02-16T01:00:00Z".to_string()),
                     }),
                     tertiary: None,
+                    extra_windows: Vec::new(),
+                    windows: Vec::new(),
                     credits_remaining: None,
                     code_review_remaining_percent: None,
                     identity: Some(IdentityInfo {
                         account_email: Some("claude@example.com".to_string()),
                         account_organization: None,
                         login_method: Some("oauth".to_string()),
+                        plan: Some("max".to_string()),
                     }),
                     status: Some(StatusInfo {
                         indicator: Some("none".to_string()),
@@ -131,21 +446,93 @@ impl WidgetSnapshot {
                         updated_at: Some(now_iso8601()),
                         url: Some("https://status.anthropic.com/".to_string()),
                     }),
+                    error: None,
                 },
             ],
+            next_reset_at: None,
+        };
+        for entry in &mut snapshot.entries {
+            entry.name_unnamed_windows();
+        }
+        snapshot
+    }
+
+    /// Compares against the `previous` poll. Providers are matched by id;
+    /// a provider present in only one snapshot is reported as added or
+    /// removed rather than as window changes.
+    pub fn diff(&self, previous: &WidgetSnapshot) -> SnapshotDiff {
+        self.diff_with_thresholds(previous, &USAGE_THRESHOLDS)
+    }
+
+    /// [`WidgetSnapshot::diff`] with `crossed_thresholds` drawn from
+    /// `thresholds` (used percents) instead of [`USAGE_THRESHOLDS`].
+    pub fn diff_with_thresholds(
+        &self,
+        previous: &WidgetSnapshot,
+        thresholds: &[f64],
+    ) -> SnapshotDiff {
+        let mut diff = SnapshotDiff::default();
+        for entry in &self.entries {
+            let Some(before) = previous.entry(&entry.provider) else {
+                diff.added_providers.push(entry.provider.clone());
+                continue;
+            };
+            let slots = [
+                ("primary", &before.primary, &entry.primary),
+                ("secondary", &before.secondary, &entry.secondary),
+                ("tertiary", &before.tertiary, &entry.tertiary),
+            ];
+            for (slot, before, after) in slots {
+                let previous_used = before.as_ref().and_then(|window| window.used_percent);
+                let used = after.as_ref().and_then(|window| window.used_percent);
+                if previous_used == used {
+                    continue;
+                }
+                let crossed_thresholds = match (previous_used, used) {
+                    (Some(from), Some(to)) => thresholds
+                        .iter()
+                        .copied()
+                        .filter(|threshold| from < *threshold && to >= *threshold)
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                diff.changes.push(WindowChange {
+                    provider: entry.provider.clone(),
+                    window: slot.to_string(),
+                    previous_used_percent: previous_used,
+                    used_percent: used,
+                    crossed_thresholds,
+                });
+            }
         }
+        diff.removed_providers = previous
+            .entries
+            .iter()
+            .filter(|entry| self.entry(&entry.provider).is_none())
+            .map(|entry| entry.provider.clone())
+            .collect();
+        diff
+    }
+
+    fn entry(&self, provider: &str) -> Option<&ProviderEntry> {
+        self.entries.iter().find(|entry| entry.provider == provider)
     }
 }
 
 impl ProviderEntry {
     pub fn from_codexbar_cli_value(value: &Value) -> Option<Self> {
+        Self::from_codexbar_cli_value_with_clock(value, &SystemClock)
+    }
+
+    /// `clock` stamps `updatedAt` when the value doesn't carry one.
+    pub fn from_codexbar_cli_value_with_clock(value: &Value, clock: &dyn Clock) -> Option<Self> {
         let provider = value.get("provider")?.as_str()?.to_string();
         let usage = value.get("usage");
 
         let updated_at = usage
             .and_then(|obj| get_string(obj, "updatedAt"))
             .or_else(|| get_string(value, "updatedAt"))
-            .unwrap_or_else(now_iso8601);
+            .unwrap_or_else(|| clock.iso8601());
 
         let source = get_string(value, "source");
         let primary = usage
@@ -157,6 +544,26 @@ impl ProviderEntry {
         let tertiary = usage
             .and_then(|obj| obj.get("tertiary"))
             .and_then(RateWindow::from_codexbar_cli_value);
+        let extra_windows = usage
+            .and_then(|obj| obj.get("extraWindows"))
+            .and_then(Value::as_array)
+            .map(|windows| {
+                windows
+                    .iter()
+                    .filter_map(RateWindow::from_codexbar_cli_value)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let windows = usage
+            .and_then(|obj| obj.get("windows"))
+            .and_then(Value::as_array)
+            .map(|windows| {
+                windows
+                    .iter()
+                    .filter_map(NamedRateWindow::from_codexbar_cli_value)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
 
         let credits_remaining = value
             .get("credits")
@@ -174,31 +581,233 @@ impl ProviderEntry {
                 account_email: get_string(identity_obj, "accountEmail"),
                 account_organization: get_string(identity_obj, "accountOrganization"),
                 login_method: get_string(identity_obj, "loginMethod"),
+                plan: get_string(identity_obj, "plan"),
             });
 
-        let status = value.get("status").map(|status_obj| StatusInfo {
-            indicator: get_string(status_obj, "indicator"),
-            description: get_string(status_obj, "description"),
-            updated_at: get_string(status_obj, "updatedAt"),
-            url: get_string(status_obj, "url"),
+        // The CLI prints `"status": null` when it wasn't asked for status.
+        let status = value
+            .get("status")
+            .filter(|status| !status.is_null())
+            .map(|status_obj| StatusInfo {
+                indicator: get_string(status_obj, "indicator"),
+                description: get_string(status_obj, "description"),
+                updated_at: get_string(status_obj, "updatedAt"),
+                url: get_string(status_obj, "url"),
+            });
+
+        let error = value.get("error").and_then(|error| {
+            error
+                .as_str()
+                .map(str::to_string)
+                .or_else(|| get_string(error, "message"))
         });
 
-        Some(Self {
+        let mut entry = Self {
             provider,
             source,
             updated_at,
             primary,
             secondary,
             tertiary,
+            extra_windows,
+            windows: Vec::new(),
             credits_remaining,
             code_review_remaining_percent,
             identity,
             status,
+            error,
+        };
+        if windows.is_empty() {
+            entry.name_unnamed_windows();
+        } else {
+            entry.set_windows(windows);
+        }
+        Some(entry)
+    }
+
+    /// Stores named windows and derives the fixed fields from them: each
+    /// window fills its `slot`, and the unslotted ones become
+    /// `extra_windows`. When no window names a slot, the first three take
+    /// `primary`/`secondary`/`tertiary` in order.
+    pub fn set_windows(&mut self, mut windows: Vec<NamedRateWindow>) {
+        if windows.iter().all(|named| named.slot.is_none()) {
+            let slots = [
+                WindowSlot::Primary,
+                WindowSlot::Secondary,
+                WindowSlot::Tertiary,
+            ];
+            for (named, slot) in windows.iter_mut().zip(slots) {
+                named.slot = Some(slot);
+            }
+        }
+        self.primary = None;
+        self.secondary = None;
+        self.tertiary = None;
+        self.extra_windows = Vec::new();
+        for named in &windows {
+            let window = Some(named.window.clone());
+            match named.slot {
+                Some(WindowSlot::Primary) => self.primary = window,
+                Some(WindowSlot::Secondary) => self.secondary = window,
+                Some(WindowSlot::Tertiary) => self.tertiary = window,
+                None => self.extra_windows.push(named.window.clone()),
+            }
+        }
+        self.windows = windows;
+    }
+
+    /// Fills `windows` from the fixed fields when nothing named them, using
+    /// the window length (`5h`, `7d`) or else the slot as the name.
+    pub fn name_unnamed_windows(&mut self) {
+        if !self.windows.is_empty() {
+            return;
+        }
+        let slots = [
+            (
+                "primary".to_string(),
+                Some(WindowSlot::Primary),
+                &self.primary,
+            ),
+            (
+                "secondary".to_string(),
+                Some(WindowSlot::Secondary),
+                &self.secondary,
+            ),
+            (
+                "tertiary".to_string(),
+                Some(WindowSlot::Tertiary),
+                &self.tertiary,
+            ),
+        ];
+        let extras = self
+            .extra_windows
+            .iter()
+            .enumerate()
+            .map(|(index, window)| (format!("extra{index}"), None, window));
+        self.windows = slots
+            .into_iter()
+            .filter_map(|(label, slot, window)| Some((label, slot, window.as_ref()?)))
+            .chain(extras)
+            .map(|(label, slot, window)| NamedRateWindow {
+                name: window
+                    .window_minutes
+                    .map(window_length_label)
+                    .unwrap_or(label),
+                slot,
+                window: window.clone(),
+            })
+            .collect();
+    }
+}
+
+impl ProviderEntry {
+    fn windows_mut(&mut self) -> impl Iterator<Item = &mut RateWindow> {
+        self.primary
+            .iter_mut()
+            .chain(self.secondary.iter_mut())
+            .chain(self.tertiary.iter_mut())
+            .chain(self.extra_windows.iter_mut())
+            .chain(self.windows.iter_mut().map(|named| &mut named.window))
+    }
+
+    /// Applies [`normalized_window_minutes`] to every window, as parsing a
+    /// CLI payload does. Returns each distinct correction as
+    /// `(reported, corrected)` so callers can say what changed.
+    pub fn normalize_window_minutes(&mut self) -> Vec<(u64, u64)> {
+        let mut corrections = Vec::new();
+        for window in self.windows_mut() {
+            let Some(reported) = window.window_minutes else {
+                continue;
+            };
+            let corrected = normalized_window_minutes(reported);
+            if corrected != reported {
+                window.window_minutes = Some(corrected);
+                corrections.push((reported, corrected));
+            }
+        }
+        corrections.sort_unstable();
+        corrections.dedup();
+        corrections
+    }
+
+    /// Rounds percentages and credits to `decimals` places, the way
+    /// `codexbar usage` prints them.
+    pub fn round_numbers(&mut self, decimals: u32) {
+        let round = |value: Option<f64>| value.map(|value| round_to_decimals(value, decimals));
+        for window in self.windows_mut() {
+            window.used_percent = round(window.used_percent);
+        }
+        self.credits_remaining = round(self.credits_remaining);
+        self.code_review_remaining_percent = round(self.code_review_remaining_percent);
+    }
+}
+
+/// Decimal places kept for numbers in JSON output, so arithmetic such as
+/// `100.0 - used` doesn't leak float tails like `38.99999999999999`.
+pub const JSON_DECIMALS: u32 = 2;
+
+pub fn round_to_decimals(value: f64, decimals: u32) -> f64 {
+    let factor = 10f64.powi(decimals as i32);
+    (value * factor).round() / factor
+}
+
+impl NamedRateWindow {
+    pub fn from_codexbar_cli_value(value: &Value) -> Option<Self> {
+        Some(Self {
+            name: get_string(value, "name")?,
+            slot: value
+                .get("slot")
+                .and_then(|slot| serde_json::from_value(slot.clone()).ok()),
+            window: RateWindow::from_codexbar_cli_value(value)?,
         })
     }
 }
 
+/// Short length label for a window: `90m`, `5h`, `7d`.
+pub fn window_length_label(minutes: u64) -> String {
+    const DAY: u64 = 24 * 60;
+    if minutes >= DAY && minutes.is_multiple_of(DAY) {
+        format!("{}d", minutes / DAY)
+    } else if minutes >= 60 && minutes.is_multiple_of(60) {
+        format!("{}h", minutes / 60)
+    } else {
+        format!("{minutes}m")
+    }
+}
+
+/// Window lengths upstreams actually use, in minutes: 5h, 1d, 7d and 30d.
+const KNOWN_WINDOW_MINUTES: [u64; 4] = [300, 1440, 10080, 43200];
+
+/// Hour counts of known windows that are implausible as minutes: 5h and
+/// 7d. `24` and `720` are left alone since 24 minutes and 12 hours are
+/// believable window lengths.
+const KNOWN_WINDOW_HOURS: [u64; 2] = [5, 168];
+
+/// Longest window we believe is really in minutes: 31 days.
+const MAX_WINDOW_MINUTES: u64 = 31 * 24 * 60;
+
+/// Corrects a `windowMinutes` an upstream reported in the wrong unit: the 5h
+/// and 7d windows given in hours (`5` → 300, `168` → 10080) and anything
+/// given in seconds (`18000` → 300, `604800` → 10080). Other values pass
+/// through.
+pub fn normalized_window_minutes(minutes: u64) -> u64 {
+    if KNOWN_WINDOW_MINUTES.contains(&minutes) {
+        return minutes;
+    }
+    if KNOWN_WINDOW_HOURS.contains(&minutes) {
+        return minutes * 60;
+    }
+    let from_seconds = minutes / 60;
+    if minutes.is_multiple_of(60)
+        && (KNOWN_WINDOW_MINUTES.contains(&from_seconds) || minutes > MAX_WINDOW_MINUTES)
+    {
+        return from_seconds;
+    }
+    minutes
+}
+
 impl RateWindow {
+    /// `windowMinutes` goes through [`normalized_window_minutes`].
     pub fn from_codexbar_cli_value(value: &Value) -> Option<Self> {
         if value.is_null() {
             return None;
@@ -206,24 +815,183 @@ impl RateWindow {
 
         Some(Self {
             used_percent: value.get("usedPercent").and_then(to_f64),
-            window_minutes: value.get("windowMinutes").and_then(to_u64),
+            window_minutes: value
+                .get("windowMinutes")
+                .and_then(to_u64)
+                .map(normalized_window_minutes),
             resets_at: get_string(value, "resetsAt"),
         })
     }
 
+    /// `None` when there is no reading or it isn't a finite number.
     pub fn remaining_percent(&self) -> Option<f64> {
         self.used_percent
-            .map(|used| (100.0 - used).max(0.0).min(100.0))
+            .filter(|used| used.is_finite())
+            .map(|used| (100.0 - used).clamp(0.0, 100.0))
+    }
+
+    /// Time left until `resets_at`, zero once it has passed. `None` when the
+    /// window has no reset time or it doesn't parse.
+    pub fn remaining_until_reset(&self, clock: &dyn Clock) -> Option<Duration> {
+        let resets_at = parse_timestamp(self.resets_at.as_deref()?)?;
+        Some(Duration::try_from(resets_at - clock.now()).unwrap_or(Duration::ZERO))
+    }
+
+    pub fn is_reset_passed(&self, clock: &dyn Clock) -> Option<bool> {
+        let resets_at = parse_timestamp(self.resets_at.as_deref()?)?;
+        Some(resets_at <= clock.now())
     }
 }
 
+/// Current time as RFC 3339, or `unix:<seconds>` with the deprecated
+/// `legacy-unix-timestamps` feature.
 pub fn now_iso8601() -> String {
-    match SystemTime::now().duration_since(UNIX_EPOCH) {
-        Ok(duration) => format!("unix:{}", duration.as_secs()),
-        Err(_) => "unix:0".to_string(),
+    SystemClock.iso8601()
+}
+
+/// [`SystemClock`] as Unix seconds.
+pub fn now_unix_seconds() -> i64 {
+    SystemClock.unix_seconds()
+}
+
+/// A source of the current time, so time-dependent code can be pinned to a
+/// fixed instant in tests.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> OffsetDateTime;
+
+    /// Time since an arbitrary fixed origin; never decreases.
+    fn monotonic(&self) -> Duration;
+
+    fn unix_seconds(&self) -> i64 {
+        self.now().unix_timestamp()
+    }
+
+    /// [`Clock::now`] formatted like [`now_iso8601`].
+    fn iso8601(&self) -> String {
+        format_timestamp(self.unix_seconds())
+    }
+}
+
+/// The real clock, read through a process-wide [`MonotonicTimestamps`] so
+/// `generatedAt`/`updatedAt` never go backwards within one process.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        static PROCESS_TIMESTAMPS: MonotonicTimestamps<WallClock> =
+            MonotonicTimestamps::new(WallClock);
+        OffsetDateTime::from_unix_timestamp(PROCESS_TIMESTAMPS.now_unix_seconds())
+            .unwrap_or(OffsetDateTime::UNIX_EPOCH)
+    }
+
+    fn monotonic(&self) -> Duration {
+        WallClock.monotonic()
+    }
+}
+
+/// `SystemTime` and `Instant` as they are, without the regression guard.
+#[derive(Debug)]
+struct WallClock;
+
+impl Clock for WallClock {
+    fn now(&self) -> OffsetDateTime {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(duration) => OffsetDateTime::UNIX_EPOCH + duration,
+            Err(_) => OffsetDateTime::UNIX_EPOCH,
+        }
+    }
+
+    fn monotonic(&self) -> Duration {
+        static ORIGIN: OnceLock<Instant> = OnceLock::new();
+        ORIGIN.get_or_init(Instant::now).elapsed()
+    }
+}
+
+/// Always reads the same instant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedClock(pub OffsetDateTime);
+
+impl FixedClock {
+    /// Panics when `seconds` is outside what `OffsetDateTime` can represent.
+    pub fn at_unix_seconds(seconds: i64) -> Self {
+        Self(OffsetDateTime::from_unix_timestamp(seconds).expect("timestamp in range"))
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> OffsetDateTime {
+        self.0
+    }
+
+    fn monotonic(&self) -> Duration {
+        Duration::ZERO
     }
 }
 
+/// Wall-clock seconds that never regress. The last wall reading is recorded
+/// with the monotonic time it was taken at; when the wall clock later reads
+/// earlier than that base plus the monotonic time elapsed since (an NTP step
+/// or a manual change), the latter is used instead. A forward jump is taken
+/// as-is and becomes the new base.
+#[derive(Debug)]
+pub struct MonotonicTimestamps<C> {
+    clock: C,
+    base: Mutex<Option<(i64, Duration)>>,
+}
+
+impl<C: Clock> MonotonicTimestamps<C> {
+    pub const fn new(clock: C) -> Self {
+        Self {
+            clock,
+            base: Mutex::new(None),
+        }
+    }
+
+    pub fn now_unix_seconds(&self) -> i64 {
+        let wall = self.clock.unix_seconds();
+        let monotonic = self.clock.monotonic();
+        let mut base = self.base.lock().unwrap_or_else(|error| error.into_inner());
+        if let Some((base_wall, base_monotonic)) = *base {
+            let elapsed = monotonic.saturating_sub(base_monotonic).as_secs();
+            let floor = base_wall.saturating_add(i64::try_from(elapsed).unwrap_or(i64::MAX));
+            if wall < floor {
+                return floor;
+            }
+        }
+        *base = Some((wall, monotonic));
+        wall
+    }
+}
+
+/// Formats Unix seconds the way payload timestamps are written: RFC 3339 in
+/// UTC, with the offset spelled out as `Z`.
+pub fn format_timestamp(seconds: i64) -> String {
+    if cfg!(feature = "legacy-unix-timestamps") {
+        return format!("unix:{seconds}");
+    }
+    OffsetDateTime::from_unix_timestamp(seconds)
+        .ok()
+        .and_then(|timestamp| timestamp.format(&Rfc3339).ok())
+        .unwrap_or_else(|| format!("unix:{seconds}"))
+}
+
+/// Parses RFC 3339 timestamps as well as the legacy `unix:<seconds>` form,
+/// so payloads cached by older versions still load.
+pub fn parse_timestamp(raw: &str) -> Option<OffsetDateTime> {
+    let trimmed = raw.trim();
+    if let Some(seconds) = trimmed.strip_prefix("unix:") {
+        let seconds = seconds.trim().parse::<i64>().ok()?;
+        return OffsetDateTime::from_unix_timestamp(seconds).ok();
+    }
+    OffsetDateTime::parse(trimmed, &Rfc3339).ok()
+}
+
+/// [`parse_timestamp`] as Unix seconds.
+pub fn parse_unix_seconds(raw: &str) -> Option<i64> {
+    parse_timestamp(raw).map(OffsetDateTime::unix_timestamp)
+}
+
 fn get_string(value: &Value, key: &str) -> Option<String> {
     value
         .get(key)
@@ -231,12 +999,14 @@ fn get_string(value: &Value, key: &str) -> Option<String> {
         .map(ToOwned::to_owned)
 }
 
+/// Rejects `NaN` and infinities, which `str::parse` happily accepts.
 fn to_f64(value: &Value) -> Option<f64> {
-    match value {
+    let number = match value {
         Value::Number(number) => number.as_f64(),
         Value::String(string_value) => string_value.parse::<f64>().ok(),
         _ => None,
-    }
+    }?;
+    number.is_finite().then_some(number)
 }
 
 fn to_u64(value: &Value) -> Option<u64> {
@@ -251,6 +1021,366 @@ fn to_u64(value: &Value) -> Option<u64> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn measures_time_until_reset() {
+        let window = |resets_at: Option<&str>| RateWindow {
+            used_percent: Some(40.0),
+            window_minutes: Some(300),
+            resets_at: resets_at.map(ToOwned::to_owned),
+        };
+        let now = &FixedClock::at_unix_seconds(1_760_000_000);
+
+        let future = window(Some("unix:1760007980"));
+        assert_eq!(
+            future.remaining_until_reset(now),
+            Some(Duration::from_secs(2 * 3600 + 13 * 60))
+        );
+        assert_eq!(future.is_reset_passed(now), Some(false));
+
+        let past = window(Some("2025-10-09T08:00:00Z"));
+        assert_eq!(past.remaining_until_reset(now), Some(Duration::ZERO));
+        assert_eq!(past.is_reset_passed(now), Some(true));
+
+        for unusable in [window(Some("tomorrow")), window(None)] {
+            assert_eq!(unusable.remaining_until_reset(now), None);
+            assert_eq!(unusable.is_reset_passed(now), None);
+        }
+    }
+
+    #[test]
+    fn diffs_snapshots_by_provider() {
+        let previous = WidgetSnapshot::sample();
+        let mut current = previous.clone();
+        assert!(current.diff(&previous).is_empty());
+
+        // claude weekly 54% -> 83%, codex dropped, gemini appeared.
+        current.entries[1].secondary.as_mut().unwrap().used_percent = Some(83.0);
+        current.entries[1].primary = None;
+        let mut gemini = current.entries.remove(0);
+        gemini.provider = "gemini".to_string();
+        current.entries.push(gemini);
+
+        let diff = current.diff(&previous);
+        assert_eq!(diff.added_providers, ["gemini"]);
+        assert_eq!(diff.removed_providers, ["codex"]);
+        assert_eq!(
+            diff.changes,
+            vec![
+                WindowChange {
+                    provider: "claude".to_string(),
+                    window: "primary".to_string(),
+                    previous_used_percent: Some(41.0),
+                    used_percent: None,
+                    crossed_thresholds: Vec::new(),
+                },
+                WindowChange {
+                    provider: "claude".to_string(),
+                    window: "secondary".to_string(),
+                    previous_used_percent: Some(54.0),
+                    used_percent: Some(83.0),
+                    crossed_thresholds: vec![80.0],
+                },
+            ]
+        );
+
+        let json = serde_json::to_value(&diff).unwrap();
+        assert_eq!(
+            json["changes"][1]["crossedThresholds"],
+            serde_json::json!([80.0])
+        );
+        assert_eq!(serde_json::from_value::<SnapshotDiff>(json).unwrap(), diff);
+
+        // Falling usage (e.g. after a reset) crosses nothing.
+        let reset = previous.diff(&current);
+        assert!(reset
+            .changes
+            .iter()
+            .all(|change| change.crossed_thresholds.is_empty()));
+    }
+
+    #[test]
+    fn parses_cli_json_around_log_lines() {
+        let prefixed = "WARN codex: config is deprecated\n[codex] starting\n[\n  {\"provider\": \"codex\"},\n  {\"provider\": \"claude\"}\n]\nfinished in 2.1s\n";
+        let values = parse_cli_json_values(prefixed).unwrap();
+        assert_eq!(
+            values
+                .iter()
+                .map(|value| value["provider"].as_str().unwrap())
+                .collect::<Vec<_>>(),
+            ["codex", "claude"]
+        );
+
+        let mixed = "{\"provider\": \"codex\"}\nerror: rate limited {retrying}\n{\"provider\": \"gemini\", \"usage\": {\"primary\": null}}\n";
+        let values = parse_cli_json_values(mixed).unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[1]["provider"], "gemini");
+
+        assert_eq!(
+            parse_cli_json_values("[warn] nothing to report").unwrap_err(),
+            CliJsonError::Unparseable
+        );
+    }
+
+    #[test]
+    fn maps_errors_to_exit_codes() {
+        let message = || "detail".to_string();
+        let errors = [
+            CodexBarError::MissingDependency(message()),
+            CodexBarError::AuthRequired(message()),
+            CodexBarError::Timeout(message()),
+            CodexBarError::NoData(message()),
+            CodexBarError::Upstream {
+                service: "api".to_string(),
+                status: 503,
+            },
+            CodexBarError::Parse(message()),
+            CodexBarError::Other(message()),
+        ];
+        let codes = errors
+            .iter()
+            .map(CodexBarError::exit_code)
+            .collect::<Vec<_>>();
+        assert_eq!(codes, [3, 4, 5, 6, 7, 8, 1]);
+        assert_eq!(errors[1].kind(), "authRequired");
+        assert_eq!(errors[4].kind(), "upstream");
+    }
+
+    #[test]
+    fn named_windows_round_trip_and_fill_fixed_fields() {
+        let named = |name: &str, used: f64, minutes: u64| NamedRateWindow {
+            name: name.to_string(),
+            slot: None,
+            window: RateWindow {
+                used_percent: Some(used),
+                window_minutes: Some(minutes),
+                resets_at: None,
+            },
+        };
+        let mut entry = WidgetSnapshot::sample().entries.remove(1);
+        entry.set_windows(vec![
+            named("5h", 10.0, 300),
+            named("7d", 20.0, 10080),
+            named("Opus 7d", 30.0, 10080),
+            named("Haiku 1h", 40.0, 60),
+        ]);
+        assert_eq!(entry.tertiary.as_ref().unwrap().used_percent, Some(30.0));
+        assert_eq!(entry.extra_windows.len(), 1);
+
+        let json = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json["windows"][2]["name"], "Opus 7d");
+        assert_eq!(json["windows"][2]["usedPercent"], 30.0);
+        assert_eq!(
+            serde_json::from_value::<ProviderEntry>(json).unwrap(),
+            entry
+        );
+
+        // CLI payloads with named windows derive the fixed fields from them...
+        let from_cli = ProviderEntry::from_codexbar_cli_value(&serde_json::json!({
+            "provider": "claude",
+            "usage": {
+                "primary": {"usedPercent": 99},
+                "windows": [
+                    {"name": "5h", "usedPercent": 12, "windowMinutes": 300},
+                    {"name": "Opus 7d", "usedPercent": 34, "windowMinutes": 10080},
+                ],
+            },
+        }))
+        .unwrap();
+        assert_eq!(from_cli.windows[1].name, "Opus 7d");
+        assert_eq!(from_cli.primary.unwrap().used_percent, Some(12.0));
+        assert_eq!(from_cli.secondary.unwrap().used_percent, Some(34.0));
+
+        // ...and older ones get names from the fixed fields.
+        let legacy = ProviderEntry::from_codexbar_cli_value(&serde_json::json!({
+            "provider": "codex",
+            "usage": {
+                "primary": {"usedPercent": 28, "windowMinutes": 300},
+                "secondary": {"usedPercent": 61},
+            },
+        }))
+        .unwrap();
+        assert_eq!(
+            legacy
+                .windows
+                .iter()
+                .map(|window| window.name.as_str())
+                .collect::<Vec<_>>(),
+            ["5h", "secondary"]
+        );
+        assert_eq!(window_length_label(90), "90m");
+    }
+
+    #[test]
+    fn named_windows_keep_their_slot_through_a_reparse() {
+        let entry = ProviderEntry::from_codexbar_cli_value(&serde_json::json!({
+            "provider": "codex",
+            "usage": {
+                "secondary": {"usedPercent": 61, "windowMinutes": 10080},
+            },
+        }))
+        .unwrap();
+        assert_eq!(entry.windows[0].slot, Some(WindowSlot::Secondary));
+
+        let json = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json["windows"][0]["slot"], "secondary");
+        let reparsed = ProviderEntry::from_codexbar_cli_value(&serde_json::json!({
+            "provider": "codex",
+            "updatedAt": json["updatedAt"],
+            "usage": json,
+        }))
+        .unwrap();
+        assert_eq!(reparsed.primary, None);
+        assert_eq!(reparsed.secondary, entry.secondary);
+        assert_eq!(reparsed.windows, entry.windows);
+    }
+
+    #[test]
+    fn normalizes_window_minutes_in_the_wrong_unit() {
+        // Already minutes, including lengths we don't know about.
+        assert_eq!(normalized_window_minutes(300), 300);
+        assert_eq!(normalized_window_minutes(10080), 10080);
+        assert_eq!(normalized_window_minutes(90), 90);
+        assert_eq!(normalized_window_minutes(0), 0);
+        // Hours, but only where minutes make no sense.
+        assert_eq!(normalized_window_minutes(5), 300);
+        assert_eq!(normalized_window_minutes(168), 10080);
+        assert_eq!(normalized_window_minutes(24), 24);
+        assert_eq!(normalized_window_minutes(720), 720);
+        // Seconds.
+        assert_eq!(normalized_window_minutes(18000), 300);
+        assert_eq!(normalized_window_minutes(86400), 1440);
+        assert_eq!(normalized_window_minutes(604800), 10080);
+        assert_eq!(normalized_window_minutes(2_592_000), 43200);
+        assert_eq!(normalized_window_minutes(7200 * 60), 7200);
+        // Too long for minutes but not whole minutes of seconds either.
+        assert_eq!(normalized_window_minutes(100_001), 100_001);
+        assert_eq!(normalized_window_minutes(u64::MAX), u64::MAX);
+
+        let window = RateWindow::from_codexbar_cli_value(&serde_json::json!({
+            "usedPercent": 10,
+            "windowMinutes": 604800
+        }))
+        .unwrap();
+        assert_eq!(window.window_minutes, Some(10080));
+    }
+
+    #[test]
+    fn identity_plan_is_optional_in_json() {
+        let identity = IdentityInfo {
+            account_email: None,
+            account_organization: None,
+            login_method: Some("chatgpt".to_string()),
+            plan: Some("pro".to_string()),
+        };
+        let json = serde_json::to_value(&identity).unwrap();
+        assert_eq!(json["plan"], "pro");
+        assert_eq!(
+            serde_json::from_value::<IdentityInfo>(json).unwrap(),
+            identity
+        );
+
+        // Snapshots written before `plan` existed still load, and stay that way.
+        let old = serde_json::json!({
+            "accountEmail": null,
+            "accountOrganization": null,
+            "loginMethod": "oauth",
+        });
+        let parsed = serde_json::from_value::<IdentityInfo>(old.clone()).unwrap();
+        assert_eq!(parsed.plan, None);
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), old);
+
+        let entry = ProviderEntry::from_codexbar_cli_value(&serde_json::json!({
+            "provider": "codex",
+            "usage": {"identity": {"loginMethod": "chatgpt", "plan": "plus"}},
+        }))
+        .unwrap();
+        assert_eq!(entry.identity.unwrap().plan.as_deref(), Some("plus"));
+    }
+
+    #[test]
+    fn non_finite_numbers_are_dropped() {
+        let entry = ProviderEntry::from_codexbar_cli_value(&serde_json::json!({
+            "provider": "codex",
+            "usage": {
+                "primary": {"usedPercent": "NaN", "windowMinutes": 300},
+                "secondary": {"usedPercent": "inf"},
+                "tertiary": {"usedPercent": "-Infinity"},
+            },
+            "credits": {"remaining": "infinity"},
+            "openaiDashboard": {"codeReviewRemainingPercent": "nan"},
+        }))
+        .unwrap();
+        for window in [&entry.primary, &entry.secondary, &entry.tertiary] {
+            let window = window.as_ref().unwrap();
+            assert_eq!(window.used_percent, None);
+            assert_eq!(window.remaining_percent(), None);
+        }
+        assert_eq!(entry.credits_remaining, None);
+        assert_eq!(entry.code_review_remaining_percent, None);
+
+        // Built in-process rather than parsed, so the guard has to be in the
+        // accessor too.
+        for used in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let window = RateWindow {
+                used_percent: Some(used),
+                window_minutes: None,
+                resets_at: None,
+            };
+            assert_eq!(window.remaining_percent(), None);
+        }
+        let over = RateWindow {
+            used_percent: Some(130.0),
+            window_minutes: None,
+            resets_at: None,
+        };
+        assert_eq!(over.remaining_percent(), Some(0.0));
+    }
+
+    #[test]
+    fn pinned_clock_stamps_snapshots_and_entries() {
+        let clock = Arc::new(FixedClock::at_unix_seconds(1_770_840_000));
+        let entry = ProviderEntry::from_codexbar_cli_value_with_clock(
+            &serde_json::json!({"provider": "codex", "usage": {}}),
+            clock.as_ref(),
+        )
+        .unwrap();
+        assert_eq!(entry.updated_at, format_timestamp(1_770_840_000));
+
+        let snapshot = SnapshotBuilder::new()
+            .with_provider_entries([entry])
+            .clock(clock.clone())
+            .build();
+        assert_eq!(snapshot.generated_at, format_timestamp(1_770_840_000));
+        assert_eq!(clock.unix_seconds(), 1_770_840_000);
+    }
+
+    #[test]
+    fn builder_accepts_cli_json_and_entries() {
+        let ndjson = "{\"provider\": \"codex\"}\nnot json\n{\"provider\": \"claude\"}\n";
+        let snapshot = SnapshotBuilder::from_cli_json(ndjson).unwrap().build();
+        assert_eq!(snapshot.enabled_providers, ["codex", "claude"]);
+
+        let sample = WidgetSnapshot::sample();
+        let embedded = SnapshotBuilder::new()
+            .with_provider_entries(sample.entries.clone())
+            .build();
+        assert_eq!(embedded.entries, sample.entries);
+        assert_eq!(embedded.enabled_providers, sample.enabled_providers);
+
+        assert_eq!(
+            SnapshotBuilder::from_cli_json("  ").unwrap_err(),
+            CliJsonError::Empty
+        );
+        assert_eq!(
+            SnapshotBuilder::from_cli_json("42").unwrap_err(),
+            CliJsonError::NotObjectOrArray
+        );
+        assert_eq!(
+            SnapshotBuilder::from_cli_json("nope").unwrap_err(),
+            CliJsonError::Unparseable
+        );
+    }
+
     #[test]
     fn maps_basic_codexbar_payload() {
         let payload = serde_json::json!({
@@ -289,4 +1419,99 @@ mod tests {
             Some(70.0)
         );
     }
+
+    #[test]
+    fn formats_rfc3339_timestamps() {
+        if cfg!(feature = "legacy-unix-timestamps") {
+            assert_eq!(format_timestamp(1_770_840_000), "unix:1770840000");
+        } else {
+            assert_eq!(format_timestamp(1_770_840_000), "2026-02-11T20:00:00Z");
+        }
+        assert_eq!(
+            parse_unix_seconds(&format_timestamp(1_770_840_000)),
+            Some(1_770_840_000)
+        );
+        assert!(parse_timestamp(&now_iso8601()).is_some());
+    }
+
+    /// Wall and monotonic readings, moved by hand.
+    #[derive(Debug)]
+    struct FakeClock(Mutex<(i64, Duration)>);
+
+    impl FakeClock {
+        fn advance(&self, seconds: u64, wall: i64) {
+            let mut state = self.0.lock().unwrap();
+            *state = (wall, state.1 + Duration::from_secs(seconds));
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> OffsetDateTime {
+            OffsetDateTime::from_unix_timestamp(self.0.lock().unwrap().0).unwrap()
+        }
+
+        fn monotonic(&self) -> Duration {
+            self.0.lock().unwrap().1
+        }
+    }
+
+    #[test]
+    fn timestamps_survive_backward_clock_jumps() {
+        let timestamps =
+            MonotonicTimestamps::new(FakeClock(Mutex::new((1_000, Duration::from_secs(50)))));
+        let clock = &timestamps.clock;
+        assert_eq!(timestamps.now_unix_seconds(), 1_000);
+
+        clock.advance(5, 1_005);
+        assert_eq!(timestamps.now_unix_seconds(), 1_005);
+
+        // Stepped back an hour: keep counting from the last good reading.
+        clock.advance(3, 1_008 - 3_600);
+        assert_eq!(timestamps.now_unix_seconds(), 1_008);
+        clock.advance(2, 1_010 - 3_600);
+        assert_eq!(timestamps.now_unix_seconds(), 1_010);
+
+        // A forward jump is believed and becomes the new base.
+        clock.advance(1, 5_000);
+        assert_eq!(timestamps.now_unix_seconds(), 5_000);
+        clock.advance(1, 4_000);
+        assert_eq!(timestamps.now_unix_seconds(), 5_001);
+
+        let first = now_unix_seconds();
+        assert!(now_unix_seconds() >= first);
+    }
+
+    #[test]
+    fn emitted_timestamps_are_utc() {
+        let now = now_iso8601();
+        if cfg!(feature = "legacy-unix-timestamps") {
+            assert!(now.starts_with("unix:"));
+            return;
+        }
+        assert!(now.ends_with('Z'), "{now}");
+        let parsed = OffsetDateTime::parse(&now, &Rfc3339).unwrap();
+        assert!(parsed.offset().is_utc());
+        assert_eq!(format_timestamp(-1), "1969-12-31T23:59:59Z");
+    }
+
+    #[test]
+    fn parses_unix_and_rfc3339_timestamps() {
+        assert_eq!(parse_unix_seconds("unix:1760000000"), Some(1_760_000_000));
+        assert_eq!(parse_unix_seconds("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(
+            parse_unix_seconds("2026-02-11T20:00:00Z"),
+            Some(1_770_840_000)
+        );
+        assert_eq!(
+            parse_unix_seconds("2026-02-11T21:30:00.123456+01:30"),
+            Some(1_770_840_000)
+        );
+        assert_eq!(
+            parse_unix_seconds("2024-02-29T00:00:00-05:00"),
+            Some(1_709_182_800)
+        );
+        assert_eq!(parse_unix_seconds("not a timestamp"), None);
+        assert_eq!(parse_unix_seconds("2026-13-01T00:00:00Z"), None);
+        assert_eq!(parse_unix_seconds("2026-02-11T20:00:00"), None);
+    }
 }
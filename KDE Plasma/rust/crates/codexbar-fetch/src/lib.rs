@@ -0,0 +1,7528 @@
+//! Provider fetchers behind `codexbar usage`, shared by the CLI and the
+//! service so the service can build snapshots without spawning the CLI.
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use codexbar_core::{
+    format_timestamp, normalized_window_minutes, now_iso8601, parse_unix_seconds,
+    window_length_label, CliJsonError, Clock, CodexBarError, IdentityInfo, NamedRateWindow,
+    ProviderEntry, RateWindow, SnapshotBuilder, StatusInfo, SystemClock, WidgetSnapshot,
+    WindowSlot,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, TryLockError};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use time::{Date, Month, OffsetDateTime, UtcOffset};
+use wait_timeout::ChildExt;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum CodexSource {
+    /// app-server RPC, falling back to scraping `/status`.
+    Auto,
+    Rpc,
+    Status,
+    /// ChatGPT usage API with a bearer token; no codex subprocess.
+    Http,
+}
+
+/// How rate windows from a provider response map onto primary/secondary.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum WindowOrder {
+    /// Shortest window is primary, next is secondary, the rest are extra.
+    Duration,
+    /// Keep the provider's own primary/secondary keys.
+    Response,
+}
+
+/// The `codexbar usage` options that change what a provider fetches, as
+/// opposed to how the result is rendered.
+#[derive(Debug, Clone)]
+pub struct FetchOptions {
+    /// Reported as each entry's `source` unless `auto`.
+    pub source: String,
+    pub status: bool,
+    pub window_order: WindowOrder,
+    pub codex_source: CodexSource,
+    /// Applies to provider subprocesses and HTTP calls; each fetch keeps its
+    /// own default when unset.
+    pub timeout: Option<Duration>,
+    /// Extra attempts at the Claude usage API after a 429 or 5xx response.
+    pub retries: u32,
+    /// Extra attempts at starting the codex app-server before falling back
+    /// to `/status`.
+    pub codex_start_retries: u32,
+    /// `CODEX_HOME` for spawned `codex` processes; unset inherits ours.
+    pub codex_home: Option<PathBuf>,
+    /// When set, providers that fail or have no data still get an entry:
+    /// `placeholder_entry` with this `source` and the reason in `error`.
+    pub missing_source: Option<String>,
+    /// Where to copy raw upstream responses, with credentials redacted.
+    pub raw: Option<RawDump>,
+    /// Stamps `updatedAt` and turns relative reset times into absolute ones.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            source: "auto".to_string(),
+            status: false,
+            window_order: WindowOrder::Duration,
+            codex_source: CodexSource::Auto,
+            timeout: None,
+            retries: 3,
+            codex_start_retries: 2,
+            codex_home: None,
+            missing_source: None,
+            raw: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+/// Destination for `--raw` dumps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RawDump {
+    Stderr,
+    /// One file per upstream, e.g. `claude-usage.http`, overwritten each run.
+    Dir(PathBuf),
+}
+
+impl FetchOptions {
+    /// Writes `content` to the `--raw` destination, if any, after
+    /// `redact_secrets`. Failures only warn; they never fail the fetch.
+    fn dump_raw(&self, name: &str, content: &str) {
+        let content = redact_secrets(content);
+        match &self.raw {
+            None => {}
+            Some(RawDump::Stderr) => eprintln!("codexbar: raw {name}:\n{}", content.trim_end()),
+            Some(RawDump::Dir(dir)) => {
+                let path = dir.join(name);
+                if let Err(error) = fs::create_dir_all(dir).and_then(|()| fs::write(&path, content))
+                {
+                    eprintln!(
+                        "codexbar: warning: failed to write {}: {error}",
+                        path.display()
+                    );
+                }
+            }
+        }
+    }
+
+    /// `timeout` when given, otherwise the fetch's own `default`.
+    pub fn timeout_or(&self, default: Duration) -> Duration {
+        self.timeout.unwrap_or(default)
+    }
+}
+
+/// Fetches `provider` (an id, `all`, or a comma-separated list) the way
+/// `codexbar usage --format json --source auto` does, minus the CLI's cache
+/// and throttle. Providers without data are skipped unless
+/// `FetchOptions::missing_source` is set; it's an error only when no entry
+/// is left.
+pub fn fetch_snapshot(provider: &str, status: bool) -> Result<WidgetSnapshot> {
+    let options = FetchOptions {
+        status,
+        ..FetchOptions::default()
+    };
+    fetch_snapshot_with(&provider_registry(&[]), provider, &options)
+}
+
+/// `fetch_snapshot` against an explicit registry.
+pub fn fetch_snapshot_with(
+    registry: &[Box<dyn Provider>],
+    provider: &str,
+    options: &FetchOptions,
+) -> Result<WidgetSnapshot> {
+    fetch_snapshot_observed(registry, provider, options, |_, _| {})
+}
+
+/// `fetch_snapshot_with`, also handing each provider's outcome to `observe`
+/// before failures are collected, e.g. to keep a health report.
+pub fn fetch_snapshot_observed(
+    registry: &[Box<dyn Provider>],
+    provider: &str,
+    options: &FetchOptions,
+    observe: impl Fn(&str, &Result<Option<ProviderEntry>>),
+) -> Result<WidgetSnapshot> {
+    let providers = select_providers(registry, provider)?;
+    let results = fetch_concurrently(&providers, |id| {
+        match registry.iter().find(|provider| provider.id() == id) {
+            Some(provider) => provider.fetch(options).map_err(anyhow::Error::from),
+            None => Ok(None),
+        }
+    });
+    for (provider, result) in providers.iter().zip(&results) {
+        observe(provider, result);
+    }
+
+    let (entries, first_error) = collect_entries(&providers, results, options);
+
+    if entries.is_empty() {
+        let message = format!("no live usage data available for provider '{provider}'");
+        return Err(match first_error {
+            Some(error) => error.context(message),
+            None => CodexBarError::NoData(message).into(),
+        });
+    }
+    Ok(SnapshotBuilder::new()
+        .clock(options.clock.clone())
+        .with_provider_entries(entries)
+        .build())
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ProviderHealth {
+    pub provider: String,
+    pub healthy: bool,
+    pub reason: String,
+}
+
+impl ProviderHealth {
+    fn new(provider: &str, healthy: bool, reason: &str) -> Self {
+        Self {
+            provider: provider.to_string(),
+            healthy,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+/// Cheap presence checks only: no network requests and no app-server launch.
+pub fn provider_health(provider: &str) -> ProviderHealth {
+    match provider {
+        "codex" => {
+            if find_binary_on_path("codex").is_some() {
+                ProviderHealth::new(provider, true, "codex CLI found")
+            } else {
+                ProviderHealth::new(provider, false, "codex CLI not found on PATH")
+            }
+        }
+        "claude" => {
+            if peek_claude_oauth_access_token().is_some() {
+                ProviderHealth::new(provider, true, "OAuth token resolved")
+            } else {
+                ProviderHealth::new(provider, false, "no Claude OAuth token found")
+            }
+        }
+        "gemini" => {
+            let has_token = resolve_home_directory()
+                .ok()
+                .and_then(|home| load_gemini_oauth_credentials(&home).ok().flatten())
+                .and_then(|credentials| credentials.access_token)
+                .and_then(|token| clean_token_value(&token))
+                .is_some()
+                || resolve_gemini_access_token_override().is_some();
+            if has_token {
+                ProviderHealth::new(provider, true, "Gemini OAuth credentials found")
+            } else {
+                ProviderHealth::new(provider, false, "no Gemini OAuth credentials found")
+            }
+        }
+        "cursor" => {
+            if resolve_cursor_cookie_header().is_some() {
+                ProviderHealth::new(provider, true, "Cursor cookie header configured")
+            } else {
+                ProviderHealth::new(provider, false, "no Cursor cookie header configured")
+            }
+        }
+        "copilot" => {
+            if resolve_copilot_token_for_internal_api().is_some() {
+                ProviderHealth::new(provider, true, "GitHub token resolved")
+            } else {
+                ProviderHealth::new(provider, false, "no GitHub token found")
+            }
+        }
+        other => ProviderHealth::new(other, false, "unsupported provider"),
+    }
+}
+
+pub fn find_binary_on_path(program: &str) -> Option<PathBuf> {
+    let path_var = std::env::var_os("PATH")?;
+    find_binary_in_dirs(program, std::env::split_paths(&path_var))
+}
+
+fn find_binary_in_dirs(program: &str, dirs: impl IntoIterator<Item = PathBuf>) -> Option<PathBuf> {
+    dirs.into_iter()
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Why a provider produced no entry, kept so `--include-missing` placeholders
+/// can say "token expired" rather than a generic "unavailable".
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProviderFailure {
+    /// Presence check failed: CLI missing or no credentials.
+    Unavailable(String),
+    /// Credentials exist but the fetch returned nothing usable.
+    NoUsageData,
+    FetchFailed(String),
+}
+
+impl ProviderFailure {
+    pub fn reason(&self) -> String {
+        match self {
+            Self::Unavailable(reason) => reason.clone(),
+            Self::NoUsageData => "no live usage data".to_string(),
+            Self::FetchFailed(reason) => format!("fetch failed: {reason}"),
+        }
+    }
+}
+
+pub fn placeholder_entry(provider: &str, failure: &ProviderFailure, source: &str) -> ProviderEntry {
+    ProviderEntry {
+        provider: provider.to_string(),
+        source: Some(source.to_string()),
+        updated_at: now_iso8601(),
+        primary: None,
+        secondary: None,
+        tertiary: None,
+        extra_windows: Vec::new(),
+        windows: Vec::new(),
+        credits_remaining: None,
+        code_review_remaining_percent: None,
+        identity: None,
+        status: None,
+        error: Some(failure.reason()),
+    }
+}
+
+fn note_window_minutes_correction(provider: &str, reported: u64, corrected: u64) {
+    eprintln!(
+        "codexbar: note: provider '{provider}' reported a {reported}-minute window; using {corrected} minutes"
+    );
+}
+
+/// Pairs `fetch_concurrently` results with their providers, logging each
+/// failure to stderr. Failed providers are dropped, or become placeholders
+/// when `options.missing_source` is set. The first fetch error is returned
+/// alongside so callers can pick an exit code when nothing came back.
+pub fn collect_entries(
+    providers: &[&str],
+    results: Vec<Result<Option<ProviderEntry>>>,
+    options: &FetchOptions,
+) -> (Vec<ProviderEntry>, Option<anyhow::Error>) {
+    let mut entries = Vec::with_capacity(providers.len());
+    let mut first_error = None;
+
+    for (&provider, result) in providers.iter().zip(results) {
+        let failure = match result {
+            Ok(Some(mut entry)) => {
+                for (reported, corrected) in entry.normalize_window_minutes() {
+                    note_window_minutes_correction(provider, reported, corrected);
+                }
+                entry.name_unnamed_windows();
+                entries.push(entry);
+                continue;
+            }
+            Ok(None) => {
+                eprintln!("codexbar: provider '{provider}' has no live usage data");
+                if options.missing_source.is_none() {
+                    continue;
+                }
+                let health = provider_health(provider);
+                if health.healthy {
+                    ProviderFailure::NoUsageData
+                } else {
+                    ProviderFailure::Unavailable(health.reason)
+                }
+            }
+            Err(error) => {
+                eprintln!("codexbar: provider '{provider}' live fetch failed: {error:#}");
+                let failure = ProviderFailure::FetchFailed(format!("{error:#}"));
+                first_error.get_or_insert(error);
+                failure
+            }
+        };
+
+        if let Some(source) = options.missing_source.as_deref() {
+            entries.push(placeholder_entry(provider, &failure, source));
+        }
+    }
+
+    (entries, first_error)
+}
+
+/// Runs `fetch` for every provider on its own thread so slow providers (the
+/// codex app-server, a stalled curl) overlap instead of adding up. Results
+/// come back in `providers` order; one failing fetch doesn't affect the rest.
+pub fn fetch_concurrently<'a, F>(
+    providers: &[&'a str],
+    fetch: F,
+) -> Vec<Result<Option<ProviderEntry>>>
+where
+    F: Fn(&'a str) -> Result<Option<ProviderEntry>> + Sync,
+{
+    let fetch = &fetch;
+    thread::scope(|scope| {
+        let handles = providers
+            .iter()
+            .map(|&provider| scope.spawn(move || fetch(provider)))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .zip(providers)
+            .map(|(handle, provider)| {
+                handle
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("{provider} fetch panicked")))
+            })
+            .collect()
+    })
+}
+
+/// A usage source `codexbar usage` can query. `fetch` resolves its own
+/// credentials and returns `Ok(None)` when there is nothing to report.
+pub trait Provider: Send + Sync {
+    fn id(&self) -> &str;
+    fn fetch(&self, args: &FetchOptions) -> Result<Option<ProviderEntry>, CodexBarError>;
+
+    /// Whether credentials resolve, without fetching anything.
+    fn health(&self) -> ProviderHealth {
+        provider_health(self.id())
+    }
+}
+
+/// Recovers the structured error from a fetch's anyhow chain. I/O errors map
+/// by kind; anything unrecognised keeps its full context as `Other`.
+fn provider_error(error: anyhow::Error) -> CodexBarError {
+    let message = format!("{error:#}");
+    for cause in error.chain() {
+        if let Some(known) = cause.downcast_ref::<CodexBarError>() {
+            return known.clone();
+        }
+        if let Some(io_error) = cause.downcast_ref::<io::Error>() {
+            match io_error.kind() {
+                ErrorKind::NotFound => return CodexBarError::MissingDependency(message),
+                ErrorKind::TimedOut => return CodexBarError::Timeout(message),
+                _ => {}
+            }
+        }
+        if cause.is::<serde_json::Error>() || cause.is::<CliJsonError>() {
+            return CodexBarError::Parse(message);
+        }
+    }
+    CodexBarError::Other(message)
+}
+
+struct CodexProvider;
+struct ClaudeProvider;
+struct GeminiProvider;
+struct CursorProvider;
+struct CopilotProvider;
+
+impl Provider for CodexProvider {
+    fn id(&self) -> &'static str {
+        "codex"
+    }
+
+    fn fetch(&self, args: &FetchOptions) -> Result<Option<ProviderEntry>, CodexBarError> {
+        with_concurrent_status(args, "codex", || fetch_codex_usage(args)).map_err(provider_error)
+    }
+}
+
+impl Provider for ClaudeProvider {
+    fn id(&self) -> &'static str {
+        "claude"
+    }
+
+    fn fetch(&self, args: &FetchOptions) -> Result<Option<ProviderEntry>, CodexBarError> {
+        with_concurrent_status(args, "claude", || fetch_claude_usage(args)).map_err(provider_error)
+    }
+}
+
+impl Provider for GeminiProvider {
+    fn id(&self) -> &'static str {
+        "gemini"
+    }
+
+    fn fetch(&self, args: &FetchOptions) -> Result<Option<ProviderEntry>, CodexBarError> {
+        fetch_gemini_entry(args).map_err(provider_error)
+    }
+}
+
+impl Provider for CursorProvider {
+    fn id(&self) -> &'static str {
+        "cursor"
+    }
+
+    fn fetch(&self, args: &FetchOptions) -> Result<Option<ProviderEntry>, CodexBarError> {
+        fetch_cursor_entry(args).map_err(provider_error)
+    }
+}
+
+impl Provider for CopilotProvider {
+    fn id(&self) -> &'static str {
+        "copilot"
+    }
+
+    fn fetch(&self, args: &FetchOptions) -> Result<Option<ProviderEntry>, CodexBarError> {
+        fetch_copilot_entry(args).map_err(provider_error)
+    }
+}
+
+/// Providers with a usage fetcher, in default output order: the built-in
+/// ones, then `custom` (from `load_custom_providers`).
+pub fn provider_registry(custom: &[CustomProvider]) -> Vec<Box<dyn Provider>> {
+    let mut registry: Vec<Box<dyn Provider>> = vec![
+        Box::new(CodexProvider),
+        Box::new(ClaudeProvider),
+        Box::new(GeminiProvider),
+        Box::new(CursorProvider),
+        Box::new(CopilotProvider),
+    ];
+    for provider in custom {
+        registry.push(Box::new(provider.clone()));
+    }
+    registry
+}
+
+/// A provider defined in a `--provider-file`, for gateways CodexBar has no
+/// built-in fetcher for:
+///
+/// ```toml
+/// [[providers]]
+/// id = "gateway"
+/// endpoint = "https://llm.example.com/v1/usage"
+/// authHeader = "Authorization: Bearer ${GATEWAY_TOKEN}"
+///
+/// [[providers.windows]]
+/// usedPercent = "/limits/daily/percent"
+/// windowMinutes = "/limits/daily/minutes"
+/// resetsAt = "/limits/daily/resets_at"
+/// ```
+///
+/// Mappings are JSON Pointers (RFC 6901) into the endpoint's response. An
+/// `http://` endpoint is refused when `authHeader` is set, unless the
+/// definition also sets `allowInsecureHttp = true`.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct CustomProviderDefinition {
+    id: String,
+    endpoint: String,
+    /// `Name: value`; each `${VAR}` is replaced from the environment.
+    #[serde(default)]
+    auth_header: Option<String>,
+    /// Send `authHeader` over a plain `http://` endpoint anyway.
+    #[serde(default)]
+    allow_insecure_http: bool,
+    windows: Vec<CustomWindowMapping>,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct CustomWindowMapping {
+    used_percent: String,
+    #[serde(default)]
+    window_minutes: Option<String>,
+    /// Unix seconds, seconds from now, or an RFC 3339 timestamp.
+    #[serde(default)]
+    resets_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CustomProviderFile {
+    providers: Vec<CustomProviderDefinition>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CustomProvider {
+    definition: CustomProviderDefinition,
+}
+
+impl CustomProvider {
+    /// The expanded auth header, or the name of the first unset variable.
+    fn auth_header(&self) -> Result<Option<String>, String> {
+        self.definition
+            .auth_header
+            .as_deref()
+            .map(expand_env_template)
+            .transpose()
+    }
+}
+
+impl Provider for CustomProvider {
+    fn id(&self) -> &str {
+        &self.definition.id
+    }
+
+    fn fetch(&self, args: &FetchOptions) -> Result<Option<ProviderEntry>, CodexBarError> {
+        fetch_custom_entry(self, args).map_err(provider_error)
+    }
+
+    fn health(&self) -> ProviderHealth {
+        match self.auth_header() {
+            Ok(_) => ProviderHealth::new(self.id(), true, "custom provider credentials resolved"),
+            Err(missing) => ProviderHealth::new(self.id(), false, &format!("{missing} is not set")),
+        }
+    }
+}
+
+/// Reads every `--provider-file`, in order. An id defined twice, in one
+/// file or across several, is an error rather than a silent override.
+pub fn load_custom_providers(paths: &[PathBuf]) -> Result<Vec<CustomProvider>> {
+    let mut providers = Vec::<CustomProvider>::new();
+    for path in paths {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("failed to read provider file {}", path.display()))?;
+        let is_toml = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("toml"));
+        let definitions = parse_custom_providers(&text, is_toml)
+            .with_context(|| format!("invalid provider file {}", path.display()))?;
+        for definition in definitions {
+            if providers
+                .iter()
+                .any(|provider| provider.id() == definition.id)
+            {
+                bail!(
+                    "provider id '{}' in {} is already defined by an earlier provider file",
+                    definition.id,
+                    path.display()
+                );
+            }
+            providers.push(CustomProvider { definition });
+        }
+    }
+    Ok(providers)
+}
+
+fn parse_custom_providers(text: &str, is_toml: bool) -> Result<Vec<CustomProviderDefinition>> {
+    let value = if is_toml {
+        let document = toml_edit::Document::parse(text).map_err(|error| anyhow!("{error}"))?;
+        Value::Object(
+            document
+                .iter()
+                .map(|(key, item)| (key.to_string(), toml_item_to_json(item)))
+                .collect(),
+        )
+    } else {
+        serde_json::from_str(text)?
+    };
+    let file = serde_json::from_value::<CustomProviderFile>(value)?;
+
+    let builtin = [
+        CodexProvider.id(),
+        ClaudeProvider.id(),
+        GeminiProvider.id(),
+        CursorProvider.id(),
+        CopilotProvider.id(),
+    ];
+    let mut seen = Vec::new();
+    for definition in &file.providers {
+        let id = definition.id.as_str();
+        if id.is_empty()
+            || !id
+                .chars()
+                .all(|ch| ch.is_ascii_lowercase() || ch.is_ascii_digit() || matches!(ch, '-' | '_'))
+        {
+            bail!("provider id '{id}' must be lowercase letters, digits, '-' or '_'");
+        }
+        if builtin.contains(&id) || matches!(id, "all" | "both") || seen.contains(&id) {
+            bail!("provider id '{id}' is already taken");
+        }
+        seen.push(id);
+        if !definition.endpoint.starts_with("https://")
+            && !definition.endpoint.starts_with("http://")
+        {
+            bail!("provider '{id}' endpoint must be an http(s) URL");
+        }
+        if definition.endpoint.starts_with("http://")
+            && definition.auth_header.is_some()
+            && !definition.allow_insecure_http
+        {
+            bail!(
+                "provider '{id}' would send authHeader over plain http; use https or set allowInsecureHttp"
+            );
+        }
+        if definition.windows.is_empty() {
+            bail!("provider '{id}' maps no windows");
+        }
+        let pointers = definition.windows.iter().flat_map(|window| {
+            [
+                Some(&window.used_percent),
+                window.window_minutes.as_ref(),
+                window.resets_at.as_ref(),
+            ]
+        });
+        if let Some(pointer) = pointers.flatten().find(|pointer| !pointer.starts_with('/')) {
+            bail!(
+                "provider '{id}' mapping '{pointer}' is not a JSON Pointer (must start with '/')"
+            );
+        }
+    }
+    Ok(file.providers)
+}
+
+fn toml_item_to_json(item: &toml_edit::Item) -> Value {
+    match item {
+        toml_edit::Item::None => Value::Null,
+        toml_edit::Item::Value(value) => toml_value_to_json(value),
+        toml_edit::Item::Table(table) => Value::Object(
+            table
+                .iter()
+                .map(|(key, item)| (key.to_string(), toml_item_to_json(item)))
+                .collect(),
+        ),
+        toml_edit::Item::ArrayOfTables(tables) => Value::Array(
+            tables
+                .iter()
+                .map(|table| {
+                    Value::Object(
+                        table
+                            .iter()
+                            .map(|(key, item)| (key.to_string(), toml_item_to_json(item)))
+                            .collect(),
+                    )
+                })
+                .collect(),
+        ),
+    }
+}
+
+fn toml_value_to_json(value: &toml_edit::Value) -> Value {
+    match value {
+        toml_edit::Value::String(text) => json!(text.value()),
+        toml_edit::Value::Integer(number) => json!(number.value()),
+        toml_edit::Value::Float(number) => json!(number.value()),
+        toml_edit::Value::Boolean(flag) => json!(flag.value()),
+        toml_edit::Value::Datetime(datetime) => json!(datetime.value().to_string()),
+        toml_edit::Value::Array(items) => {
+            Value::Array(items.iter().map(toml_value_to_json).collect())
+        }
+        toml_edit::Value::InlineTable(table) => Value::Object(
+            table
+                .iter()
+                .map(|(key, value)| (key.to_string(), toml_value_to_json(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Replaces each `${VAR}` with the variable's value; returns the name of the
+/// first one that is unset or empty.
+fn expand_env_template(template: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        let Some(length) = rest[start + 2..].find('}') else {
+            break;
+        };
+        let name = &rest[start + 2..start + 2 + length];
+        let value = first_env_value(&[name]).ok_or_else(|| name.to_string())?;
+        out.push_str(&rest[..start]);
+        out.push_str(&value);
+        rest = &rest[start + 3 + length..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn fetch_custom_entry(
+    provider: &CustomProvider,
+    args: &FetchOptions,
+) -> Result<Option<ProviderEntry>> {
+    let header = provider.auth_header().map_err(|name| {
+        CodexBarError::AuthRequired(format!(
+            "provider '{}' needs ${name} for its auth header",
+            provider.id()
+        ))
+    })?;
+    let definition = &provider.definition;
+
+    let client = http_client();
+    let headers = header.as_deref().into_iter().collect::<Vec<_>>();
+    let response = send_with_retries(
+        provider.id(),
+        args.retries,
+        || client.get_json(&definition.endpoint, &headers, args.timeout),
+        thread::sleep,
+    );
+    let HttpResponse {
+        status: status_code,
+        body,
+        ..
+    } = response.with_context(|| format!("failed to query {}", definition.endpoint))?;
+    match status_code {
+        200 => {}
+        401 | 403 => {
+            return Err(CodexBarError::AuthRequired(format!(
+                "{} rejected the configured auth header",
+                definition.endpoint
+            ))
+            .into())
+        }
+        status => {
+            return Err(CodexBarError::Upstream {
+                service: provider.id().to_string(),
+                status,
+            }
+            .into())
+        }
+    }
+    if warn_on_empty_body(provider.id(), &body) {
+        return Ok(None);
+    }
+
+    Ok(custom_entry_from_json(
+        provider.id(),
+        &definition.windows,
+        &body,
+        args,
+        args.clock.unix_seconds(),
+    ))
+}
+
+fn custom_entry_from_json(
+    provider: &str,
+    mappings: &[CustomWindowMapping],
+    raw_json: &str,
+    args: &FetchOptions,
+    now: i64,
+) -> Option<ProviderEntry> {
+    let value = serde_json::from_str::<Value>(raw_json).ok()?;
+    let windows = mappings
+        .iter()
+        .map(|mapping| custom_rate_window(&value, mapping, now))
+        .collect::<Vec<_>>();
+    if windows.iter().all(Option::is_none) {
+        return None;
+    }
+    let windows = order_rate_windows(windows, args.window_order);
+    let source = if args.source.eq_ignore_ascii_case("auto") {
+        "custom".to_string()
+    } else {
+        args.source.clone()
+    };
+
+    Some(ProviderEntry {
+        provider: provider.to_string(),
+        source: Some(source),
+        updated_at: format_timestamp(now),
+        primary: windows.primary,
+        secondary: windows.secondary,
+        tertiary: None,
+        extra_windows: windows.extra,
+        windows: Vec::new(),
+        credits_remaining: None,
+        code_review_remaining_percent: None,
+        identity: None,
+        status: None,
+        error: None,
+    })
+}
+
+fn custom_rate_window(
+    value: &Value,
+    mapping: &CustomWindowMapping,
+    now: i64,
+) -> Option<RateWindow> {
+    let at = |pointer: Option<&String>| pointer.and_then(|pointer| value.pointer(pointer));
+    let used_percent = at(Some(&mapping.used_percent)).and_then(json_number_value)?;
+    let window_minutes = at(mapping.window_minutes.as_ref())
+        .and_then(json_number_value)
+        .filter(|minutes| *minutes >= 0.0)
+        .map(|minutes| minutes.round() as u64);
+    let resets_at = at(mapping.resets_at.as_ref()).and_then(|reset| match reset {
+        Value::String(text) if text.trim().parse::<f64>().is_err() => {
+            parse_unix_seconds(text.trim())
+        }
+        other => {
+            json_number_value(other).map(|seconds| absolute_reset_timestamp(seconds as i64, now))
+        }
+    });
+
+    Some(RateWindow {
+        used_percent: Some(used_percent.clamp(0.0, 100.0)),
+        window_minutes,
+        resets_at: resets_at.map(format_timestamp),
+    })
+}
+
+/// Other names people reach for, mapped to the provider they mean. A
+/// registered provider with the same id takes precedence.
+const PROVIDER_ALIASES: &[(&str, &str)] = &[
+    ("gpt", "codex"),
+    ("oai", "codex"),
+    ("openai", "codex"),
+    ("chatgpt", "codex"),
+    ("anthropic", "claude"),
+    ("google", "gemini"),
+    ("github", "copilot"),
+];
+
+/// Accepts one provider, `all`/`both`, or a comma-separated list such as
+/// `codex,gemini`. Duplicates are dropped, keeping first-mention order.
+/// Aliases from `PROVIDER_ALIASES` resolve silently; `provider_alias_notes`
+/// says which ones did.
+pub fn requested_providers(raw: &str) -> Result<Vec<&'static str>> {
+    select_provider_ids(&BUILTIN_PROVIDER_IDS, raw)
+}
+
+/// The ids of `provider_registry(&[])`.
+const BUILTIN_PROVIDER_IDS: [&str; 5] = ["codex", "claude", "gemini", "cursor", "copilot"];
+
+/// One note per distinct alias in `raw`, e.g. `provider 'gpt' is an alias
+/// for 'codex'`, for a front end to show once rather than on every fetch.
+pub fn provider_alias_notes(raw: &str) -> Vec<String> {
+    let ids = BUILTIN_PROVIDER_IDS;
+    let mut notes = Vec::new();
+    for token in raw.split(',').map(str::trim) {
+        let alias = token.to_ascii_lowercase();
+        if ids.contains(&alias.as_str()) {
+            continue;
+        }
+        if let Some(id) = resolve_provider_alias(&ids, &alias) {
+            let note = format!("provider '{alias}' is an alias for '{id}'");
+            if !notes.contains(&note) {
+                notes.push(note);
+            }
+        }
+    }
+    notes
+}
+
+/// `requested_providers` against an explicit registry.
+pub fn select_providers<'a>(registry: &'a [Box<dyn Provider>], raw: &str) -> Result<Vec<&'a str>> {
+    let ids = registry
+        .iter()
+        .map(|provider| provider.id())
+        .collect::<Vec<_>>();
+    select_provider_ids(&ids, raw)
+}
+
+fn select_provider_ids<'a>(ids: &[&'a str], raw: &str) -> Result<Vec<&'a str>> {
+    let mut selected = Vec::new();
+    let mut unknown = Vec::new();
+    for token in raw
+        .split(',')
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+    {
+        let normalized = token.to_ascii_lowercase();
+        let matched = match normalized.as_str() {
+            "all" | "both" => ids.to_vec(),
+            _ => match ids
+                .iter()
+                .find(|id| **id == normalized)
+                .or_else(|| resolve_provider_alias(ids, &normalized))
+            {
+                Some(id) => vec![*id],
+                None => {
+                    unknown.push(token);
+                    continue;
+                }
+            },
+        };
+        for id in matched {
+            if !selected.contains(&id) {
+                selected.push(id);
+            }
+        }
+    }
+
+    if !unknown.is_empty() {
+        bail!(
+            "unknown provider{} '{}'; expected all or a comma-separated list of: {}",
+            if unknown.len() == 1 { "" } else { "s" },
+            unknown.join("', '"),
+            ids.join(", ")
+        );
+    }
+    if selected.is_empty() {
+        bail!("no provider given in '{raw}'");
+    }
+    Ok(selected)
+}
+
+fn resolve_provider_alias<'a, 'b>(ids: &'b [&'a str], alias: &str) -> Option<&'b &'a str> {
+    let (_, target) = PROVIDER_ALIASES.iter().find(|(name, _)| *name == alias)?;
+    ids.iter().find(|id| *id == target)
+}
+
+/// Runs the provider's status lookup on its own thread while `fetch_usage`
+/// runs, then attaches the result. A failed status lookup only drops the
+/// status; the usage entry is still returned.
+fn with_concurrent_status<F>(
+    args: &FetchOptions,
+    provider: &'static str,
+    fetch_usage: F,
+) -> Result<Option<ProviderEntry>>
+where
+    F: FnOnce() -> Result<Option<ProviderEntry>>,
+{
+    if !args.status {
+        return fetch_usage();
+    }
+
+    let clock = Arc::clone(&args.clock);
+    let status_handle = thread::spawn(move || fetch_provider_status(provider, clock.as_ref()));
+    let mut entry = fetch_usage();
+    let status = match status_handle.join() {
+        Ok(Ok(status)) => Some(status),
+        Ok(Err(error)) => {
+            eprintln!("codexbar: warning: {provider} status fetch failed: {error:#}");
+            None
+        }
+        Err(_) => {
+            eprintln!("codexbar: warning: {provider} status fetch panicked");
+            None
+        }
+    };
+
+    if let Ok(Some(entry)) = entry.as_mut() {
+        entry.status = status;
+    }
+    entry
+}
+
+/// Statuspage lookups aren't wired up yet, so this reports the provider as
+/// operational and links its status page.
+fn fetch_provider_status(provider: &str, clock: &dyn Clock) -> Result<StatusInfo> {
+    let url = match provider {
+        "codex" => "https://status.openai.com/",
+        "claude" => "https://status.claude.com/",
+        other => bail!("no status page known for provider '{other}'"),
+    };
+
+    Ok(StatusInfo {
+        indicator: Some("none".to_string()),
+        description: Some("Operational".to_string()),
+        updated_at: Some(clock.iso8601()),
+        url: Some(url.to_string()),
+    })
+}
+
+fn fetch_codex_usage(args: &FetchOptions) -> Result<Option<ProviderEntry>> {
+    match args.codex_source {
+        CodexSource::Auto => {}
+        CodexSource::Rpc => return fetch_codex_entry_via_rpc(args),
+        CodexSource::Status => return fetch_codex_entry_via_status(args),
+        CodexSource::Http => {
+            return fetch_codex_entry_via_http_only(args, resolve_codex_access_token())
+        }
+    }
+
+    fetch_codex_entry_with_fallbacks(
+        || fetch_codex_entry_via_rpc(args),
+        || fetch_codex_entry_via_status(args),
+        || fetch_codex_entry_via_http(args),
+    )
+}
+
+/// RPC, then `/status` scraping, then the usage API. A `/status` error is
+/// only reported when the usage API has nothing either (e.g. no token).
+fn fetch_codex_entry_with_fallbacks(
+    rpc: impl FnOnce() -> Result<Option<ProviderEntry>>,
+    status: impl FnOnce() -> Result<Option<ProviderEntry>>,
+    http: impl FnOnce() -> Result<Option<ProviderEntry>>,
+) -> Result<Option<ProviderEntry>> {
+    match rpc() {
+        Ok(Some(entry)) => return Ok(Some(entry)),
+        Ok(None) => {}
+        Err(error) => {
+            eprintln!("codexbar: codex RPC fetch failed, trying /status fallback: {error:#}");
+        }
+    }
+
+    let status_error = match status() {
+        Ok(Some(entry)) => return Ok(Some(entry)),
+        Ok(None) => None,
+        Err(error) => {
+            eprintln!("codexbar: codex /status fallback failed, trying usage API: {error:#}");
+            Some(error)
+        }
+    };
+
+    match http()? {
+        Some(entry) => Ok(Some(entry)),
+        None => status_error.map_or(Ok(None), Err),
+    }
+}
+
+fn fetch_codex_entry_via_rpc(args: &FetchOptions) -> Result<Option<ProviderEntry>> {
+    let launch = || -> Result<Option<CodexRpcSession>> {
+        let Some(mut session) =
+            CodexRpcSession::start(codex_command(args), args.timeout_or(CODEX_RPC_LOCK_TIMEOUT))?
+        else {
+            return Ok(None);
+        };
+        if args.raw.is_some() {
+            session.transcript = Some(Vec::new());
+        }
+        session.read_timeout = args.timeout_or(CODEX_RPC_READ_TIMEOUT);
+        let initialized = session.initialize();
+        session.dump_transcript(args);
+        initialized.map(|()| Some(session))
+    };
+    let mut session = match start_with_retries(args.codex_start_retries, launch, thread::sleep)? {
+        Some(session) => session,
+        None => return Ok(None),
+    };
+    let entry = codex_entry_from_session(args, &mut session);
+    session.dump_transcript(args);
+    entry
+}
+
+const CODEX_START_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Runs `launch` until it succeeds, up to `retries` extra times, sleeping
+/// 250ms, 500ms, ... plus up to 250ms of jitter in between so competing
+/// processes don't retry in lockstep. A failed attempt's session is dropped,
+/// killing its app-server, before the next one starts.
+fn start_with_retries<T, L, W>(retries: u32, mut launch: L, mut wait: W) -> Result<Option<T>>
+where
+    L: FnMut() -> Result<Option<T>>,
+    W: FnMut(Duration),
+{
+    let mut attempt = 0;
+    loop {
+        match launch() {
+            Err(error) if attempt < retries => {
+                let delay = CODEX_START_RETRY_DELAY.saturating_mul(2u32.saturating_pow(attempt))
+                    + retry_jitter(CODEX_START_RETRY_DELAY);
+                eprintln!(
+                    "codexbar: codex app-server failed to start, retrying in {}ms: {error:#}",
+                    delay.as_millis()
+                );
+                wait(delay);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// A pseudo-random delay below `max`. Each `RandomState` gets fresh keys,
+/// which is all the randomness a retry delay needs.
+fn retry_jitter(max: Duration) -> Duration {
+    use std::hash::{BuildHasher, Hasher};
+
+    let hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    let max_nanos = max.as_nanos().max(1) as u64;
+    Duration::from_nanos(hasher.finish() % max_nanos)
+}
+
+fn codex_entry_from_session(
+    args: &FetchOptions,
+    session: &mut CodexRpcSession,
+) -> Result<Option<ProviderEntry>> {
+    session.initialize()?;
+    let account = session.fetch_account().ok();
+    let limits = session
+        .fetch_rate_limits()
+        .context("failed to fetch codex rate limits via app-server")?;
+
+    Ok(codex_entry_from_rpc(args, account, limits))
+}
+
+fn codex_entry_from_rpc(
+    args: &FetchOptions,
+    account: Option<RpcAccountResponse>,
+    limits: RpcRateLimitsResponse,
+) -> Option<ProviderEntry> {
+    // Older app-servers and unauthenticated sessions may omit rateLimits
+    // entirely; treat that as "no data" so the /status fallback runs.
+    let rate_limits = limits.rate_limits.unwrap_or_default();
+    let windows = order_rate_windows(
+        vec![
+            rate_window_from_codex(rate_limits.primary, args.clock.unix_seconds()),
+            rate_window_from_codex(rate_limits.secondary, args.clock.unix_seconds()),
+        ],
+        args.window_order,
+    );
+    // Newer app-servers add a third window; it stays out of the duration
+    // ordering and always lands in `tertiary`.
+    let tertiary = rate_window_from_codex(rate_limits.tertiary, args.clock.unix_seconds());
+    if windows.is_empty() && tertiary.is_none() {
+        return None;
+    }
+
+    let identity =
+        account
+            .and_then(|response| response.account)
+            .and_then(|details| match details {
+                RpcAccountDetails::ApiKey => None,
+                RpcAccountDetails::ChatGPT { email, plan_type } => Some(IdentityInfo {
+                    account_email: email,
+                    account_organization: None,
+                    login_method: Some("chatgpt".to_string()),
+                    plan: plan_type,
+                }),
+            });
+
+    let credits_remaining = rate_limits
+        .credits
+        .and_then(|credits| credits.balance)
+        .and_then(|balance| balance.parse::<f64>().ok());
+
+    let mut entry = build_codex_entry(args, windows, credits_remaining, identity, "codex-cli");
+    entry.tertiary = tertiary;
+    Some(entry)
+}
+
+/// `codex` with `CODEX_HOME` pointed at `FetchOptions::codex_home` when
+/// set; otherwise the variable, if any, is inherited from our environment.
+fn codex_command(args: &FetchOptions) -> Command {
+    let mut command = Command::new("codex");
+    if let Some(home) = args.codex_home.as_deref() {
+        command.env("CODEX_HOME", home);
+    }
+    command
+}
+
+fn fetch_codex_entry_via_status(args: &FetchOptions) -> Result<Option<ProviderEntry>> {
+    let mut command = codex_command(args);
+    command.args(["-s", "read-only", "-a", "untrusted"]);
+    let output = match run_prepared_command_with_timeout_and_input(
+        command,
+        Some("/status\n"),
+        args.timeout_or(Duration::from_secs(20)),
+    ) {
+        Ok(output) => output,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) if error.kind() == ErrorKind::TimedOut => return Ok(None),
+        Err(error) => return Err(error).context("failed to run codex /status"),
+    };
+
+    let text = format!(
+        "{}\n{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let cleaned = strip_ansi_sequences(&text);
+
+    let five_line = first_line_containing_case_insensitive(&cleaned, "5h limit");
+    let weekly_line = first_line_containing_case_insensitive(&cleaned, "weekly limit");
+    let five_left = five_line
+        .as_deref()
+        .and_then(extract_percent_left_from_line)
+        .map(|left| (100.0 - left).clamp(0.0, 100.0));
+    let weekly_left = weekly_line
+        .as_deref()
+        .and_then(extract_percent_left_from_line)
+        .map(|left| (100.0 - left).clamp(0.0, 100.0));
+    let credits_remaining = extract_credits_from_status(&cleaned);
+
+    if five_left.is_none() && weekly_left.is_none() && credits_remaining.is_none() {
+        return Ok(None);
+    }
+
+    let now = local_now(args.clock.as_ref());
+    let reset_of = |line: Option<&String>| {
+        line.and_then(|line| parse_status_reset(line, now))
+            .map(format_timestamp)
+    };
+    let primary = five_left.map(|used| RateWindow {
+        used_percent: Some(used),
+        window_minutes: Some(300),
+        resets_at: reset_of(five_line.as_ref()),
+    });
+    let secondary = weekly_left.map(|used| RateWindow {
+        used_percent: Some(used),
+        window_minutes: Some(10080),
+        resets_at: reset_of(weekly_line.as_ref()),
+    });
+
+    Ok(Some(build_codex_entry(
+        args,
+        OrderedWindows {
+            primary,
+            secondary,
+            extra: Vec::new(),
+        },
+        credits_remaining,
+        None,
+        "codex-status",
+    )))
+}
+
+/// The usage API as the last `auto` fallback, where having no token just
+/// means there is nothing more to try.
+fn fetch_codex_entry_via_http(args: &FetchOptions) -> Result<Option<ProviderEntry>> {
+    match resolve_codex_access_token() {
+        Some(token) => fetch_codex_entry_with_token(args, &token),
+        None => Ok(None),
+    }
+}
+
+/// `--codex-source http`: the usage API was asked for by name, so a missing
+/// token is an auth failure rather than an empty result.
+fn fetch_codex_entry_via_http_only(
+    args: &FetchOptions,
+    token: Option<String>,
+) -> Result<Option<ProviderEntry>> {
+    let token = token.ok_or_else(|| {
+        CodexBarError::AuthRequired(
+            "--codex-source http needs a token in $CODEXBAR_CODEX_TOKEN or the keyring".to_string(),
+        )
+    })?;
+    fetch_codex_entry_with_token(args, &token)
+}
+
+fn fetch_codex_entry_with_token(
+    args: &FetchOptions,
+    access_token: &str,
+) -> Result<Option<ProviderEntry>> {
+    let output = match fetch_json_get_with_bearer(
+        "https://chatgpt.com/backend-api/wham/usage",
+        access_token,
+    ) {
+        Ok(output) => output,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) if error.kind() == ErrorKind::TimedOut => return Ok(None),
+        Err(error) => return Err(error).context("failed to query ChatGPT usage API"),
+    };
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (body, status_code) = match split_curl_body_and_status(&stdout) {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    match status_code {
+        200 => {}
+        401 | 403 => {
+            return Err(CodexBarError::AuthRequired(
+                "ChatGPT usage API rejected the codex token; run `codex login`".to_string(),
+            )
+            .into())
+        }
+        status => {
+            return Err(CodexBarError::Upstream {
+                service: "ChatGPT usage API".to_string(),
+                status,
+            }
+            .into())
+        }
+    }
+    if warn_on_empty_body("codex", body) {
+        return Ok(None);
+    }
+
+    Ok(codex_entry_from_usage_json(body, args, "openai-web"))
+}
+
+fn resolve_codex_access_token() -> Option<String> {
+    first_env_value(&["CODEXBAR_CODEX_TOKEN", "CODEX_ACCESS_TOKEN"])
+        .and_then(|value| clean_token_value(&value))
+        .or_else(|| lookup_secret("codex", "access_token"))
+}
+
+pub fn codex_entry_from_usage_json(
+    raw_json: &str,
+    args: &FetchOptions,
+    source_label: &str,
+) -> Option<ProviderEntry> {
+    let value = serde_json::from_str::<Value>(raw_json).ok()?;
+    let rate_limit = value.get("rate_limit");
+    let now = args.clock.unix_seconds();
+    let windows = order_rate_windows(
+        vec![
+            rate_limit
+                .and_then(|limits| limits.get("primary_window"))
+                .and_then(|window| rate_window_from_codex_usage_json(window, now)),
+            rate_limit
+                .and_then(|limits| limits.get("secondary_window"))
+                .and_then(|window| rate_window_from_codex_usage_json(window, now)),
+        ],
+        args.window_order,
+    );
+
+    let credits_remaining = value
+        .get("credits")
+        .and_then(|credits| credits.get("balance"))
+        .and_then(json_number_value);
+    let code_review_remaining_percent = value
+        .get("codeReviewRemainingPercent")
+        .and_then(json_number_value)
+        .or_else(|| {
+            value
+                .get("code_review_rate_limit")
+                .and_then(|limits| limits.get("primary_window"))
+                .and_then(|window| window.get("used_percent"))
+                .and_then(json_number_value)
+                .map(|used| (100.0 - used).clamp(0.0, 100.0))
+        });
+
+    if windows.is_empty() && credits_remaining.is_none() && code_review_remaining_percent.is_none()
+    {
+        return None;
+    }
+
+    let identity = Some(IdentityInfo {
+        account_email: value
+            .get("email")
+            .and_then(Value::as_str)
+            .map(ToOwned::to_owned),
+        account_organization: None,
+        login_method: Some("chatgpt".to_string()),
+        plan: value
+            .get("plan_type")
+            .and_then(Value::as_str)
+            .map(ToOwned::to_owned),
+    });
+
+    let mut entry = build_codex_entry(args, windows, credits_remaining, identity, source_label);
+    entry.code_review_remaining_percent = code_review_remaining_percent;
+    Some(entry)
+}
+
+fn rate_window_from_codex_usage_json(window: &Value, now: i64) -> Option<RateWindow> {
+    let used_percent = window.get("used_percent").and_then(json_number_value)?;
+    let window_minutes = window
+        .get("limit_window_seconds")
+        .and_then(Value::as_u64)
+        .map(|seconds| seconds / 60);
+    let resets_at = window
+        .get("reset_at")
+        .and_then(Value::as_i64)
+        .map(|timestamp| absolute_reset_timestamp(timestamp, now))
+        .or_else(|| {
+            window
+                .get("reset_after_seconds")
+                .and_then(Value::as_i64)
+                .map(|seconds| now + seconds.max(0))
+        })
+        .map(format_timestamp);
+
+    Some(RateWindow {
+        used_percent: Some(used_percent.clamp(0.0, 100.0)),
+        window_minutes,
+        resets_at,
+    })
+}
+
+fn build_codex_entry(
+    args: &FetchOptions,
+    windows: OrderedWindows,
+    credits_remaining: Option<f64>,
+    identity: Option<IdentityInfo>,
+    default_source: &str,
+) -> ProviderEntry {
+    let source = if args.source.eq_ignore_ascii_case("auto") {
+        default_source.to_string()
+    } else {
+        args.source.clone()
+    };
+
+    ProviderEntry {
+        provider: "codex".to_string(),
+        source: Some(source),
+        updated_at: args.clock.iso8601(),
+        primary: windows.primary,
+        secondary: windows.secondary,
+        tertiary: None,
+        extra_windows: windows.extra,
+        windows: Vec::new(),
+        credits_remaining,
+        code_review_remaining_percent: None,
+        identity,
+        status: None,
+        error: None,
+    }
+}
+
+fn fetch_claude_usage(args: &FetchOptions) -> Result<Option<ProviderEntry>> {
+    fetch_claude_usage_from(
+        http_client().as_ref(),
+        CLAUDE_USAGE_ENDPOINT,
+        resolve_claude_oauth_access_token().as_deref(),
+        args,
+    )
+}
+
+/// Transport failures keep their `io::Error` so `provider_error` can tell a
+/// missing `curl` and a timeout from other failures.
+fn fetch_claude_usage_from(
+    client: &dyn HttpClient,
+    endpoint: &str,
+    access_token: Option<&str>,
+    args: &FetchOptions,
+) -> Result<Option<ProviderEntry>> {
+    let Some(access_token) = access_token else {
+        return Err(CodexBarError::AuthRequired(
+            "no Claude OAuth token found; run `codexbar auth --provider claude`".to_string(),
+        )
+        .into());
+    };
+    let headers = claude_request_headers(access_token);
+    let header_refs = headers.iter().map(String::as_str).collect::<Vec<_>>();
+    let response = send_with_retries(
+        "claude",
+        args.retries,
+        || {
+            let output = client.get_json(endpoint, &header_refs, args.timeout);
+            if let (Some(_), Ok(output)) = (&args.raw, &output) {
+                args.dump_raw(
+                    "claude-usage.http",
+                    &format!(
+                        "GET {endpoint}\n{}\n\n{}",
+                        headers.join("\n"),
+                        output.to_raw()
+                    ),
+                );
+            }
+            output
+        },
+        thread::sleep,
+    );
+    let HttpResponse {
+        status: status_code,
+        body,
+        ..
+    } = response.context("failed to query Claude OAuth usage API")?;
+    match status_code {
+        200 => {}
+        // Expired or revoked. Neither the token nor the response body (which
+        // could echo it) goes into the message.
+        401 | 403 => {
+            return Err(CodexBarError::AuthRequired(format!(
+                "Claude rejected the OAuth token (HTTP {status_code}); run `codexbar auth --provider claude`"
+            ))
+            .into())
+        }
+        status => {
+            return Err(CodexBarError::Upstream {
+                service: "claude".to_string(),
+                status,
+            }
+            .into())
+        }
+    }
+    if warn_on_empty_body("claude", &body) {
+        return Ok(None);
+    }
+
+    Ok(
+        claude_entry_from_usage_json(&body, args, "claude-oauth-api").map(|mut entry| {
+            if let Some(identity) = entry.identity.as_mut() {
+                identity.plan = load_claude_plan_from_credentials_file();
+            }
+            entry
+        }),
+    )
+}
+
+/// Header names whose values are credentials.
+const SECRET_HEADERS: [&str; 5] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+];
+
+/// Masks credentials in raw HTTP exchanges and JSON lines: the values of
+/// `SECRET_HEADERS` and of JSON keys naming a token, secret or password.
+fn redact_secrets(raw: &str) -> String {
+    let mut out = String::with_capacity(raw.len());
+    for line in raw.split_inclusive('\n') {
+        let text = line.trim_end_matches(['\r', '\n']);
+        let newline = &line[text.len()..];
+        if let Ok(mut value) = serde_json::from_str::<Value>(text) {
+            if value.is_object() || value.is_array() {
+                redact_json_secrets(&mut value);
+                out.push_str(&value.to_string());
+                out.push_str(newline);
+                continue;
+            }
+        }
+        match text.split_once(':') {
+            Some((name, _))
+                if SECRET_HEADERS
+                    .iter()
+                    .any(|secret| name.trim().eq_ignore_ascii_case(secret)) =>
+            {
+                out.push_str(name);
+                out.push_str(": [redacted]");
+                out.push_str(newline);
+            }
+            _ => out.push_str(line),
+        }
+    }
+    out
+}
+
+fn redact_json_secrets(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_ascii_lowercase();
+                if value.is_string()
+                    && ["token", "secret", "password", "apikey", "api_key"]
+                        .iter()
+                        .any(|needle| key.contains(needle))
+                {
+                    *value = json!("[redacted]");
+                } else {
+                    redact_json_secrets(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_json_secrets),
+        _ => {}
+    }
+}
+
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Runs `send` (an `HttpClient::get_json`) and retries 429 and 5xx
+/// responses up to `retries` times, waiting for `Retry-After` when the server
+/// sends one and 1s, 2s, 4s, ... otherwise. Requests that got no response are
+/// retried the same way, except timeouts, which already used up the budget,
+/// and a missing `curl`.
+fn send_with_retries<S, W>(
+    provider: &str,
+    retries: u32,
+    mut send: S,
+    mut wait: W,
+) -> io::Result<HttpResponse>
+where
+    S: FnMut() -> io::Result<HttpResponse>,
+    W: FnMut(Duration),
+{
+    let mut attempt = 0;
+    loop {
+        let (reason, retry_after) = match send() {
+            Ok(response) => {
+                let retryable = response.status == 429 || (500..600).contains(&response.status);
+                if !retryable || attempt >= retries {
+                    return Ok(response);
+                }
+                (
+                    format!("returned HTTP {}", response.status),
+                    response.header("retry-after").and_then(retry_after),
+                )
+            }
+            Err(error)
+                if attempt >= retries
+                    || matches!(error.kind(), ErrorKind::NotFound | ErrorKind::TimedOut) =>
+            {
+                return Err(error)
+            }
+            Err(error) => (format!("failed ({error})"), None),
+        };
+
+        let delay = retry_after
+            .unwrap_or_else(|| RETRY_BASE_DELAY.saturating_mul(2u32.saturating_pow(attempt)))
+            .min(RETRY_MAX_DELAY);
+        eprintln!(
+            "codexbar: provider '{provider}' {reason}; retrying in {}s",
+            delay.as_secs()
+        );
+        wait(delay);
+        attempt += 1;
+    }
+}
+
+/// Splits `-D -` header blocks off a curl response. With `--location` every
+/// hop writes a block; only the last one describes the body.
+fn split_curl_headers(response: &str) -> (&str, &str) {
+    let mut headers = "";
+    let mut rest = response;
+    while rest.starts_with("HTTP/") {
+        let Some((block, tail)) = rest
+            .split_once("\r\n\r\n")
+            .or_else(|| rest.split_once("\n\n"))
+        else {
+            break;
+        };
+        headers = block;
+        rest = tail;
+    }
+    (headers, rest)
+}
+
+/// `Retry-After` in delay-seconds form; HTTP dates fall back to backoff.
+fn retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse::<u64>().ok().map(Duration::from_secs)
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum GeminiAuthType {
+    OauthPersonal,
+    ApiKey,
+    VertexAi,
+    Unknown,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum GeminiUserTierId {
+    Free,
+    Legacy,
+    Standard,
+}
+
+#[derive(Debug, Deserialize)]
+struct GeminiOAuthCredentials {
+    #[serde(default)]
+    access_token: Option<String>,
+    #[serde(default)]
+    id_token: Option<String>,
+    #[serde(default)]
+    refresh_token: Option<String>,
+    #[serde(default)]
+    expiry_date: Option<f64>,
+}
+
+#[derive(Debug)]
+struct GeminiTokenClaims {
+    email: Option<String>,
+    hosted_domain: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct GeminiModelQuota {
+    remaining_fraction: f64,
+    reset_time: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct GeminiCodeAssistStatus {
+    tier: Option<GeminiUserTierId>,
+    project_id: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct GeminiOAuthClientCredentials {
+    client_id: String,
+    client_secret: String,
+}
+
+fn fetch_gemini_entry(args: &FetchOptions) -> Result<Option<ProviderEntry>> {
+    if let Some(access_token) = resolve_gemini_access_token_override() {
+        let claims = GeminiTokenClaims {
+            email: None,
+            hosted_domain: None,
+        };
+        return fetch_gemini_entry_with_token(args, &access_token, claims);
+    }
+
+    let home = resolve_home_directory()?;
+    let auth_type = current_gemini_auth_type(&home);
+    match auth_type {
+        GeminiAuthType::ApiKey => {
+            bail!("Gemini API key auth is not supported; use Gemini OAuth login")
+        }
+        GeminiAuthType::VertexAi => {
+            bail!("Gemini Vertex AI auth is not supported in Gemini provider")
+        }
+        GeminiAuthType::OauthPersonal | GeminiAuthType::Unknown => {}
+    }
+
+    let mut credentials = match load_gemini_oauth_credentials(&home)? {
+        Some(credentials) => credentials,
+        None => return Ok(None),
+    };
+
+    let mut access_token = match credentials
+        .access_token
+        .as_deref()
+        .and_then(clean_token_value)
+    {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+
+    let token_expired = credentials
+        .expiry_date
+        .map(|expiry_ms| expiry_ms < current_unix_millis())
+        .unwrap_or(false);
+    if token_expired {
+        let refresh_token = match credentials
+            .refresh_token
+            .as_deref()
+            .and_then(clean_token_value)
+        {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+
+        access_token = refresh_gemini_access_token(&refresh_token, &home)?;
+        credentials.access_token = Some(access_token.clone());
+    }
+
+    let claims = extract_gemini_token_claims(credentials.id_token.as_deref());
+    fetch_gemini_entry_with_token(args, &access_token, claims)
+}
+
+/// A token from `CODEXBAR_GEMINI_TOKEN` or the keyring, used instead of the
+/// Gemini CLI's OAuth credentials file. Such tokens are never refreshed.
+fn resolve_gemini_access_token_override() -> Option<String> {
+    first_env_value(&["CODEXBAR_GEMINI_TOKEN"])
+        .and_then(|value| clean_token_value(&value))
+        .or_else(|| lookup_secret("gemini", "access_token"))
+}
+
+fn fetch_gemini_entry_with_token(
+    args: &FetchOptions,
+    access_token: &str,
+    claims: GeminiTokenClaims,
+) -> Result<Option<ProviderEntry>> {
+    let code_assist = load_gemini_code_assist_status(access_token)?;
+    let project_id = if let Some(project_id) = code_assist.project_id.clone() {
+        Some(project_id)
+    } else {
+        discover_gemini_project_id(access_token)?
+    };
+
+    let quota_output = match fetch_gemini_quota_json(access_token, project_id.as_deref()) {
+        Ok(output) => output,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) if error.kind() == ErrorKind::TimedOut => return Ok(None),
+        Err(error) => return Err(error).context("failed to query Gemini quota API"),
+    };
+    let quota_stdout = String::from_utf8_lossy(&quota_output.stdout);
+    let (quota_body, quota_status) = match split_curl_body_and_status(&quota_stdout) {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    if quota_status == 401 {
+        return Ok(None);
+    }
+    if quota_status != 200 {
+        return Err(CodexBarError::Upstream {
+            service: "Gemini quota API".to_string(),
+            status: quota_status,
+        }
+        .into());
+    }
+    if warn_on_empty_body("gemini", quota_body) {
+        return Ok(None);
+    }
+
+    let plan_label = gemini_plan_label(code_assist.tier, claims.hosted_domain.as_deref());
+    gemini_entry_from_quota_json(quota_body, args, claims.email, plan_label).map(Some)
+}
+
+pub fn gemini_entry_from_quota_json(
+    raw_json: &str,
+    args: &FetchOptions,
+    account_email: Option<String>,
+    plan_label: Option<String>,
+) -> Result<ProviderEntry> {
+    let (primary, secondary) = parse_gemini_quota_windows(raw_json)?;
+    let source = if args.source.eq_ignore_ascii_case("auto") {
+        "api".to_string()
+    } else {
+        args.source.clone()
+    };
+    let status = if args.status {
+        Some(StatusInfo {
+            indicator: Some("none".to_string()),
+            description: Some("Operational".to_string()),
+            updated_at: Some(args.clock.iso8601()),
+            url: Some(
+                "https://www.google.com/appsstatus/dashboard/products/npdyhgECDJ6tB66MxXyo/history"
+                    .to_string(),
+            ),
+        })
+    } else {
+        None
+    };
+
+    Ok(ProviderEntry {
+        provider: "gemini".to_string(),
+        source: Some(source),
+        updated_at: args.clock.iso8601(),
+        primary: Some(primary),
+        secondary,
+        tertiary: None,
+        extra_windows: Vec::new(),
+        windows: Vec::new(),
+        credits_remaining: None,
+        code_review_remaining_percent: None,
+        identity: Some(IdentityInfo {
+            account_email,
+            account_organization: None,
+            login_method: Some("oauth".to_string()),
+            plan: plan_label,
+        }),
+        status,
+        error: None,
+    })
+}
+
+fn resolve_home_directory() -> Result<PathBuf> {
+    let home = std::env::var("HOME").context("HOME is not set")?;
+    Ok(PathBuf::from(home))
+}
+
+fn current_unix_millis() -> f64 {
+    UNIX_EPOCH
+        .elapsed()
+        .map(|duration| duration.as_millis() as f64)
+        .unwrap_or(0.0)
+}
+
+fn current_gemini_auth_type(home: &Path) -> GeminiAuthType {
+    let settings_path = home.join(".gemini").join("settings.json");
+    let raw = match fs::read_to_string(settings_path) {
+        Ok(raw) => raw,
+        Err(_) => return GeminiAuthType::Unknown,
+    };
+    let json = match serde_json::from_str::<Value>(&raw) {
+        Ok(json) => json,
+        Err(_) => return GeminiAuthType::Unknown,
+    };
+    let selected_type = json
+        .get("security")
+        .and_then(|security| security.get("auth"))
+        .and_then(|auth| auth.get("selectedType"))
+        .and_then(Value::as_str)
+        .map(str::trim)
+        .unwrap_or_default();
+
+    match selected_type {
+        "oauth-personal" => GeminiAuthType::OauthPersonal,
+        "api-key" => GeminiAuthType::ApiKey,
+        "vertex-ai" => GeminiAuthType::VertexAi,
+        _ => GeminiAuthType::Unknown,
+    }
+}
+
+fn load_gemini_oauth_credentials(home: &Path) -> Result<Option<GeminiOAuthCredentials>> {
+    let creds_path = home.join(".gemini").join("oauth_creds.json");
+    let raw = match fs::read_to_string(&creds_path) {
+        Ok(raw) => raw,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) => {
+            return Err(error).with_context(|| format!("failed to read {}", creds_path.display()))
+        }
+    };
+    let credentials = serde_json::from_str::<GeminiOAuthCredentials>(&raw)
+        .with_context(|| format!("failed to parse {}", creds_path.display()))?;
+    Ok(Some(credentials))
+}
+
+fn extract_gemini_token_claims(id_token: Option<&str>) -> GeminiTokenClaims {
+    let token = match id_token.and_then(clean_token_value) {
+        Some(token) => token,
+        None => {
+            return GeminiTokenClaims {
+                email: None,
+                hosted_domain: None,
+            }
+        }
+    };
+    let mut parts = token.split('.');
+    let _header = parts.next();
+    let payload = match parts.next() {
+        Some(payload) => payload,
+        None => {
+            return GeminiTokenClaims {
+                email: None,
+                hosted_domain: None,
+            }
+        }
+    };
+
+    let mut normalized = payload.replace('-', "+").replace('_', "/");
+    while normalized.len() % 4 != 0 {
+        normalized.push('=');
+    }
+    let decoded = match base64_decode(&normalized) {
+        Some(decoded) => decoded,
+        None => {
+            return GeminiTokenClaims {
+                email: None,
+                hosted_domain: None,
+            }
+        }
+    };
+    let value = match serde_json::from_slice::<Value>(&decoded) {
+        Ok(value) => value,
+        Err(_) => {
+            return GeminiTokenClaims {
+                email: None,
+                hosted_domain: None,
+            }
+        }
+    };
+
+    GeminiTokenClaims {
+        email: value
+            .get("email")
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(ToOwned::to_owned),
+        hosted_domain: value
+            .get("hd")
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(ToOwned::to_owned),
+    }
+}
+
+fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut chunk = [0u8; 4];
+    let mut chunk_len = 0usize;
+
+    for byte in input.bytes() {
+        if matches!(byte, b' ' | b'\n' | b'\r' | b'\t') {
+            continue;
+        }
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => 26 + (byte - b'a'),
+            b'0'..=b'9' => 52 + (byte - b'0'),
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => 64,
+            _ => return None,
+        };
+        chunk[chunk_len] = value;
+        chunk_len += 1;
+
+        if chunk_len == 4 {
+            if chunk[0] == 64 || chunk[1] == 64 {
+                return None;
+            }
+            output.push((chunk[0] << 2) | (chunk[1] >> 4));
+            if chunk[2] != 64 {
+                output.push((chunk[1] << 4) | (chunk[2] >> 2));
+            }
+            if chunk[3] != 64 {
+                output.push((chunk[2] << 6) | chunk[3]);
+            }
+            chunk_len = 0;
+        }
+    }
+
+    if chunk_len != 0 {
+        return None;
+    }
+
+    Some(output)
+}
+
+fn load_gemini_code_assist_status(access_token: &str) -> Result<GeminiCodeAssistStatus> {
+    let output = match fetch_gemini_load_code_assist_json(access_token) {
+        Ok(output) => output,
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            return Ok(GeminiCodeAssistStatus {
+                tier: None,
+                project_id: None,
+            })
+        }
+        Err(error) if error.kind() == ErrorKind::TimedOut => {
+            return Ok(GeminiCodeAssistStatus {
+                tier: None,
+                project_id: None,
+            })
+        }
+        Err(error) => return Err(error).context("failed to query Gemini loadCodeAssist API"),
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (body, status_code) = match split_curl_body_and_status(&stdout) {
+        Some(parts) => parts,
+        None => {
+            return Ok(GeminiCodeAssistStatus {
+                tier: None,
+                project_id: None,
+            })
+        }
+    };
+    if status_code != 200 {
+        return Ok(GeminiCodeAssistStatus {
+            tier: None,
+            project_id: None,
+        });
+    }
+    let value = match serde_json::from_str::<Value>(body) {
+        Ok(value) => value,
+        Err(_) => {
+            return Ok(GeminiCodeAssistStatus {
+                tier: None,
+                project_id: None,
+            })
+        }
+    };
+
+    let project_id = extract_gemini_code_assist_project_id(&value);
+    let tier = value
+        .get("currentTier")
+        .and_then(|tier| tier.get("id"))
+        .and_then(Value::as_str)
+        .and_then(gemini_tier_from_str);
+    Ok(GeminiCodeAssistStatus { tier, project_id })
+}
+
+fn extract_gemini_code_assist_project_id(value: &Value) -> Option<String> {
+    if let Some(project) = value.get("cloudaicompanionProject").and_then(Value::as_str) {
+        let trimmed = project.trim();
+        if !trimmed.is_empty() {
+            return Some(trimmed.to_string());
+        }
+    }
+
+    let project_obj = value
+        .get("cloudaicompanionProject")
+        .and_then(Value::as_object)?;
+    if let Some(project_id) = project_obj.get("id").and_then(Value::as_str) {
+        let trimmed = project_id.trim();
+        if !trimmed.is_empty() {
+            return Some(trimmed.to_string());
+        }
+    }
+    if let Some(project_id) = project_obj.get("projectId").and_then(Value::as_str) {
+        let trimmed = project_id.trim();
+        if !trimmed.is_empty() {
+            return Some(trimmed.to_string());
+        }
+    }
+    None
+}
+
+fn gemini_tier_from_str(raw: &str) -> Option<GeminiUserTierId> {
+    match raw.trim() {
+        "free-tier" => Some(GeminiUserTierId::Free),
+        "legacy-tier" => Some(GeminiUserTierId::Legacy),
+        "standard-tier" => Some(GeminiUserTierId::Standard),
+        _ => None,
+    }
+}
+
+fn discover_gemini_project_id(access_token: &str) -> Result<Option<String>> {
+    let output = match fetch_gemini_projects_json(access_token) {
+        Ok(output) => output,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) if error.kind() == ErrorKind::TimedOut => return Ok(None),
+        Err(error) => return Err(error).context("failed to query Google Cloud project list"),
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (body, status_code) = match split_curl_body_and_status(&stdout) {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    if status_code != 200 {
+        return Ok(None);
+    }
+    let value = match serde_json::from_str::<Value>(body) {
+        Ok(value) => value,
+        Err(_) => return Ok(None),
+    };
+    let projects = match value.get("projects").and_then(Value::as_array) {
+        Some(projects) => projects,
+        None => return Ok(None),
+    };
+
+    for project in projects {
+        let project_id = match project.get("projectId").and_then(Value::as_str) {
+            Some(project_id) => project_id.trim(),
+            None => continue,
+        };
+        if project_id.is_empty() {
+            continue;
+        }
+        if project_id.starts_with("gen-lang-client") {
+            return Ok(Some(project_id.to_string()));
+        }
+        if project
+            .get("labels")
+            .and_then(Value::as_object)
+            .map(|labels| labels.contains_key("generative-language"))
+            .unwrap_or(false)
+        {
+            return Ok(Some(project_id.to_string()));
+        }
+    }
+
+    Ok(None)
+}
+
+fn fetch_gemini_load_code_assist_json(access_token: &str) -> io::Result<Output> {
+    fetch_json_post_with_bearer(
+        "https://cloudcode-pa.googleapis.com/v1internal:loadCodeAssist",
+        access_token,
+        "{\"metadata\":{\"ideType\":\"GEMINI_CLI\",\"pluginType\":\"GEMINI\"}}",
+    )
+}
+
+fn fetch_gemini_projects_json(access_token: &str) -> io::Result<Output> {
+    fetch_json_get_with_bearer(
+        "https://cloudresourcemanager.googleapis.com/v1/projects",
+        access_token,
+    )
+}
+
+fn fetch_gemini_quota_json(access_token: &str, project_id: Option<&str>) -> io::Result<Output> {
+    let body = match project_id {
+        Some(project_id) => json!({"project": project_id}).to_string(),
+        None => "{}".to_string(),
+    };
+    fetch_json_post_with_bearer(
+        "https://cloudcode-pa.googleapis.com/v1internal:retrieveUserQuota",
+        access_token,
+        &body,
+    )
+}
+
+fn fetch_json_get_with_bearer(endpoint: &str, access_token: &str) -> io::Result<Output> {
+    let args_owned = [
+        "-sS".to_string(),
+        "--location".to_string(),
+        "--max-time".to_string(),
+        "15".to_string(),
+        "-H".to_string(),
+        format!("Authorization: Bearer {access_token}"),
+        "-H".to_string(),
+        "Accept: application/json".to_string(),
+        "-w".to_string(),
+        "\n%{http_code}".to_string(),
+        endpoint.to_string(),
+    ];
+    let args = args_owned.iter().map(String::as_str).collect::<Vec<_>>();
+    run_command_with_timeout("curl", &args, Duration::from_secs(20))
+}
+
+fn fetch_json_post_with_bearer(
+    endpoint: &str,
+    access_token: &str,
+    body_json: &str,
+) -> io::Result<Output> {
+    let args_owned = [
+        "-sS".to_string(),
+        "--location".to_string(),
+        "--max-time".to_string(),
+        "15".to_string(),
+        "-H".to_string(),
+        format!("Authorization: Bearer {access_token}"),
+        "-H".to_string(),
+        "Accept: application/json".to_string(),
+        "-H".to_string(),
+        "Content-Type: application/json".to_string(),
+        "--data".to_string(),
+        body_json.to_string(),
+        "-w".to_string(),
+        "\n%{http_code}".to_string(),
+        endpoint.to_string(),
+    ];
+    let args = args_owned.iter().map(String::as_str).collect::<Vec<_>>();
+    run_command_with_timeout("curl", &args, Duration::from_secs(20))
+}
+
+fn parse_gemini_quota_windows(raw_json: &str) -> Result<(RateWindow, Option<RateWindow>)> {
+    let value =
+        serde_json::from_str::<Value>(raw_json).context("failed to parse Gemini quota JSON")?;
+    let buckets = value
+        .get("buckets")
+        .and_then(Value::as_array)
+        .context("Gemini quota JSON did not include buckets")?;
+    if buckets.is_empty() {
+        bail!("Gemini quota JSON contained no buckets");
+    }
+
+    let mut model_quotas: HashMap<String, GeminiModelQuota> = HashMap::new();
+    for bucket in buckets {
+        let model_id = match bucket.get("modelId").and_then(Value::as_str).map(str::trim) {
+            Some(model_id) if !model_id.is_empty() => model_id,
+            _ => continue,
+        };
+        let remaining_fraction = match bucket.get("remainingFraction").and_then(json_number_value) {
+            Some(remaining) => remaining.clamp(0.0, 1.0),
+            None => continue,
+        };
+        let reset_time = bucket
+            .get("resetTime")
+            .and_then(Value::as_str)
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(ToOwned::to_owned);
+
+        let entry = model_quotas
+            .entry(model_id.to_string())
+            .or_insert_with(|| GeminiModelQuota {
+                remaining_fraction,
+                reset_time: reset_time.clone(),
+            });
+        if remaining_fraction < entry.remaining_fraction {
+            entry.remaining_fraction = remaining_fraction;
+            entry.reset_time = reset_time;
+        }
+    }
+
+    if model_quotas.is_empty() {
+        bail!("Gemini quota JSON did not include usable quota buckets");
+    }
+
+    let pro_min = model_quotas
+        .iter()
+        .filter(|(model_id, _)| model_id.to_ascii_lowercase().contains("pro"))
+        .map(|(_, quota)| quota)
+        .min_by(|lhs, rhs| lhs.remaining_fraction.total_cmp(&rhs.remaining_fraction));
+    let flash_min = model_quotas
+        .iter()
+        .filter(|(model_id, _)| model_id.to_ascii_lowercase().contains("flash"))
+        .map(|(_, quota)| quota)
+        .min_by(|lhs, rhs| lhs.remaining_fraction.total_cmp(&rhs.remaining_fraction));
+
+    let pro_used = pro_min
+        .map(|quota| (100.0 - (quota.remaining_fraction * 100.0)).clamp(0.0, 100.0))
+        .unwrap_or(0.0);
+    let primary = RateWindow {
+        used_percent: Some(pro_used),
+        window_minutes: Some(1440),
+        resets_at: pro_min.and_then(|quota| quota.reset_time.clone()),
+    };
+    let secondary = flash_min.map(|quota| RateWindow {
+        used_percent: Some((100.0 - (quota.remaining_fraction * 100.0)).clamp(0.0, 100.0)),
+        window_minutes: Some(1440),
+        resets_at: quota.reset_time.clone(),
+    });
+
+    Ok((primary, secondary))
+}
+
+pub fn gemini_plan_label(
+    tier: Option<GeminiUserTierId>,
+    hosted_domain: Option<&str>,
+) -> Option<String> {
+    match (tier, hosted_domain) {
+        (Some(GeminiUserTierId::Standard), _) => Some("Paid".to_string()),
+        (Some(GeminiUserTierId::Free), Some(domain)) if !domain.trim().is_empty() => {
+            Some("Workspace".to_string())
+        }
+        (Some(GeminiUserTierId::Free), _) => Some("Free".to_string()),
+        (Some(GeminiUserTierId::Legacy), _) => Some("Legacy".to_string()),
+        (None, _) => None,
+    }
+}
+
+fn refresh_gemini_access_token(refresh_token: &str, home: &Path) -> Result<String> {
+    let oauth_credentials = extract_gemini_oauth_client_credentials()
+        .context("Could not find Gemini CLI OAuth configuration")?;
+    let refresh_body = format!(
+        "client_id={}&client_secret={}&refresh_token={}&grant_type=refresh_token",
+        percent_encode_form_value(&oauth_credentials.client_id),
+        percent_encode_form_value(&oauth_credentials.client_secret),
+        percent_encode_form_value(refresh_token),
+    );
+
+    let output = fetch_oauth_token_refresh_json(&refresh_body)
+        .context("failed to request Google OAuth token refresh")?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (body, status_code) =
+        split_curl_body_and_status(&stdout).context("failed to parse OAuth refresh response")?;
+    if status_code != 200 {
+        bail!("Gemini OAuth refresh failed with HTTP {status_code}");
+    }
+
+    let refresh_json = serde_json::from_str::<Value>(body)
+        .context("failed to parse OAuth refresh JSON payload")?;
+    let access_token = refresh_json
+        .get("access_token")
+        .and_then(Value::as_str)
+        .and_then(clean_token_value)
+        .context("OAuth refresh response did not include access_token")?;
+
+    update_gemini_stored_credentials(home, &refresh_json)?;
+    Ok(access_token)
+}
+
+fn fetch_oauth_token_refresh_json(form_body: &str) -> io::Result<Output> {
+    let args_owned = [
+        "-sS".to_string(),
+        "--location".to_string(),
+        "--max-time".to_string(),
+        "15".to_string(),
+        "-H".to_string(),
+        "Content-Type: application/x-www-form-urlencoded".to_string(),
+        "--data".to_string(),
+        form_body.to_string(),
+        "-w".to_string(),
+        "\n%{http_code}".to_string(),
+        "https://oauth2.googleapis.com/token".to_string(),
+    ];
+    let args = args_owned.iter().map(String::as_str).collect::<Vec<_>>();
+    run_command_with_timeout("curl", &args, Duration::from_secs(20))
+}
+
+fn percent_encode_form_value(value: &str) -> String {
+    let mut output = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        let is_unreserved =
+            byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~');
+        if is_unreserved {
+            output.push(byte as char);
+        } else {
+            output.push('%');
+            output.push_str(&format!("{byte:02X}"));
+        }
+    }
+    output
+}
+
+fn update_gemini_stored_credentials(home: &Path, refresh_json: &Value) -> Result<()> {
+    let creds_path = home.join(".gemini").join("oauth_creds.json");
+    let raw = fs::read_to_string(&creds_path)
+        .with_context(|| format!("failed to read {}", creds_path.display()))?;
+    let mut root = serde_json::from_str::<Value>(&raw)
+        .with_context(|| format!("failed to parse {}", creds_path.display()))?;
+    if !root.is_object() {
+        bail!("Gemini credentials file was not a JSON object");
+    }
+    let obj = root
+        .as_object_mut()
+        .context("internal error: Gemini credentials root was not an object")?;
+
+    if let Some(token) = refresh_json
+        .get("access_token")
+        .and_then(Value::as_str)
+        .and_then(clean_token_value)
+    {
+        obj.insert("access_token".to_string(), Value::String(token));
+    }
+    if let Some(expires_in) = refresh_json.get("expires_in").and_then(json_number_value) {
+        let expiry_date_ms = current_unix_millis() + (expires_in * 1000.0);
+        if let Some(number) = serde_json::Number::from_f64(expiry_date_ms) {
+            obj.insert("expiry_date".to_string(), Value::Number(number));
+        }
+    }
+    if let Some(id_token) = refresh_json
+        .get("id_token")
+        .and_then(Value::as_str)
+        .and_then(clean_token_value)
+    {
+        obj.insert("id_token".to_string(), Value::String(id_token));
+    }
+
+    let encoded = serde_json::to_string_pretty(&root)
+        .with_context(|| format!("failed to encode {}", creds_path.display()))?;
+    fs::write(&creds_path, format!("{encoded}\n"))
+        .with_context(|| format!("failed to write {}", creds_path.display()))?;
+    Ok(())
+}
+
+fn extract_gemini_oauth_client_credentials() -> Option<GeminiOAuthClientCredentials> {
+    let gemini_path = resolve_gemini_binary_path()?;
+    let resolved_binary = resolve_single_symlink_path(&gemini_path);
+    let bin_dir = resolved_binary.parent()?;
+    let base_dir = bin_dir.parent()?;
+
+    let candidates = [
+        base_dir.join("libexec/lib/node_modules/@google/gemini-cli/node_modules/@google/gemini-cli-core/dist/src/code_assist/oauth2.js"),
+        base_dir.join("lib/node_modules/@google/gemini-cli/node_modules/@google/gemini-cli-core/dist/src/code_assist/oauth2.js"),
+        base_dir.join("share/gemini-cli/node_modules/@google/gemini-cli-core/dist/src/code_assist/oauth2.js"),
+        base_dir.join("../gemini-cli-core/dist/src/code_assist/oauth2.js"),
+        base_dir.join("node_modules/@google/gemini-cli-core/dist/src/code_assist/oauth2.js"),
+    ];
+
+    for path in candidates {
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(_) => continue,
+        };
+        let client_id = match extract_js_constant(&content, "OAUTH_CLIENT_ID") {
+            Some(value) => value,
+            None => continue,
+        };
+        let client_secret = match extract_js_constant(&content, "OAUTH_CLIENT_SECRET") {
+            Some(value) => value,
+            None => continue,
+        };
+        return Some(GeminiOAuthClientCredentials {
+            client_id,
+            client_secret,
+        });
+    }
+
+    None
+}
+
+fn resolve_gemini_binary_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var("GEMINI_CLI_PATH")
+        .ok()
+        .and_then(|value| clean_token_value(&value))
+        .map(PathBuf::from)
+        .filter(|path| path.is_file())
+    {
+        return Some(path);
+    }
+
+    let which_output =
+        run_command_with_timeout("which", &["gemini"], Duration::from_secs(5)).ok()?;
+    if !which_output.status.success() {
+        return None;
+    }
+    let which_stdout = String::from_utf8_lossy(&which_output.stdout).to_string();
+    let candidate = which_stdout
+        .lines()
+        .next()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())?
+        .to_string();
+    let path = PathBuf::from(candidate);
+    if path.is_file() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+fn resolve_single_symlink_path(path: &Path) -> PathBuf {
+    let link_target = match fs::read_link(path) {
+        Ok(target) => target,
+        Err(_) => return path.to_path_buf(),
+    };
+    if link_target.is_absolute() {
+        return link_target;
+    }
+    match path.parent() {
+        Some(parent) => parent.join(link_target),
+        None => link_target,
+    }
+}
+
+fn extract_js_constant(content: &str, key: &str) -> Option<String> {
+    let key_index = content.find(key)?;
+    let after_key = &content[(key_index + key.len())..];
+    let equals_index = after_key.find('=')?;
+    let after_equals = after_key[(equals_index + 1)..].trim_start();
+    let quote = after_equals.chars().next()?;
+    if quote != '\'' && quote != '"' {
+        return None;
+    }
+    let quoted = &after_equals[1..];
+    let end_index = quoted.find(quote)?;
+    let value = quoted[..end_index].trim();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+fn fetch_cursor_entry(args: &FetchOptions) -> Result<Option<ProviderEntry>> {
+    let cookie_header = match resolve_cursor_cookie_header() {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+
+    let output = match fetch_cursor_usage_summary_json(&cookie_header) {
+        Ok(output) => output,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) if error.kind() == ErrorKind::TimedOut => return Ok(None),
+        Err(error) => return Err(error).context("failed to query Cursor usage summary API"),
+    };
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (body, status_code) = match split_curl_body_and_status(&stdout) {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    if status_code != 200 {
+        return Ok(None);
+    }
+    if warn_on_empty_body("cursor", body) {
+        return Ok(None);
+    }
+
+    Ok(cursor_entry_from_usage_summary_json(
+        body,
+        args,
+        "cursor-usage-summary",
+    ))
+}
+
+fn resolve_cursor_cookie_header() -> Option<String> {
+    first_env_value(&[
+        "CODEXBAR_CURSOR_COOKIE_HEADER",
+        "CURSOR_COOKIE_HEADER",
+        "CURSOR_COOKIE",
+    ])
+    .and_then(|value| clean_token_value(&value))
+    .or_else(load_cursor_cookie_header_from_config)
+}
+
+fn load_cursor_cookie_header_from_config() -> Option<String> {
+    let home = std::env::var("HOME").ok()?;
+    let path = PathBuf::from(home).join(".codexbar").join("config.json");
+    let raw = fs::read_to_string(path).ok()?;
+    let json = serde_json::from_str::<Value>(&raw).ok()?;
+    let providers = json.get("providers").and_then(Value::as_array)?;
+
+    for provider in providers {
+        let id = provider
+            .get("id")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .trim();
+        if !id.eq_ignore_ascii_case("cursor") {
+            continue;
+        }
+
+        let cookie_source = provider
+            .get("cookieSource")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .trim();
+        if cookie_source.eq_ignore_ascii_case("off") {
+            return None;
+        }
+
+        if let Some(cookie_header) = provider.get("cookieHeader").and_then(Value::as_str) {
+            if let Some(value) = clean_token_value(cookie_header) {
+                return Some(value);
+            }
+        }
+    }
+
+    None
+}
+
+pub fn fetch_cursor_usage_summary_json(cookie_header: &str) -> io::Result<Output> {
+    let args_owned = [
+        "-sS".to_string(),
+        "--location".to_string(),
+        "--max-time".to_string(),
+        "15".to_string(),
+        "-H".to_string(),
+        "Accept: application/json".to_string(),
+        "-H".to_string(),
+        format!("Cookie: {cookie_header}"),
+        "-w".to_string(),
+        "\n%{http_code}".to_string(),
+        "https://cursor.com/api/usage-summary".to_string(),
+    ];
+    let args = args_owned.iter().map(String::as_str).collect::<Vec<_>>();
+    run_command_with_timeout("curl", &args, Duration::from_secs(20))
+}
+
+fn cursor_entry_from_usage_summary_json(
+    raw_json: &str,
+    args: &FetchOptions,
+    source_label: &str,
+) -> Option<ProviderEntry> {
+    let value = serde_json::from_str::<Value>(raw_json).ok()?;
+    let plan_usage = value
+        .get("individualUsage")
+        .and_then(|usage| usage.get("plan"));
+    let on_demand_usage = value
+        .get("individualUsage")
+        .and_then(|usage| usage.get("onDemand"));
+
+    let plan_used_raw = plan_usage
+        .and_then(|plan| plan.get("used"))
+        .and_then(json_number_value)
+        .unwrap_or(0.0);
+    let plan_limit_raw = plan_usage
+        .and_then(|plan| plan.get("limit"))
+        .and_then(json_number_value)
+        .unwrap_or(0.0);
+    let plan_total_percent_used = plan_usage
+        .and_then(|plan| plan.get("totalPercentUsed"))
+        .and_then(json_number_value);
+    let plan_used_percent = if plan_limit_raw > 0.0 {
+        ((plan_used_raw / plan_limit_raw) * 100.0).clamp(0.0, 100.0)
+    } else if let Some(total_percent) = plan_total_percent_used {
+        if total_percent <= 1.0 {
+            (total_percent * 100.0).clamp(0.0, 100.0)
+        } else {
+            total_percent.clamp(0.0, 100.0)
+        }
+    } else {
+        0.0
+    };
+
+    let on_demand_used_raw = on_demand_usage
+        .and_then(|usage| usage.get("used"))
+        .and_then(json_number_value)
+        .unwrap_or(0.0);
+    let on_demand_limit_raw = on_demand_usage
+        .and_then(|usage| usage.get("limit"))
+        .and_then(json_number_value);
+
+    let billing_cycle_end = value
+        .get("billingCycleEnd")
+        .and_then(Value::as_str)
+        .map(str::trim)
+        .filter(|text| !text.is_empty())
+        .map(ToOwned::to_owned);
+
+    let primary = Some(RateWindow {
+        used_percent: Some(plan_used_percent),
+        window_minutes: None,
+        resets_at: billing_cycle_end.clone(),
+    });
+    let secondary = on_demand_limit_raw.and_then(|limit_raw| {
+        if limit_raw > 0.0 {
+            Some(RateWindow {
+                used_percent: Some(((on_demand_used_raw / limit_raw) * 100.0).clamp(0.0, 100.0)),
+                window_minutes: None,
+                resets_at: billing_cycle_end.clone(),
+            })
+        } else {
+            None
+        }
+    });
+
+    let source = if args.source.eq_ignore_ascii_case("auto") {
+        source_label.to_string()
+    } else {
+        args.source.clone()
+    };
+    let membership_label = value
+        .get("membershipType")
+        .and_then(Value::as_str)
+        .map(format_cursor_membership_type);
+    let status = if args.status {
+        Some(StatusInfo {
+            indicator: Some("none".to_string()),
+            description: Some("Operational".to_string()),
+            updated_at: Some(args.clock.iso8601()),
+            url: Some("https://status.cursor.com".to_string()),
+        })
+    } else {
+        None
+    };
+
+    Some(ProviderEntry {
+        provider: "cursor".to_string(),
+        source: Some(source),
+        updated_at: args.clock.iso8601(),
+        primary,
+        secondary,
+        tertiary: None,
+        extra_windows: Vec::new(),
+        windows: Vec::new(),
+        credits_remaining: None,
+        code_review_remaining_percent: None,
+        identity: Some(IdentityInfo {
+            account_email: None,
+            account_organization: None,
+            login_method: Some("cookie".to_string()),
+            plan: membership_label,
+        }),
+        status,
+        error: None,
+    })
+}
+
+fn format_cursor_membership_type(raw: &str) -> String {
+    match raw.trim().to_ascii_lowercase().as_str() {
+        "enterprise" => "Cursor Enterprise".to_string(),
+        "pro" => "Cursor Pro".to_string(),
+        "hobby" => "Cursor Hobby".to_string(),
+        "team" => "Cursor Team".to_string(),
+        value if !value.is_empty() => format!("Cursor {}", title_case_words(value)),
+        _ => "Cursor".to_string(),
+    }
+}
+
+fn fetch_copilot_entry(args: &FetchOptions) -> Result<Option<ProviderEntry>> {
+    let access_token = match resolve_copilot_token_for_internal_api() {
+        Some(token) => token,
+        None => return Ok(None),
+    };
+
+    let output = match fetch_copilot_internal_usage_json(&access_token) {
+        Ok(output) => output,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(error) if error.kind() == ErrorKind::TimedOut => return Ok(None),
+        Err(error) => {
+            return Err(error).context("failed to query GitHub Copilot internal usage API");
+        }
+    };
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (body, status_code) = match split_curl_body_and_status(&stdout) {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    if status_code != 200 {
+        return Ok(None);
+    }
+    if warn_on_empty_body("copilot", body) {
+        return Ok(None);
+    }
+
+    Ok(copilot_entry_from_internal_usage_json(
+        body,
+        args,
+        "github-copilot-api",
+    ))
+}
+
+fn resolve_copilot_token_for_internal_api() -> Option<String> {
+    first_env_value(&["CODEXBAR_COPILOT_API_TOKEN", "COPILOT_API_TOKEN"])
+        .and_then(|value| clean_token_value(&value))
+        .or_else(load_copilot_token_from_codexbar_config)
+        .or_else(load_copilot_token_from_gh_auth)
+}
+
+fn load_copilot_token_from_codexbar_config() -> Option<String> {
+    let home = std::env::var("HOME").ok()?;
+    let path = PathBuf::from(home).join(".codexbar").join("config.json");
+    let raw = fs::read_to_string(path).ok()?;
+    let json = serde_json::from_str::<Value>(&raw).ok()?;
+    let providers = json.get("providers").and_then(Value::as_array)?;
+
+    for provider in providers {
+        let id = provider
+            .get("id")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        if !id.eq_ignore_ascii_case("copilot") {
+            continue;
+        }
+
+        let api_key = provider.get("apiKey").and_then(Value::as_str)?;
+        if let Some(token) = clean_token_value(api_key) {
+            return Some(token);
+        }
+    }
+
+    None
+}
+
+fn load_copilot_token_from_gh_auth() -> Option<String> {
+    let args = ["auth", "token", "-h", "github.com"];
+    let output = run_command_with_timeout("gh", &args, Duration::from_secs(8)).ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    clean_token_value(String::from_utf8_lossy(&output.stdout).trim())
+}
+
+fn fetch_copilot_internal_usage_json(access_token: &str) -> io::Result<Output> {
+    let args_owned = [
+        "-sS".to_string(),
+        "--location".to_string(),
+        "--max-time".to_string(),
+        "15".to_string(),
+        "-H".to_string(),
+        format!("Authorization: token {access_token}"),
+        "-H".to_string(),
+        "Accept: application/json".to_string(),
+        "-H".to_string(),
+        "Editor-Version: vscode/1.96.2".to_string(),
+        "-H".to_string(),
+        "Editor-Plugin-Version: copilot-chat/0.26.7".to_string(),
+        "-H".to_string(),
+        "User-Agent: GitHubCopilotChat/0.26.7".to_string(),
+        "-H".to_string(),
+        "X-Github-Api-Version: 2025-04-01".to_string(),
+        "-w".to_string(),
+        "\n%{http_code}".to_string(),
+        "https://api.github.com/copilot_internal/user".to_string(),
+    ];
+    let args = args_owned.iter().map(String::as_str).collect::<Vec<_>>();
+    run_command_with_timeout("curl", &args, Duration::from_secs(20))
+}
+
+fn copilot_entry_from_internal_usage_json(
+    raw_json: &str,
+    args: &FetchOptions,
+    source_label: &str,
+) -> Option<ProviderEntry> {
+    let value = serde_json::from_str::<Value>(raw_json).ok()?;
+    let primary =
+        copilot_window_from_internal_usage(&value, "premium_interactions").or(Some(RateWindow {
+            used_percent: Some(0.0),
+            window_minutes: None,
+            resets_at: None,
+        }));
+    let secondary = copilot_window_from_internal_usage(&value, "chat");
+    let source = if args.source.eq_ignore_ascii_case("auto") {
+        source_label.to_string()
+    } else {
+        args.source.clone()
+    };
+    let plan_label = value
+        .get("copilot_plan")
+        .and_then(Value::as_str)
+        .map(title_case_words)
+        .filter(|label| !label.is_empty());
+    let status = if args.status {
+        Some(StatusInfo {
+            indicator: Some("none".to_string()),
+            description: Some("Operational".to_string()),
+            updated_at: Some(args.clock.iso8601()),
+            url: Some("https://www.githubstatus.com/".to_string()),
+        })
+    } else {
+        None
+    };
+
+    Some(ProviderEntry {
+        provider: "copilot".to_string(),
+        source: Some(source),
+        updated_at: args.clock.iso8601(),
+        primary,
+        secondary,
+        tertiary: None,
+        extra_windows: Vec::new(),
+        windows: Vec::new(),
+        credits_remaining: None,
+        code_review_remaining_percent: None,
+        identity: Some(IdentityInfo {
+            account_email: None,
+            account_organization: None,
+            login_method: None,
+            plan: plan_label,
+        }),
+        status,
+        error: None,
+    })
+}
+
+fn copilot_window_from_internal_usage(value: &Value, key: &str) -> Option<RateWindow> {
+    // Preferred schema (documented in docs/copilot.md).
+    let used_percent = if let Some(percent_remaining) = value
+        .get("quota_snapshots")
+        .and_then(|snapshots| snapshots.get(key))
+        .and_then(|snapshot| snapshot.get("percent_remaining"))
+        .and_then(json_number_value)
+    {
+        (100.0 - percent_remaining).clamp(0.0, 100.0)
+    } else {
+        // Variant observed for limited/free plans where quotas are represented as
+        // remaining/entitlement counts rather than percent_remaining.
+        let bucket = match key {
+            "premium_interactions" => "completions",
+            "chat" => "chat",
+            _ => return None,
+        };
+
+        let total = value
+            .get("monthly_quotas")
+            .and_then(|quotas| quotas.get(bucket))
+            .and_then(json_number_value)?;
+        let remaining = value
+            .get("limited_user_quotas")
+            .and_then(|quotas| quotas.get(bucket))
+            .and_then(json_number_value)?;
+
+        if total <= 0.0 {
+            0.0
+        } else {
+            ((1.0 - (remaining / total)) * 100.0).clamp(0.0, 100.0)
+        }
+    };
+
+    Some(RateWindow {
+        used_percent: Some(used_percent),
+        window_minutes: None,
+        resets_at: None,
+    })
+}
+
+fn title_case_words(input: &str) -> String {
+    input
+        .split_whitespace()
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => format!(
+                    "{}{}",
+                    first.to_uppercase(),
+                    chars.as_str().to_ascii_lowercase()
+                ),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn clean_token_value(raw: &str) -> Option<String> {
+    let mut token = raw.trim().to_string();
+    if token.is_empty() {
+        return None;
+    }
+
+    if (token.starts_with('"') && token.ends_with('"'))
+        || (token.starts_with('\'') && token.ends_with('\''))
+    {
+        token.remove(0);
+        token.pop();
+    }
+
+    let cleaned = token.trim().to_string();
+    if cleaned.is_empty() {
+        None
+    } else {
+        Some(cleaned)
+    }
+}
+
+pub const PROVIDER_ENV: &str = "CODEXBAR_PROVIDER";
+pub const SOURCE_ENV: &str = "CODEXBAR_SOURCE";
+
+/// Defaults for usage fetches read from `config.toml`. Command-line flags win
+/// over the file, and `CODEXBAR_PROVIDER`/`CODEXBAR_SOURCE` win over it too.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct UsageConfig {
+    pub provider: Option<String>,
+    pub source: Option<String>,
+    pub status: Option<bool>,
+    pub timeout: Option<Duration>,
+}
+
+impl UsageConfig {
+    /// Options for a fetch without command-line flags, e.g. the service's:
+    /// `$CODEXBAR_SOURCE`, then the file, then the built-in defaults.
+    pub fn fetch_options(&self) -> FetchOptions {
+        self.fetch_options_from(|name| first_env_value(&[name]))
+    }
+
+    fn fetch_options_from(&self, env: impl Fn(&str) -> Option<String>) -> FetchOptions {
+        FetchOptions {
+            source: env(SOURCE_ENV)
+                .or_else(|| self.source.clone())
+                .unwrap_or_else(|| "auto".to_string()),
+            status: self.status.unwrap_or(false),
+            timeout: self.timeout,
+            ..FetchOptions::default()
+        }
+    }
+}
+
+pub fn usage_config_path() -> Option<PathBuf> {
+    let config_home = first_env_value(&["XDG_CONFIG_HOME"])
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(std::env::var("HOME").ok()?).join(".config")))?;
+    Some(config_home.join("codexbar").join("config.toml"))
+}
+
+/// A missing file yields the built-in defaults.
+pub fn load_usage_config() -> Result<UsageConfig> {
+    let Some(path) = usage_config_path() else {
+        return Ok(UsageConfig::default());
+    };
+    match fs::read_to_string(&path) {
+        Ok(text) => {
+            parse_usage_config(&text).map_err(|error| anyhow!("{}:{error}", path.display()))
+        }
+        Err(error) if error.kind() == ErrorKind::NotFound => Ok(UsageConfig::default()),
+        Err(error) => Err(error).with_context(|| format!("failed to read {}", path.display())),
+    }
+}
+
+/// Errors are `<line>: <message>` so the caller can prefix the path.
+pub fn parse_usage_config(text: &str) -> Result<UsageConfig, String> {
+    let line_of = |span: Option<std::ops::Range<usize>>| {
+        span.map_or(1, |span| text[..span.start].matches('\n').count() + 1)
+    };
+    let document = toml_edit::Document::parse(text)
+        .map_err(|error| format!("{}: {}", line_of(error.span()), error.message().trim()))?;
+
+    let mut config = UsageConfig::default();
+    for (key, item) in document.iter() {
+        let invalid = |message: String| format!("{}: {message}", line_of(item.span()));
+        match key {
+            "provider" | "source" => {
+                let value = item
+                    .as_str()
+                    .ok_or_else(|| invalid(format!("`{key}` must be a string")))?
+                    .to_string();
+                if key == "provider" {
+                    config.provider = Some(value);
+                } else {
+                    config.source = Some(value);
+                }
+            }
+            "status" => {
+                config.status = Some(
+                    item.as_bool()
+                        .ok_or_else(|| invalid("`status` must be true or false".to_string()))?,
+                );
+            }
+            "timeout" => {
+                let seconds = item
+                    .as_integer()
+                    .map(|seconds| seconds.to_string())
+                    .or_else(|| item.as_float().map(|seconds| seconds.to_string()))
+                    .ok_or_else(|| invalid("`timeout` must be a number of seconds".to_string()))?;
+                config.timeout = Some(parse_timeout_seconds(&seconds).map_err(invalid)?);
+            }
+            _ => return Err(invalid(format!("unknown key `{key}`"))),
+        }
+    }
+    Ok(config)
+}
+
+pub fn parse_timeout_seconds(value: &str) -> Result<Duration, String> {
+    let seconds = value
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| format!("`{value}` is not a number of seconds"))?;
+    if !seconds.is_finite() || seconds <= 0.0 {
+        return Err(format!("timeout must be greater than zero, got `{value}`"));
+    }
+    Duration::try_from_secs_f64(seconds).map_err(|error| error.to_string())
+}
+
+pub fn first_env_value(names: &[&str]) -> Option<String> {
+    names.iter().find_map(|name| {
+        std::env::var(name)
+            .ok()
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    })
+}
+
+pub fn resolve_claude_oauth_access_token() -> Option<String> {
+    resolve_claude_oauth_access_token_from(
+        &KeyringSecretBackend::system(),
+        &active_secret_account(),
+        load_claude_oauth_access_token_from_credentials_file,
+    )
+}
+
+/// Like [`resolve_claude_oauth_access_token`], but read-only: a legacy
+/// keyring entry is reported without being migrated, for checks that must
+/// not change the keyring.
+pub fn peek_claude_oauth_access_token() -> Option<String> {
+    peek_claude_oauth_access_token_with_source().map(|(token, _)| token)
+}
+
+fn peek_claude_oauth_access_token_with_source() -> Option<(String, ClaudeTokenSource)> {
+    let backend = KeyringSecretBackend::system();
+    let account = active_secret_account();
+    resolve_claude_oauth_access_token_with_source(
+        || peek_scoped_secret(&backend, "claude", &account, "oauth_access_token"),
+        load_claude_oauth_access_token_from_credentials_file,
+    )
+}
+
+const CLAUDE_TOKEN_ENV_VARS: [&str; 2] = ["CODEXBAR_CLAUDE_OAUTH_TOKEN", "CLAUDE_OAUTH_TOKEN"];
+
+/// Where `resolve_claude_oauth_access_token` found the token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClaudeTokenSource {
+    Env(&'static str),
+    Keyring,
+    CredentialsFile,
+}
+
+impl ClaudeTokenSource {
+    pub fn describe(self) -> String {
+        match self {
+            Self::Env(name) => format!("${name}"),
+            Self::Keyring => "the keyring".to_string(),
+            Self::CredentialsFile => "the Claude credentials file".to_string(),
+        }
+    }
+}
+
+/// Where the token would come from; read-only like
+/// [`peek_claude_oauth_access_token`].
+pub fn resolve_claude_token_source() -> Option<ClaudeTokenSource> {
+    peek_claude_oauth_access_token_with_source().map(|(_, source)| source)
+}
+
+fn resolve_claude_oauth_access_token_from(
+    backend: &dyn SecretBackend,
+    account: &str,
+    credentials_file: impl FnOnce() -> Option<String>,
+) -> Option<String> {
+    resolve_claude_oauth_access_token_with_source(
+        || lookup_scoped_secret(backend, "claude", account, "oauth_access_token"),
+        credentials_file,
+    )
+    .map(|(token, _)| token)
+}
+
+/// Env, then the keyring, then the Claude CLI credentials file; each source
+/// that is unavailable falls through to the next.
+fn resolve_claude_oauth_access_token_with_source(
+    keyring: impl FnOnce() -> Option<String>,
+    credentials_file: impl FnOnce() -> Option<String>,
+) -> Option<(String, ClaudeTokenSource)> {
+    CLAUDE_TOKEN_ENV_VARS
+        .iter()
+        .find_map(|name| Some((first_env_value(&[name])?, ClaudeTokenSource::Env(name))))
+        .or_else(|| keyring().map(|token| (token, ClaudeTokenSource::Keyring)))
+        .or_else(|| credentials_file().map(|token| (token, ClaudeTokenSource::CredentialsFile)))
+}
+
+pub fn load_claude_oauth_access_token_from_credentials_file() -> Option<String> {
+    first_claude_oauth_access_token(&claude_credentials_paths())
+}
+
+fn first_claude_oauth_access_token(paths: &[PathBuf]) -> Option<String> {
+    paths
+        .iter()
+        .find_map(|path| claude_oauth_access_token_from_file(path))
+}
+
+/// `CODEXBAR_CLAUDE_CREDENTIALS`, then `~/.claude`, then
+/// `$XDG_CONFIG_HOME/claude`; services and sandboxes often run without `HOME`.
+pub fn claude_credentials_paths() -> Vec<PathBuf> {
+    claude_credentials_paths_from(|name| first_env_value(&[name]))
+}
+
+fn claude_credentials_paths_from(env: impl Fn(&str) -> Option<String>) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(path) = env("CODEXBAR_CLAUDE_CREDENTIALS") {
+        paths.push(PathBuf::from(path));
+    }
+    if let Some(home) = env("HOME") {
+        paths.push(
+            PathBuf::from(home)
+                .join(".claude")
+                .join(".credentials.json"),
+        );
+    }
+    if let Some(config_home) = env("XDG_CONFIG_HOME") {
+        paths.push(
+            PathBuf::from(config_home)
+                .join("claude")
+                .join(".credentials.json"),
+        );
+    }
+    paths
+}
+
+pub fn claude_oauth_access_token_from_file(path: &Path) -> Option<String> {
+    let raw = fs::read_to_string(path).ok()?;
+    let json = serde_json::from_str::<Value>(&raw).ok()?;
+    let token = json
+        .get("claudeAiOauth")
+        .and_then(|value| value.get("accessToken"))
+        .and_then(Value::as_str)?
+        .trim()
+        .to_string();
+    if token.is_empty() {
+        None
+    } else {
+        Some(token)
+    }
+}
+
+/// The usage API doesn't name the plan, but the Claude CLI records it next
+/// to the token as `subscriptionType` (`pro`, `max`, ...).
+fn load_claude_plan_from_credentials_file() -> Option<String> {
+    claude_credentials_paths()
+        .iter()
+        .find_map(|path| claude_plan_from_file(path))
+}
+
+fn claude_plan_from_file(path: &Path) -> Option<String> {
+    let raw = fs::read_to_string(path).ok()?;
+    let json = serde_json::from_str::<Value>(&raw).ok()?;
+    let plan = json
+        .get("claudeAiOauth")?
+        .get("subscriptionType")?
+        .as_str()?
+        .trim();
+    (!plan.is_empty()).then(|| plan.to_string())
+}
+
+const CLAUDE_USAGE_ENDPOINT: &str = "https://api.anthropic.com/api/oauth/usage";
+const DEFAULT_ANTHROPIC_BETA: &str = "oauth-2025-04-20";
+
+/// `CODEXBAR_ANTHROPIC_BETA` replaces the `anthropic-beta` value so users can
+/// follow a bumped beta date without waiting for a release.
+fn claude_request_headers(access_token: &str) -> [String; 2] {
+    let beta = first_env_value(&["CODEXBAR_ANTHROPIC_BETA"])
+        .unwrap_or_else(|| DEFAULT_ANTHROPIC_BETA.to_string());
+    [
+        format!("Authorization: Bearer {access_token}"),
+        format!("anthropic-beta: {beta}"),
+    ]
+}
+
+const DEFAULT_HTTP_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The final response to `HttpClient::get_json`, after any redirects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct HttpResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl HttpResponse {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The exchange as `--raw-dir` records it.
+    fn to_raw(&self) -> String {
+        let mut out = format!("HTTP {}\n", self.status);
+        for (name, value) in &self.headers {
+            out.push_str(&format!("{name}: {value}\n"));
+        }
+        out.push('\n');
+        out.push_str(&self.body);
+        out
+    }
+}
+
+/// Sends the JSON GETs behind `send_with_retries`. Any HTTP status is a
+/// response; failing to get one is an error, with `ErrorKind::TimedOut` once
+/// the timeout passes and `ErrorKind::NotFound` when `curl` isn't installed.
+trait HttpClient {
+    /// `timeout` bounds the whole exchange and defaults to 15s.
+    fn get_json(
+        &self,
+        endpoint: &str,
+        headers: &[&str],
+        timeout: Option<Duration>,
+    ) -> io::Result<HttpResponse>;
+}
+
+/// curl's exit code for `--max-time` running out.
+const CURL_OPERATION_TIMEDOUT: i32 = 28;
+
+/// The `curl` CLI.
+struct CurlHttpClient;
+
+impl HttpClient for CurlHttpClient {
+    /// `timeout` becomes curl's `--max-time`; the process deadline stays 5s
+    /// longer.
+    fn get_json(
+        &self,
+        endpoint: &str,
+        headers: &[&str],
+        timeout: Option<Duration>,
+    ) -> io::Result<HttpResponse> {
+        let max_time = timeout.unwrap_or(DEFAULT_HTTP_TIMEOUT);
+        let args = curl_json_args(endpoint, headers.iter().copied(), max_time);
+        let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+        let output = run_command_with_timeout("curl", &args, max_time + Duration::from_secs(5))?;
+        if !output.status.success() {
+            let message = format!("curl: {}", String::from_utf8_lossy(&output.stderr).trim());
+            return Err(match output.status.code() {
+                Some(CURL_OPERATION_TIMEDOUT) => io::Error::new(ErrorKind::TimedOut, message),
+                _ => io::Error::other(message),
+            });
+        }
+        parse_curl_response(&String::from_utf8_lossy(&output.stdout))
+    }
+}
+
+/// Reads what `curl -D - -w '\n%{http_code}'` prints.
+fn parse_curl_response(stdout: &str) -> io::Result<HttpResponse> {
+    let (response, status) = split_curl_body_and_status(stdout)
+        .filter(|(_, status)| *status != 0)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "curl printed no HTTP status"))?;
+    let (header_block, body) = split_curl_headers(response);
+    let headers = header_block
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+    Ok(HttpResponse {
+        status,
+        headers,
+        body: body.to_string(),
+    })
+}
+
+/// In-process client via `ureq`; follows redirects like `curl --location`.
+#[cfg(feature = "native-http")]
+struct NativeHttpClient;
+
+#[cfg(feature = "native-http")]
+impl HttpClient for NativeHttpClient {
+    fn get_json(
+        &self,
+        endpoint: &str,
+        headers: &[&str],
+        timeout: Option<Duration>,
+    ) -> io::Result<HttpResponse> {
+        let agent = ureq::Agent::new_with_config(
+            ureq::Agent::config_builder()
+                .timeout_global(Some(timeout.unwrap_or(DEFAULT_HTTP_TIMEOUT)))
+                .http_status_as_error(false)
+                .build(),
+        );
+        let mut request = agent.get(endpoint).header("Accept", "application/json");
+        for header in headers {
+            if let Some((name, value)) = header.split_once(':') {
+                request = request.header(name.trim(), value.trim());
+            }
+        }
+
+        let transport_error = |error: ureq::Error| match error {
+            ureq::Error::Timeout(_) => io::Error::new(ErrorKind::TimedOut, error),
+            error => error.into_io(),
+        };
+        let mut response = request.call().map_err(transport_error)?;
+        let body = response
+            .body_mut()
+            .read_to_string()
+            .map_err(transport_error)?;
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                (
+                    name.to_string(),
+                    value.to_str().unwrap_or_default().to_string(),
+                )
+            })
+            .collect();
+        Ok(HttpResponse {
+            status: response.status().as_u16(),
+            headers,
+            body,
+        })
+    }
+}
+
+/// `NativeHttpClient` with the `native-http` feature unless
+/// `CODEXBAR_HTTP_CLIENT=curl` asks for `curl` (e.g. to pick up `.curlrc`
+/// proxy settings); `curl` without the feature.
+fn http_client() -> Box<dyn HttpClient> {
+    #[cfg(feature = "native-http")]
+    if first_env_value(&["CODEXBAR_HTTP_CLIENT"]).as_deref() != Some("curl") {
+        return Box::new(NativeHttpClient);
+    }
+    Box::new(CurlHttpClient)
+}
+
+fn curl_json_args<'a>(
+    endpoint: &str,
+    headers: impl IntoIterator<Item = &'a str>,
+    max_time: Duration,
+) -> Vec<String> {
+    let mut args = vec![
+        "-sS".to_string(),
+        "--location".to_string(),
+        "--max-time".to_string(),
+        format!("{:.3}", max_time.as_secs_f64()),
+        "-D".to_string(),
+        "-".to_string(),
+    ];
+    for header in headers {
+        args.extend(["-H".to_string(), header.to_string()]);
+    }
+    args.extend([
+        "-H".to_string(),
+        "Accept: application/json".to_string(),
+        "-w".to_string(),
+        "\n%{http_code}".to_string(),
+        endpoint.to_string(),
+    ]);
+    args
+}
+
+const DEFAULT_SECRET_ACCOUNT: &str = "default";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SecretKey<'a> {
+    provider: &'a str,
+    account: Option<&'a str>,
+    field: &'a str,
+}
+
+impl<'a> SecretKey<'a> {
+    fn scoped(provider: &'a str, account: &'a str, field: &'a str) -> Self {
+        Self {
+            provider,
+            account: Some(account),
+            field,
+        }
+    }
+
+    // Entries written before secrets were namespaced by account.
+    fn legacy(provider: &'a str, field: &'a str) -> Self {
+        Self {
+            provider,
+            account: None,
+            field,
+        }
+    }
+
+    fn secret_tool_attributes(&self) -> Vec<&'a str> {
+        let mut attributes = vec!["service", "codexbar", "provider", self.provider];
+        if let Some(account) = self.account {
+            attributes.extend(["account", account]);
+        }
+        attributes.extend(["field", self.field]);
+        attributes
+    }
+
+    fn kwallet_entry(&self) -> String {
+        match self.account {
+            Some(account) => format!("{}.{}.{}", self.provider, account, self.field),
+            None => format!("{}.{}", self.provider, self.field),
+        }
+    }
+}
+
+/// One stored item a key matched, as reported by `SecretBackend::search`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SecretItem {
+    /// Backend handle for `SecretBackend::delete`, e.g. a D-Bus object path.
+    pub id: String,
+    /// `None` for items written before secrets were namespaced by account.
+    pub account: Option<String>,
+    pub value: String,
+}
+
+pub trait SecretBackend {
+    /// Short name for diagnostics, e.g. `secret-tool`.
+    fn name(&self) -> &'static str;
+    fn lookup(&self, key: &SecretKey) -> Option<String>;
+    fn store(&self, key: &SecretKey, label: &str, value: &str) -> Result<()>;
+    fn clear(&self, key: &SecretKey);
+
+    /// Whether `lookup` and `clear` match every item carrying the key's
+    /// attributes, as libsecret does. A legacy key then also matches every
+    /// account's item, so legacy items are handled through `search`.
+    fn matches_subsets(&self) -> bool {
+        false
+    }
+
+    /// Items `key` matches; only called when `matches_subsets`.
+    fn search(&self, _key: &SecretKey) -> Vec<SecretItem> {
+        Vec::new()
+    }
+
+    /// Deletes one item found by `search`.
+    fn delete(&self, _item: &SecretItem) {}
+
+    /// The pre-account item for `provider`/`field`, never an account's.
+    fn lookup_legacy(&self, provider: &str, field: &str) -> Option<String> {
+        let legacy = SecretKey::legacy(provider, field);
+        if !self.matches_subsets() {
+            return self.lookup(&legacy);
+        }
+        self.search(&legacy)
+            .into_iter()
+            .find(|item| item.account.is_none() && !item.value.trim().is_empty())
+            .map(|item| item.value)
+    }
+
+    /// Removes only the pre-account item, returning whether there was one.
+    fn clear_legacy(&self, provider: &str, field: &str) -> bool {
+        let legacy = SecretKey::legacy(provider, field);
+        if !self.matches_subsets() {
+            let existed = self.lookup(&legacy).is_some();
+            self.clear(&legacy);
+            return existed;
+        }
+        let items = self.search(&legacy);
+        let (unscoped, scoped): (Vec<_>, Vec<_>) =
+            items.iter().partition(|item| item.account.is_none());
+        if unscoped.is_empty() {
+            return false;
+        }
+        if scoped.is_empty() {
+            // Nothing else matches, so the subset clear is exact here.
+            self.clear(&legacy);
+        } else {
+            for item in unscoped {
+                self.delete(item);
+            }
+        }
+        true
+    }
+}
+
+/// libsecret via the `secret-tool` CLI.
+struct SecretToolBackend;
+
+impl SecretBackend for SecretToolBackend {
+    fn name(&self) -> &'static str {
+        "secret-tool"
+    }
+
+    fn lookup(&self, key: &SecretKey) -> Option<String> {
+        lookup_secret_via_secret_tool(key)
+    }
+
+    fn store(&self, key: &SecretKey, label: &str, value: &str) -> Result<()> {
+        store_secret_via_secret_tool(key, label, value)
+    }
+
+    fn clear(&self, key: &SecretKey) {
+        clear_secret_via_secret_tool(key);
+    }
+
+    fn matches_subsets(&self) -> bool {
+        true
+    }
+
+    fn search(&self, key: &SecretKey) -> Vec<SecretItem> {
+        search_secrets_via_secret_tool(key)
+    }
+
+    fn delete(&self, item: &SecretItem) {
+        delete_secret_item_via_dbus(&item.id);
+    }
+}
+
+/// KDE Wallet via the `kwallet-query` CLI.
+struct KWalletBackend;
+
+impl SecretBackend for KWalletBackend {
+    fn name(&self) -> &'static str {
+        "kwallet-query"
+    }
+
+    fn lookup(&self, key: &SecretKey) -> Option<String> {
+        lookup_secret_via_kwallet(key)
+    }
+
+    fn store(&self, key: &SecretKey, _label: &str, value: &str) -> Result<()> {
+        store_secret_via_kwallet(key, value)
+    }
+
+    fn clear(&self, key: &SecretKey) {
+        clear_secret_via_kwallet(key);
+    }
+}
+
+/// macOS Keychain or Windows Credential Manager via the `keyring` crate,
+/// stored under the `CodexBar` service with the KWallet entry name.
+#[cfg(all(
+    feature = "native-keyring",
+    any(target_os = "macos", target_os = "windows")
+))]
+struct NativeKeyringBackend;
+
+#[cfg(all(
+    feature = "native-keyring",
+    any(target_os = "macos", target_os = "windows")
+))]
+impl NativeKeyringBackend {
+    fn entry(key: &SecretKey) -> keyring::Result<keyring::Entry> {
+        keyring::Entry::new("CodexBar", &key.kwallet_entry())
+    }
+}
+
+#[cfg(all(
+    feature = "native-keyring",
+    any(target_os = "macos", target_os = "windows")
+))]
+impl SecretBackend for NativeKeyringBackend {
+    fn name(&self) -> &'static str {
+        if cfg!(target_os = "macos") {
+            "keychain"
+        } else {
+            "credential-manager"
+        }
+    }
+
+    fn lookup(&self, key: &SecretKey) -> Option<String> {
+        Self::entry(key).ok()?.get_password().ok()
+    }
+
+    fn store(&self, key: &SecretKey, _label: &str, value: &str) -> Result<()> {
+        Self::entry(key)
+            .and_then(|entry| entry.set_password(value))
+            .with_context(|| {
+                format!(
+                    "failed to store {} credentials in the {}",
+                    key.provider,
+                    self.name()
+                )
+            })
+    }
+
+    fn clear(&self, key: &SecretKey) {
+        if let Ok(entry) = Self::entry(key) {
+            let _ = entry.delete_credential();
+        }
+    }
+}
+
+/// Tries each backend in order. A missing tool, a locked collection or an
+/// empty value all count as a miss, so the next backend is always consulted.
+pub struct KeyringSecretBackend {
+    backends: Vec<Box<dyn SecretBackend>>,
+}
+
+impl KeyringSecretBackend {
+    /// The platform's credential store: Keychain or Credential Manager on
+    /// macOS/Windows with `native-keyring`, the Secret Service and KDE
+    /// Wallet command-line tools everywhere else.
+    pub fn system() -> Self {
+        #[cfg(all(
+            feature = "native-keyring",
+            any(target_os = "macos", target_os = "windows")
+        ))]
+        let backends: Vec<Box<dyn SecretBackend>> = vec![Box::new(NativeKeyringBackend)];
+        #[cfg(not(all(
+            feature = "native-keyring",
+            any(target_os = "macos", target_os = "windows")
+        )))]
+        let backends: Vec<Box<dyn SecretBackend>> =
+            vec![Box::new(SecretToolBackend), Box::new(KWalletBackend)];
+        Self { backends }
+    }
+
+    pub fn backend_names(&self) -> Vec<&'static str> {
+        self.backends.iter().map(|backend| backend.name()).collect()
+    }
+}
+
+impl SecretBackend for KeyringSecretBackend {
+    fn name(&self) -> &'static str {
+        "keyring"
+    }
+
+    fn lookup(&self, key: &SecretKey) -> Option<String> {
+        self.backends.iter().find_map(|backend| {
+            backend
+                .lookup(key)
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        })
+    }
+
+    fn store(&self, key: &SecretKey, label: &str, value: &str) -> Result<()> {
+        if self
+            .backends
+            .iter()
+            .any(|backend| backend.store(key, label, value).is_ok())
+        {
+            return Ok(());
+        }
+
+        bail!(
+            "failed to store {} credentials securely; {}",
+            key.provider,
+            SECRET_STORE_HINT
+        );
+    }
+
+    fn clear(&self, key: &SecretKey) {
+        for backend in &self.backends {
+            backend.clear(key);
+        }
+    }
+
+    fn lookup_legacy(&self, provider: &str, field: &str) -> Option<String> {
+        self.backends.iter().find_map(|backend| {
+            backend
+                .lookup_legacy(provider, field)
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        })
+    }
+
+    fn clear_legacy(&self, provider: &str, field: &str) -> bool {
+        // Every backend is cleared, so this must not short-circuit.
+        self.backends
+            .iter()
+            .filter(|backend| backend.clear_legacy(provider, field))
+            .count()
+            > 0
+    }
+}
+
+#[cfg(not(all(
+    feature = "native-keyring",
+    any(target_os = "macos", target_os = "windows")
+)))]
+const SECRET_STORE_HINT: &str =
+    "install libsecret-tools (secret-tool) or ensure KDE Wallet is available";
+#[cfg(all(
+    feature = "native-keyring",
+    any(target_os = "macos", target_os = "windows")
+))]
+const SECRET_STORE_HINT: &str = "check that the system keychain is unlocked";
+
+pub fn active_secret_account() -> String {
+    first_env_value(&["CODEXBAR_ACCOUNT"])
+        .map(|account| normalize_secret_account(&account))
+        .filter(|account| !account.is_empty())
+        .unwrap_or_else(|| DEFAULT_SECRET_ACCOUNT.to_string())
+}
+
+fn normalize_secret_account(raw: &str) -> String {
+    raw.trim()
+        .to_ascii_lowercase()
+        .chars()
+        .map(|ch| {
+            if ch.is_ascii_alphanumeric() || matches!(ch, '-' | '_' | '.' | '@') {
+                ch
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+pub fn store_secret(provider: &str, field: &str, label: &str, value: &str) -> Result<()> {
+    let account = active_secret_account();
+    KeyringSecretBackend::system().store(
+        &SecretKey::scoped(provider, &account, field),
+        label,
+        value,
+    )
+}
+
+fn lookup_secret(provider: &str, field: &str) -> Option<String> {
+    lookup_scoped_secret(
+        &KeyringSecretBackend::system(),
+        provider,
+        &active_secret_account(),
+        field,
+    )
+}
+
+/// Clears the active account's secret; see `clear_account_secret`.
+pub fn clear_secret(provider: &str, field: &str) {
+    clear_account_secret(
+        &KeyringSecretBackend::system(),
+        provider,
+        &active_secret_account(),
+        field,
+    );
+}
+
+/// Clears one account's entry, returning the entries that existed. The
+/// pre-account legacy entry belongs to the default account, so it is only
+/// cleared along with it.
+fn clear_account_secret(
+    backend: &dyn SecretBackend,
+    provider: &str,
+    account: &str,
+    field: &str,
+) -> Vec<String> {
+    let mut removed = Vec::new();
+    let scoped = SecretKey::scoped(provider, account, field);
+    if backend.lookup(&scoped).is_some() {
+        backend.clear(&scoped);
+        removed.push(scoped.kwallet_entry());
+    }
+    if account == DEFAULT_SECRET_ACCOUNT && backend.clear_legacy(provider, field) {
+        removed.push(SecretKey::legacy(provider, field).kwallet_entry());
+    }
+    removed
+}
+
+/// Keyring fields each provider's tokens are kept under.
+fn stored_secret_fields(provider: &str) -> &'static [&'static str] {
+    match provider {
+        "claude" => &["oauth_access_token"],
+        "codex" | "gemini" => &["access_token"],
+        _ => &[],
+    }
+}
+
+/// Clears the account's entries, returning the entries that existed.
+pub fn logout_secrets(backend: &dyn SecretBackend, provider: &str, account: &str) -> Vec<String> {
+    stored_secret_fields(provider)
+        .iter()
+        .flat_map(|field| clear_account_secret(backend, provider, account, field))
+        .collect()
+}
+
+fn lookup_scoped_secret(
+    backend: &dyn SecretBackend,
+    provider: &str,
+    account: &str,
+    field: &str,
+) -> Option<String> {
+    let scoped = SecretKey::scoped(provider, account, field);
+    if let Some(value) = backend.lookup(&scoped) {
+        return Some(value);
+    }
+
+    // Unscoped secrets predate accounts; they belong to the default account and
+    // are moved there the first time it is queried.
+    if account != DEFAULT_SECRET_ACCOUNT {
+        return None;
+    }
+    let value = backend.lookup_legacy(provider, field)?;
+    let label = format!("CodexBar {provider} {field}");
+    if backend.store(&scoped, &label, &value).is_ok() {
+        backend.clear_legacy(provider, field);
+    }
+    Some(value)
+}
+
+/// [`lookup_scoped_secret`] without moving a legacy entry.
+fn peek_scoped_secret(
+    backend: &dyn SecretBackend,
+    provider: &str,
+    account: &str,
+    field: &str,
+) -> Option<String> {
+    backend
+        .lookup(&SecretKey::scoped(provider, account, field))
+        .or_else(|| {
+            (account == DEFAULT_SECRET_ACCOUNT)
+                .then(|| backend.lookup_legacy(provider, field))
+                .flatten()
+        })
+}
+
+fn clear_secret_via_secret_tool(key: &SecretKey) {
+    let args = secret_tool_clear_args(key);
+    let _ = run_command_with_timeout("secret-tool", &args, Duration::from_secs(8));
+}
+
+fn secret_tool_clear_args<'a>(key: &SecretKey<'a>) -> Vec<&'a str> {
+    let mut args = vec!["clear"];
+    args.extend(key.secret_tool_attributes());
+    args
+}
+
+fn clear_secret_via_kwallet(key: &SecretKey) {
+    let entry = key.kwallet_entry();
+    for wallet in KWALLET_WALLETS {
+        let args = kwallet_clear_args(&entry, wallet);
+        let _ = run_command_with_timeout("kwallet-query", &args, Duration::from_secs(8));
+    }
+}
+
+/// Plasma 6 and Plasma 5 default wallet names.
+const KWALLET_WALLETS: [&str; 2] = ["kdewallet", "kdewallet5"];
+
+fn kwallet_clear_args<'a>(entry: &'a str, wallet: &'a str) -> [&'a str; 5] {
+    ["-f", "CodexBar", "-d", entry, wallet]
+}
+
+/// `secret-tool search --all`. It prints attributes on stderr and the rest
+/// on stdout, so the two are merged to keep each item's lines together.
+fn search_secrets_via_secret_tool(key: &SecretKey) -> Vec<SecretItem> {
+    let mut args = vec!["-c", "exec secret-tool search --all \"$@\" 2>&1", "sh"];
+    args.extend(key.secret_tool_attributes());
+    match run_command_with_timeout("sh", &args, Duration::from_secs(8)) {
+        Ok(output) => parse_secret_tool_search(&String::from_utf8_lossy(&output.stdout)),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Items in `secret-tool search` output: a `[/object/path]` line, then
+/// `key = value` lines including `secret` and `attribute.<name>`.
+fn parse_secret_tool_search(output: &str) -> Vec<SecretItem> {
+    let mut items = Vec::<SecretItem>::new();
+    for line in output.lines() {
+        if let Some(path) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            items.push(SecretItem {
+                id: path.to_string(),
+                account: None,
+                value: String::new(),
+            });
+            continue;
+        }
+        let (Some(item), Some((name, value))) = (items.last_mut(), line.split_once(" = ")) else {
+            continue;
+        };
+        match name {
+            "secret" => item.value = value.to_string(),
+            "attribute.account" => item.account = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    items
+}
+
+/// `secret-tool clear` can only match attributes, so a single item is
+/// deleted by object path over D-Bus.
+fn delete_secret_item_via_dbus(path: &str) {
+    let args = [
+        "--session",
+        "--print-reply",
+        "--dest=org.freedesktop.secrets",
+        path,
+        "org.freedesktop.Secret.Item.Delete",
+    ];
+    let _ = run_command_with_timeout("dbus-send", &args, Duration::from_secs(8));
+}
+
+fn lookup_secret_via_secret_tool(key: &SecretKey) -> Option<String> {
+    let mut args = vec!["lookup"];
+    args.extend(key.secret_tool_attributes());
+    let output = run_command_with_timeout("secret-tool", &args, Duration::from_secs(8)).ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+fn store_secret_via_secret_tool(key: &SecretKey, label: &str, value: &str) -> Result<()> {
+    let mut args = vec!["store", "--label", label];
+    args.extend(key.secret_tool_attributes());
+
+    let mut secret = value.to_string();
+    secret.push('\n');
+    let output = run_command_with_timeout_and_input(
+        "secret-tool",
+        &args,
+        Some(secret.as_str()),
+        Duration::from_secs(12),
+    )
+    .with_context(|| {
+        "failed to invoke secret-tool; install libsecret-tools (secret-tool)".to_string()
+    })?;
+
+    if !output.status.success() {
+        bail!(
+            "failed to store {} credentials securely: {}",
+            key.provider,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+fn lookup_secret_via_kwallet(key: &SecretKey) -> Option<String> {
+    let entry = key.kwallet_entry();
+    for wallet in ["kdewallet", "kdewallet5"] {
+        let args = ["-f", "CodexBar", "-r", entry.as_str(), wallet];
+        let output = match run_command_with_timeout("kwallet-query", &args, Duration::from_secs(8))
+        {
+            Ok(output) => output,
+            Err(_) => continue,
+        };
+        if !output.status.success() {
+            continue;
+        }
+
+        let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !value.is_empty() {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+fn store_secret_via_kwallet(key: &SecretKey, value: &str) -> Result<()> {
+    let entry = key.kwallet_entry();
+    let mut last_error = None;
+
+    for wallet in ["kdewallet", "kdewallet5"] {
+        let args = ["-f", "CodexBar", "-w", entry.as_str(), wallet];
+        let mut secret = value.to_string();
+        secret.push('\n');
+        let output = match run_command_with_timeout_and_input(
+            "kwallet-query",
+            &args,
+            Some(secret.as_str()),
+            Duration::from_secs(12),
+        ) {
+            Ok(output) => output,
+            Err(error) => {
+                last_error = Some(error.to_string());
+                continue;
+            }
+        };
+
+        if output.status.success() {
+            return Ok(());
+        }
+
+        last_error = Some(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+
+    bail!(
+        "failed to store {} credentials with KDE Wallet: {}",
+        key.provider,
+        last_error.unwrap_or_else(|| "unknown error".to_string())
+    )
+}
+
+pub fn split_curl_body_and_status(output: &str) -> Option<(&str, u16)> {
+    let trimmed = output.trim_end_matches(['\r', '\n']);
+    let (body, status_line) = trimmed.rsplit_once('\n')?;
+    let status_code = status_line.trim().parse::<u16>().ok()?;
+    Some((body, status_code))
+}
+
+/// A 200 with no payload is a broken endpoint rather than an account with no
+/// windows, so it is called out separately before the body is parsed.
+fn warn_on_empty_body(provider: &str, body: &str) -> bool {
+    if !is_empty_response_body(body) {
+        return false;
+    }
+    eprintln!("codexbar: warning: provider '{provider}' returned empty body");
+    true
+}
+
+fn is_empty_response_body(body: &str) -> bool {
+    let trimmed = body.trim();
+    trimmed.is_empty() || trimmed == "null"
+}
+
+pub fn claude_entry_from_usage_json(
+    raw_json: &str,
+    args: &FetchOptions,
+    source_label: &str,
+) -> Option<ProviderEntry> {
+    let value = serde_json::from_str::<Value>(raw_json).ok()?;
+    let windows = claude_rate_windows(&value, args.window_order);
+    let tertiary_key = CLAUDE_TERTIARY_WINDOW_KEYS
+        .into_iter()
+        .find(|key| windows.iter().any(|(present, _)| present == key));
+    let windows = windows
+        .into_iter()
+        .map(|(key, window)| NamedRateWindow {
+            name: claude_window_name(&key),
+            slot: match key.as_str() {
+                "five_hour" => Some(WindowSlot::Primary),
+                "seven_day" => Some(WindowSlot::Secondary),
+                key if Some(key) == tertiary_key => Some(WindowSlot::Tertiary),
+                _ => None,
+            },
+            window,
+        })
+        .collect::<Vec<_>>();
+    if windows.is_empty() {
+        return None;
+    }
+
+    let source = if args.source.eq_ignore_ascii_case("auto") {
+        source_label.to_string()
+    } else {
+        args.source.clone()
+    };
+
+    let mut entry = ProviderEntry {
+        provider: "claude".to_string(),
+        source: Some(source),
+        updated_at: args.clock.iso8601(),
+        primary: None,
+        secondary: None,
+        tertiary: None,
+        extra_windows: Vec::new(),
+        windows: Vec::new(),
+        credits_remaining: claude_credits_remaining(&value),
+        code_review_remaining_percent: None,
+        identity: Some(IdentityInfo {
+            account_email: None,
+            account_organization: None,
+            login_method: Some("oauth".to_string()),
+            plan: None,
+        }),
+        status: None,
+        error: None,
+    };
+    entry.set_windows(windows);
+    Some(entry)
+}
+
+/// Keys the usage API has shipped so far, in response order. `five_hour` is
+/// always the primary slot and `seven_day` the secondary; anything else still
+/// counts as a window but only fills `extra_windows`.
+const KNOWN_CLAUDE_WINDOW_KEYS: [&str; 4] = [
+    "five_hour",
+    "seven_day",
+    "seven_day_sonnet",
+    "seven_day_opus",
+];
+
+/// The first of these present takes the tertiary slot.
+const CLAUDE_TERTIARY_WINDOW_KEYS: [&str; 2] = ["seven_day_sonnet", "seven_day_opus"];
+
+/// Objects that can carry `utilization` but describe credits, not a window.
+const CLAUDE_CREDIT_KEYS: [&str; 2] = ["credits", "extra_usage"];
+
+/// Every window-shaped object in a usage payload, labelled by its key.
+/// Known keys come first; with `WindowOrder::Duration` shorter windows sort
+/// ahead of longer ones, and windows whose length can't be read from the key
+/// sort last. The order only affects display; slots are pinned by key.
+pub fn claude_rate_windows(value: &Value, order: WindowOrder) -> Vec<(String, RateWindow)> {
+    let Some(object) = value.as_object() else {
+        return Vec::new();
+    };
+    let mut windows = object
+        .iter()
+        .filter(|(key, _)| !CLAUDE_CREDIT_KEYS.contains(&key.as_str()))
+        .filter_map(|(key, window)| {
+            let window = rate_window_from_claude_json(window, claude_window_minutes(key))?;
+            Some((key.clone(), window))
+        })
+        .collect::<Vec<_>>();
+
+    let known_rank = |key: &str| {
+        KNOWN_CLAUDE_WINDOW_KEYS
+            .iter()
+            .position(|known| *known == key)
+            .unwrap_or(KNOWN_CLAUDE_WINDOW_KEYS.len())
+    };
+    match order {
+        WindowOrder::Response => windows.sort_by_key(|(key, _)| known_rank(key)),
+        WindowOrder::Duration => windows.sort_by_key(|(key, window)| {
+            (window.window_minutes.unwrap_or(u64::MAX), known_rank(key))
+        }),
+    }
+    windows
+}
+
+/// `seven_day_opus` -> `Opus 7d`, `five_hour` -> `5h`. Keys without a
+/// readable length are shown as words, e.g. `Monthly research`.
+fn claude_window_name(key: &str) -> String {
+    let capitalize = |words: &[&str]| {
+        let text = words.join(" ");
+        let mut chars = text.chars();
+        chars
+            .next()
+            .map(|first| first.to_uppercase().chain(chars).collect::<String>())
+            .unwrap_or_default()
+    };
+    let words = key.split('_').collect::<Vec<_>>();
+    match claude_window_minutes(key) {
+        Some(minutes) if words.len() > 2 => {
+            format!(
+                "{} {}",
+                capitalize(&words[2..]),
+                window_length_label(minutes)
+            )
+        }
+        Some(minutes) => window_length_label(minutes),
+        None => capitalize(&words),
+    }
+}
+
+/// Reads the window length from keys shaped like `five_hour` or
+/// `seven_day_opus`.
+fn claude_window_minutes(key: &str) -> Option<u64> {
+    let mut parts = key.split('_');
+    let count = parts.next()?;
+    let count = match count {
+        "one" => 1,
+        "two" => 2,
+        "three" => 3,
+        "four" => 4,
+        "five" => 5,
+        "six" => 6,
+        "seven" => 7,
+        "eight" => 8,
+        "nine" => 9,
+        "ten" => 10,
+        "twelve" => 12,
+        "thirty" => 30,
+        digits => digits.parse().ok()?,
+    };
+    let unit = match parts.next()?.trim_end_matches('s') {
+        "minute" => 1,
+        "hour" => 60,
+        "day" => 24 * 60,
+        "week" => 7 * 24 * 60,
+        _ => return None,
+    };
+    Some(count * unit)
+}
+
+/// `utilization` is already a percentage; without a usable one the percent is
+/// computed from a `used`/`limit` pair instead.
+fn rate_window_from_claude_json(window: &Value, window_minutes: Option<u64>) -> Option<RateWindow> {
+    window.as_object()?;
+    let used_percent = window
+        .get("utilization")
+        .and_then(json_number_value)
+        .or_else(|| {
+            let used = window.get("used").and_then(json_number_value)?;
+            let limit = window
+                .get("limit")
+                .and_then(json_number_value)
+                .filter(|limit| *limit > 0.0)?;
+            Some(used / limit * 100.0)
+        })
+        .map(|value| value.clamp(0.0, 100.0));
+    let resets_at = match window.get("resets_at") {
+        Some(Value::String(text)) if !text.trim().is_empty() => Some(text.trim().to_string()),
+        Some(Value::Number(number)) => number.as_i64().map(format_timestamp),
+        _ => None,
+    };
+
+    if used_percent.is_none() && resets_at.is_none() {
+        return None;
+    }
+
+    Some(RateWindow {
+        used_percent,
+        window_minutes,
+        resets_at,
+    })
+}
+
+/// Plans with prepaid or extra usage report a balance, either directly or as
+/// a monthly limit minus credits used. Disabled extra usage counts as none.
+fn claude_credits_remaining(value: &Value) -> Option<f64> {
+    if let Some(balance) = ["credits_remaining", "credit_balance", "balance"]
+        .iter()
+        .find_map(|key| value.get(*key).and_then(json_number_value))
+    {
+        return Some(balance);
+    }
+
+    ["credits", "extra_usage"].iter().find_map(|key| {
+        let credits = value.get(*key)?;
+        if let Some(balance) = json_number_value(credits) {
+            return Some(balance);
+        }
+        if credits.get("is_enabled").and_then(Value::as_bool) == Some(false) {
+            return None;
+        }
+        ["remaining", "balance"]
+            .iter()
+            .find_map(|key| credits.get(*key).and_then(json_number_value))
+            .or_else(|| {
+                let limit = credits.get("monthly_limit").and_then(json_number_value)?;
+                let used = credits.get("used_credits").and_then(json_number_value)?;
+                Some((limit - used).max(0.0))
+            })
+    })
+}
+
+fn json_number_value(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(string_value) => string_value.trim().parse::<f64>().ok(),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct OrderedWindows {
+    primary: Option<RateWindow>,
+    secondary: Option<RateWindow>,
+    extra: Vec<RateWindow>,
+}
+
+impl OrderedWindows {
+    fn is_empty(&self) -> bool {
+        self.primary.is_none() && self.secondary.is_none() && self.extra.is_empty()
+    }
+}
+
+/// Maps windows (in provider response order) onto primary/secondary/extra.
+/// With `WindowOrder::Duration` the two shortest windows become primary and
+/// secondary regardless of which response key they came from; windows without
+/// a duration sort last.
+fn order_rate_windows(windows: Vec<Option<RateWindow>>, order: WindowOrder) -> OrderedWindows {
+    match order {
+        WindowOrder::Response => {
+            let mut windows = windows.into_iter();
+            let primary = windows.next().flatten();
+            let secondary = windows.next().flatten();
+            OrderedWindows {
+                primary,
+                secondary,
+                extra: windows.flatten().collect(),
+            }
+        }
+        WindowOrder::Duration => {
+            let mut present = windows.into_iter().flatten().collect::<Vec<_>>();
+            present.sort_by_key(|window| window.window_minutes.unwrap_or(u64::MAX));
+            let mut present = present.into_iter();
+            OrderedWindows {
+                primary: present.next(),
+                secondary: present.next(),
+                extra: present.collect(),
+            }
+        }
+    }
+}
+
+fn rate_window_from_codex(window: Option<RpcRateLimitWindow>, now: i64) -> Option<RateWindow> {
+    let window = window?;
+    let used_percent = window.used_percent?;
+    let reset_timestamp = match window.resets_in_seconds {
+        Some(seconds) => Some(now + seconds.max(0)),
+        None => window
+            .resets_at
+            .map(|timestamp| absolute_reset_timestamp(timestamp, now)),
+    };
+
+    let window_minutes = window.window_duration_mins.map(|reported| {
+        let corrected = normalized_window_minutes(reported);
+        if corrected != reported {
+            note_window_minutes_correction("codex", reported, corrected);
+        }
+        corrected
+    });
+
+    Some(RateWindow {
+        used_percent: Some(used_percent),
+        window_minutes,
+        resets_at: reset_timestamp.map(format_timestamp),
+    })
+}
+
+/// Resets are expected as Unix timestamps, but some codex builds report
+/// seconds-until-reset instead. Anything smaller than ten years of seconds
+/// can't be a current timestamp, so it is treated as relative to `now`.
+fn absolute_reset_timestamp(value: i64, now: i64) -> i64 {
+    const RELATIVE_RESET_LIMIT: i64 = 10 * 365 * 24 * 60 * 60;
+    if value < RELATIVE_RESET_LIMIT {
+        now + value.max(0)
+    } else {
+        value
+    }
+}
+
+pub fn run_command_with_timeout(
+    program: &str,
+    args: &[&str],
+    timeout: Duration,
+) -> io::Result<Output> {
+    run_command_with_timeout_and_input(program, args, None, timeout)
+}
+
+/// Bytes kept per stream of a subprocess; `CODEXBAR_MAX_OUTPUT_BYTES`
+/// overrides it. Anything beyond is read and discarded.
+const DEFAULT_MAX_OUTPUT_BYTES: usize = 4 * 1024 * 1024;
+
+fn max_output_bytes() -> usize {
+    first_env_value(&["CODEXBAR_MAX_OUTPUT_BYTES"])
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_MAX_OUTPUT_BYTES)
+}
+
+fn run_command_with_timeout_and_input(
+    program: &str,
+    args: &[&str],
+    input: Option<&str>,
+    timeout: Duration,
+) -> io::Result<Output> {
+    let mut command = Command::new(program);
+    command.args(args);
+    run_prepared_command_with_timeout_and_input(command, input, timeout)
+}
+
+/// `run_command_with_timeout_and_input` for a `Command` the caller already
+/// set up, e.g. with extra environment.
+fn run_prepared_command_with_timeout_and_input(
+    command: Command,
+    input: Option<&str>,
+    timeout: Duration,
+) -> io::Result<Output> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let max_output = max_output_bytes();
+    let capped = run_command_capped(command, input, timeout, max_output)?;
+    if capped.truncated {
+        eprintln!("codexbar: warning: {program} output truncated to {max_output} bytes");
+    }
+    Ok(capped.output)
+}
+
+struct CappedOutput {
+    output: Output,
+    /// Whether stdout or stderr went over the cap and lost its tail.
+    truncated: bool,
+}
+
+fn run_command_capped(
+    mut command: Command,
+    input: Option<&str>,
+    timeout: Duration,
+    max_output: usize,
+) -> io::Result<CappedOutput> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    if input.is_some() {
+        command.stdin(Stdio::piped());
+    }
+
+    let mut child = command.spawn()?;
+    // Drain both pipes before feeding stdin so a child that writes while it
+    // reads can't block on a full pipe, and so the wait returns as soon as
+    // the child exits.
+    let stdout = child
+        .stdout
+        .take()
+        .map(|pipe| read_capped_in_background(pipe, max_output));
+    let stderr = child
+        .stderr
+        .take()
+        .map(|pipe| read_capped_in_background(pipe, max_output));
+    if let Some(input_text) = input {
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(input_text.as_bytes())?;
+            stdin.flush()?;
+        }
+    }
+
+    let status = match child.wait_timeout(timeout)? {
+        Some(status) => status,
+        None => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                format!("{program} timed out"),
+            ));
+        }
+    };
+
+    let collect = |reader: Option<CappedReader>| match reader {
+        Some(reader) => reader
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("output reader panicked"))),
+        None => Ok((Vec::new(), false)),
+    };
+    let (stdout, stdout_truncated) = collect(stdout)?;
+    let (stderr, stderr_truncated) = collect(stderr)?;
+    Ok(CappedOutput {
+        output: Output {
+            status,
+            stdout,
+            stderr,
+        },
+        truncated: stdout_truncated || stderr_truncated,
+    })
+}
+
+type CappedReader = thread::JoinHandle<io::Result<(Vec<u8>, bool)>>;
+
+/// Keeps the first `max` bytes and discards the rest, still reading to EOF
+/// so the writer never blocks. Returns the bytes and whether any were dropped.
+fn read_capped_in_background(pipe: impl Read + Send + 'static, max: usize) -> CappedReader {
+    thread::spawn(move || {
+        let mut kept = Vec::new();
+        let mut limited = pipe.take(u64::try_from(max).unwrap_or(u64::MAX));
+        limited.read_to_end(&mut kept)?;
+        let dropped = io::copy(&mut limited.into_inner(), &mut io::sink())?;
+        Ok((kept, dropped > 0))
+    })
+}
+
+/// Removes terminal escapes: CSI (`ESC [`, colors and cursor movement),
+/// OSC (`ESC ]`, e.g. window titles) and the other string sequences up to
+/// BEL or ST (`ESC \`), and two-character escapes like `ESC ( B`. Works on
+/// chars so multi-byte UTF-8 passes through untouched.
+fn strip_ansi_sequences(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    let mut chars = input.chars().peekable();
+
+    while let Some(ch) = chars.next() {
+        if ch != '\x1b' {
+            output.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('[') => {
+                for ch in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&ch) {
+                        break;
+                    }
+                }
+            }
+            Some(']' | 'P' | 'X' | '^' | '_') => {
+                while let Some(ch) = chars.next() {
+                    if ch == '\x07' {
+                        break;
+                    }
+                    if ch == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            }
+            Some(ch) if ('\x20'..='\x2f').contains(&ch) => {
+                // Intermediate bytes, then one final character.
+                while chars.next_if(|ch| ('\x20'..='\x2f').contains(ch)).is_some() {}
+                chars.next();
+            }
+            _ => {}
+        }
+    }
+
+    output
+}
+
+fn first_line_containing_case_insensitive(text: &str, needle: &str) -> Option<String> {
+    let needle_lower = needle.to_ascii_lowercase();
+    text.lines()
+        .find(|line| line.to_ascii_lowercase().contains(&needle_lower))
+        .map(ToOwned::to_owned)
+}
+
+fn extract_percent_left_from_line(line: &str) -> Option<f64> {
+    if !line.to_ascii_lowercase().contains("left") {
+        return None;
+    }
+    let percent_index = line.find('%')?;
+    let prefix = &line[..percent_index];
+    parse_last_number(prefix)
+}
+
+/// The local UTC offset, read once by `init_local_offset`: `time` refuses to
+/// read it once other threads exist, and `/status` prints reset times in local
+/// time.
+static LOCAL_OFFSET: OnceLock<UtcOffset> = OnceLock::new();
+
+/// Call from `main` before any thread starts; see `LOCAL_OFFSET`.
+pub fn init_local_offset() {
+    let _ = LOCAL_OFFSET.set(UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC));
+}
+
+/// The offset `init_local_offset` read, or UTC if it wasn't called.
+pub fn local_offset() -> UtcOffset {
+    LOCAL_OFFSET.get().copied().unwrap_or(UtcOffset::UTC)
+}
+
+/// The local offset in effect at `instant`, so a time past a DST change gets
+/// that side's offset. `time` can only look it up while the process has a
+/// single thread; otherwise this falls back to [`local_offset`].
+pub fn local_offset_at(instant: OffsetDateTime) -> UtcOffset {
+    UtcOffset::local_offset_at(instant).unwrap_or_else(|_| local_offset())
+}
+
+fn local_now(clock: &dyn Clock) -> OffsetDateTime {
+    clock.now().to_offset(local_offset())
+}
+
+/// Unix seconds of the reset named on a `/status` limit line: `resets 15:04`,
+/// `resets 3:00pm`, `resets 09:00 on 22 Oct`, or `resets in 2h 13m`. A bare
+/// clock time already past today means tomorrow.
+fn parse_status_reset(line: &str, now: OffsetDateTime) -> Option<i64> {
+    let lower = line.to_ascii_lowercase();
+    let (_, phrase) = lower.split_once("resets")?;
+    let phrase = phrase.split(')').next()?.trim();
+    let phrase = phrase.strip_prefix("at ").unwrap_or(phrase).trim();
+
+    if let Some(relative) = phrase.strip_prefix("in ") {
+        let mut seconds = 0_i64;
+        for token in relative.split_whitespace() {
+            let unit = token.chars().last()?;
+            let amount = token.strip_suffix(unit)?.parse::<i64>().ok()?;
+            let unit_seconds = match unit {
+                'd' => 86_400,
+                'h' => 3_600,
+                'm' => 60,
+                's' => 1,
+                _ => return None,
+            };
+            seconds = seconds.checked_add(amount.checked_mul(unit_seconds)?)?;
+        }
+        return now.unix_timestamp().checked_add(seconds);
+    }
+
+    let (clock, day) = match phrase.split_once(" on ") {
+        Some((clock, day)) => (clock, Some(day)),
+        None => (phrase, None),
+    };
+    let (hour, minute) = parse_clock_time(clock)?;
+    let date = match day {
+        Some(day) => parse_day_and_month(day, now.date())?,
+        None => now.date(),
+    };
+    let mut reset = date
+        .with_hms(hour, minute, 0)
+        .ok()?
+        .assume_offset(now.offset());
+    if day.is_none() && reset <= now {
+        reset += time::Duration::DAY;
+    }
+    Some(reset.unix_timestamp())
+}
+
+/// `15:04`, `3:00pm`, `3:00 pm`, or `3pm`.
+fn parse_clock_time(text: &str) -> Option<(u8, u8)> {
+    let text = text.trim();
+    let (digits, pm) = if let Some(digits) = text.strip_suffix("am") {
+        (digits.trim_end(), Some(false))
+    } else if let Some(digits) = text.strip_suffix("pm") {
+        (digits.trim_end(), Some(true))
+    } else {
+        (text, None)
+    };
+    let (hour, minute) = match digits.split_once(':') {
+        Some((hour, minute)) => (hour.parse::<u8>().ok()?, minute.parse::<u8>().ok()?),
+        None => (digits.parse::<u8>().ok()?, 0),
+    };
+    if minute > 59 {
+        return None;
+    }
+    match pm {
+        Some(pm) if (1..=12).contains(&hour) => Some((hour % 12 + if pm { 12 } else { 0 }, minute)),
+        Some(_) => None,
+        None if hour <= 23 => Some((hour, minute)),
+        None => None,
+    }
+}
+
+/// `22 oct` or `oct 22`, in the year that puts it closest after `today`,
+/// or in the year given (`oct 22, 2026`).
+fn parse_day_and_month(text: &str, today: Date) -> Option<Date> {
+    let mut day = None;
+    let mut month = None;
+    let mut year = None;
+    for token in text.split_whitespace() {
+        let token = token.trim_matches(|ch: char| ch == ',' || ch == '.');
+        if let Ok(number) = token.parse::<u8>() {
+            day = Some(number);
+        } else if let Ok(number) = token.parse::<i32>() {
+            year = Some(number);
+        } else if let Some(prefix) = token.get(..3) {
+            let named = (1..=12)
+                .filter_map(|number| Month::try_from(number).ok())
+                .find(|month| month.to_string().to_ascii_lowercase().starts_with(prefix));
+            month = named.or(month);
+        }
+    }
+    let (day, month) = (day?, month?);
+    if let Some(year) = year {
+        return Date::from_calendar_date(year, month, day).ok();
+    }
+
+    let date = Date::from_calendar_date(today.year(), month, day).ok()?;
+    // "on 3 Jan" printed in late December is next year's reset.
+    if date < today.previous_day()? {
+        Date::from_calendar_date(today.year() + 1, month, day).ok()
+    } else {
+        Some(date)
+    }
+}
+
+fn extract_credits_from_status(text: &str) -> Option<f64> {
+    for line in text.lines() {
+        let lower = line.to_ascii_lowercase();
+        if !lower.contains("credits") {
+            continue;
+        }
+        if let Some((_, tail)) = line.split_once(':') {
+            if let Some(value) = parse_first_number(tail) {
+                return Some(value);
+            }
+        }
+        if let Some(value) = parse_first_number(line) {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn parse_first_number(input: &str) -> Option<f64> {
+    let bytes = input.as_bytes();
+    if bytes.is_empty() {
+        return None;
+    }
+
+    let mut start = 0;
+    while start < bytes.len() && !bytes[start].is_ascii_digit() {
+        start += 1;
+    }
+    if start == bytes.len() {
+        return None;
+    }
+
+    let mut end = start;
+    while end < bytes.len()
+        && (bytes[end].is_ascii_digit() || bytes[end] == b'.' || bytes[end] == b',')
+    {
+        end += 1;
+    }
+
+    let raw = input[start..end].replace(',', "");
+    raw.parse::<f64>().ok()
+}
+
+fn parse_last_number(input: &str) -> Option<f64> {
+    let bytes = input.as_bytes();
+    if bytes.is_empty() {
+        return None;
+    }
+
+    let mut end = bytes.len();
+    while end > 0 && !bytes[end - 1].is_ascii_digit() {
+        end -= 1;
+    }
+    if end == 0 {
+        return None;
+    }
+
+    let mut start = end;
+    while start > 0 {
+        let byte = bytes[start - 1];
+        if byte.is_ascii_digit() || byte == b'.' {
+            start -= 1;
+        } else {
+            break;
+        }
+    }
+
+    input[start..end].parse::<f64>().ok()
+}
+
+struct CodexRpcSession {
+    /// `None` for sessions built by `from_streams`.
+    child: Option<Child>,
+    stdin: Box<dyn Write + Send>,
+    /// Lines of server output, read on their own thread so a silent server
+    /// can't block `read_message` past `read_timeout`.
+    stdout_lines: mpsc::Receiver<io::Result<String>>,
+    read_timeout: Duration,
+    stderr_tail: Arc<Mutex<String>>,
+    next_id: i64,
+    initialized: bool,
+    /// Every message read from the server, kept for `--raw`.
+    transcript: Option<Vec<String>>,
+    // Held for the session's lifetime; released after the child is killed.
+    _lock: Option<File>,
+}
+
+/// How long to wait for another codexbar process to finish its app-server session.
+/// Starts and initializes a codex app-server, then lets it exit.
+pub fn probe_codex_app_server() -> Result<()> {
+    if find_binary_on_path("codex").is_none() {
+        bail!("codex CLI not found");
+    }
+    match CodexRpcSession::start(
+        codex_command(&FetchOptions::default()),
+        CODEX_RPC_LOCK_TIMEOUT,
+    )? {
+        Some(mut session) => session.initialize(),
+        None => bail!("codex CLI not found"),
+    }
+}
+
+const CODEX_RPC_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for each app-server message before killing it.
+const CODEX_RPC_READ_TIMEOUT: Duration = Duration::from_secs(20);
+/// Bytes of app-server stderr kept for diagnosing startup failures.
+const CODEX_STDERR_TAIL_BYTES: usize = 4096;
+
+/// In `$XDG_RUNTIME_DIR`, else the per-user cache dir, never a shared
+/// directory where another user's lock file could block or lock us out.
+fn codex_rpc_lock_path() -> Result<PathBuf> {
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+    {
+        Some(dir) => dir,
+        None => {
+            let dir = codexbar_cache_dir()
+                .context("neither XDG_RUNTIME_DIR nor HOME is set for the codex RPC lock")?;
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+            dir
+        }
+    };
+    let path = dir.join("codexbar-codex-rpc.lock");
+    create_private_file(&path).with_context(|| format!("failed to create {}", path.display()))?;
+    Ok(path)
+}
+
+/// `$XDG_CACHE_HOME/codexbar`, or `~/.cache/codexbar`.
+pub fn codexbar_cache_dir() -> Option<PathBuf> {
+    let cache_home = first_env_value(&["XDG_CACHE_HOME"])
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(std::env::var("HOME").ok()?).join(".cache")))?;
+    Some(cache_home.join("codexbar"))
+}
+
+/// Creates `path` readable by the user alone, tightening the mode of one
+/// left behind by an older version too.
+pub fn create_private_file(path: &Path) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}
+
+/// Serializes app-server sessions across codexbar processes (widget refresh
+/// plus a manual run) so they don't race to start one per profile.
+pub fn acquire_rpc_lock(path: &Path, timeout: Duration) -> Result<File> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Ok(file),
+            Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(100));
+            }
+            Err(TryLockError::WouldBlock) => bail!(
+                "another codexbar process is still using codex app-server (lock {}); try again shortly",
+                path.display()
+            ),
+            Err(TryLockError::Error(error)) => {
+                return Err(error).with_context(|| format!("failed to lock {}", path.display()))
+            }
+        }
+    }
+}
+
+fn is_app_server_collision(stderr: &str) -> bool {
+    let stderr = stderr.to_ascii_lowercase();
+    [
+        "already running",
+        "already in use",
+        "address in use",
+        "eaddrinuse",
+    ]
+    .iter()
+    .any(|pattern| stderr.contains(pattern))
+}
+
+impl CodexRpcSession {
+    /// Spawns `codex` (from `codex_command`) as an app-server.
+    /// `lock_timeout` bounds the wait for another codexbar's session to finish.
+    fn start(mut codex: Command, lock_timeout: Duration) -> Result<Option<Self>> {
+        let lock = acquire_rpc_lock(&codex_rpc_lock_path()?, lock_timeout)?;
+        let mut child = match codex
+            .args(["-s", "read-only", "-a", "untrusted", "app-server"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Ok(child) => child,
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error).context("failed to launch codex app-server"),
+        };
+
+        let stdin = child
+            .stdin
+            .take()
+            .context("failed to open codex app-server stdin")?;
+        let stdout = child
+            .stdout
+            .take()
+            .context("failed to open codex app-server stdout")?;
+
+        // Drain stderr so the server never blocks on it, keeping only the tail.
+        let stderr_tail = Arc::new(Mutex::new(String::new()));
+        if let Some(stderr) = child.stderr.take() {
+            let tail = Arc::clone(&stderr_tail);
+            thread::spawn(move || {
+                for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                    let mut tail = tail.lock().unwrap_or_else(|error| error.into_inner());
+                    tail.push_str(&line);
+                    tail.push('\n');
+                    if tail.len() > CODEX_STDERR_TAIL_BYTES {
+                        let mut cut = tail.len() - CODEX_STDERR_TAIL_BYTES;
+                        while !tail.is_char_boundary(cut) {
+                            cut += 1;
+                        }
+                        tail.drain(..cut);
+                    }
+                }
+            });
+        }
+
+        let mut session = Self::from_streams(stdin, stdout);
+        session.child = Some(child);
+        session.stderr_tail = stderr_tail;
+        session._lock = Some(lock);
+        Ok(Some(session))
+    }
+
+    /// A session over arbitrary streams instead of a spawned app-server, so
+    /// tests can replay a recorded transcript.
+    fn from_streams(
+        stdin: impl Write + Send + 'static,
+        stdout: impl io::Read + Send + 'static,
+    ) -> Self {
+        let (sender, stdout_lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let failed = line.is_err();
+                if sender.send(line).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Self {
+            child: None,
+            stdin: Box::new(stdin),
+            stdout_lines,
+            read_timeout: CODEX_RPC_READ_TIMEOUT,
+            stderr_tail: Arc::new(Mutex::new(String::new())),
+            next_id: 1,
+            initialized: false,
+            transcript: None,
+            _lock: None,
+        }
+    }
+
+    /// Replaces a raw RPC failure with an actionable message when the server
+    /// reported that another app-server already owns this profile.
+    fn explain_failure(&mut self, error: anyhow::Error) -> anyhow::Error {
+        // Give the server a moment to exit so its last stderr lines land.
+        if let Some(child) = self.child.as_mut() {
+            let deadline = Instant::now() + Duration::from_millis(500);
+            while matches!(child.try_wait(), Ok(None)) && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(25));
+            }
+        }
+
+        let stderr = self
+            .stderr_tail
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .clone();
+        if is_app_server_collision(&stderr) {
+            error.context(
+                "codex app-server is already running for this profile (another codex or codexbar session owns it); close it or use --codex-source status",
+            )
+        } else {
+            error
+        }
+    }
+
+    /// Idempotent, so a session started by `start_with_retries` can be
+    /// handed to `codex_entry_from_session` as is.
+    fn initialize(&mut self) -> Result<()> {
+        if !self.initialized {
+            initialize_rpc(self)?;
+            self.initialized = true;
+        }
+        Ok(())
+    }
+
+    fn fetch_account(&mut self) -> Result<RpcAccountResponse> {
+        let value = self.request("account/read", json!({}))?;
+        serde_json::from_value(value).context("failed to decode codex account response")
+    }
+
+    fn fetch_rate_limits(&mut self) -> Result<RpcRateLimitsResponse> {
+        let value = self.request("account/rateLimits/read", json!({}))?;
+        serde_json::from_value(value).context("failed to decode codex rate limits response")
+    }
+
+    fn request(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.send_request(method, params)
+            .map_err(|error| self.explain_failure(error))
+    }
+
+    fn send_request(&mut self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.send_payload(json!({
+            "id": id,
+            "method": method,
+            "params": params
+        }))?;
+
+        loop {
+            let message = self.read_message()?;
+            if let Some(server_method) = message.get("method").and_then(Value::as_str) {
+                // Notifications need no reply. Server requests do, or the
+                // server may wait on us forever; codexbar handles none of them.
+                if let Some(request_id) = message.get("id") {
+                    self.send_payload(json!({
+                        "id": request_id,
+                        "error": {
+                            "code": -32601,
+                            "message": format!("codexbar does not handle '{server_method}'"),
+                        }
+                    }))?;
+                }
+                continue;
+            }
+
+            let message_id = message.get("id").and_then(Value::as_i64);
+            if message_id != Some(id) {
+                continue;
+            }
+
+            if let Some(error) = message.get("error") {
+                return Err(RpcServerError::new(method, error).into());
+            }
+
+            if let Some(result) = message.get("result") {
+                return Ok(result.clone());
+            }
+
+            bail!("codex app-server response missing result for method '{method}'");
+        }
+    }
+
+    fn notify(&mut self, method: &str, params: Value) -> Result<()> {
+        self.send_payload(json!({
+            "method": method,
+            "params": params
+        }))
+    }
+
+    fn send_payload(&mut self, payload: Value) -> Result<()> {
+        let bytes =
+            serde_json::to_vec(&payload).context("failed to serialize codex RPC payload")?;
+        self.stdin
+            .write_all(&bytes)
+            .context("failed to write codex RPC payload")?;
+        self.stdin
+            .write_all(b"\n")
+            .context("failed to terminate codex RPC payload line")?;
+        self.stdin
+            .flush()
+            .context("failed to flush codex RPC payload")?;
+        Ok(())
+    }
+
+    /// Kills the app-server when nothing parseable arrives in `read_timeout`.
+    fn read_message(&mut self) -> Result<Value> {
+        let deadline = Instant::now() + self.read_timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let line = match self.stdout_lines.recv_timeout(remaining) {
+                Ok(line) => line.context("failed reading codex app-server output")?,
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    bail!("codex app-server closed stdout")
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if let Some(child) = self.child.as_mut() {
+                        let _ = child.kill();
+                        let _ = child.wait();
+                    }
+                    return Err(CodexBarError::Timeout(format!(
+                        "codex app-server sent nothing for {}s",
+                        self.read_timeout.as_secs_f64()
+                    ))
+                    .into());
+                }
+            };
+
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+
+            if let Ok(value) = serde_json::from_str::<Value>(trimmed) {
+                if let Some(transcript) = self.transcript.as_mut() {
+                    transcript.push(trimmed.to_string());
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    fn dump_transcript(&self, args: &FetchOptions) {
+        if let Some(transcript) = self.transcript.as_ref() {
+            args.dump_raw("codex-rpc.jsonl", &(transcript.join("\n") + "\n"));
+        }
+    }
+}
+
+/// The request/notify surface of an app-server session, split out so the
+/// handshake can be exercised against a scripted server.
+trait RpcChannel {
+    fn call(&mut self, method: &str, params: Value) -> Result<Value>;
+    fn send_notification(&mut self, method: &str, params: Value) -> Result<()>;
+}
+
+impl RpcChannel for CodexRpcSession {
+    fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.request(method, params)
+    }
+
+    fn send_notification(&mut self, method: &str, params: Value) -> Result<()> {
+        self.notify(method, params)
+    }
+}
+
+/// A JSON-RPC `error` object returned by the app-server.
+#[derive(Debug, Clone, PartialEq)]
+struct RpcServerError {
+    method: String,
+    message: String,
+    data: Value,
+    raw: Value,
+}
+
+impl RpcServerError {
+    fn new(method: &str, error: &Value) -> Self {
+        Self {
+            method: method.to_string(),
+            message: error
+                .get("message")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+            data: error.get("data").cloned().unwrap_or(Value::Null),
+            raw: error.clone(),
+        }
+    }
+
+    fn mentions_version(&self) -> bool {
+        let message = self.message.to_ascii_lowercase();
+        message.contains("version") || message.contains("protocol")
+    }
+
+    /// The newest protocol version the server says it accepts, if it listed any.
+    fn supported_protocol_version(&self) -> Option<Value> {
+        ["supportedVersions", "supportedProtocolVersions"]
+            .iter()
+            .find_map(|key| self.data.get(*key).and_then(Value::as_array))
+            .and_then(|versions| {
+                versions
+                    .iter()
+                    .filter(|version| version.is_string() || version.is_u64())
+                    .max_by(|left, right| compare_protocol_versions(left, right))
+                    .cloned()
+            })
+    }
+}
+
+fn compare_protocol_versions(left: &Value, right: &Value) -> std::cmp::Ordering {
+    let key = |value: &Value| -> Vec<u64> {
+        match value {
+            Value::Number(number) => vec![number.as_u64().unwrap_or_default()],
+            Value::String(text) => text
+                .split(|ch: char| !ch.is_ascii_digit())
+                .filter_map(|part| part.parse().ok())
+                .collect(),
+            _ => Vec::new(),
+        }
+    };
+    key(left).cmp(&key(right))
+}
+
+impl fmt::Display for RpcServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "codex app-server request '{}' failed: {}",
+            self.method, self.raw
+        )
+    }
+}
+
+impl std::error::Error for RpcServerError {}
+
+const CODEX_UPGRADE_HINT: &str =
+    "codex is too old for codexbar's app-server client; upgrade codex (npm install -g @openai/codex)";
+
+fn initialize_params(protocol_version: Option<&Value>) -> Value {
+    let mut params = json!({
+        "clientInfo": {
+            "name": "codexbar-rust",
+            "version": env!("CARGO_PKG_VERSION")
+        }
+    });
+    if let Some(version) = protocol_version {
+        params["protocolVersion"] = version.clone();
+    }
+    params
+}
+
+/// Sends `initialize`/`initialized`. When the server rejects the handshake
+/// over a version mismatch but lists versions it accepts, retries once with
+/// the newest of those; otherwise the rejection becomes an upgrade hint.
+fn initialize_rpc(channel: &mut impl RpcChannel) -> Result<()> {
+    let rejection = match channel.call("initialize", initialize_params(None)) {
+        Ok(_) => return channel.send_notification("initialized", json!({})),
+        Err(error) => error,
+    };
+
+    let Some(server_error) = rejection.downcast_ref::<RpcServerError>() else {
+        return Err(rejection);
+    };
+    if let Some(version) = server_error.supported_protocol_version() {
+        channel
+            .call("initialize", initialize_params(Some(&version)))
+            .with_context(|| {
+                format!(
+                    "codex app-server rejected protocol version {version}; {CODEX_UPGRADE_HINT}"
+                )
+            })?;
+        return channel.send_notification("initialized", json!({}));
+    }
+    if server_error.mentions_version() {
+        let message = server_error.message.clone();
+        return Err(rejection.context(format!(
+            "codex app-server rejected initialize ({message}); {CODEX_UPGRADE_HINT}"
+        )));
+    }
+    Err(rejection)
+}
+
+impl Drop for CodexRpcSession {
+    fn drop(&mut self) {
+        if let Some(child) = self.child.as_mut() {
+            if let Ok(None) = child.try_wait() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcAccountResponse {
+    account: Option<RpcAccountDetails>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+enum RpcAccountDetails {
+    #[serde(rename = "apiKey")]
+    ApiKey,
+    #[serde(rename = "chatgpt")]
+    ChatGPT {
+        email: Option<String>,
+        #[serde(rename = "planType")]
+        plan_type: Option<String>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcRateLimitsResponse {
+    #[serde(rename = "rateLimits", default)]
+    rate_limits: Option<RpcRateLimitSnapshot>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RpcRateLimitSnapshot {
+    primary: Option<RpcRateLimitWindow>,
+    secondary: Option<RpcRateLimitWindow>,
+    #[serde(default)]
+    tertiary: Option<RpcRateLimitWindow>,
+    credits: Option<RpcCreditsSnapshot>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcRateLimitWindow {
+    #[serde(rename = "usedPercent")]
+    used_percent: Option<f64>,
+    #[serde(rename = "windowDurationMins")]
+    window_duration_mins: Option<u64>,
+    #[serde(rename = "resetsAt")]
+    resets_at: Option<i64>,
+    #[serde(rename = "resetsInSeconds", default)]
+    resets_in_seconds: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcCreditsSnapshot {
+    balance: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codexbar_core::FixedClock;
+    use std::cell::RefCell;
+    use std::sync::Mutex;
+
+    /// Serializes tests that read or mutate process environment variables.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    #[derive(Default)]
+    struct MemorySecretBackend {
+        entries: RefCell<HashMap<String, String>>,
+    }
+
+    impl SecretBackend for MemorySecretBackend {
+        fn name(&self) -> &'static str {
+            "memory"
+        }
+
+        fn lookup(&self, key: &SecretKey) -> Option<String> {
+            self.entries.borrow().get(&key.kwallet_entry()).cloned()
+        }
+
+        fn store(&self, key: &SecretKey, _label: &str, value: &str) -> Result<()> {
+            self.entries
+                .borrow_mut()
+                .insert(key.kwallet_entry(), value.to_string());
+            Ok(())
+        }
+
+        fn clear(&self, key: &SecretKey) {
+            self.entries.borrow_mut().remove(&key.kwallet_entry());
+        }
+    }
+
+    /// Matches like libsecret: a key finds every item carrying its
+    /// attributes, whatever else the item carries.
+    #[derive(Default)]
+    struct SubsetSecretBackend {
+        items: RefCell<Vec<(Vec<String>, String)>>,
+    }
+
+    impl SubsetSecretBackend {
+        fn attributes(key: &SecretKey) -> Vec<String> {
+            key.secret_tool_attributes()
+                .chunks(2)
+                .map(|pair| pair.join("="))
+                .collect()
+        }
+
+        fn matches(item: &[String], key: &[String]) -> bool {
+            key.iter().all(|attribute| item.contains(attribute))
+        }
+    }
+
+    impl SecretBackend for SubsetSecretBackend {
+        fn name(&self) -> &'static str {
+            "subset"
+        }
+
+        fn lookup(&self, key: &SecretKey) -> Option<String> {
+            let key = Self::attributes(key);
+            self.items
+                .borrow()
+                .iter()
+                .find(|(item, _)| Self::matches(item, &key))
+                .map(|(_, value)| value.clone())
+        }
+
+        fn store(&self, key: &SecretKey, _label: &str, value: &str) -> Result<()> {
+            let key = Self::attributes(key);
+            let mut items = self.items.borrow_mut();
+            items.retain(|(item, _)| *item != key);
+            items.push((key, value.to_string()));
+            Ok(())
+        }
+
+        fn clear(&self, key: &SecretKey) {
+            let key = Self::attributes(key);
+            self.items
+                .borrow_mut()
+                .retain(|(item, _)| !Self::matches(item, &key));
+        }
+
+        fn matches_subsets(&self) -> bool {
+            true
+        }
+
+        fn search(&self, key: &SecretKey) -> Vec<SecretItem> {
+            let key = Self::attributes(key);
+            self.items
+                .borrow()
+                .iter()
+                .filter(|(item, _)| Self::matches(item, &key))
+                .map(|(item, value)| SecretItem {
+                    id: item.join(","),
+                    account: item
+                        .iter()
+                        .find_map(|attribute| attribute.strip_prefix("account="))
+                        .map(str::to_string),
+                    value: value.clone(),
+                })
+                .collect()
+        }
+
+        fn delete(&self, item: &SecretItem) {
+            self.items
+                .borrow_mut()
+                .retain(|(attributes, _)| attributes.join(",") != item.id);
+        }
+    }
+
+    fn bare_entry(provider: &str, source: &str) -> ProviderEntry {
+        ProviderEntry {
+            provider: provider.to_string(),
+            source: Some(source.to_string()),
+            updated_at: now_iso8601(),
+            primary: None,
+            secondary: None,
+            tertiary: None,
+            extra_windows: Vec::new(),
+            windows: Vec::new(),
+            credits_remaining: None,
+            code_review_remaining_percent: None,
+            identity: None,
+            status: None,
+            error: None,
+        }
+    }
+
+    fn window(minutes: Option<u64>) -> RateWindow {
+        RateWindow {
+            used_percent: Some(10.0),
+            window_minutes: minutes,
+            resets_at: None,
+        }
+    }
+
+    #[test]
+    fn duration_order_assigns_shortest_windows_first() {
+        let ordered = order_rate_windows(
+            vec![
+                Some(window(Some(10080))),
+                Some(window(None)),
+                Some(window(Some(300))),
+                Some(window(Some(1440))),
+            ],
+            WindowOrder::Duration,
+        );
+        assert_eq!(ordered.primary, Some(window(Some(300))));
+        assert_eq!(ordered.secondary, Some(window(Some(1440))));
+        assert_eq!(ordered.extra, vec![window(Some(10080)), window(None)]);
+    }
+
+    #[test]
+    fn response_order_keeps_provider_keys() {
+        let ordered = order_rate_windows(
+            vec![None, Some(window(Some(300))), Some(window(Some(60)))],
+            WindowOrder::Response,
+        );
+        assert_eq!(ordered.primary, None);
+        assert_eq!(ordered.secondary, Some(window(Some(300))));
+        assert_eq!(ordered.extra, vec![window(Some(60))]);
+
+        let empty = order_rate_windows(vec![None, None], WindowOrder::Duration);
+        assert!(empty.is_empty());
+    }
+
+    #[test]
+    fn maps_codex_usage_api_json() {
+        let body = r#"{
+            "plan_type": "plus",
+            "rate_limit": {
+                "primary_window": {"used_percent": 28, "limit_window_seconds": 18000, "reset_at": 1760000000},
+                "secondary_window": {"used_percent": 61, "limit_window_seconds": 604800, "reset_at": 1760500000}
+            },
+            "credits": {"has_credits": true, "balance": "92.4"},
+            "codeReviewRemainingPercent": 75
+        }"#;
+
+        let entry =
+            codex_entry_from_usage_json(body, &FetchOptions::default(), "openai-web").unwrap();
+        assert_eq!(entry.provider, "codex");
+        assert_eq!(entry.source.as_deref(), Some("openai-web"));
+        let primary = entry.primary.unwrap();
+        assert_eq!(primary.used_percent, Some(28.0));
+        assert_eq!(primary.window_minutes, Some(300));
+        assert_eq!(
+            primary.resets_at.as_deref().and_then(parse_unix_seconds),
+            Some(1_760_000_000)
+        );
+        assert_eq!(entry.secondary.unwrap().window_minutes, Some(10080));
+        assert_eq!(entry.credits_remaining, Some(92.4));
+        assert_eq!(entry.code_review_remaining_percent, Some(75.0));
+        let identity = entry.identity.unwrap();
+        assert_eq!(identity.login_method.as_deref(), Some("chatgpt"));
+        assert_eq!(identity.plan.as_deref(), Some("plus"));
+    }
+
+    #[test]
+    fn codex_falls_back_to_usage_api_after_status() {
+        let entry_from = |source: &str| {
+            codex_entry_from_usage_json(
+                r#"{"rate_limit": {"primary_window": {"used_percent": 28}}, "codeReviewRemainingPercent": 75}"#,
+                &FetchOptions::default(),
+                source,
+            )
+        };
+        let dashboard = || Ok(entry_from("openai-web"));
+
+        let entry = fetch_codex_entry_with_fallbacks(
+            || Err(anyhow!("app-server exited")),
+            || Ok(None),
+            dashboard,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(entry.source.as_deref(), Some("openai-web"));
+        assert_eq!(entry.code_review_remaining_percent, Some(75.0));
+
+        let error = fetch_codex_entry_with_fallbacks(
+            || Ok(None),
+            || Err(anyhow!("codex /status timed out")),
+            || Ok(None),
+        )
+        .unwrap_err();
+        assert!(error.to_string().contains("/status timed out"));
+
+        let entry = fetch_codex_entry_with_fallbacks(
+            || Ok(None),
+            || Ok(entry_from("codex-status")),
+            || panic!("usage API should not be queried"),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(entry.source.as_deref(), Some("codex-status"));
+
+        let error = fetch_codex_entry_via_http_only(&FetchOptions::default(), None).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CodexBarError>(),
+            Some(CodexBarError::AuthRequired(_))
+        ));
+    }
+
+    #[test]
+    fn derives_code_review_percent_from_rate_limit() {
+        let body = r#"{
+            "rate_limit": {"primary_window": {"used_percent": 10, "limit_window_seconds": 18000}},
+            "code_review_rate_limit": {"primary_window": {"used_percent": 40}}
+        }"#;
+        let entry =
+            codex_entry_from_usage_json(body, &FetchOptions::default(), "openai-web").unwrap();
+        assert_eq!(entry.code_review_remaining_percent, Some(60.0));
+        assert!(
+            codex_entry_from_usage_json("{}", &FetchOptions::default(), "openai-web").is_none()
+        );
+    }
+
+    #[test]
+    fn missing_rate_limits_yields_no_rpc_entry() {
+        for raw in [json!({}), json!({"rateLimits": null})] {
+            let limits: RpcRateLimitsResponse = serde_json::from_value(raw).unwrap();
+            assert!(codex_entry_from_rpc(&FetchOptions::default(), None, limits).is_none());
+        }
+
+        let limits: RpcRateLimitsResponse = serde_json::from_value(json!({
+            "rateLimits": {
+                "primary": {"usedPercent": 12.5, "windowDurationMins": 300, "resetsAt": 1760000000},
+                "secondary": null,
+                "credits": {"balance": "4.5"}
+            }
+        }))
+        .unwrap();
+        let entry = codex_entry_from_rpc(&FetchOptions::default(), None, limits).unwrap();
+        assert_eq!(entry.primary.unwrap().used_percent, Some(12.5));
+        assert_eq!(entry.tertiary, None);
+        assert_eq!(entry.credits_remaining, Some(4.5));
+    }
+
+    #[test]
+    fn maps_codex_tertiary_rpc_window() {
+        let limits: RpcRateLimitsResponse = serde_json::from_value(json!({
+            "rateLimits": {
+                "primary": {"usedPercent": 25.0, "windowDurationMins": 300, "resetsAt": 1760018000},
+                "secondary": {"usedPercent": 60.0, "windowDurationMins": 10080, "resetsAt": 1760500000},
+                "tertiary": {"usedPercent": 5.0, "windowDurationMins": 43200, "resetsAt": 1762000000},
+                "credits": null
+            }
+        }))
+        .unwrap();
+
+        let entry = codex_entry_from_rpc(&FetchOptions::default(), None, limits).unwrap();
+        assert_eq!(entry.primary.unwrap().window_minutes, Some(300));
+        assert_eq!(entry.secondary.unwrap().window_minutes, Some(10080));
+        assert!(entry.extra_windows.is_empty());
+        assert_eq!(
+            entry.tertiary,
+            Some(RateWindow {
+                used_percent: Some(5.0),
+                window_minutes: Some(43200),
+                resets_at: Some(format_timestamp(1_762_000_000)),
+            })
+        );
+    }
+
+    #[test]
+    fn requested_providers_come_from_the_registry() {
+        assert_eq!(
+            requested_providers("all").unwrap(),
+            ["codex", "claude", "gemini", "cursor", "copilot"]
+        );
+        assert_eq!(requested_providers(" Claude ").unwrap(), ["claude"]);
+        assert!(requested_providers("factory").is_err());
+    }
+
+    #[test]
+    fn provider_aliases_resolve_to_canonical_ids() {
+        for (alias, provider) in [
+            ("gpt", "codex"),
+            ("OAI", "codex"),
+            ("openai", "codex"),
+            ("chatgpt", "codex"),
+            ("Anthropic", "claude"),
+            ("google", "gemini"),
+            ("github", "copilot"),
+        ] {
+            assert_eq!(requested_providers(alias).unwrap(), [provider], "{alias}");
+        }
+        assert_eq!(
+            requested_providers("codex,gpt,anthropic").unwrap(),
+            ["codex", "claude"]
+        );
+
+        let error = requested_providers("gpt,grok").unwrap_err().to_string();
+        assert!(error.contains("unknown provider 'grok'"), "{error}");
+
+        assert_eq!(
+            provider_alias_notes("codex, gpt,Anthropic,GPT,grok"),
+            [
+                "provider 'gpt' is an alias for 'codex'",
+                "provider 'anthropic' is an alias for 'claude'",
+            ]
+        );
+        assert!(provider_alias_notes("all").is_empty());
+    }
+
+    #[test]
+    fn parses_comma_separated_provider_lists() {
+        assert_eq!(
+            requested_providers("gemini, codex,GEMINI,,claude").unwrap(),
+            ["gemini", "codex", "claude"]
+        );
+        assert_eq!(
+            requested_providers("copilot,all").unwrap(),
+            ["copilot", "codex", "claude", "gemini", "cursor"]
+        );
+
+        let error = requested_providers("codex,factory,cluade").unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("unknown providers 'factory', 'cluade'"),
+            "{error}"
+        );
+        assert!(requested_providers(" , ").is_err());
+    }
+
+    #[test]
+    fn classifies_fetch_errors() {
+        let missing = anyhow::Error::from(io::Error::from(ErrorKind::NotFound))
+            .context("failed to run codex /status");
+        assert!(matches!(
+            provider_error(missing),
+            CodexBarError::MissingDependency(message) if message.starts_with("failed to run codex")
+        ));
+
+        let upstream = anyhow::Error::from(CodexBarError::Upstream {
+            service: "Gemini quota API".to_string(),
+            status: 503,
+        })
+        .context("gemini fetch");
+        assert_eq!(
+            provider_error(upstream),
+            CodexBarError::Upstream {
+                service: "Gemini quota API".to_string(),
+                status: 503
+            }
+        );
+
+        let parse = serde_json::from_str::<Value>("{").unwrap_err();
+        assert!(matches!(
+            provider_error(anyhow::Error::from(parse)),
+            CodexBarError::Parse(_)
+        ));
+        assert_eq!(
+            provider_error(anyhow!("app-server crashed")),
+            CodexBarError::Other("app-server crashed".to_string())
+        );
+    }
+
+    #[test]
+    fn custom_provider_maps_mock_response() {
+        let toml = r#"
+            [[providers]]
+            id = "gateway"
+            endpoint = "https://llm.example.com/v1/usage"
+            authHeader = "Authorization: Bearer ${GATEWAY_TOKEN}"
+
+            [[providers.windows]]
+            usedPercent = "/limits/0/percent"
+            windowMinutes = "/limits/0/minutes"
+            resetsAt = "/limits/0/resets_at"
+
+            [[providers.windows]]
+            usedPercent = "/limits/1/percent"
+            windowMinutes = "/limits/1/minutes"
+            resetsAt = "/limits/1/resets_in"
+        "#;
+        let json = r#"{"providers": [{
+            "id": "gateway",
+            "endpoint": "https://llm.example.com/v1/usage",
+            "authHeader": "Authorization: Bearer ${GATEWAY_TOKEN}",
+            "windows": [
+                {"usedPercent": "/limits/0/percent", "windowMinutes": "/limits/0/minutes", "resetsAt": "/limits/0/resets_at"},
+                {"usedPercent": "/limits/1/percent", "windowMinutes": "/limits/1/minutes", "resetsAt": "/limits/1/resets_in"}
+            ]
+        }]}"#;
+        let definitions = parse_custom_providers(toml, true).unwrap();
+        assert_eq!(definitions, parse_custom_providers(json, false).unwrap());
+
+        let response = r#"{"limits": [
+            {"percent": 12.5, "minutes": 43200, "resets_at": "2025-11-01T00:00:00Z"},
+            {"percent": "42", "minutes": 1440, "resets_in": 3600}
+        ]}"#;
+        let now = 1_760_000_000;
+        let entry = custom_entry_from_json(
+            "gateway",
+            &definitions[0].windows,
+            response,
+            &FetchOptions::default(),
+            now,
+        )
+        .unwrap();
+        assert_eq!(entry.provider, "gateway");
+        assert_eq!(entry.source.as_deref(), Some("custom"));
+
+        // Default window order puts the shorter (daily) window first.
+        let daily = entry.primary.unwrap();
+        assert_eq!(daily.used_percent, Some(42.0));
+        assert_eq!(daily.window_minutes, Some(1440));
+        assert_eq!(
+            daily.resets_at.as_deref().and_then(parse_unix_seconds),
+            Some(now + 3600)
+        );
+        let monthly = entry.secondary.unwrap();
+        assert_eq!(monthly.used_percent, Some(12.5));
+        assert_eq!(
+            monthly.resets_at.as_deref().and_then(parse_unix_seconds),
+            Some(1_761_955_200)
+        );
+
+        assert!(custom_entry_from_json(
+            "gateway",
+            &definitions[0].windows,
+            r#"{"limits": []}"#,
+            &FetchOptions::default(),
+            now
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_custom_providers() {
+        let file = |id: &str, pointer: &str| {
+            format!(
+                r#"{{"providers": [{{"id": "{id}", "endpoint": "https://example.com", "windows": [{{"usedPercent": "{pointer}"}}]}}]}}"#
+            )
+        };
+        assert!(parse_custom_providers(&file("gateway", "/used"), false).is_ok());
+        for (id, pointer) in [
+            ("codex", "/used"),
+            ("Gateway", "/used"),
+            ("gateway", "used"),
+        ] {
+            assert!(
+                parse_custom_providers(&file(id, pointer), false).is_err(),
+                "{id} {pointer}"
+            );
+        }
+        assert!(parse_custom_providers(r#"{"providers": [{"id": "x"}]}"#, false).is_err());
+
+        let http = |extra: &str| {
+            format!(
+                r#"{{"providers": [{{"id": "gateway", "endpoint": "http://example.com", {extra}"windows": [{{"usedPercent": "/used"}}]}}]}}"#
+            )
+        };
+        assert!(parse_custom_providers(&http(""), false).is_ok());
+        assert!(parse_custom_providers(&http(r#""authHeader": "X-Key: k", "#), false).is_err());
+        assert!(parse_custom_providers(
+            &http(r#""authHeader": "X-Key: k", "allowInsecureHttp": true, "#),
+            false
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn loads_custom_providers_into_the_registry() {
+        let dir = std::env::temp_dir().join(format!("codexbar-providers-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str, id: &str| {
+            let path = dir.join(name);
+            fs::write(
+                &path,
+                format!(
+                    r#"{{"providers": [{{"id": "{id}", "endpoint": "https://example.com", "windows": [{{"usedPercent": "/used"}}]}}]}}"#
+                ),
+            )
+            .unwrap();
+            path
+        };
+        let first = file("first.json", "gateway");
+        let second = file("second.json", "relay");
+        let duplicate = file("duplicate.json", "gateway");
+
+        let custom = load_custom_providers(&[first.clone(), second]).unwrap();
+        let registry = provider_registry(&custom);
+        let ids = select_providers(&registry, "all").unwrap();
+        assert!(ids.contains(&"gateway") && ids.contains(&"relay"));
+        assert!(provider_registry(&[])
+            .iter()
+            .all(|provider| provider.id() != "gateway"));
+
+        let error = load_custom_providers(&[first, duplicate]).unwrap_err();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(format!("{error:#}").contains("'gateway'"), "{error:#}");
+    }
+
+    #[test]
+    fn expands_env_references_in_auth_header() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|error| error.into_inner());
+        std::env::set_var("CODEXBAR_TEST_GATEWAY_TOKEN", "secret");
+        std::env::remove_var("CODEXBAR_TEST_UNSET");
+
+        assert_eq!(
+            expand_env_template("Authorization: Bearer ${CODEXBAR_TEST_GATEWAY_TOKEN}"),
+            Ok("Authorization: Bearer secret".to_string())
+        );
+        assert_eq!(
+            expand_env_template("X-Key: ${CODEXBAR_TEST_UNSET}"),
+            Err("CODEXBAR_TEST_UNSET".to_string())
+        );
+        assert_eq!(
+            expand_env_template("X-Key: plain"),
+            Ok("X-Key: plain".to_string())
+        );
+
+        std::env::remove_var("CODEXBAR_TEST_GATEWAY_TOKEN");
+    }
+
+    #[test]
+    fn anthropic_beta_env_reaches_curl_args() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|error| error.into_inner());
+        let beta_arg = || {
+            let headers = claude_request_headers("token");
+            let args = curl_json_args(
+                CLAUDE_USAGE_ENDPOINT,
+                headers.iter().map(String::as_str),
+                Duration::from_secs(15),
+            );
+            let index = args
+                .iter()
+                .position(|arg| arg.starts_with("anthropic-beta:"))
+                .unwrap();
+            assert_eq!(args[index - 1], "-H");
+            args[index].clone()
+        };
+
+        std::env::remove_var("CODEXBAR_ANTHROPIC_BETA");
+        assert_eq!(beta_arg(), "anthropic-beta: oauth-2025-04-20");
+
+        std::env::set_var("CODEXBAR_ANTHROPIC_BETA", " oauth-2026-01-01 ");
+        assert_eq!(beta_arg(), "anthropic-beta: oauth-2026-01-01");
+        std::env::remove_var("CODEXBAR_ANTHROPIC_BETA");
+    }
+
+    #[test]
+    fn command_exit_is_detected_promptly() {
+        // Only the wait is timed; spawning varies too much on loaded machines.
+        let mut child = Command::new("true").spawn().unwrap();
+        let started = Instant::now();
+        let status = child.wait_timeout(Duration::from_secs(10)).unwrap();
+        assert!(status.is_some_and(|status| status.success()));
+        assert!(
+            started.elapsed() < Duration::from_secs(2),
+            "took {:?}",
+            started.elapsed()
+        );
+
+        let output = run_command_with_timeout("true", &[], Duration::from_secs(5)).unwrap();
+        assert!(output.status.success());
+
+        // More output than a pipe buffer holds must not stall the child.
+        let output = run_command_with_timeout(
+            "head",
+            &["-c", "200000", "/dev/zero"],
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert_eq!(output.stdout.len(), 200_000);
+
+        let output =
+            run_command_with_timeout_and_input("cat", &[], Some("piped"), Duration::from_secs(5))
+                .unwrap();
+        assert_eq!(output.stdout, b"piped");
+
+        let started = Instant::now();
+        let error =
+            run_command_with_timeout("sleep", &["5"], Duration::from_millis(100)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn command_output_is_capped() {
+        let mut head = Command::new("head");
+        head.args(["-c", "200000", "/dev/zero"]);
+        let capped = run_command_capped(head, None, Duration::from_secs(5), 1000).unwrap();
+        assert!(capped.output.status.success());
+        assert_eq!(capped.output.stdout.len(), 1000);
+        assert!(capped.truncated);
+
+        let mut printf = Command::new("printf");
+        printf.arg("12345");
+        let exact = run_command_capped(printf, None, Duration::from_secs(5), 5).unwrap();
+        assert_eq!(exact.output.stdout, b"12345");
+        assert!(!exact.truncated);
+    }
+
+    #[test]
+    fn codex_home_is_set_on_spawned_codex() {
+        let inherited = codex_command(&FetchOptions::default());
+        assert_eq!(inherited.get_program(), "codex");
+        assert_eq!(inherited.get_envs().count(), 0);
+
+        let options = FetchOptions {
+            codex_home: Some(PathBuf::from("/tmp/codex-work")),
+            ..FetchOptions::default()
+        };
+        let command = codex_command(&options);
+        let envs = command.get_envs().collect::<Vec<_>>();
+        assert_eq!(
+            envs,
+            vec![(
+                std::ffi::OsStr::new("CODEX_HOME"),
+                Some(std::ffi::OsStr::new("/tmp/codex-work"))
+            )]
+        );
+    }
+
+    #[test]
+    fn strips_ansi_escapes_without_mangling_utf8() {
+        assert_eq!(
+            strip_ansi_sequences("5h limit: 72% left • resets 14:00 — ✓"),
+            "5h limit: 72% left • resets 14:00 — ✓"
+        );
+        assert_eq!(
+            strip_ansi_sequences("\x1b[1m\x1b[38;5;208mWeekly\x1b[0m: \x1b[32m40%\x1b[39m left"),
+            "Weekly: 40% left"
+        );
+        assert_eq!(
+            strip_ansi_sequences("\x1b]0;codex — status\x07\x1b]2;title\x1b\\ready ✓"),
+            "ready ✓"
+        );
+        assert_eq!(
+            strip_ansi_sequences("\x1b]8;;https://example.com\x1b\\link\x1b]8;;\x1b\\ 🙂"),
+            "link 🙂"
+        );
+        assert_eq!(strip_ansi_sequences("\x1b(B\x1b=\x1b7saved\x1b8"), "saved");
+        assert_eq!(strip_ansi_sequences("cut off \x1b[3"), "cut off ");
+    }
+
+    #[test]
+    fn pinned_clock_fixes_entry_timestamps() {
+        let args = FetchOptions {
+            status: true,
+            clock: Arc::new(FixedClock::at_unix_seconds(1_760_000_000)),
+            ..FetchOptions::default()
+        };
+        let entry = codex_entry_from_usage_json(
+            r#"{"rate_limit": {"primary_window": {"used_percent": 28, "reset_after_seconds": 600}}}"#,
+            &args,
+            "openai-web",
+        )
+        .unwrap();
+        assert_eq!(entry.updated_at, format_timestamp(1_760_000_000));
+        assert_eq!(
+            entry.primary.unwrap().resets_at,
+            Some(format_timestamp(1_760_000_600))
+        );
+
+        let status =
+            with_concurrent_status(&args, "claude", || Ok(Some(bare_entry("claude", "api"))))
+                .unwrap()
+                .unwrap()
+                .status
+                .unwrap();
+        assert_eq!(status.updated_at, Some(format_timestamp(1_760_000_000)));
+
+        let pinned = parse_status_reset(
+            "5h limit: 40% left (resets in 2h 13m)",
+            local_now(args.clock.as_ref()),
+        );
+        assert_eq!(pinned, Some(1_760_000_000 + 2 * 3600 + 13 * 60));
+    }
+
+    #[test]
+    fn codex_resets_accept_absolute_and_relative_values() {
+        let now = 1_760_000_000;
+        assert_eq!(absolute_reset_timestamp(1_760_007_200, now), 1_760_007_200);
+        assert_eq!(absolute_reset_timestamp(7200, now), 1_760_007_200);
+        assert_eq!(absolute_reset_timestamp(-5, now), now);
+
+        let window = |resets_at: Option<i64>, resets_in_seconds: Option<i64>| RpcRateLimitWindow {
+            used_percent: Some(10.0),
+            window_duration_mins: Some(300),
+            resets_at,
+            resets_in_seconds,
+        };
+        let reset_of = |window: Option<RateWindow>| {
+            parse_unix_seconds(window.unwrap().resets_at.as_deref().unwrap()).unwrap()
+        };
+
+        let absolute = rate_window_from_codex(Some(window(Some(1_760_007_200), None)), now);
+        assert_eq!(reset_of(absolute), 1_760_007_200);
+
+        let relative = rate_window_from_codex(Some(window(Some(7200), None)), now);
+        assert_eq!(reset_of(relative), now + 7200);
+
+        let explicit = rate_window_from_codex(Some(window(Some(1_760_007_200), Some(60))), now);
+        assert_eq!(reset_of(explicit), now + 60);
+    }
+
+    #[test]
+    fn codex_window_durations_in_the_wrong_unit_are_corrected() {
+        let window = |minutes: u64| RpcRateLimitWindow {
+            used_percent: Some(10.0),
+            window_duration_mins: Some(minutes),
+            resets_at: None,
+            resets_in_seconds: None,
+        };
+        let minutes_of = |minutes: u64| {
+            rate_window_from_codex(Some(window(minutes)), 0)
+                .unwrap()
+                .window_minutes
+        };
+        assert_eq!(minutes_of(168), Some(10080));
+        assert_eq!(minutes_of(604_800), Some(10080));
+        assert_eq!(minutes_of(720), Some(720));
+    }
+
+    #[test]
+    fn detects_empty_200_bodies() {
+        let (body, status_code) = split_curl_body_and_status("\n200\n").unwrap();
+        assert_eq!(status_code, 200);
+        assert!(is_empty_response_body(body));
+
+        let (body, _) = split_curl_body_and_status("  null \n200").unwrap();
+        assert!(is_empty_response_body(body));
+
+        let (body, _) = split_curl_body_and_status("{}\n200").unwrap();
+        assert!(!is_empty_response_body(body));
+        assert!(
+            claude_entry_from_usage_json(body, &FetchOptions::default(), "claude-oauth-api")
+                .is_none()
+        );
+    }
+
+    /// Answers one connection per canned response, in order, and hands back
+    /// the request heads it saw.
+    #[cfg(feature = "native-http")]
+    fn serve_canned_responses(responses: Vec<String>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/oauth/usage", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            responses
+                .into_iter()
+                .map(|response| {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut head = String::new();
+                    // Up to and including the blank line ending the head.
+                    while reader.read_line(&mut head).unwrap() > 2 {}
+                    stream.write_all(response.as_bytes()).unwrap();
+                    head
+                })
+                .collect()
+        });
+        (url, server)
+    }
+
+    #[cfg(feature = "native-http")]
+    #[test]
+    fn native_client_fetches_claude_usage_from_a_mock_server() {
+        let body = r#"{"five_hour": {"utilization": 28.0, "resets_at": null}, "seven_day": {"utilization": 61.0, "resets_at": null}}"#;
+        let throttled = "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 0\r\n\
+                         Content-Length: 0\r\nConnection: close\r\n\r\n"
+            .to_string();
+        let ok = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        let (url, server) = serve_canned_responses(vec![throttled, ok]);
+
+        let options = FetchOptions {
+            retries: 1,
+            timeout: Some(Duration::from_secs(5)),
+            ..FetchOptions::default()
+        };
+        let entry = fetch_claude_usage_from(&NativeHttpClient, &url, Some("test-token"), &options)
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.source.as_deref(), Some("claude-oauth-api"));
+        assert_eq!(entry.primary.unwrap().used_percent, Some(28.0));
+        assert_eq!(entry.secondary.unwrap().used_percent, Some(61.0));
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        for request in requests {
+            let request = request.to_ascii_lowercase();
+            assert!(request.starts_with("get /api/oauth/usage http/1.1\r\n"));
+            assert!(request.contains("\r\nauthorization: bearer test-token\r\n"));
+            assert!(request.contains("\r\nanthropic-beta: "));
+            assert!(request.contains("\r\naccept: application/json\r\n"));
+        }
+
+        // Nothing listening: no response at all is an error, not a status.
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = closed.local_addr().unwrap();
+        drop(closed);
+        assert!(NativeHttpClient
+            .get_json(&format!("http://{address}/"), &[], None)
+            .is_err());
+
+        // A server that never answers runs into the timeout.
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let error = NativeHttpClient
+            .get_json(
+                &format!("http://{}/", silent.local_addr().unwrap()),
+                &[],
+                Some(Duration::from_millis(200)),
+            )
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+    }
+
+    fn http_response(status: u16, headers: &[(&str, &str)], body: &str) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body: body.to_string(),
+        }
+    }
+
+    /// Replies to every request with the same response.
+    struct CannedHttpClient(HttpResponse);
+
+    impl HttpClient for CannedHttpClient {
+        fn get_json(&self, _: &str, _: &[&str], _: Option<Duration>) -> io::Result<HttpResponse> {
+            Ok(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn rejected_claude_token_asks_for_reauth() {
+        let token = "sk-ant-oat01-expired";
+        let options = FetchOptions {
+            retries: 0,
+            ..FetchOptions::default()
+        };
+        for status in [401, 403] {
+            let client = CannedHttpClient(http_response(
+                status,
+                &[],
+                &format!(r#"{{"error": {{"message": "bad token {token}"}}}}"#),
+            ));
+            let error =
+                fetch_claude_usage_from(&client, CLAUDE_USAGE_ENDPOINT, Some(token), &options)
+                    .unwrap_err();
+            let error = provider_error(error);
+            assert_eq!(error.kind(), "authRequired");
+            let message = error.to_string();
+            assert!(
+                message.contains("codexbar auth --provider claude"),
+                "{message}"
+            );
+            assert!(!message.contains(token), "{message}");
+        }
+    }
+
+    /// Fails every request with `kind`, as a client with no response would.
+    struct FailingHttpClient(ErrorKind);
+
+    impl HttpClient for FailingHttpClient {
+        fn get_json(&self, _: &str, _: &[&str], _: Option<Duration>) -> io::Result<HttpResponse> {
+            Err(io::Error::from(self.0))
+        }
+    }
+
+    #[test]
+    fn claude_fetch_failures_keep_their_exit_codes() {
+        let options = FetchOptions {
+            retries: 0,
+            ..FetchOptions::default()
+        };
+        let exit_code = |client: &dyn HttpClient, token: Option<&str>| {
+            // As `ClaudeProvider::fetch` and the CLI see it.
+            let result = fetch_claude_usage_from(client, CLAUDE_USAGE_ENDPOINT, token, &options)
+                .map_err(provider_error)
+                .map_err(anyhow::Error::from);
+            let (entries, first_error) = collect_entries(&["claude"], vec![result], &options);
+            assert!(entries.is_empty());
+            first_error
+                .unwrap()
+                .downcast_ref::<CodexBarError>()
+                .unwrap()
+                .exit_code()
+        };
+        let ok = CannedHttpClient(http_response(200, &[], "{}"));
+
+        assert_eq!(exit_code(&ok, None), 4);
+        assert_eq!(
+            exit_code(&FailingHttpClient(ErrorKind::NotFound), Some("token")),
+            3
+        );
+        assert_eq!(
+            exit_code(&FailingHttpClient(ErrorKind::TimedOut), Some("token")),
+            5
+        );
+        assert_eq!(
+            exit_code(
+                &CannedHttpClient(http_response(503, &[], "")),
+                Some("token")
+            ),
+            7
+        );
+        assert_eq!(
+            exit_code(
+                &FailingHttpClient(ErrorKind::ConnectionRefused),
+                Some("token")
+            ),
+            1
+        );
+    }
+
+    #[test]
+    fn computes_claude_utilization_from_used_and_limit() {
+        let entry = claude_entry_from_usage_json(
+            r#"{
+                "five_hour": {"utilization": "n/a", "used": 30, "limit": 120, "resets_at": null},
+                "seven_day": {"used": "45.5", "limit": 50},
+                "seven_day_opus": {"used": 80, "limit": 0}
+            }"#,
+            &FetchOptions::default(),
+            "claude-oauth-api",
+        )
+        .unwrap();
+        assert_eq!(entry.primary.unwrap().used_percent, Some(25.0));
+        assert_eq!(entry.secondary.unwrap().used_percent, Some(91.0));
+        // A zero limit can't produce a percentage, and nothing else is left.
+        assert_eq!(entry.tertiary, None);
+
+        let over = rate_window_from_claude_json(&json!({"used": 150, "limit": 100}), None);
+        assert_eq!(over.unwrap().used_percent, Some(100.0));
+        let preferred =
+            rate_window_from_claude_json(&json!({"utilization": 10, "used": 9, "limit": 10}), None);
+        assert_eq!(preferred.unwrap().used_percent, Some(10.0));
+    }
+
+    #[test]
+    fn reads_claude_credits_when_present() {
+        let entry = |body: &str| {
+            claude_entry_from_usage_json(body, &FetchOptions::default(), "claude-oauth-api")
+                .unwrap()
+        };
+        let five_hour = r#""five_hour": {"utilization": 12.0, "resets_at": null}"#;
+
+        let with_balance = entry(&format!(
+            r#"{{{five_hour}, "credits": {{"balance": "42.50", "currency": "USD"}}}}"#
+        ));
+        assert_eq!(with_balance.credits_remaining, Some(42.5));
+
+        let extra_usage = entry(&format!(
+            r#"{{{five_hour}, "extra_usage": {{"is_enabled": true, "monthly_limit": 50.0, "used_credits": 12.5}}}}"#
+        ));
+        assert_eq!(extra_usage.credits_remaining, Some(37.5));
+
+        let disabled = entry(&format!(
+            r#"{{{five_hour}, "extra_usage": {{"is_enabled": false, "monthly_limit": null}}}}"#
+        ));
+        assert_eq!(disabled.credits_remaining, None);
+        assert_eq!(entry(&format!("{{{five_hour}}}")).credits_remaining, None);
+    }
+
+    #[test]
+    fn parses_curl_responses_after_redirects() {
+        let response = parse_curl_response(
+            "HTTP/1.1 302 Found\r\nlocation: /usage\r\n\r\n\
+             HTTP/2 429\r\nretry-after: 7\r\n\r\n{\"error\":\"rate_limited\"}\n429",
+        )
+        .unwrap();
+        assert_eq!(
+            response,
+            http_response(429, &[("retry-after", "7")], "{\"error\":\"rate_limited\"}")
+        );
+        assert_eq!(response.header("Retry-After"), Some("7"));
+
+        assert!(parse_curl_response("\n000").is_err());
+        assert!(parse_curl_response("").is_err());
+    }
+
+    #[test]
+    fn retries_rate_limited_requests_with_backoff() {
+        let mut responses = vec![
+            http_response(429, &[("retry-after", "7")], "{\"error\":\"rate_limited\"}"),
+            http_response(503, &[("content-type", "text/plain")], "unavailable"),
+            http_response(200, &[], "{\"five_hour\":{}}"),
+        ]
+        .into_iter();
+        let mut waits = Vec::new();
+
+        let response = send_with_retries(
+            "claude",
+            3,
+            || Ok(responses.next().unwrap()),
+            |delay| waits.push(delay),
+        )
+        .unwrap();
+        assert_eq!(response, http_response(200, &[], "{\"five_hour\":{}}"));
+        assert_eq!(waits, [Duration::from_secs(7), Duration::from_secs(2)]);
+
+        let mut attempts = 0;
+        let exhausted = send_with_retries(
+            "claude",
+            2,
+            || {
+                attempts += 1;
+                Ok(http_response(500, &[], ""))
+            },
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(exhausted.status, 500);
+        assert_eq!(attempts, 3);
+
+        let mut attempts = 0;
+        let unauthorized = send_with_retries(
+            "claude",
+            3,
+            || {
+                attempts += 1;
+                Ok(http_response(401, &[], "{}"))
+            },
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(unauthorized.status, 401);
+        assert_eq!(attempts, 1);
+
+        // Connection failures are retried; timeouts come straight back.
+        let mut attempts = 0;
+        let refused = send_with_retries(
+            "claude",
+            2,
+            || {
+                attempts += 1;
+                Err::<HttpResponse, _>(io::Error::from(ErrorKind::ConnectionRefused))
+            },
+            |_| {},
+        )
+        .unwrap_err();
+        assert_eq!(refused.kind(), ErrorKind::ConnectionRefused);
+        assert_eq!(attempts, 3);
+
+        let mut attempts = 0;
+        let timed_out = send_with_retries(
+            "claude",
+            2,
+            || {
+                attempts += 1;
+                Err::<HttpResponse, _>(io::Error::from(ErrorKind::TimedOut))
+            },
+            |_| {},
+        )
+        .unwrap_err();
+        assert_eq!(timed_out.kind(), ErrorKind::TimedOut);
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn status_fetch_failure_keeps_usage_entry() {
+        let args = FetchOptions {
+            status: true,
+            ..FetchOptions::default()
+        };
+        let usage = || Ok(Some(bare_entry("codex", "rpc")));
+
+        let entry = with_concurrent_status(&args, "codex", usage)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            entry.status.and_then(|status| status.url).as_deref(),
+            Some("https://status.openai.com/")
+        );
+
+        // No status page for this provider: the status is dropped, not the entry.
+        let entry = with_concurrent_status(&args, "cursor", usage)
+            .unwrap()
+            .unwrap();
+        assert!(entry.status.is_none());
+
+        let failed = with_concurrent_status(&args, "codex", || bail!("usage down"));
+        assert!(failed.is_err());
+
+        let entry = with_concurrent_status(&FetchOptions::default(), "codex", usage)
+            .unwrap()
+            .unwrap();
+        assert!(entry.status.is_none());
+    }
+
+    #[test]
+    fn claude_credentials_honor_override_and_xdg() {
+        let dir = std::env::temp_dir().join(format!("codexbar-claude-{}", std::process::id()));
+        let override_path = dir.join("override.json");
+        let xdg_path = dir.join("claude").join(".credentials.json");
+        fs::create_dir_all(xdg_path.parent().unwrap()).unwrap();
+        fs::write(
+            &override_path,
+            r#"{"claudeAiOauth": {"accessToken": " override "}}"#,
+        )
+        .unwrap();
+        fs::write(
+            &xdg_path,
+            r#"{"claudeAiOauth": {"accessToken": "xdg", "subscriptionType": "max"}}"#,
+        )
+        .unwrap();
+
+        // `HOME` points at the temp dir too, so real credentials never answer.
+        let env = |credentials: PathBuf| {
+            let dir = dir.clone();
+            move |name: &str| match name {
+                "CODEXBAR_CLAUDE_CREDENTIALS" => Some(credentials.display().to_string()),
+                "HOME" | "XDG_CONFIG_HOME" => Some(dir.display().to_string()),
+                _ => None,
+            }
+        };
+        let paths = claude_credentials_paths_from(env(override_path.clone()));
+        assert_eq!(
+            paths,
+            [
+                override_path.clone(),
+                dir.join(".claude").join(".credentials.json"),
+                xdg_path.clone(),
+            ]
+        );
+        assert_eq!(
+            first_claude_oauth_access_token(&paths).as_deref(),
+            Some("override")
+        );
+        assert_eq!(
+            claude_oauth_access_token_from_file(&xdg_path).as_deref(),
+            Some("xdg")
+        );
+        assert_eq!(claude_plan_from_file(&xdg_path).as_deref(), Some("max"));
+        assert_eq!(claude_plan_from_file(&override_path), None);
+
+        // A missing override falls through instead of giving up.
+        let paths = claude_credentials_paths_from(env(dir.join("missing.json")));
+        assert_eq!(
+            first_claude_oauth_access_token(&paths).as_deref(),
+            Some("xdg")
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn replay_session(messages: &[Value]) -> CodexRpcSession {
+        let transcript = messages
+            .iter()
+            .map(|message| format!("{message}\n"))
+            .collect::<String>();
+        CodexRpcSession::from_streams(io::sink(), io::Cursor::new(transcript.into_bytes()))
+    }
+
+    #[test]
+    fn reads_codex_entry_from_rpc_transcript() {
+        let mut session = replay_session(&[
+            json!({"id": 1, "result": {"userAgent": "codex/0.50.0"}}),
+            json!({"method": "account/updated", "params": {}}),
+            json!({"id": 2, "result": {"account": {
+                "type": "chatgpt", "email": "dev@example.com", "planType": "pro"
+            }}}),
+            json!({"id": 3, "result": {"rateLimits": {
+                "primary": {"usedPercent": 25.0, "windowDurationMins": 300, "resetsAt": 1760018000},
+                "secondary": {"usedPercent": 60.0, "windowDurationMins": 10080, "resetsAt": 1760500000},
+                "credits": {"balance": "12.5"}
+            }}}),
+        ]);
+
+        let entry = codex_entry_from_session(&FetchOptions::default(), &mut session)
+            .unwrap()
+            .unwrap();
+        let primary = entry.primary.unwrap();
+        assert_eq!(primary.used_percent, Some(25.0));
+        assert_eq!(primary.window_minutes, Some(300));
+        assert_eq!(
+            entry
+                .secondary
+                .unwrap()
+                .resets_at
+                .as_deref()
+                .and_then(parse_unix_seconds),
+            Some(1_760_500_000)
+        );
+        assert_eq!(entry.credits_remaining, Some(12.5));
+        assert_eq!(entry.source.as_deref(), Some("codex-cli"));
+        let identity = entry.identity.unwrap();
+        assert_eq!(identity.account_email.as_deref(), Some("dev@example.com"));
+        assert_eq!(identity.login_method.as_deref(), Some("chatgpt"));
+        assert_eq!(identity.plan.as_deref(), Some("pro"));
+    }
+
+    #[test]
+    fn surfaces_rpc_errors_and_early_close() {
+        let mut session = replay_session(&[
+            json!({"id": 1, "result": {}}),
+            json!({"id": 2, "result": {"account": null}}),
+            json!({"id": 3, "error": {"code": -32603, "message": "not logged in"}}),
+        ]);
+        let error = codex_entry_from_session(&FetchOptions::default(), &mut session).unwrap_err();
+        let message = format!("{error:#}");
+        assert!(message.contains("account/rateLimits/read"), "{message}");
+        assert!(message.contains("not logged in"), "{message}");
+
+        let mut session = replay_session(&[json!({"id": 1, "result": {}})]);
+        let error = codex_entry_from_session(&FetchOptions::default(), &mut session).unwrap_err();
+        assert!(format!("{error:#}").contains("closed stdout"));
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn skips_notifications_and_rejects_server_requests() {
+        let transcript = [
+            json!({"method": "codex/event", "params": {"msg": "session_configured"}}),
+            json!({"id": 1, "method": "execCommandApproval", "params": {}}),
+            json!({"id": 1, "result": {"account": null}}),
+        ]
+        .iter()
+        .map(|message| format!("{message}\n"))
+        .collect::<String>();
+        let written = SharedBuffer::default();
+        let mut session = CodexRpcSession::from_streams(
+            written.clone(),
+            io::Cursor::new(transcript.into_bytes()),
+        );
+
+        let result = session.call("account/read", json!({})).unwrap();
+        assert_eq!(result, json!({"account": null}));
+
+        let written = String::from_utf8(written.0.lock().unwrap().clone()).unwrap();
+        let sent = written
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0]["method"], "account/read");
+        assert_eq!(sent[1]["id"], 1);
+        assert_eq!(sent[1]["error"]["code"], -32601);
+    }
+
+    #[test]
+    fn silent_app_server_times_out_and_is_killed() {
+        let mut child = Command::new("sleep")
+            .arg("30")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut session = CodexRpcSession::from_streams(
+            child.stdin.take().unwrap(),
+            child.stdout.take().unwrap(),
+        );
+        session.child = Some(child);
+        session.read_timeout = Duration::from_millis(200);
+
+        let started = Instant::now();
+        let error = session.call("account/read", json!({})).unwrap_err();
+        assert!(
+            matches!(
+                error.downcast_ref::<CodexBarError>(),
+                Some(CodexBarError::Timeout(_))
+            ),
+            "{error:#}"
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let child = session.child.as_mut().unwrap();
+        assert!(child.try_wait().unwrap().is_some());
+    }
+
+    #[test]
+    fn parses_reset_times_from_status_lines() {
+        let offset = UtcOffset::from_hms(2, 0, 0).unwrap();
+        let at = |year, month, day, hour, minute| {
+            Date::from_calendar_date(year, month, day)
+                .unwrap()
+                .with_hms(hour, minute, 0)
+                .unwrap()
+                .assume_offset(offset)
+        };
+        let now = at(2025, Month::October, 20, 10, 0);
+        let reset = |line: &str| parse_status_reset(line, now);
+
+        assert_eq!(
+            reset("5h limit:     [████░░░░] 72% left (resets 15:04)"),
+            Some(at(2025, Month::October, 20, 15, 4).unix_timestamp())
+        );
+        assert_eq!(
+            reset("5h limit: 72% left (resets 3:00pm)"),
+            Some(at(2025, Month::October, 20, 15, 0).unix_timestamp())
+        );
+        assert_eq!(
+            reset("5h limit: 72% left (resets at 9:30 AM)"),
+            Some(at(2025, Month::October, 21, 9, 30).unix_timestamp())
+        );
+        assert_eq!(
+            reset("Weekly limit: [██░░░░░░] 39% left (resets 09:00 on 22 Oct)"),
+            Some(at(2025, Month::October, 22, 9, 0).unix_timestamp())
+        );
+        assert_eq!(
+            reset("Weekly limit: 39% left (resets 12am on Jan 3)"),
+            Some(at(2026, Month::January, 3, 0, 0).unix_timestamp())
+        );
+        assert_eq!(
+            reset("Weekly limit: 39% left (resets 09:00 on Oct 22, 2026)"),
+            Some(at(2026, Month::October, 22, 9, 0).unix_timestamp())
+        );
+        assert_eq!(
+            reset("5h limit: 72% left (resets in 2h 13m)"),
+            Some(now.unix_timestamp() + 2 * 3600 + 13 * 60)
+        );
+        assert_eq!(
+            reset("5h limit: 72% left (resets in 9223372036854775807d)"),
+            None
+        );
+        assert_eq!(
+            reset("5h limit: 72% left (resets in 9223372036854775800s 9s)"),
+            None
+        );
+
+        assert_eq!(reset("5h limit: 72% left"), None);
+        assert_eq!(reset("5h limit: 72% left (resets soon)"), None);
+        assert_eq!(reset("5h limit: 72% left (resets 25:00)"), None);
+    }
+
+    #[test]
+    fn detects_app_server_collisions() {
+        assert!(is_app_server_collision(
+            "Error: app-server already running for profile default"
+        ));
+        assert!(is_app_server_collision(
+            "bind failed: Address already in use (os error 98)"
+        ));
+        assert!(!is_app_server_collision("error: not logged in"));
+        assert!(!is_app_server_collision(""));
+    }
+
+    #[test]
+    fn rpc_lock_serializes_sessions() {
+        let path = std::env::temp_dir().join(format!("codexbar-rpc-{}.lock", std::process::id()));
+        let held = acquire_rpc_lock(&path, Duration::ZERO).unwrap();
+
+        let error = acquire_rpc_lock(&path, Duration::from_millis(150)).unwrap_err();
+        assert!(error.to_string().contains("another codexbar process"));
+
+        drop(held);
+        assert!(acquire_rpc_lock(&path, Duration::ZERO).is_ok());
+        let _ = fs::remove_file(path);
+    }
+
+    /// Replays canned `initialize` responses and records what was sent.
+    struct ScriptedChannel {
+        responses: Vec<std::result::Result<Value, Value>>,
+        sent: Vec<(String, Value)>,
+    }
+
+    impl RpcChannel for ScriptedChannel {
+        fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+            self.sent.push((method.to_string(), params));
+            match self.responses.remove(0) {
+                Ok(result) => Ok(result),
+                Err(error) => Err(RpcServerError::new(method, &error).into()),
+            }
+        }
+
+        fn send_notification(&mut self, method: &str, params: Value) -> Result<()> {
+            self.sent.push((method.to_string(), params));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn raw_dumps_redact_credentials() {
+        let raw = "GET https://api.anthropic.com/api/oauth/usage\n\
+                   Authorization: Bearer sk-ant-oat01-secret\n\
+                   anthropic-beta: oauth-2025-04-20\n\
+                   \n\
+                   HTTP/2 200\r\n\
+                   set-cookie: session=abc123; Path=/\r\n\
+                   \r\n\
+                   {\"five_hour\":{\"utilization\":12.5},\"refresh_token\":\"rt-secret\"}\n\
+                   200";
+        let dir = std::env::temp_dir().join(format!("codexbar-raw-{}", std::process::id()));
+        let args = FetchOptions {
+            raw: Some(RawDump::Dir(dir.clone())),
+            ..FetchOptions::default()
+        };
+        args.dump_raw("claude-usage.http", raw);
+        args.dump_raw(
+            "codex-rpc.jsonl",
+            "{\"id\":2,\"result\":{\"account\":{\"email\":\"me@example.com\",\"accessToken\":\"at-secret\"}}}\n",
+        );
+
+        let http = fs::read_to_string(dir.join("claude-usage.http")).unwrap();
+        let rpc = fs::read_to_string(dir.join("codex-rpc.jsonl")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        for secret in ["sk-ant-oat01-secret", "abc123", "rt-secret"] {
+            assert!(!http.contains(secret), "{secret} leaked:\n{http}");
+        }
+        assert!(http.contains("Authorization: [redacted]\n"));
+        assert!(http.contains("set-cookie: [redacted]\r\n"));
+        assert!(http.contains("anthropic-beta: oauth-2025-04-20\n"));
+        assert!(http.contains("\"utilization\":12.5"));
+        assert!(!rpc.contains("at-secret"));
+        assert!(rpc.contains("me@example.com"));
+    }
+
+    #[test]
+    fn app_server_start_retries_tear_down_failed_attempts() {
+        /// Stands in for a session; counts how many are alive.
+        struct FakeSession(Arc<Mutex<usize>>);
+
+        impl Drop for FakeSession {
+            fn drop(&mut self) {
+                *self.0.lock().unwrap() -= 1;
+            }
+        }
+
+        let live = Arc::new(Mutex::new(0usize));
+        let mut launches = 0;
+        let mut delays = Vec::new();
+        let launch = || -> Result<Option<FakeSession>> {
+            *live.lock().unwrap() += 1;
+            let session = FakeSession(Arc::clone(&live));
+            launches += 1;
+            if launches <= 2 {
+                bail!("another app-server is already running");
+            }
+            Ok(Some(session))
+        };
+
+        let session = start_with_retries(3, launch, |delay| {
+            assert_eq!(*live.lock().unwrap(), 0, "previous attempt still running");
+            delays.push(delay);
+        })
+        .unwrap();
+        assert!(session.is_some());
+        assert_eq!(*live.lock().unwrap(), 1);
+        assert_eq!(delays.len(), 2);
+        assert!(delays[0] >= Duration::from_millis(250) && delays[0] < Duration::from_millis(500));
+        assert!(delays[1] >= Duration::from_millis(500) && delays[1] < Duration::from_millis(750));
+
+        let exhausted = start_with_retries::<FakeSession, _, _>(1, || bail!("still busy"), |_| {});
+        assert!(exhausted.is_err());
+    }
+
+    #[test]
+    fn initialize_retries_with_a_supported_protocol_version() {
+        let mut channel = ScriptedChannel {
+            responses: vec![
+                Err(json!({
+                    "code": -32600,
+                    "message": "unsupported protocol version",
+                    "data": {"supportedVersions": ["2024-11-05", "2025-03-26"]}
+                })),
+                Ok(json!({})),
+            ],
+            sent: Vec::new(),
+        };
+        initialize_rpc(&mut channel).unwrap();
+
+        let methods = channel
+            .sent
+            .iter()
+            .map(|(method, _)| method.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(methods, ["initialize", "initialize", "initialized"]);
+        assert!(channel.sent[0].1.get("protocolVersion").is_none());
+        assert_eq!(channel.sent[1].1["protocolVersion"], json!("2025-03-26"));
+    }
+
+    #[test]
+    fn initialize_rejection_without_versions_suggests_upgrade() {
+        let mut channel = ScriptedChannel {
+            responses: vec![Err(
+                json!({"code": -32600, "message": "client version too old"}),
+            )],
+            sent: Vec::new(),
+        };
+        let error = format!("{:#}", initialize_rpc(&mut channel).unwrap_err());
+        assert!(error.contains("upgrade codex"));
+        assert!(error.contains("client version too old"));
+
+        let mut channel = ScriptedChannel {
+            responses: vec![Err(json!({"code": -32603, "message": "internal error"}))],
+            sent: Vec::new(),
+        };
+        let error = format!("{:#}", initialize_rpc(&mut channel).unwrap_err());
+        assert!(!error.contains("upgrade codex"));
+    }
+
+    #[test]
+    fn fetches_providers_concurrently_in_order() {
+        let started = Instant::now();
+        let results =
+            fetch_concurrently(&["codex", "claude", "cursor"], |provider| match provider {
+                "codex" => {
+                    thread::sleep(Duration::from_millis(300));
+                    Ok(Some(bare_entry(provider, "rpc")))
+                }
+                "claude" => {
+                    thread::sleep(Duration::from_millis(200));
+                    bail!("token expired")
+                }
+                _ => Ok(None),
+            });
+        let elapsed = started.elapsed();
+
+        assert!(elapsed < Duration::from_millis(450), "took {elapsed:?}");
+        assert_eq!(results.len(), 3);
+        assert_eq!(
+            results[0].as_ref().unwrap().as_ref().unwrap().provider,
+            "codex"
+        );
+        assert_eq!(
+            results[1].as_ref().unwrap_err().to_string(),
+            "token expired"
+        );
+        assert!(results[2].as_ref().unwrap().is_none());
+    }
+
+    #[test]
+    fn finds_binary_in_search_dirs() {
+        let dir = std::env::temp_dir().join(format!("codexbar-path-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("codex"), "#!/bin/sh\n").unwrap();
+
+        let missing = dir.join("missing");
+        assert_eq!(
+            find_binary_in_dirs("codex", vec![missing.clone(), dir.clone()]),
+            Some(dir.join("codex"))
+        );
+        assert_eq!(
+            find_binary_in_dirs("claude", vec![missing, dir.clone()]),
+            None
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn health_report_serializes_minimal_shape() {
+        let health = ProviderHealth::new("codex", false, "codex CLI not found on PATH");
+        assert_eq!(
+            serde_json::to_value(&health).unwrap(),
+            json!({"provider": "codex", "healthy": false, "reason": "codex CLI not found on PATH"})
+        );
+        assert!(!provider_health("unknown").healthy);
+    }
+
+    /// A backend whose CLI is missing: lookups miss and stores fail.
+    struct UnavailableSecretBackend;
+
+    impl SecretBackend for UnavailableSecretBackend {
+        fn name(&self) -> &'static str {
+            "unavailable"
+        }
+
+        fn lookup(&self, _key: &SecretKey) -> Option<String> {
+            None
+        }
+
+        fn store(&self, _key: &SecretKey, _label: &str, _value: &str) -> Result<()> {
+            bail!("backend unavailable")
+        }
+
+        fn clear(&self, _key: &SecretKey) {}
+    }
+
+    /// A locked collection that reports success with an empty secret.
+    struct LockedSecretBackend;
+
+    impl SecretBackend for LockedSecretBackend {
+        fn name(&self) -> &'static str {
+            "locked"
+        }
+
+        fn lookup(&self, _key: &SecretKey) -> Option<String> {
+            Some("  \n".to_string())
+        }
+
+        fn store(&self, _key: &SecretKey, _label: &str, _value: &str) -> Result<()> {
+            bail!("collection locked")
+        }
+
+        fn clear(&self, _key: &SecretKey) {}
+    }
+
+    #[test]
+    #[cfg(not(all(
+        feature = "native-keyring",
+        any(target_os = "macos", target_os = "windows")
+    )))]
+    fn system_keyring_uses_command_line_tools() {
+        assert_eq!(
+            KeyringSecretBackend::system().backend_names(),
+            ["secret-tool", "kwallet-query"]
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "native-keyring", target_os = "macos"))]
+    fn system_keyring_uses_keychain_on_macos() {
+        assert_eq!(KeyringSecretBackend::system().backend_names(), ["keychain"]);
+    }
+
+    #[test]
+    #[cfg(all(feature = "native-keyring", target_os = "windows"))]
+    fn system_keyring_uses_credential_manager_on_windows() {
+        assert_eq!(
+            KeyringSecretBackend::system().backend_names(),
+            ["credential-manager"]
+        );
+    }
+
+    fn populated_backend(value: &str) -> MemorySecretBackend {
+        let backend = MemorySecretBackend::default();
+        backend
+            .store(
+                &SecretKey::scoped("claude", DEFAULT_SECRET_ACCOUNT, "oauth_access_token"),
+                "",
+                value,
+            )
+            .unwrap();
+        backend
+    }
+
+    #[test]
+    fn keyring_chain_falls_through_unavailable_and_locked_backends() {
+        let key = SecretKey::scoped("claude", DEFAULT_SECRET_ACCOUNT, "oauth_access_token");
+        let chain = KeyringSecretBackend {
+            backends: vec![
+                Box::new(UnavailableSecretBackend),
+                Box::new(LockedSecretBackend),
+                Box::new(populated_backend("kwallet-token")),
+            ],
+        };
+        assert_eq!(chain.lookup(&key).as_deref(), Some("kwallet-token"));
+
+        let dead = KeyringSecretBackend {
+            backends: vec![
+                Box::new(UnavailableSecretBackend),
+                Box::new(LockedSecretBackend),
+            ],
+        };
+        assert_eq!(dead.lookup(&key), None);
+        assert!(dead.store(&key, "label", "value").is_err());
+
+        let fallback = KeyringSecretBackend {
+            backends: vec![
+                Box::new(UnavailableSecretBackend),
+                Box::new(MemorySecretBackend::default()),
+            ],
+        };
+        fallback.store(&key, "label", "stored").unwrap();
+        assert_eq!(fallback.lookup(&key).as_deref(), Some("stored"));
+    }
+
+    #[test]
+    fn reports_where_the_claude_token_came_from() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|error| error.into_inner());
+        std::env::remove_var("CODEXBAR_CLAUDE_OAUTH_TOKEN");
+        std::env::remove_var("CLAUDE_OAUTH_TOKEN");
+        let source = |backend: &dyn SecretBackend, file: Option<&str>| {
+            resolve_claude_oauth_access_token_with_source(
+                || {
+                    lookup_scoped_secret(
+                        backend,
+                        "claude",
+                        DEFAULT_SECRET_ACCOUNT,
+                        "oauth_access_token",
+                    )
+                },
+                || file.map(str::to_string),
+            )
+            .map(|(_, source)| source)
+        };
+        let dead = KeyringSecretBackend {
+            backends: vec![Box::new(UnavailableSecretBackend)],
+        };
+        let keyring = populated_backend("keyring-token");
+
+        assert_eq!(source(&dead, None), None);
+        assert_eq!(
+            source(&dead, Some("file-token")),
+            Some(ClaudeTokenSource::CredentialsFile)
+        );
+        assert_eq!(
+            source(&keyring, Some("file-token")),
+            Some(ClaudeTokenSource::Keyring)
+        );
+
+        std::env::set_var("CLAUDE_OAUTH_TOKEN", "secret-from-env");
+        assert_eq!(
+            source(&keyring, None),
+            Some(ClaudeTokenSource::Env("CLAUDE_OAUTH_TOKEN"))
+        );
+        std::env::set_var("CODEXBAR_CLAUDE_OAUTH_TOKEN", "  ");
+        assert_eq!(
+            source(&keyring, None),
+            Some(ClaudeTokenSource::Env("CLAUDE_OAUTH_TOKEN"))
+        );
+        std::env::set_var("CODEXBAR_CLAUDE_OAUTH_TOKEN", "preferred");
+        let found = source(&keyring, None).unwrap();
+        assert_eq!(found, ClaudeTokenSource::Env("CODEXBAR_CLAUDE_OAUTH_TOKEN"));
+        assert!(!found.describe().contains("preferred"));
+
+        std::env::remove_var("CODEXBAR_CLAUDE_OAUTH_TOKEN");
+        std::env::remove_var("CLAUDE_OAUTH_TOKEN");
+    }
+
+    #[test]
+    fn logout_clears_scoped_and_legacy_secrets() {
+        let key = SecretKey::scoped("claude", "work", "oauth_access_token");
+        assert_eq!(
+            secret_tool_clear_args(&key),
+            [
+                "clear",
+                "service",
+                "codexbar",
+                "provider",
+                "claude",
+                "account",
+                "work",
+                "field",
+                "oauth_access_token",
+            ]
+        );
+        let entry = key.kwallet_entry();
+        assert_eq!(
+            kwallet_clear_args(&entry, KWALLET_WALLETS[0]),
+            [
+                "-f",
+                "CodexBar",
+                "-d",
+                "claude.work.oauth_access_token",
+                "kdewallet"
+            ]
+        );
+        let legacy = SecretKey::legacy("claude", "oauth_access_token");
+        assert_eq!(
+            secret_tool_clear_args(&legacy),
+            [
+                "clear",
+                "service",
+                "codexbar",
+                "provider",
+                "claude",
+                "field",
+                "oauth_access_token"
+            ]
+        );
+
+        let backend = populated_backend("token");
+        backend.store(&legacy, "", "old-token").unwrap();
+        backend
+            .store(
+                &SecretKey::scoped("codex", DEFAULT_SECRET_ACCOUNT, "access_token"),
+                "",
+                "kept",
+            )
+            .unwrap();
+        assert_eq!(
+            logout_secrets(&backend, "claude", DEFAULT_SECRET_ACCOUNT),
+            [
+                format!("claude.{DEFAULT_SECRET_ACCOUNT}.oauth_access_token"),
+                "claude.oauth_access_token".to_string(),
+            ]
+        );
+        assert!(logout_secrets(&backend, "claude", DEFAULT_SECRET_ACCOUNT).is_empty());
+        assert_eq!(backend.entries.borrow().len(), 1);
+        assert!(logout_secrets(&backend, "cursor", DEFAULT_SECRET_ACCOUNT).is_empty());
+    }
+
+    #[test]
+    fn claude_token_falls_through_to_credentials_file() {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|error| error.into_inner());
+        std::env::remove_var("CODEXBAR_CLAUDE_OAUTH_TOKEN");
+        std::env::remove_var("CLAUDE_OAUTH_TOKEN");
+
+        let dead = KeyringSecretBackend {
+            backends: vec![
+                Box::new(UnavailableSecretBackend),
+                Box::new(LockedSecretBackend),
+            ],
+        };
+        assert_eq!(
+            resolve_claude_oauth_access_token_from(&dead, DEFAULT_SECRET_ACCOUNT, || Some(
+                "file-token".to_string()
+            ))
+            .as_deref(),
+            Some("file-token")
+        );
+
+        let keyring = populated_backend("keyring-token");
+        assert_eq!(
+            resolve_claude_oauth_access_token_from(&keyring, DEFAULT_SECRET_ACCOUNT, || Some(
+                "file-token".to_string()
+            ))
+            .as_deref(),
+            Some("keyring-token")
+        );
+        assert_eq!(
+            resolve_claude_oauth_access_token_from(&dead, DEFAULT_SECRET_ACCOUNT, || None),
+            None
+        );
+    }
+
+    #[test]
+    fn secret_keys_include_account_segment() {
+        let scoped = SecretKey::scoped("claude", "work", "oauth_access_token");
+        assert_eq!(
+            scoped.secret_tool_attributes(),
+            vec![
+                "service",
+                "codexbar",
+                "provider",
+                "claude",
+                "account",
+                "work",
+                "field",
+                "oauth_access_token"
+            ]
+        );
+        assert_eq!(scoped.kwallet_entry(), "claude.work.oauth_access_token");
+
+        let legacy = SecretKey::legacy("claude", "oauth_access_token");
+        assert_eq!(legacy.kwallet_entry(), "claude.oauth_access_token");
+        assert!(!legacy.secret_tool_attributes().contains(&"account"));
+    }
+
+    #[test]
+    fn secret_keys_are_namespaced_by_provider() {
+        let codex = SecretKey::scoped("codex", DEFAULT_SECRET_ACCOUNT, "access_token");
+        assert_eq!(
+            codex.secret_tool_attributes(),
+            vec![
+                "service",
+                "codexbar",
+                "provider",
+                "codex",
+                "account",
+                DEFAULT_SECRET_ACCOUNT,
+                "field",
+                "access_token"
+            ]
+        );
+        assert_eq!(
+            codex.kwallet_entry(),
+            format!("codex.{DEFAULT_SECRET_ACCOUNT}.access_token")
+        );
+        assert_eq!(
+            SecretKey::legacy("codex", "access_token").kwallet_entry(),
+            "codex.access_token"
+        );
+
+        let backend = MemorySecretBackend::default();
+        backend.store(&codex, "", "codex-token").unwrap();
+        backend
+            .store(
+                &SecretKey::scoped("claude", DEFAULT_SECRET_ACCOUNT, "access_token"),
+                "",
+                "claude-token",
+            )
+            .unwrap();
+        assert_eq!(
+            lookup_scoped_secret(&backend, "codex", DEFAULT_SECRET_ACCOUNT, "access_token")
+                .as_deref(),
+            Some("codex-token")
+        );
+        assert_eq!(
+            lookup_scoped_secret(&backend, "claude", DEFAULT_SECRET_ACCOUNT, "access_token")
+                .as_deref(),
+            Some("claude-token")
+        );
+    }
+
+    #[test]
+    fn scoped_lookup_only_reads_active_account() {
+        let backend = MemorySecretBackend::default();
+        backend
+            .store(
+                &SecretKey::scoped("claude", "work", "token"),
+                "",
+                "work-token",
+            )
+            .unwrap();
+        backend
+            .store(
+                &SecretKey::scoped("claude", "personal", "token"),
+                "",
+                "home-token",
+            )
+            .unwrap();
+
+        assert_eq!(
+            lookup_scoped_secret(&backend, "claude", "work", "token").as_deref(),
+            Some("work-token")
+        );
+        assert_eq!(
+            lookup_scoped_secret(&backend, "claude", "personal", "token").as_deref(),
+            Some("home-token")
+        );
+        assert_eq!(
+            lookup_scoped_secret(&backend, "claude", "other", "token"),
+            None
+        );
+    }
+
+    #[test]
+    fn legacy_secret_migrates_to_default_account() {
+        let backend = MemorySecretBackend::default();
+        backend
+            .store(&SecretKey::legacy("claude", "token"), "", "legacy-token")
+            .unwrap();
+
+        assert_eq!(
+            lookup_scoped_secret(&backend, "claude", "work", "token"),
+            None
+        );
+        assert_eq!(
+            lookup_scoped_secret(&backend, "claude", DEFAULT_SECRET_ACCOUNT, "token").as_deref(),
+            Some("legacy-token")
+        );
+        assert_eq!(
+            backend.lookup(&SecretKey::scoped(
+                "claude",
+                DEFAULT_SECRET_ACCOUNT,
+                "token"
+            )),
+            Some("legacy-token".to_string())
+        );
+        assert_eq!(backend.lookup(&SecretKey::legacy("claude", "token")), None);
+    }
+
+    #[test]
+    fn parses_config_and_reports_bad_lines() {
+        let config = parse_usage_config(
+            "# defaults for codexbar usage\n\
+             provider = \"claude\"\n\
+             status = true\n\
+             timeout = 30\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            UsageConfig {
+                provider: Some("claude".to_string()),
+                source: None,
+                status: Some(true),
+                timeout: Some(Duration::from_secs(30)),
+            }
+        );
+        assert_eq!(parse_usage_config("").unwrap(), UsageConfig::default());
+
+        let error = parse_usage_config("provider = \"codex\"\nstatus = \"yes\"\n").unwrap_err();
+        assert!(error.starts_with("2: "), "{error}");
+        let error = parse_usage_config("\n\ntimeout = 0\n").unwrap_err();
+        assert!(error.starts_with("3: "), "{error}");
+        let error = parse_usage_config("source = \"oauth\"\nproviders = [\n").unwrap_err();
+        assert!(error.starts_with("2: "), "{error}");
+        let error = parse_usage_config("verbose = true\n").unwrap_err();
+        assert!(error.contains("unknown key `verbose`"), "{error}");
+    }
+
+    #[test]
+    fn config_defaults_yield_to_the_source_env() {
+        let unset = |_: &str| None;
+        let config = UsageConfig {
+            source: Some("oauth".to_string()),
+            status: Some(true),
+            timeout: Some(Duration::from_secs(30)),
+            ..UsageConfig::default()
+        };
+        let options = config.fetch_options_from(unset);
+        assert_eq!(options.source, "oauth");
+        assert!(options.status);
+        assert_eq!(options.timeout, Some(Duration::from_secs(30)));
+        assert_eq!(
+            UsageConfig::default().fetch_options_from(unset).source,
+            "auto"
+        );
+
+        let env = |name: &str| (name == SOURCE_ENV).then(|| "api".to_string());
+        assert_eq!(config.fetch_options_from(env).source, "api");
+    }
+
+    #[test]
+    fn legacy_lookup_ignores_other_accounts_when_backends_match_subsets() {
+        let backend = SubsetSecretBackend::default();
+        let work = SecretKey::scoped("claude", "work", "token");
+        backend.store(&work, "", "work-token").unwrap();
+
+        // A subset match would have copied the work token into `default`.
+        assert_eq!(
+            lookup_scoped_secret(&backend, "claude", DEFAULT_SECRET_ACCOUNT, "token"),
+            None
+        );
+        assert!(!backend.clear_legacy("claude", "token"));
+        assert_eq!(backend.lookup(&work).as_deref(), Some("work-token"));
+
+        backend
+            .store(&SecretKey::legacy("claude", "token"), "", "legacy-token")
+            .unwrap();
+        assert_eq!(
+            lookup_scoped_secret(&backend, "claude", DEFAULT_SECRET_ACCOUNT, "token").as_deref(),
+            Some("legacy-token")
+        );
+        assert_eq!(backend.lookup_legacy("claude", "token"), None);
+        assert_eq!(backend.lookup(&work).as_deref(), Some("work-token"));
+        assert_eq!(backend.items.borrow().len(), 2);
+    }
+
+    #[test]
+    fn peeking_leaves_a_legacy_secret_in_place() {
+        let backend = MemorySecretBackend::default();
+        let legacy = SecretKey::legacy("claude", "oauth_access_token");
+        let scoped = SecretKey::scoped("claude", DEFAULT_SECRET_ACCOUNT, "oauth_access_token");
+        backend.store(&legacy, "", "legacy-token").unwrap();
+
+        let peek = || {
+            peek_scoped_secret(
+                &backend,
+                "claude",
+                DEFAULT_SECRET_ACCOUNT,
+                "oauth_access_token",
+            )
+        };
+        assert_eq!(peek().as_deref(), Some("legacy-token"));
+        assert_eq!(backend.lookup(&legacy).as_deref(), Some("legacy-token"));
+        assert_eq!(backend.lookup(&scoped), None);
+        assert_eq!(
+            peek_scoped_secret(&backend, "claude", "work", "oauth_access_token"),
+            None
+        );
+
+        // The real lookup still migrates.
+        assert_eq!(
+            lookup_scoped_secret(
+                &backend,
+                "claude",
+                DEFAULT_SECRET_ACCOUNT,
+                "oauth_access_token"
+            )
+            .as_deref(),
+            Some("legacy-token")
+        );
+        assert_eq!(backend.lookup(&legacy), None);
+        assert_eq!(peek().as_deref(), Some("legacy-token"));
+    }
+
+    #[test]
+    fn logout_leaves_other_accounts_when_backends_match_subsets() {
+        let backend = SubsetSecretBackend::default();
+        let work = SecretKey::scoped("claude", "work", "oauth_access_token");
+        backend.store(&work, "", "work-token").unwrap();
+        backend
+            .store(
+                &SecretKey::legacy("claude", "oauth_access_token"),
+                "",
+                "old",
+            )
+            .unwrap();
+
+        assert_eq!(
+            logout_secrets(&backend, "claude", DEFAULT_SECRET_ACCOUNT),
+            ["claude.oauth_access_token"]
+        );
+        assert!(logout_secrets(&backend, "claude", DEFAULT_SECRET_ACCOUNT).is_empty());
+        assert_eq!(backend.lookup(&work).as_deref(), Some("work-token"));
+
+        backend
+            .store(
+                &SecretKey::legacy("claude", "oauth_access_token"),
+                "",
+                "old",
+            )
+            .unwrap();
+        assert_eq!(
+            logout_secrets(&backend, "claude", "work"),
+            ["claude.work.oauth_access_token"]
+        );
+        assert_eq!(
+            backend
+                .lookup_legacy("claude", "oauth_access_token")
+                .as_deref(),
+            Some("old")
+        );
+    }
+
+    #[test]
+    fn clearing_a_secret_keeps_other_accounts() {
+        let backend = SubsetSecretBackend::default();
+        let work = SecretKey::scoped("codex", "work", "access_token");
+        backend.store(&work, "", "work-token").unwrap();
+        backend
+            .store(&SecretKey::legacy("codex", "access_token"), "", "old")
+            .unwrap();
+
+        assert!(clear_account_secret(&backend, "codex", "home", "access_token").is_empty());
+        assert_eq!(
+            backend.lookup_legacy("codex", "access_token").as_deref(),
+            Some("old")
+        );
+        assert_eq!(
+            clear_account_secret(&backend, "codex", DEFAULT_SECRET_ACCOUNT, "access_token"),
+            ["codex.access_token"]
+        );
+        assert_eq!(backend.lookup(&work).as_deref(), Some("work-token"));
+    }
+
+    #[test]
+    fn parses_secret_tool_search_output() {
+        let output = "[/org/freedesktop/secrets/collection/login/7]\n\
+                      label = CodexBar claude token\n\
+                      secret = work-token\n\
+                      created = 2025-10-01 10:00:00\n\
+                      schema = org.freedesktop.Secret.Generic\n\
+                      attribute.service = codexbar\n\
+                      attribute.account = work\n\
+                      attribute.provider = claude\n\
+                      [/org/freedesktop/secrets/collection/login/3]\n\
+                      label = CodexBar claude token\n\
+                      secret = legacy-token\n\
+                      attribute.provider = claude\n";
+        assert_eq!(
+            parse_secret_tool_search(output),
+            [
+                SecretItem {
+                    id: "/org/freedesktop/secrets/collection/login/7".to_string(),
+                    account: Some("work".to_string()),
+                    value: "work-token".to_string(),
+                },
+                SecretItem {
+                    id: "/org/freedesktop/secrets/collection/login/3".to_string(),
+                    account: None,
+                    value: "legacy-token".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn normalizes_account_names() {
+        assert_eq!(normalize_secret_account(" Work Laptop "), "work-laptop");
+        assert_eq!(normalize_secret_account("me@example.com"), "me@example.com");
+    }
+}
@@ -1,10 +1,19 @@
-use codexbar_core::WidgetSnapshot;
+use codexbar_core::{Clock, CodexBarError, SystemClock, WidgetSnapshot};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 pub const DBUS_SERVICE_NAME: &str = "dev.codexbar.WidgetService";
 pub const DBUS_OBJECT_PATH: &str = "/dev/codexbar/WidgetService";
 pub const DBUS_INTERFACE_NAME: &str = "dev.codexbar.WidgetService";
 
+/// Version 2 added the optional `extraWindows`, `windows` and `error` fields on
+/// entries and `nextResetAt` on the snapshot; v1 documents carry none of them.
+pub const CURRENT_SCHEMA_VERSION: u32 = 2;
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct SnapshotEnvelope {
@@ -12,15 +21,472 @@ pub struct SnapshotEnvelope {
     pub snapshot: WidgetSnapshot,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchemaError {
+    Malformed(String),
+    UnsupportedMigration {
+        from: u32,
+        to: u32,
+    },
+    /// Written by a newer CodexBar; the reader has to be updated.
+    FutureVersion {
+        found: u32,
+        supported: u32,
+    },
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed(message) => write!(f, "malformed snapshot: {message}"),
+            Self::UnsupportedMigration { from, to } => {
+                write!(f, "cannot migrate snapshot schema v{from} to v{to}")
+            }
+            Self::FutureVersion { found, supported } => write!(
+                f,
+                "snapshot schema v{found} is newer than supported v{supported}; update CodexBar"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SchemaError {}
+
 impl SnapshotEnvelope {
     pub fn new(snapshot: WidgetSnapshot) -> Self {
         Self {
-            schema_version: 1,
+            schema_version: CURRENT_SCHEMA_VERSION,
+            snapshot,
+        }
+    }
+
+    /// Checks that `schema_version` is one this build can read.
+    pub fn validate(&self) -> Result<(), SchemaError> {
+        check_schema_version(self.schema_version)
+    }
+
+    /// Parses an envelope, upgrading snapshots written with an older schema.
+    /// Envelopes without `schemaVersion` are treated as v1.
+    pub fn parse(json: &str) -> Result<Self, SchemaError> {
+        let mut value = serde_json::from_str::<Value>(json)
+            .map_err(|error| SchemaError::Malformed(error.to_string()))?;
+        let version = match value.get("schemaVersion") {
+            None => 1,
+            Some(version) => version
+                .as_u64()
+                .and_then(|version| u32::try_from(version).ok())
+                .ok_or_else(|| {
+                    SchemaError::Malformed("schemaVersion is not an integer".to_string())
+                })?,
+        };
+        check_schema_version(version)?;
+        let snapshot = value
+            .get_mut("snapshot")
+            .map(Value::take)
+            .ok_or_else(|| SchemaError::Malformed("missing snapshot".to_string()))?;
+
+        Ok(Self::new(migrate_snapshot(
             snapshot,
+            version,
+            CURRENT_SCHEMA_VERSION,
+        )?))
+    }
+}
+
+/// Parses and validates an envelope; see `SnapshotEnvelope::parse`.
+pub fn parse_envelope(json: &str) -> Result<SnapshotEnvelope, SchemaError> {
+    let envelope = SnapshotEnvelope::parse(json)?;
+    envelope.validate()?;
+    Ok(envelope)
+}
+
+fn check_schema_version(version: u32) -> Result<(), SchemaError> {
+    if version > CURRENT_SCHEMA_VERSION {
+        return Err(SchemaError::FutureVersion {
+            found: version,
+            supported: CURRENT_SCHEMA_VERSION,
+        });
+    }
+    if version == 0 {
+        return Err(SchemaError::UnsupportedMigration {
+            from: version,
+            to: CURRENT_SCHEMA_VERSION,
+        });
+    }
+    Ok(())
+}
+
+/// Upgrades a raw snapshot value one schema step at a time.
+pub fn migrate_snapshot(
+    mut value: Value,
+    from: u32,
+    to: u32,
+) -> Result<WidgetSnapshot, SchemaError> {
+    if from == 0 || from > to || to > CURRENT_SCHEMA_VERSION {
+        return Err(SchemaError::UnsupportedMigration { from, to });
+    }
+
+    for version in from..to {
+        match version {
+            1 => migrate_v1_to_v2(&mut value)?,
+            _ => return Err(SchemaError::UnsupportedMigration { from, to }),
         }
     }
+
+    serde_json::from_value(value).map_err(|error| SchemaError::Malformed(error.to_string()))
+}
+
+fn migrate_v1_to_v2(value: &mut Value) -> Result<(), SchemaError> {
+    let entries = value
+        .get_mut("entries")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| SchemaError::Malformed("snapshot entries are missing".to_string()))?;
+    for entry in entries.iter_mut().filter_map(Value::as_object_mut) {
+        for field in ["extraWindows", "windows"] {
+            entry
+                .entry(field)
+                .or_insert_with(|| Value::Array(Vec::new()));
+        }
+    }
+    Ok(())
 }
 
 pub trait SnapshotProvider {
     fn current_snapshot(&self) -> SnapshotEnvelope;
 }
+
+/// What `Health()` reports for one provider.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProviderHealth {
+    pub provider: String,
+    pub last_success_at: Option<String>,
+    /// Cleared by the next successful fetch.
+    pub last_error: Option<HealthError>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthError {
+    /// `CodexBarError::kind`, e.g. `authRequired`.
+    pub kind: String,
+    pub message: String,
+    pub at: String,
+}
+
+/// The JSON returned by `Health()`. `degraded` is set while any provider's
+/// latest fetch failed, so the widget can badge the service.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthReport {
+    pub started_at: String,
+    pub uptime_seconds: u64,
+    pub degraded: bool,
+    pub providers: Vec<ProviderHealth>,
+}
+
+/// Records fetch outcomes for `Health()`. Shared between the fetch loop and
+/// the exported service; timestamps and uptime come from `clock`.
+#[derive(Debug)]
+pub struct HealthTracker {
+    clock: Arc<dyn Clock>,
+    started_at: String,
+    started: Duration,
+    providers: Mutex<BTreeMap<String, ProviderHealth>>,
+}
+
+impl HealthTracker {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            started_at: clock.iso8601(),
+            started: clock.monotonic(),
+            clock,
+            providers: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn record_success(&self, provider: &str) {
+        let at = self.clock.iso8601();
+        self.update(provider, |health| {
+            health.last_success_at = Some(at);
+            health.last_error = None;
+        });
+    }
+
+    pub fn record_failure(&self, provider: &str, error: &CodexBarError) {
+        let last_error = HealthError {
+            kind: error.kind().to_string(),
+            message: error.to_string(),
+            at: self.clock.iso8601(),
+        };
+        self.update(provider, |health| health.last_error = Some(last_error));
+    }
+
+    pub fn report(&self) -> HealthReport {
+        let providers = self
+            .providers
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        HealthReport {
+            started_at: self.started_at.clone(),
+            uptime_seconds: self
+                .clock
+                .monotonic()
+                .saturating_sub(self.started)
+                .as_secs(),
+            degraded: providers.iter().any(|health| health.last_error.is_some()),
+            providers,
+        }
+    }
+
+    fn update(&self, provider: &str, apply: impl FnOnce(&mut ProviderHealth)) {
+        let mut providers = self
+            .providers
+            .lock()
+            .unwrap_or_else(|error| error.into_inner());
+        let health = providers
+            .entry(provider.to_string())
+            .or_insert_with(|| ProviderHealth {
+                provider: provider.to_string(),
+                ..ProviderHealth::default()
+            });
+        apply(health);
+    }
+}
+
+/// The exported `dev.codexbar.WidgetService` object.
+struct WidgetService {
+    provider: Box<dyn SnapshotProvider + Send + Sync>,
+    health: Arc<HealthTracker>,
+}
+
+#[zbus::interface(name = "dev.codexbar.WidgetService")]
+impl WidgetService {
+    /// JSON of the provider's current `SnapshotEnvelope`.
+    fn current_snapshot(&self) -> zbus::fdo::Result<String> {
+        serde_json::to_string(&self.provider.current_snapshot())
+            .map_err(|error| zbus::fdo::Error::Failed(error.to_string()))
+    }
+
+    /// JSON of the current `HealthReport`.
+    fn health(&self) -> zbus::fdo::Result<String> {
+        serde_json::to_string(&self.health.report())
+            .map_err(|error| zbus::fdo::Error::Failed(error.to_string()))
+    }
+
+    /// Carries the JSON of a changed `SnapshotEnvelope`; sent by `SnapshotPublisher`.
+    #[zbus(signal)]
+    async fn snapshot_updated(
+        emitter: &zbus::object_server::SignalEmitter<'_>,
+        snapshot: String,
+    ) -> zbus::Result<()>;
+}
+
+pub const SNAPSHOT_UPDATED_SIGNAL: &str = "SnapshotUpdated";
+
+/// Emits `SnapshotUpdated` on a service connection, skipping envelopes equal
+/// to the last one sent so subscribers only wake for real changes.
+pub struct SnapshotPublisher {
+    connection: zbus::blocking::Connection,
+    last_sent: Option<SnapshotEnvelope>,
+}
+
+impl SnapshotPublisher {
+    pub fn new(connection: zbus::blocking::Connection) -> Self {
+        Self {
+            connection,
+            last_sent: None,
+        }
+    }
+
+    /// Returns whether a signal was sent.
+    pub fn emit_if_changed(&mut self, envelope: &SnapshotEnvelope) -> zbus::Result<bool> {
+        if self.last_sent.as_ref() == Some(envelope) {
+            return Ok(false);
+        }
+
+        let json = serde_json::to_string(envelope)
+            .map_err(|error| zbus::Error::Failure(error.to_string()))?;
+        self.connection.emit_signal(
+            None::<()>,
+            DBUS_OBJECT_PATH,
+            DBUS_INTERFACE_NAME,
+            SNAPSHOT_UPDATED_SIGNAL,
+            &(json,),
+        )?;
+        self.last_sent = Some(envelope.clone());
+        Ok(true)
+    }
+}
+
+/// Exports the widget service on `builder`'s bus and claims the well-known
+/// name. Requests are handled on zbus' own thread for as long as the
+/// returned connection is alive. `Health()` only reports uptime; use
+/// `serve_with_health` to record fetch outcomes too.
+pub fn serve<P>(
+    builder: zbus::blocking::connection::Builder<'_>,
+    provider: P,
+) -> zbus::Result<zbus::blocking::Connection>
+where
+    P: SnapshotProvider + Send + Sync + 'static,
+{
+    let health = Arc::new(HealthTracker::new(Arc::new(SystemClock)));
+    serve_with_health(builder, provider, health)
+}
+
+/// `serve`, with `Health()` backed by a tracker the caller records into.
+pub fn serve_with_health<P>(
+    builder: zbus::blocking::connection::Builder<'_>,
+    provider: P,
+    health: Arc<HealthTracker>,
+) -> zbus::Result<zbus::blocking::Connection>
+where
+    P: SnapshotProvider + Send + Sync + 'static,
+{
+    builder
+        .name(DBUS_SERVICE_NAME)?
+        .serve_at(
+            DBUS_OBJECT_PATH,
+            WidgetService {
+                provider: Box::new(provider),
+                health,
+            },
+        )?
+        .build()
+}
+
+/// Runs the widget service on the session bus until the process exits, so
+/// the Plasma widget can call `CurrentSnapshot()` instead of polling the CLI.
+pub fn run_service<P>(provider: P) -> zbus::Result<()>
+where
+    P: SnapshotProvider + Send + Sync + 'static,
+{
+    let _connection = serve(zbus::blocking::connection::Builder::session()?, provider)?;
+    loop {
+        std::thread::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V1_SAMPLE: &str = r#"{
+        "schemaVersion": 1,
+        "snapshot": {
+            "generatedAt": "unix:1760000000",
+            "enabledProviders": ["codex", "claude"],
+            "entries": [
+                {
+                    "provider": "codex",
+                    "source": "codex-cli",
+                    "updatedAt": "unix:1760000000",
+                    "primary": {"usedPercent": 28.0, "windowMinutes": 300, "resetsAt": "unix:1760018000"},
+                    "secondary": {"usedPercent": 61.0, "windowMinutes": 10080, "resetsAt": "unix:1760500000"},
+                    "tertiary": null,
+                    "creditsRemaining": 92.4,
+                    "codeReviewRemainingPercent": null,
+                    "identity": {"accountEmail": "codex@example.com", "accountOrganization": null, "loginMethod": "plus"},
+                    "status": null
+                }
+            ]
+        }
+    }"#;
+
+    #[test]
+    fn migrates_v1_envelope_to_current_schema() {
+        let envelope = SnapshotEnvelope::parse(V1_SAMPLE).unwrap();
+        assert_eq!(envelope.schema_version, CURRENT_SCHEMA_VERSION);
+        assert_eq!(
+            envelope.snapshot.enabled_providers,
+            vec!["codex".to_string(), "claude".to_string()]
+        );
+        assert!(envelope.snapshot.next_reset_at.is_none());
+
+        let entry = &envelope.snapshot.entries[0];
+        assert!(entry.extra_windows.is_empty());
+        assert!(entry.windows.is_empty());
+        assert!(entry.error.is_none());
+        assert_eq!(entry.credits_remaining, Some(92.4));
+        assert_eq!(
+            entry
+                .primary
+                .as_ref()
+                .and_then(|window| window.window_minutes),
+            Some(300)
+        );
+    }
+
+    #[test]
+    fn current_envelope_round_trips() {
+        let envelope = SnapshotEnvelope::new(WidgetSnapshot::sample());
+        let json = serde_json::to_string(&envelope).unwrap();
+        assert_eq!(SnapshotEnvelope::parse(&json).unwrap(), envelope);
+    }
+
+    fn with_version(version: u32) -> String {
+        let mut value: Value = serde_json::from_str(V1_SAMPLE).unwrap();
+        value["schemaVersion"] = Value::from(version);
+        value.to_string()
+    }
+
+    #[test]
+    fn negotiates_schema_versions() {
+        assert_eq!(
+            parse_envelope(&with_version(0)),
+            Err(SchemaError::UnsupportedMigration {
+                from: 0,
+                to: CURRENT_SCHEMA_VERSION
+            })
+        );
+        assert_eq!(
+            parse_envelope(&with_version(1)).unwrap().schema_version,
+            CURRENT_SCHEMA_VERSION
+        );
+
+        let current = SnapshotEnvelope::new(WidgetSnapshot::sample());
+        let json = serde_json::to_string(&current).unwrap();
+        assert_eq!(parse_envelope(&json), Ok(current.clone()));
+
+        let future = CURRENT_SCHEMA_VERSION + 1;
+        let error = parse_envelope(&with_version(future)).unwrap_err();
+        assert_eq!(
+            error,
+            SchemaError::FutureVersion {
+                found: future,
+                supported: CURRENT_SCHEMA_VERSION
+            }
+        );
+        assert!(error.to_string().contains("update CodexBar"));
+
+        let mut stamped = current;
+        stamped.schema_version = future;
+        assert!(matches!(
+            stamped.validate(),
+            Err(SchemaError::FutureVersion { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_migrations() {
+        let value: Value = serde_json::from_str(V1_SAMPLE).unwrap();
+        assert_eq!(
+            migrate_snapshot(value["snapshot"].clone(), 2, 1),
+            Err(SchemaError::UnsupportedMigration { from: 2, to: 1 })
+        );
+        assert_eq!(
+            migrate_snapshot(value["snapshot"].clone(), 1, CURRENT_SCHEMA_VERSION + 1),
+            Err(SchemaError::UnsupportedMigration {
+                from: 1,
+                to: CURRENT_SCHEMA_VERSION + 1
+            })
+        );
+        assert!(matches!(
+            SnapshotEnvelope::parse("{\"schemaVersion\": 1}"),
+            Err(SchemaError::Malformed(_))
+        ));
+    }
+}
@@ -0,0 +1,223 @@
+use codexbar_core::{format_timestamp, Clock, CodexBarError, WidgetSnapshot};
+use codexbar_kde_bridge::{
+    serve, serve_with_health, HealthReport, HealthTracker, SnapshotEnvelope, SnapshotProvider,
+    SnapshotPublisher, DBUS_INTERFACE_NAME, DBUS_OBJECT_PATH, DBUS_SERVICE_NAME,
+    SNAPSHOT_UPDATED_SIGNAL,
+};
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time::OffsetDateTime;
+use zbus::blocking::connection::Builder;
+use zbus::blocking::MessageIterator;
+use zbus::message::Type as MessageType;
+use zbus::MatchRule;
+
+struct MockProvider(SnapshotEnvelope);
+
+impl SnapshotProvider for MockProvider {
+    fn current_snapshot(&self) -> SnapshotEnvelope {
+        self.0.clone()
+    }
+}
+
+/// A throwaway session bus so the test never touches the user's own bus.
+struct PrivateBus {
+    daemon: Child,
+    address: String,
+}
+
+impl PrivateBus {
+    fn start() -> Option<Self> {
+        let mut daemon = match Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(daemon) => daemon,
+            Err(error) if error.kind() == ErrorKind::NotFound => return None,
+            Err(error) => panic!("failed to start dbus-daemon: {error}"),
+        };
+
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        Some(Self {
+            daemon,
+            address: address.trim().to_string(),
+        })
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+#[test]
+fn serves_current_snapshot_over_the_bus() {
+    let Some(bus) = PrivateBus::start() else {
+        eprintln!("skipping: dbus-daemon is not installed");
+        return;
+    };
+
+    let envelope = SnapshotEnvelope::new(WidgetSnapshot::sample());
+    let _service = serve(
+        Builder::address(bus.address.as_str()).unwrap(),
+        MockProvider(envelope.clone()),
+    )
+    .unwrap();
+
+    let client = Builder::address(bus.address.as_str())
+        .unwrap()
+        .build()
+        .unwrap();
+    let reply = client
+        .call_method(
+            Some(DBUS_SERVICE_NAME),
+            DBUS_OBJECT_PATH,
+            Some(DBUS_INTERFACE_NAME),
+            "CurrentSnapshot",
+            &(),
+        )
+        .unwrap();
+    let json = reply.body().deserialize::<String>().unwrap();
+
+    assert_eq!(SnapshotEnvelope::parse(&json).unwrap(), envelope);
+}
+
+/// Starts at Unix time 1760000000 and only moves when told to.
+#[derive(Debug)]
+struct SteppedClock(Mutex<i64>);
+
+impl SteppedClock {
+    fn advance(&self, seconds: i64) {
+        *self.0.lock().unwrap() += seconds;
+    }
+}
+
+impl Clock for SteppedClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::from_unix_timestamp(*self.0.lock().unwrap()).unwrap()
+    }
+
+    fn monotonic(&self) -> Duration {
+        Duration::from_secs((*self.0.lock().unwrap() - 1_760_000_000) as u64)
+    }
+}
+
+#[test]
+fn reports_health_after_a_provider_failure() {
+    let Some(bus) = PrivateBus::start() else {
+        eprintln!("skipping: dbus-daemon is not installed");
+        return;
+    };
+
+    let clock = Arc::new(SteppedClock(Mutex::new(1_760_000_000)));
+    let health = Arc::new(HealthTracker::new(clock.clone()));
+    let _service = serve_with_health(
+        Builder::address(bus.address.as_str()).unwrap(),
+        MockProvider(SnapshotEnvelope::new(WidgetSnapshot::sample())),
+        Arc::clone(&health),
+    )
+    .unwrap();
+
+    clock.advance(30);
+    health.record_success("codex");
+    health.record_success("claude");
+    clock.advance(60);
+    health.record_failure(
+        "claude",
+        &CodexBarError::AuthRequired("token expired".to_string()),
+    );
+
+    let client = Builder::address(bus.address.as_str())
+        .unwrap()
+        .build()
+        .unwrap();
+    let reply = client
+        .call_method(
+            Some(DBUS_SERVICE_NAME),
+            DBUS_OBJECT_PATH,
+            Some(DBUS_INTERFACE_NAME),
+            "Health",
+            &(),
+        )
+        .unwrap();
+    let json = reply.body().deserialize::<String>().unwrap();
+    let report = serde_json::from_str::<HealthReport>(&json).unwrap();
+
+    assert_eq!(report.started_at, format_timestamp(1_760_000_000));
+    assert_eq!(report.uptime_seconds, 90);
+    assert!(report.degraded);
+    let [claude, codex] = report.providers.as_slice() else {
+        panic!("expected two providers: {json}");
+    };
+    assert_eq!(codex.provider, "codex");
+    assert_eq!(
+        codex.last_success_at.as_deref(),
+        Some(format_timestamp(1_760_000_030).as_str())
+    );
+    assert_eq!(codex.last_error, None);
+    assert_eq!(
+        claude.last_success_at.as_deref(),
+        Some(format_timestamp(1_760_000_030).as_str())
+    );
+    let error = claude.last_error.as_ref().unwrap();
+    assert_eq!(error.kind, "authRequired");
+    assert_eq!(error.message, "authentication required: token expired");
+    assert_eq!(error.at, format_timestamp(1_760_000_090));
+
+    health.record_success("claude");
+    assert!(!health.report().degraded);
+}
+
+#[test]
+fn emits_snapshot_updated_only_on_change() {
+    let Some(bus) = PrivateBus::start() else {
+        eprintln!("skipping: dbus-daemon is not installed");
+        return;
+    };
+
+    let first = SnapshotEnvelope::new(WidgetSnapshot::sample());
+    let service = serve(
+        Builder::address(bus.address.as_str()).unwrap(),
+        MockProvider(first.clone()),
+    )
+    .unwrap();
+
+    let client = Builder::address(bus.address.as_str())
+        .unwrap()
+        .build()
+        .unwrap();
+    let rule = MatchRule::builder()
+        .msg_type(MessageType::Signal)
+        .interface(DBUS_INTERFACE_NAME)
+        .unwrap()
+        .member(SNAPSHOT_UPDATED_SIGNAL)
+        .unwrap()
+        .build();
+    let mut signals = MessageIterator::for_match_rule(rule, &client, None).unwrap();
+
+    let mut second = first.clone();
+    second.snapshot.entries.truncate(1);
+
+    let mut publisher = SnapshotPublisher::new(service);
+    assert!(publisher.emit_if_changed(&first).unwrap());
+    assert!(!publisher.emit_if_changed(&first.clone()).unwrap());
+    assert!(publisher.emit_if_changed(&second).unwrap());
+
+    // Signals arrive in order, so the duplicate would have shown up second.
+    let mut received = Vec::new();
+    for _ in 0..2 {
+        let message = signals.next().unwrap().unwrap();
+        let json = message.body().deserialize::<String>().unwrap();
+        received.push(SnapshotEnvelope::parse(&json).unwrap());
+    }
+    assert_eq!(received, vec![first, second]);
+}
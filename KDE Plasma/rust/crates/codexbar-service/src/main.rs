@@ -1,10 +1,21 @@
 use anyhow::{bail, Context, Result};
 use clap::{Parser, Subcommand};
-use codexbar_core::WidgetSnapshot;
+#[cfg(feature = "in-process")]
+use codexbar_core::JSON_DECIMALS;
+use codexbar_core::{
+    now_iso8601, now_unix_seconds, parse_cli_json_values, parse_unix_seconds, CodexBarError,
+    NextReset, ProviderEntry, SnapshotDiff, SystemClock, WidgetSnapshot,
+};
+use codexbar_kde_bridge::{
+    serve_with_health, HealthTracker, SnapshotEnvelope, SnapshotProvider, SnapshotPublisher,
+};
 use serde_json::Value;
 use std::fs;
+use std::io::{ErrorKind, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus, Output};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[derive(Debug, Parser)]
 #[command(name = "codexbar-service")]
@@ -17,8 +28,13 @@ struct Cli {
 #[derive(Debug, Subcommand)]
 enum Commands {
     Snapshot(SnapshotArgs),
+    /// Combine snapshots written on several machines into one.
+    Merge(MergeArgs),
     Auth(AuthArgs),
     Remove(RemoveArgs),
+    /// Export the snapshot and `Health()` on the session bus, refetching
+    /// every `--interval` seconds.
+    Serve(ServeArgs),
 }
 
 #[derive(Debug, Parser, Clone)]
@@ -26,6 +42,11 @@ struct SnapshotArgs {
     #[arg(long, default_value_t = false)]
     pretty: bool,
 
+    /// Wrap the snapshot in the same `SnapshotEnvelope` the D-Bus service
+    /// returns. `--write-cache` still gets the bare snapshot.
+    #[arg(long, default_value_t = false)]
+    envelope: bool,
+
     #[arg(long, default_value_t = false)]
     from_codexbar_cli: bool,
 
@@ -35,11 +56,107 @@ struct SnapshotArgs {
     #[arg(long, default_value_t = false)]
     status: bool,
 
+    /// Keep providers that failed or have no data as entries with `error`
+    /// set (and `source: "unavailable"`), so the widget can tell a failed
+    /// provider from one that isn't enabled.
+    #[arg(long, default_value_t = false)]
+    include_missing: bool,
+
     #[arg(long)]
     input: Option<PathBuf>,
 
+    /// Emit the built-in sample snapshot instead of fetching anything, for
+    /// working on the widget without codex or claude installed. Every entry
+    /// carries `source: "sample"`.
+    #[arg(long, default_value_t = false, conflicts_with = "input")]
+    sample: bool,
+
+    /// Drop entries whose `updatedAt` is more than this many seconds old.
+    #[arg(long, value_name = "SECONDS")]
+    max_age: Option<u64>,
+
+    /// Write the snapshot JSON here instead of stdout.
+    #[arg(long)]
+    output: Option<PathBuf>,
+
     #[arg(long)]
     write_cache: Option<PathBuf>,
+
+    /// Leave `--write-cache` untouched when it already holds this snapshot
+    /// apart from timestamps, so a timer doesn't wake file watchers for
+    /// nothing. The kept file's timestamps still age: it is rewritten anyway
+    /// once its `generatedAt` is over ten minutes old, so readers passing
+    /// `--input <cache> --max-age` of at least 600 never drop its entries.
+    #[arg(long, default_value_t = false)]
+    only_changed: bool,
+
+    /// Print what changed since the snapshot in this file (a
+    /// `SnapshotDiff`) instead of the snapshot. A missing file counts as an
+    /// empty snapshot. May name the `--write-cache` file; it is read first.
+    #[arg(long, value_name = "PATH", conflicts_with = "envelope")]
+    diff_against: Option<PathBuf>,
+
+    /// Send a desktop notification when a window's remaining percent drops
+    /// to `--notify-below` (default 20) or under it since the snapshot in
+    /// `--write-cache`, which is what keeps a crossing from notifying twice.
+    #[arg(long, default_value_t = false, requires = "write_cache")]
+    notify: bool,
+
+    /// Remaining-percent threshold for notifications; implies `--notify`.
+    #[arg(long, value_parser = parse_percent, requires = "write_cache")]
+    notify_below: Option<f64>,
+}
+
+const DEFAULT_NOTIFY_BELOW: f64 = 20.0;
+
+impl SnapshotArgs {
+    fn notify_threshold(&self) -> Option<f64> {
+        match self.notify_below {
+            Some(threshold) => Some(threshold),
+            None => self.notify.then_some(DEFAULT_NOTIFY_BELOW),
+        }
+    }
+}
+
+fn parse_percent(raw: &str) -> Result<f64, String> {
+    let value = raw
+        .parse::<f64>()
+        .map_err(|_| format!("`{raw}` is not a number"))?;
+    if !(0.0..=100.0).contains(&value) {
+        return Err(format!("`{raw}` is not between 0 and 100"));
+    }
+    Ok(value)
+}
+
+#[derive(Debug, Parser, Clone)]
+struct MergeArgs {
+    /// A snapshot JSON file, e.g. one written by `snapshot --write-cache`. Repeatable.
+    #[arg(long = "input", required = true)]
+    inputs: Vec<PathBuf>,
+
+    #[arg(long, default_value_t = false)]
+    pretty: bool,
+
+    /// Write the merged JSON here instead of stdout.
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Parser, Clone)]
+struct ServeArgs {
+    #[arg(long, default_value = "all")]
+    provider: String,
+
+    #[arg(long, default_value_t = false)]
+    status: bool,
+
+    /// See `snapshot --include-missing`.
+    #[arg(long, default_value_t = false)]
+    include_missing: bool,
+
+    /// Seconds between fetches.
+    #[arg(long, default_value_t = 300, value_parser = clap::value_parser!(u64).range(1..))]
+    interval: u64,
 }
 
 #[derive(Debug, Parser, Clone)]
@@ -58,6 +175,9 @@ struct RemoveArgs {
 }
 
 fn main() {
+    // Must happen before any thread starts; see `codexbar_fetch::init_local_offset`.
+    #[cfg(feature = "in-process")]
+    codexbar_fetch::init_local_offset();
     if let Err(error) = run() {
         eprintln!("codexbar-service: {error:#}");
         std::process::exit(1);
@@ -68,61 +188,453 @@ fn run() -> Result<()> {
     let cli = Cli::parse();
     let command = cli.command.unwrap_or(Commands::Snapshot(SnapshotArgs {
         pretty: false,
+        envelope: false,
         from_codexbar_cli: true,
         provider: "all".to_string(),
         status: true,
+        include_missing: false,
         input: None,
+        sample: false,
+        max_age: None,
+        output: None,
         write_cache: None,
+        only_changed: false,
+        diff_against: None,
+        notify: false,
+        notify_below: None,
     }));
 
     match command {
         Commands::Snapshot(args) => render_snapshot(&args),
+        Commands::Merge(args) => run_merge(&args),
         Commands::Auth(args) => run_auth(&args),
         Commands::Remove(args) => run_remove(&args),
+        Commands::Serve(args) => run_serve(&args),
+    }
+}
+
+/// The snapshot `CurrentSnapshot()` returns, replaced by each refresh.
+#[derive(Clone)]
+struct LatestSnapshot(Arc<Mutex<SnapshotEnvelope>>);
+
+impl SnapshotProvider for LatestSnapshot {
+    fn current_snapshot(&self) -> SnapshotEnvelope {
+        self.0
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+            .clone()
+    }
+}
+
+fn run_serve(args: &ServeArgs) -> Result<()> {
+    let health = Arc::new(HealthTracker::new(Arc::new(SystemClock)));
+    let latest = LatestSnapshot(Arc::new(Mutex::new(SnapshotEnvelope::new(
+        WidgetSnapshot {
+            generated_at: now_iso8601(),
+            enabled_providers: Vec::new(),
+            entries: Vec::new(),
+            next_reset_at: None,
+        },
+    ))));
+    let connection = serve_with_health(
+        zbus::blocking::connection::Builder::session()?,
+        latest.clone(),
+        Arc::clone(&health),
+    )
+    .context("failed to export the D-Bus service")?;
+    let mut publisher = SnapshotPublisher::new(connection);
+
+    loop {
+        match refresh_snapshot(args, &health) {
+            Ok(snapshot) => {
+                let envelope = SnapshotEnvelope::new(snapshot);
+                *latest.0.lock().unwrap_or_else(|error| error.into_inner()) = envelope.clone();
+                if let Err(error) = publisher.emit_if_changed(&envelope) {
+                    eprintln!("codexbar-service: failed to signal the new snapshot: {error}");
+                }
+            }
+            Err(error) => eprintln!("codexbar-service: refresh failed: {error:#}"),
+        }
+        std::thread::sleep(Duration::from_secs(args.interval));
+    }
+}
+
+/// One fetch of the serve loop, with every provider's outcome recorded in
+/// `health` for `Health()`.
+fn refresh_snapshot(args: &ServeArgs, health: &HealthTracker) -> Result<WidgetSnapshot> {
+    let mut snapshot = fetch_live_snapshot(
+        &args.provider,
+        args.status,
+        args.include_missing,
+        &|provider, result| record_fetch_outcome(health, provider, result),
+    )?;
+    apply_pinned_providers(&mut snapshot);
+    snapshot.next_reset_at = next_upcoming_reset(&snapshot, now_unix_seconds());
+    Ok(snapshot)
+}
+
+fn record_fetch_outcome(
+    health: &HealthTracker,
+    provider: &str,
+    result: &Result<Option<ProviderEntry>>,
+) {
+    match result {
+        Ok(Some(entry)) => match entry.error.as_deref() {
+            Some(error) => {
+                health.record_failure(provider, &CodexBarError::Other(error.to_string()))
+            }
+            None => health.record_success(provider),
+        },
+        Ok(None) => health.record_failure(
+            provider,
+            &CodexBarError::NoData(format!("provider '{provider}' has no live usage data")),
+        ),
+        Err(error) => {
+            let error = error
+                .downcast_ref::<CodexBarError>()
+                .cloned()
+                .unwrap_or_else(|| CodexBarError::Other(format!("{error:#}")));
+            health.record_failure(provider, &error);
+        }
     }
 }
 
 fn render_snapshot(args: &SnapshotArgs) -> Result<()> {
     let snapshot = build_snapshot(args)?;
+    let diff = match args.diff_against.as_deref() {
+        Some(path) => Some(snapshot_diff_against(&snapshot, path)?),
+        None => None,
+    };
     let json = if args.pretty {
         serde_json::to_string_pretty(&snapshot)?
     } else {
         serde_json::to_string(&snapshot)?
     };
 
+    let mut notifications = Vec::new();
     if let Some(cache_path) = args.write_cache.as_ref() {
-        write_cache_file(cache_path, &json)?;
+        // Compared under the writers' lock, so a concurrent refresh can't
+        // land between the read and the decision to skip.
+        let _lock = lock_for_writing(cache_path)?;
+        let cached = fs::read_to_string(cache_path)
+            .ok()
+            .and_then(|raw| serde_json::from_str::<WidgetSnapshot>(&raw).ok());
+        if let (Some(threshold), Some(cached)) = (args.notify_threshold(), cached.as_ref()) {
+            notifications = low_window_notifications(&snapshot, cached, threshold);
+        }
+        let unchanged = args.only_changed
+            && cached.is_some_and(|cached| {
+                cached_snapshot_is_recent(&cached, now_unix_seconds())
+                    && snapshot_equal_ignoring_time(&cached, &snapshot)
+            });
+        if !unchanged {
+            write_locked_file_atomically(cache_path, &json)?;
+        }
+    }
+    for notification in &notifications {
+        send_desktop_notification(notification);
     }
 
-    println!("{json}");
-    Ok(())
+    let json = if let Some(diff) = diff {
+        if args.pretty {
+            serde_json::to_string_pretty(&diff)?
+        } else {
+            serde_json::to_string(&diff)?
+        }
+    } else if args.envelope {
+        envelope_json(snapshot, args.pretty)?
+    } else {
+        json
+    };
+    write_output(args.output.as_deref(), &json)
+}
+
+fn snapshot_diff_against(snapshot: &WidgetSnapshot, path: &Path) -> Result<SnapshotDiff> {
+    let previous = match fs::read_to_string(path) {
+        Ok(raw) => serde_json::from_str::<WidgetSnapshot>(&raw)
+            .with_context(|| format!("failed to parse snapshot {}", path.display()))?,
+        Err(error) if error.kind() == ErrorKind::NotFound => WidgetSnapshot {
+            generated_at: String::new(),
+            enabled_providers: Vec::new(),
+            entries: Vec::new(),
+            next_reset_at: None,
+        },
+        Err(error) => {
+            return Err(error).with_context(|| format!("failed to read {}", path.display()))
+        }
+    };
+    Ok(snapshot.diff(&previous))
+}
+
+/// Oldest `generatedAt` an `--only-changed` cache is left in place with.
+const ONLY_CHANGED_MAX_AGE_SECONDS: i64 = 600;
+
+fn cached_snapshot_is_recent(cached: &WidgetSnapshot, now: i64) -> bool {
+    parse_unix_seconds(&cached.generated_at)
+        .is_some_and(|generated| now - generated <= ONLY_CHANGED_MAX_AGE_SECONDS)
+}
+
+/// Equal once `generatedAt` and every entry's and status's `updatedAt` are
+/// set aside; those move on every fetch even when no reading did.
+fn snapshot_equal_ignoring_time(left: &WidgetSnapshot, right: &WidgetSnapshot) -> bool {
+    let without_time = |snapshot: &WidgetSnapshot| {
+        let mut snapshot = snapshot.clone();
+        snapshot.generated_at.clear();
+        for entry in &mut snapshot.entries {
+            entry.updated_at.clear();
+            if let Some(status) = entry.status.as_mut() {
+                status.updated_at = None;
+            }
+        }
+        snapshot
+    };
+    without_time(left) == without_time(right)
+}
+
+fn envelope_json(snapshot: WidgetSnapshot, pretty: bool) -> Result<String> {
+    let envelope = SnapshotEnvelope::new(snapshot);
+    Ok(if pretty {
+        serde_json::to_string_pretty(&envelope)?
+    } else {
+        serde_json::to_string(&envelope)?
+    })
+}
+
+fn write_output(output: Option<&Path>, json: &str) -> Result<()> {
+    match output {
+        Some(path) => write_file_atomically(path, json),
+        None => {
+            println!("{json}");
+            Ok(())
+        }
+    }
+}
+
+fn run_merge(args: &MergeArgs) -> Result<()> {
+    let snapshots = args
+        .inputs
+        .iter()
+        .map(|path| {
+            let raw = fs::read_to_string(path)
+                .with_context(|| format!("failed to read snapshot from {}", path.display()))?;
+            serde_json::from_str::<WidgetSnapshot>(&raw)
+                .with_context(|| format!("{} is not a snapshot", path.display()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut merged = merge_snapshots(snapshots);
+    merged.next_reset_at = next_upcoming_reset(&merged, now_unix_seconds());
+    let json = if args.pretty {
+        serde_json::to_string_pretty(&merged)?
+    } else {
+        serde_json::to_string(&merged)?
+    };
+    write_output(args.output.as_deref(), &json)
+}
+
+/// Keeps one entry per provider, the one with the newest `updatedAt` (the
+/// earlier input wins ties and unparseable timestamps lose), in first-seen
+/// order. `enabledProviders` is the union of every input's.
+fn merge_snapshots(snapshots: Vec<WidgetSnapshot>) -> WidgetSnapshot {
+    let mut merged = WidgetSnapshot {
+        generated_at: now_iso8601(),
+        enabled_providers: Vec::new(),
+        entries: Vec::new(),
+        next_reset_at: None,
+    };
+    let updated = |entry: &ProviderEntry| parse_unix_seconds(&entry.updated_at);
+
+    for snapshot in snapshots {
+        for provider in snapshot.enabled_providers {
+            push_provider_unique(&mut merged.enabled_providers, &provider);
+        }
+        for entry in snapshot.entries {
+            match merged
+                .entries
+                .iter_mut()
+                .find(|existing| existing.provider == entry.provider)
+            {
+                Some(existing) => {
+                    if updated(&entry) > updated(existing) {
+                        *existing = entry;
+                    }
+                }
+                None => merged.entries.push(entry),
+            }
+        }
+    }
+    merged
 }
 
 fn build_snapshot(args: &SnapshotArgs) -> Result<WidgetSnapshot> {
-    if let Some(path) = args.input.as_ref() {
+    let mut snapshot = if args.sample {
+        sample_snapshot()
+    } else if let Some(path) = args.input.as_ref() {
         let raw = fs::read_to_string(path)
             .with_context(|| format!("failed to read JSON input from {}", path.display()))?;
         let values = parse_json_values(&raw)?;
-        let mut snapshot = WidgetSnapshot::from_codexbar_cli_values(&values);
-        apply_pinned_providers(&mut snapshot);
-        return Ok(snapshot);
+        WidgetSnapshot::from_codexbar_cli_values(&values)
+    } else if args.from_codexbar_cli {
+        fetch_live_snapshot(
+            &args.provider,
+            args.status,
+            args.include_missing,
+            &|_, _| {},
+        )?
+    } else {
+        bail!("no live data source selected; pass --from-codexbar-cli or --input <path>")
+    };
+
+    apply_pinned_providers(&mut snapshot);
+    let now = now_unix_seconds();
+    if let Some(max_age) = args.max_age {
+        drop_stale_entries(&mut snapshot, max_age, now);
+    }
+    snapshot.next_reset_at = next_upcoming_reset(&snapshot, now);
+    Ok(snapshot)
+}
+
+/// `WidgetSnapshot::sample()` with every entry marked as synthetic so it
+/// can't be mistaken for real usage.
+fn sample_snapshot() -> WidgetSnapshot {
+    let mut snapshot = WidgetSnapshot::sample();
+    for entry in &mut snapshot.entries {
+        entry.source = Some("sample".to_string());
+    }
+    snapshot
+}
+
+/// Keeps entries updated within `max_age` seconds of `now`. An `updatedAt`
+/// that doesn't parse can't be shown to be fresh, so it is dropped too.
+/// Dropping every entry leaves an empty snapshot rather than an error.
+fn drop_stale_entries(snapshot: &mut WidgetSnapshot, max_age: u64, now: i64) {
+    let cutoff = now.saturating_sub(i64::try_from(max_age).unwrap_or(i64::MAX));
+    snapshot.entries.retain(|entry| {
+        parse_unix_seconds(&entry.updated_at).is_some_and(|updated| updated >= cutoff)
+    });
+}
+
+/// Finds the soonest reset that hasn't passed yet, across every named
+/// window including `extra_windows`, so the widget can drive a single
+/// countdown without scanning every window itself.
+fn next_upcoming_reset(snapshot: &WidgetSnapshot, now: i64) -> Option<NextReset> {
+    let mut soonest: Option<(i64, NextReset)> = None;
+
+    for entry in &snapshot.entries {
+        let mut entry = entry.clone();
+        entry.name_unnamed_windows();
+        for named in &entry.windows {
+            let Some(resets_at) = named.window.resets_at.as_deref() else {
+                continue;
+            };
+            let Some(timestamp) = parse_unix_seconds(resets_at) else {
+                continue;
+            };
+            if timestamp <= now {
+                continue;
+            }
+            if soonest
+                .as_ref()
+                .is_some_and(|(current, _)| *current <= timestamp)
+            {
+                continue;
+            }
+            soonest = Some((
+                timestamp,
+                NextReset {
+                    provider: entry.provider.clone(),
+                    window: named.name.clone(),
+                    resets_at: resets_at.to_string(),
+                },
+            ));
+        }
     }
 
-    if args.from_codexbar_cli {
-        let mut snapshot = fetch_from_codexbar_cli(&args.provider, args.status)?;
-        apply_pinned_providers(&mut snapshot);
-        return Ok(snapshot);
+    soonest.map(|(_, next)| next)
+}
+
+/// Sees each provider's fetch outcome; see `record_fetch_outcome`.
+type FetchObserver<'a> = &'a dyn Fn(&str, &Result<Option<ProviderEntry>>);
+
+/// Fetches in-process through `codexbar-fetch`, falling back to spawning
+/// `codexbar usage` when built without the `in-process` feature or when
+/// `provider` names one this build doesn't know (e.g. from a newer CLI).
+/// The CLI only reports the entries it got, so `observe` then sees those,
+/// or the whole `provider` request when the CLI failed.
+fn fetch_live_snapshot(
+    provider: &str,
+    status: bool,
+    include_missing: bool,
+    observe: FetchObserver<'_>,
+) -> Result<WidgetSnapshot> {
+    #[cfg(feature = "in-process")]
+    {
+        let registry = codexbar_fetch::provider_registry(&[]);
+        if codexbar_fetch::select_providers(&registry, provider).is_ok() {
+            let config = codexbar_fetch::load_usage_config()?;
+            let options = in_process_options(&config, status, include_missing);
+            return fetch_in_process(&registry, provider, &options, observe);
+        }
     }
+    match fetch_from_codexbar_cli(provider, status, include_missing) {
+        Ok(snapshot) => {
+            for entry in &snapshot.entries {
+                observe(&entry.provider, &Ok(Some(entry.clone())));
+            }
+            Ok(snapshot)
+        }
+        Err(error) => {
+            observe(provider, &Err(anyhow::anyhow!("{error:#}")));
+            Err(error)
+        }
+    }
+}
+
+/// `source` of the placeholder entries `--include-missing` adds; the same
+/// default as `codexbar usage --missing-source`.
+const MISSING_SOURCE: &str = "unavailable";
 
-    bail!("no live data source selected; pass --from-codexbar-cli or --input <path>")
+/// What the spawned `codexbar usage` would fetch with: `config.toml` and
+/// `$CODEXBAR_SOURCE`, plus the flags `run_codexbar_command` passes.
+#[cfg(feature = "in-process")]
+fn in_process_options(
+    config: &codexbar_fetch::UsageConfig,
+    status: bool,
+    include_missing: bool,
+) -> codexbar_fetch::FetchOptions {
+    let mut options = config.fetch_options();
+    options.status |= status;
+    options.missing_source = include_missing.then(|| MISSING_SOURCE.to_string());
+    options
 }
 
-fn fetch_from_codexbar_cli(provider: &str, status: bool) -> Result<WidgetSnapshot> {
+/// Rounds like the CLI's JSON output, so a snapshot doesn't depend on which
+/// path fetched it; window lengths are normalized in `collect_entries`.
+#[cfg(feature = "in-process")]
+fn fetch_in_process(
+    registry: &[Box<dyn codexbar_fetch::Provider>],
+    provider: &str,
+    options: &codexbar_fetch::FetchOptions,
+    observe: FetchObserver<'_>,
+) -> Result<WidgetSnapshot> {
+    let mut snapshot =
+        codexbar_fetch::fetch_snapshot_observed(registry, provider, options, observe)?;
+    for entry in &mut snapshot.entries {
+        entry.round_numbers(JSON_DECIMALS);
+    }
+    Ok(snapshot)
+}
+
+fn fetch_from_codexbar_cli(
+    provider: &str,
+    status: bool,
+    include_missing: bool,
+) -> Result<WidgetSnapshot> {
     let output = if let Some(sibling) = sibling_codexbar_path() {
-        run_codexbar_command(&sibling, provider, status)
+        run_codexbar_command(&sibling, provider, status, include_missing)
             .with_context(|| format!("failed to spawn codexbar CLI at {}", sibling.display()))?
     } else {
-        run_codexbar_command(Path::new("codexbar"), provider, status)
+        run_codexbar_command(Path::new("codexbar"), provider, status, include_missing)
             .with_context(|| "failed to spawn codexbar CLI".to_string())?
     };
 
@@ -135,7 +647,11 @@ fn fetch_from_codexbar_cli(provider: &str, status: bool) -> Result<WidgetSnapsho
     }
 
     let stdout = String::from_utf8(output.stdout).context("codexbar stdout was not valid UTF-8")?;
-    let values = parse_json_values(&stdout).context("failed to decode codexbar JSON payload")?;
+    snapshot_from_codexbar_output(&stdout)
+}
+
+fn snapshot_from_codexbar_output(stdout: &str) -> Result<WidgetSnapshot> {
+    let values = parse_json_values(stdout).context("failed to decode codexbar JSON payload")?;
     Ok(WidgetSnapshot::from_codexbar_cli_values(&values))
 }
 
@@ -200,7 +716,12 @@ fn normalize_provider_id(raw: &str) -> String {
     }
 }
 
-fn run_codexbar_command(program: &Path, provider: &str, status: bool) -> std::io::Result<Output> {
+fn run_codexbar_command(
+    program: &Path,
+    provider: &str,
+    status: bool,
+    include_missing: bool,
+) -> std::io::Result<Output> {
     let mut command = Command::new(program);
     command
         .arg("usage")
@@ -214,6 +735,9 @@ fn run_codexbar_command(program: &Path, provider: &str, status: bool) -> std::io
     if status {
         command.arg("--status");
     }
+    if include_missing {
+        command.args(["--include-missing", "--missing-source", MISSING_SOURCE]);
+    }
 
     command.output()
 }
@@ -231,43 +755,118 @@ fn sibling_codexbar_path() -> Option<PathBuf> {
 }
 
 fn parse_json_values(raw: &str) -> Result<Vec<Value>> {
-    let trimmed = raw.trim();
-    if trimmed.is_empty() {
-        bail!("empty JSON payload");
-    }
+    Ok(parse_cli_json_values(raw)?)
+}
 
-    if let Ok(value) = serde_json::from_str::<Value>(trimmed) {
-        return Ok(match value {
-            Value::Array(items) => items,
-            Value::Object(_) => vec![value],
-            _ => {
-                bail!("JSON payload must be an object or an array");
-            }
-        });
+/// Writes to a temporary sibling and renames it over `path`, so the widget
+/// reading `path` sees either the old or the new file, never a partial one.
+/// The temporary file is removed if any step fails. Writers of the same
+/// `path` take turns on a lock file beside it, so two refreshes can't share
+/// (and interleave into) the temporary file.
+fn write_file_atomically(path: &Path, payload: &str) -> Result<()> {
+    let _lock = lock_for_writing(path)?;
+    write_locked_file_atomically(path, payload)
+}
+
+/// Takes the lock file beside `path`, creating `path`'s directory first.
+/// The lock is released when the returned file is dropped.
+fn lock_for_writing(path: &Path) -> Result<fs::File> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
     }
 
-    let line_values = raw
-        .lines()
-        .map(str::trim)
-        .filter(|line| !line.is_empty())
-        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
-        .collect::<Vec<_>>();
+    let lock_path = sibling_path(path, "lock");
+    let lock = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("failed to open {}", lock_path.display()))?;
+    lock.lock()
+        .with_context(|| format!("failed to lock {}", lock_path.display()))?;
+    Ok(lock)
+}
 
-    if line_values.is_empty() {
-        bail!("unable to parse payload as JSON");
+/// [`write_file_atomically`] for a caller already holding
+/// [`lock_for_writing`].
+fn write_locked_file_atomically(path: &Path, payload: &str) -> Result<()> {
+    let temp_path = atomic_temp_path(path);
+    let result = (|| -> std::io::Result<()> {
+        let mut file = fs::File::create(&temp_path)?;
+        file.write_all(payload.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
+    })();
+    if let Err(error) = result {
+        let _ = fs::remove_file(&temp_path);
+        return Err(error).with_context(|| format!("failed to write {}", path.display()));
     }
+    Ok(())
+}
 
-    Ok(line_values)
+fn atomic_temp_path(path: &Path) -> PathBuf {
+    sibling_path(path, &format!("{}.tmp", std::process::id()))
 }
 
-fn write_cache_file(path: &PathBuf, payload: &str) -> Result<()> {
-    if let Some(parent) = path.parent() {
-        fs::create_dir_all(parent)
-            .with_context(|| format!("failed to create {}", parent.display()))?;
-    }
+/// `.<file name>.<suffix>` next to `path`.
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{name}.{suffix}"))
+}
 
-    fs::write(path, payload).with_context(|| format!("failed to write {}", path.display()))?;
-    Ok(())
+#[derive(Debug, Clone, PartialEq)]
+struct LowUsageNotification {
+    summary: String,
+    body: String,
+}
+
+/// One notification per window whose remaining percent fell to `threshold`
+/// or under it since `cached`. The cache holds the last reading, so a window
+/// that stays low, or whose reading or provider went missing in between,
+/// isn't reported again until it recovers and drops back.
+fn low_window_notifications(
+    snapshot: &WidgetSnapshot,
+    cached: &WidgetSnapshot,
+    threshold: f64,
+) -> Vec<LowUsageNotification> {
+    snapshot
+        .diff_with_thresholds(cached, &[100.0 - threshold])
+        .changes
+        .into_iter()
+        .filter(|change| !change.crossed_thresholds.is_empty())
+        .filter_map(|change| {
+            let label = match change.window.as_str() {
+                "primary" => "session",
+                "secondary" => "weekly",
+                other => other,
+            };
+            let remaining = (100.0 - change.used_percent?).max(0.0);
+            Some(LowUsageNotification {
+                summary: format!("CodexBar: {} {label} limit low", change.provider),
+                body: format!("{remaining:.0}% left"),
+            })
+        })
+        .collect()
+}
+
+fn send_desktop_notification(notification: &LowUsageNotification) {
+    let result = Command::new("notify-send")
+        .arg("--app-name=CodexBar")
+        .arg(&notification.summary)
+        .arg(&notification.body)
+        .status();
+    match result {
+        Ok(_) => {}
+        Err(error) if error.kind() == ErrorKind::NotFound => {}
+        Err(error) => eprintln!("codexbar-service: warning: notify-send failed: {error}"),
+    }
 }
 
 fn run_auth(args: &AuthArgs) -> Result<()> {
@@ -322,3 +921,564 @@ fn run_codexbar_remove_command(
     }
     command.status()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codexbar_core::{format_timestamp, NamedRateWindow, RateWindow};
+
+    fn snapshot_with_claude_used(primary_used: f64, secondary_used: f64) -> WidgetSnapshot {
+        let mut snapshot = WidgetSnapshot::sample();
+        let claude = &mut snapshot.entries[1];
+        claude.primary.as_mut().unwrap().used_percent = Some(primary_used);
+        claude.secondary.as_mut().unwrap().used_percent = Some(secondary_used);
+        snapshot
+    }
+
+    #[test]
+    fn notifies_once_per_crossing() {
+        let fine = snapshot_with_claude_used(50.0, 54.0);
+        let low = snapshot_with_claude_used(50.0, 95.0);
+        assert_eq!(
+            low_window_notifications(&low, &fine, 10.0),
+            vec![LowUsageNotification {
+                summary: "CodexBar: claude weekly limit low".to_string(),
+                body: "5% left".to_string(),
+            }]
+        );
+        assert!(low_window_notifications(&low, &low, 10.0).is_empty());
+
+        let recovered = snapshot_with_claude_used(50.0, 20.0);
+        assert!(low_window_notifications(&recovered, &low, 10.0).is_empty());
+        assert_eq!(low_window_notifications(&low, &recovered, 10.0).len(), 1);
+    }
+
+    #[test]
+    fn missing_reading_does_not_renotify() {
+        let low = snapshot_with_claude_used(50.0, 95.0);
+        let mut failed = low.clone();
+        failed.entries[1].secondary = None;
+        assert!(low_window_notifications(&failed, &low, 10.0).is_empty());
+        assert!(low_window_notifications(&low, &failed, 10.0).is_empty());
+    }
+
+    #[test]
+    fn missing_provider_does_not_renotify() {
+        let low = snapshot_with_claude_used(50.0, 95.0);
+        let mut failed = low.clone();
+        failed.entries.retain(|entry| entry.provider != "claude");
+        assert!(low_window_notifications(&failed, &low, 10.0).is_empty());
+        assert!(low_window_notifications(&low, &failed, 10.0).is_empty());
+    }
+
+    #[test]
+    fn notify_flags_resolve_threshold() {
+        let cli = |args: &[&str]| {
+            let mut argv = vec!["codexbar-service", "snapshot"];
+            argv.extend(args);
+            match Cli::try_parse_from(argv).map(|cli| cli.command) {
+                Ok(Some(Commands::Snapshot(args))) => Ok(args.notify_threshold()),
+                Ok(_) => unreachable!(),
+                Err(error) => Err(error),
+            }
+        };
+
+        assert_eq!(cli(&[]).unwrap(), None);
+        let cache = ["--write-cache", "snapshot.json"];
+        assert_eq!(
+            cli(&[&cache[..], &["--notify"]].concat()).unwrap(),
+            Some(DEFAULT_NOTIFY_BELOW)
+        );
+        assert_eq!(
+            cli(&[&cache[..], &["--notify-below", "5"]].concat()).unwrap(),
+            Some(5.0)
+        );
+        assert!(cli(&[&cache[..], &["--notify-below", "150"]].concat()).is_err());
+        // The cache is where the previous reading comes from.
+        assert!(cli(&["--notify"]).is_err());
+    }
+
+    #[test]
+    fn writes_output_atomically() {
+        let dir = std::env::temp_dir().join(format!("codexbar-service-{}", std::process::id()));
+        let path = dir.join("snapshot.json");
+        write_file_atomically(&path, "{\"entries\": []}").unwrap();
+        write_file_atomically(&path, "{\"entries\": [1]}").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "{\"entries\": [1]}");
+        assert!(!atomic_temp_path(&path).exists());
+
+        // Renaming a file over a directory fails after the temp file is written.
+        let blocked = dir.join("blocked");
+        fs::create_dir_all(&blocked).unwrap();
+        assert!(write_file_atomically(&blocked, "{}").is_err());
+        assert!(!atomic_temp_path(&blocked).exists());
+        assert!(blocked.is_dir());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn diffs_against_a_snapshot_file() {
+        let dir =
+            std::env::temp_dir().join(format!("codexbar-service-diff-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("snapshot.json");
+        let _ = fs::remove_file(&path);
+
+        let previous = snapshot_with_claude_used(41.0, 54.0);
+        let first = snapshot_diff_against(&previous, &path).unwrap();
+        assert_eq!(first.added_providers, ["codex", "claude"]);
+
+        fs::write(&path, serde_json::to_string(&previous).unwrap()).unwrap();
+        let current = snapshot_with_claude_used(41.0, 83.0);
+        let diff = snapshot_diff_against(&current, &path).unwrap();
+        assert_eq!(diff.changes.len(), 1);
+        assert_eq!(diff.changes[0].window, "secondary");
+        assert_eq!(diff.changes[0].crossed_thresholds, [80.0]);
+
+        fs::write(&path, "not json").unwrap();
+        assert!(snapshot_diff_against(&current, &path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compares_snapshots_ignoring_timestamps() {
+        let snapshot = WidgetSnapshot::sample();
+        let mut later = snapshot.clone();
+        later.generated_at = "2030-01-01T00:00:00Z".to_string();
+        for entry in &mut later.entries {
+            entry.updated_at = "2030-01-01T00:00:00Z".to_string();
+            entry.status.as_mut().unwrap().updated_at = None;
+        }
+        assert!(snapshot_equal_ignoring_time(&snapshot, &later));
+
+        let mut changed = later.clone();
+        changed.entries[0].primary.as_mut().unwrap().used_percent = Some(29.0);
+        assert!(!snapshot_equal_ignoring_time(&snapshot, &changed));
+        let mut reordered = later;
+        reordered.entries.reverse();
+        assert!(!snapshot_equal_ignoring_time(&snapshot, &reordered));
+
+        let dir = std::env::temp_dir().join(format!(
+            "codexbar-service-only-changed-{}",
+            std::process::id()
+        ));
+        let cache = dir.join("cache.json");
+        let output = dir.join("snapshot.json");
+        let args = SnapshotArgs::try_parse_from([
+            "snapshot",
+            "--sample",
+            "--only-changed",
+            "--write-cache",
+            cache.to_str().unwrap(),
+            "--output",
+            output.to_str().unwrap(),
+        ])
+        .unwrap();
+
+        let mut stale = build_snapshot(&args).unwrap();
+        let earlier = format_timestamp(now_unix_seconds() - 60);
+        stale.generated_at = earlier.clone();
+        for entry in &mut stale.entries {
+            entry.updated_at = earlier.clone();
+        }
+        let stale_json = serde_json::to_string(&stale).unwrap();
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&cache, &stale_json).unwrap();
+        render_snapshot(&args).unwrap();
+        assert_eq!(fs::read_to_string(&cache).unwrap(), stale_json);
+
+        // Unchanged but old enough for a `--max-age` reader to drop it.
+        stale.generated_at = "2020-01-01T00:00:00Z".to_string();
+        let old_json = serde_json::to_string(&stale).unwrap();
+        fs::write(&cache, &old_json).unwrap();
+        render_snapshot(&args).unwrap();
+        assert_ne!(fs::read_to_string(&cache).unwrap(), old_json);
+
+        stale.entries[0].primary.as_mut().unwrap().used_percent = Some(1.0);
+        fs::write(&cache, serde_json::to_string(&stale).unwrap()).unwrap();
+        render_snapshot(&args).unwrap();
+        let rewritten = fs::read_to_string(&cache).unwrap();
+        assert_eq!(rewritten, fs::read_to_string(&output).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_writers_never_mix_payloads() {
+        let dir = std::env::temp_dir().join(format!(
+            "codexbar-service-concurrent-{}",
+            std::process::id()
+        ));
+        let path = dir.join("cache.json");
+        let payloads = ["a".repeat(256 * 1024), "b".repeat(256 * 1024)];
+
+        std::thread::scope(|scope| {
+            for payload in &payloads {
+                let path = &path;
+                scope.spawn(move || {
+                    for _ in 0..20 {
+                        write_file_atomically(path, payload).unwrap();
+                    }
+                });
+            }
+        });
+
+        let written = fs::read_to_string(&path).unwrap();
+        assert!(payloads.contains(&written), "mixed payload on disk");
+        assert!(!atomic_temp_path(&path).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn drops_entries_older_than_max_age() {
+        let mut snapshot = WidgetSnapshot::sample();
+        snapshot.entries[0].updated_at = "unix:9400".to_string();
+        snapshot.entries[1].updated_at = "1970-01-01T02:00:00Z".to_string();
+        let mut unparseable = snapshot.entries[0].clone();
+        unparseable.provider = "gemini".to_string();
+        unparseable.updated_at = "yesterday".to_string();
+        snapshot.entries.push(unparseable);
+
+        let mut fresh = snapshot.clone();
+        drop_stale_entries(&mut fresh, 600, 10_000);
+        assert_eq!(
+            fresh
+                .entries
+                .iter()
+                .map(|entry| entry.provider.as_str())
+                .collect::<Vec<_>>(),
+            ["codex"]
+        );
+
+        drop_stale_entries(&mut snapshot, 60, 10_000);
+        assert!(snapshot.entries.is_empty());
+        assert_eq!(snapshot.enabled_providers, ["codex", "claude"]);
+    }
+
+    #[test]
+    fn merge_keeps_freshest_entry_per_provider() {
+        let mut laptop = WidgetSnapshot::sample();
+        laptop.entries[0].updated_at = "unix:2000".to_string();
+        laptop.entries[1].updated_at = "unix:1000".to_string();
+
+        let mut desktop = WidgetSnapshot::sample();
+        desktop.enabled_providers = vec!["claude".to_string(), "gemini".to_string()];
+        desktop.entries[0].updated_at = "1970-01-01T00:25:00Z".to_string();
+        desktop.entries[0].source = Some("desktop".to_string());
+        desktop.entries[1].updated_at = "1970-01-01T00:25:00Z".to_string();
+        desktop.entries[1].source = Some("desktop".to_string());
+        let mut gemini = desktop.entries[1].clone();
+        gemini.provider = "gemini".to_string();
+        desktop.entries.push(gemini);
+
+        let merged = merge_snapshots(vec![laptop.clone(), desktop]);
+        assert_eq!(merged.enabled_providers, ["codex", "claude", "gemini"]);
+        let sources = merged
+            .entries
+            .iter()
+            .map(|entry| (entry.provider.as_str(), entry.source.as_deref().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sources,
+            [
+                ("codex", "openai-web"),
+                ("claude", "desktop"),
+                ("gemini", "desktop")
+            ]
+        );
+
+        // Equal timestamps keep the earlier input; unparseable ones never win.
+        let mut stale = laptop.clone();
+        stale.entries[0].source = Some("stale".to_string());
+        stale.entries[1].updated_at = "later".to_string();
+        stale.entries[1].source = Some("stale".to_string());
+        let merged = merge_snapshots(vec![laptop, stale]);
+        assert!(merged
+            .entries
+            .iter()
+            .all(|entry| entry.source.as_deref() != Some("stale")));
+    }
+
+    #[test]
+    fn sample_snapshot_is_marked_and_valid() {
+        let args = SnapshotArgs::try_parse_from(["snapshot", "--sample"]).unwrap();
+        let snapshot = build_snapshot(&args).unwrap();
+        assert!(!snapshot.entries.is_empty());
+        assert!(snapshot
+            .entries
+            .iter()
+            .all(|entry| entry.source.as_deref() == Some("sample")));
+        assert!(parse_unix_seconds(&snapshot.generated_at).is_some());
+
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(
+            serde_json::from_str::<WidgetSnapshot>(&json).unwrap(),
+            snapshot
+        );
+        assert!(
+            SnapshotArgs::try_parse_from(["snapshot", "--sample", "--input", "x.json"]).is_err()
+        );
+    }
+
+    #[test]
+    fn envelope_output_matches_the_bus_payload() {
+        let dir = std::env::temp_dir().join(format!("codexbar-envelope-{}", std::process::id()));
+        let output = dir.join("snapshot.json");
+        let cache = dir.join("cache.json");
+        let args = SnapshotArgs::try_parse_from([
+            "snapshot",
+            "--sample",
+            "--envelope",
+            "--output",
+            output.to_str().unwrap(),
+            "--write-cache",
+            cache.to_str().unwrap(),
+        ])
+        .unwrap();
+        render_snapshot(&args).unwrap();
+
+        let cached =
+            serde_json::from_str::<WidgetSnapshot>(&fs::read_to_string(&cache).unwrap()).unwrap();
+        let written = fs::read_to_string(&output).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&written).unwrap(),
+            serde_json::to_value(SnapshotEnvelope::new(cached)).unwrap()
+        );
+        assert!(SnapshotEnvelope::parse(&written).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "in-process")]
+    struct MockProvider {
+        id: &'static str,
+        result: fn() -> Result<Option<ProviderEntry>, codexbar_core::CodexBarError>,
+    }
+
+    #[cfg(feature = "in-process")]
+    impl codexbar_fetch::Provider for MockProvider {
+        fn id(&self) -> &'static str {
+            self.id
+        }
+
+        fn fetch(
+            &self,
+            options: &codexbar_fetch::FetchOptions,
+        ) -> Result<Option<ProviderEntry>, codexbar_core::CodexBarError> {
+            let mut entry = (self.result)()?;
+            if let Some(entry) = entry.as_mut() {
+                entry.status = options.status.then(|| codexbar_core::StatusInfo {
+                    indicator: Some("none".to_string()),
+                    description: None,
+                    updated_at: None,
+                    url: None,
+                });
+            }
+            Ok(entry)
+        }
+    }
+
+    /// `fetch_in_process` with what the service passes when there's no
+    /// `config.toml`.
+    #[cfg(feature = "in-process")]
+    fn fetch_in_process_with(
+        registry: &[Box<dyn codexbar_fetch::Provider>],
+        provider: &str,
+        status: bool,
+        include_missing: bool,
+    ) -> Result<WidgetSnapshot> {
+        let config = codexbar_fetch::UsageConfig::default();
+        let options = in_process_options(&config, status, include_missing);
+        fetch_in_process(registry, provider, &options, &|_, _| {})
+    }
+
+    #[cfg(feature = "in-process")]
+    #[test]
+    fn fetches_in_process_from_mocked_providers() {
+        use codexbar_core::CodexBarError;
+        use codexbar_fetch::Provider;
+
+        let registry: Vec<Box<dyn Provider>> = vec![
+            Box::new(MockProvider {
+                id: "codex",
+                result: || Ok(Some(WidgetSnapshot::sample().entries[0].clone())),
+            }),
+            Box::new(MockProvider {
+                id: "claude",
+                result: || Err(CodexBarError::AuthRequired("token expired".to_string())),
+            }),
+            Box::new(MockProvider {
+                id: "cursor",
+                result: || Ok(None),
+            }),
+        ];
+
+        let snapshot = fetch_in_process_with(&registry, "all", true, false).unwrap();
+        assert_eq!(snapshot.enabled_providers, ["codex"]);
+        assert_eq!(snapshot.entries.len(), 1);
+        assert!(snapshot.entries[0].status.is_some());
+        assert!(!snapshot.entries[0].windows.is_empty());
+
+        let snapshot = fetch_in_process_with(&registry, "codex", false, false).unwrap();
+        assert!(snapshot.entries[0].status.is_none());
+
+        let error = fetch_in_process_with(&registry, "claude,cursor", false, false).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<CodexBarError>(),
+            Some(CodexBarError::AuthRequired(_))
+        ));
+        assert!(fetch_in_process_with(&registry, "gemini", false, false).is_err());
+
+        // With --include-missing the failure becomes an entry instead.
+        let snapshot = fetch_in_process_with(&registry, "codex,claude", false, true).unwrap();
+        let [codex, claude] = snapshot.entries.as_slice() else {
+            panic!("expected codex and claude entries");
+        };
+        assert_eq!(codex.error, None);
+        assert_eq!(claude.provider, "claude");
+        assert_eq!(claude.source.as_deref(), Some(MISSING_SOURCE));
+        assert!(claude
+            .error
+            .as_deref()
+            .is_some_and(|error| error.contains("token expired")));
+        assert!(claude.primary.is_none() && claude.secondary.is_none());
+        assert_eq!(claude.credits_remaining, None);
+    }
+
+    #[cfg(feature = "in-process")]
+    #[test]
+    fn in_process_fetch_matches_the_cli_output() {
+        use codexbar_fetch::{Provider, UsageConfig};
+
+        let registry: Vec<Box<dyn Provider>> = vec![Box::new(MockProvider {
+            id: "codex",
+            result: || {
+                let mut entry = WidgetSnapshot::sample().entries.remove(0);
+                entry.updated_at = "2026-02-11T12:00:00Z".to_string();
+                entry.windows.clear();
+                let primary = entry.primary.as_mut().unwrap();
+                primary.used_percent = Some(28.456);
+                // Reported in hours.
+                primary.window_minutes = Some(5);
+                entry.credits_remaining = Some(92.444);
+                Ok(Some(entry))
+            },
+        })];
+        let config = UsageConfig {
+            source: Some("rust".to_string()),
+            ..UsageConfig::default()
+        };
+        let options = in_process_options(&config, false, false);
+        assert_eq!(options.source, "rust");
+        let in_process = fetch_in_process(&registry, "codex", &options, &|_, _| {}).unwrap();
+
+        // `codexbar usage --format json --provider codex` for the same entry.
+        let cli = snapshot_from_codexbar_output(
+            r#"[{"provider":"codex","version":"0.1.0","source":"openai-web","status":null,
+                "usage":{"primary":{"usedPercent":28.46,"windowMinutes":300,"resetsAt":"2026-02-11T20:00:00Z"},
+                "secondary":{"usedPercent":61.0,"windowMinutes":10080,"resetsAt":"2026-02-14T20:00:00Z"},
+                "tertiary":null,"extraWindows":[],
+                "windows":[
+                    {"usedPercent":28.46,"windowMinutes":300,"resetsAt":"2026-02-11T20:00:00Z","name":"5h","slot":"primary"},
+                    {"usedPercent":61.0,"windowMinutes":10080,"resetsAt":"2026-02-14T20:00:00Z","name":"7d","slot":"secondary"}],
+                "updatedAt":"2026-02-11T12:00:00Z",
+                "identity":{"providerID":"codex","accountEmail":"codex@example.com","accountOrganization":null,"loginMethod":"chatgpt","plan":"plus"}},
+                "credits":{"remaining":92.44,"updatedAt":"2026-02-11T12:00:00Z"},
+                "antigravityPlanInfo":null,
+                "openaiDashboard":{"codeReviewRemainingPercent":100.0,"updatedAt":"2026-02-11T12:00:00Z"}}]"#,
+        )
+        .unwrap();
+        assert_eq!(in_process.entries, cli.entries);
+    }
+
+    #[cfg(feature = "in-process")]
+    #[test]
+    fn fetch_outcomes_feed_the_health_report() {
+        use codexbar_core::{CodexBarError, FixedClock};
+        use codexbar_fetch::{Provider, UsageConfig};
+
+        let registry: Vec<Box<dyn Provider>> = vec![
+            Box::new(MockProvider {
+                id: "codex",
+                result: || Ok(Some(WidgetSnapshot::sample().entries[0].clone())),
+            }),
+            Box::new(MockProvider {
+                id: "claude",
+                result: || Err(CodexBarError::AuthRequired("token expired".to_string())),
+            }),
+            Box::new(MockProvider {
+                id: "cursor",
+                result: || Ok(None),
+            }),
+        ];
+        let health = HealthTracker::new(Arc::new(FixedClock::at_unix_seconds(1_770_832_800)));
+        let options = in_process_options(&UsageConfig::default(), false, false);
+        fetch_in_process(&registry, "all", &options, &|provider, result| {
+            record_fetch_outcome(&health, provider, result)
+        })
+        .unwrap();
+
+        let report = health.report();
+        assert!(report.degraded);
+        let [claude, codex, cursor] = report.providers.as_slice() else {
+            panic!("expected three providers, got {:?}", report.providers);
+        };
+        assert!(codex.last_success_at.is_some() && codex.last_error.is_none());
+        let claude_error = claude.last_error.as_ref().unwrap();
+        assert_eq!(claude_error.kind, "authRequired");
+        assert!(claude_error.message.contains("token expired"));
+        assert_eq!(cursor.last_error.as_ref().unwrap().kind, "noData");
+    }
+
+    #[test]
+    fn picks_earliest_upcoming_reset() {
+        let mut snapshot = WidgetSnapshot::sample();
+        let mut set_resets = |entry: usize, resets: [Option<&str>; 2]| {
+            let entry = &mut snapshot.entries[entry];
+            let mut windows = entry.windows.clone();
+            for (named, resets_at) in windows.iter_mut().zip(resets) {
+                named.window.resets_at = resets_at.map(str::to_string);
+            }
+            entry.set_windows(windows);
+        };
+        set_resets(0, [Some("unix:900"), Some("unix:5000")]);
+        set_resets(1, [Some("unix:3000"), None]);
+
+        assert_eq!(
+            next_upcoming_reset(&snapshot, 1000),
+            Some(NextReset {
+                provider: "claude".to_string(),
+                window: "5h".to_string(),
+                resets_at: "unix:3000".to_string(),
+            })
+        );
+        assert_eq!(next_upcoming_reset(&snapshot, 6000), None);
+
+        // A model-specific window outside the fixed slots, by its name.
+        let claude = &mut snapshot.entries[1];
+        let mut windows = claude.windows.clone();
+        for (name, resets_at) in [("Opus 7d", "unix:2000"), ("Haiku 1h", "unix:1500")] {
+            windows.push(NamedRateWindow {
+                name: name.to_string(),
+                slot: None,
+                window: RateWindow {
+                    used_percent: Some(10.0),
+                    window_minutes: None,
+                    resets_at: Some(resets_at.to_string()),
+                },
+            });
+        }
+        claude.set_windows(windows);
+        assert!(claude
+            .extra_windows
+            .iter()
+            .any(|window| window.resets_at.as_deref() == Some("unix:1500")));
+        assert_eq!(
+            next_upcoming_reset(&snapshot, 1000),
+            Some(NextReset {
+                provider: "claude".to_string(),
+                window: "Haiku 1h".to_string(),
+                resets_at: "unix:1500".to_string(),
+            })
+        );
+    }
+}